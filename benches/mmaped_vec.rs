@@ -0,0 +1,195 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Benchmarks of MmapedVec against the approach it replaces: keeping the data in a Vec and
+//! persisting snapshots of it with bincode.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use persistence::MmapedVec;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::hint::black_box;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use tempfile::TempDir;
+
+const MAGIC_BYTES: [u8; 8] = *b"BENCHMRK";
+const DATA_CONTAINED_VERSION: [u8; 3] = [0, 0, 1];
+
+const N: usize = 100_000;
+const MUTATIONS: usize = 100;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct Element {
+    id: u64,
+    value: f32,
+    flags: u32,
+}
+
+fn element(i: usize) -> Element {
+    Element {
+        id: i as u64,
+        value: i as f32 * 0.5,
+        flags: i as u32 & 0xff,
+    }
+}
+
+fn open(path: &Path) -> MmapedVec<Element> {
+    MmapedVec::try_new(path, MAGIC_BYTES, DATA_CONTAINED_VERSION).unwrap()
+}
+
+fn populated(dir: &TempDir) -> (MmapedVec<Element>, Vec<Element>) {
+    let mut mv = open(&dir.path().join("mmaped.bin"));
+    mv.extend((0..N).map(element)).unwrap();
+    mv.flush().unwrap();
+    (mv, (0..N).map(element).collect())
+}
+
+fn snapshot(path: &Path, v: &[Element]) {
+    let mut w = BufWriter::new(File::create(path).unwrap());
+    bincode::serialize_into(&mut w, v).unwrap();
+    w.flush().unwrap();
+    w.get_ref().sync_all().unwrap();
+}
+
+/// Indexes spread over the whole vector, without pulling in a random number generator.
+fn mutation_indexes() -> impl Iterator<Item = usize> {
+    (0..MUTATIONS).map(|i| (i * 7919) % N)
+}
+
+fn push(c: &mut Criterion) {
+    let mut group = c.benchmark_group("push");
+    group.throughput(Throughput::Elements(N as u64));
+    group.sample_size(10);
+
+    group.bench_function("MmapedVec::extend", |b| {
+        b.iter_batched(
+            || tempfile::tempdir().unwrap(),
+            |dir| {
+                let mut mv = open(&dir.path().join("mmaped.bin"));
+                mv.extend((0..N).map(element)).unwrap();
+                mv.flush().unwrap();
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.bench_function("MmapedVec::push", |b| {
+        b.iter_batched(
+            || tempfile::tempdir().unwrap(),
+            |dir| {
+                let mut mv = open(&dir.path().join("mmaped.bin"));
+                for i in 0..N {
+                    mv.push(element(i)).unwrap();
+                }
+                mv.flush().unwrap();
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.bench_function("Vec::push + bincode snapshot", |b| {
+        b.iter_batched(
+            || tempfile::tempdir().unwrap(),
+            |dir| {
+                let mut v = vec![];
+                for i in 0..N {
+                    v.push(element(i));
+                }
+                snapshot(&dir.path().join("snapshot.bin"), &v);
+            },
+            BatchSize::PerIteration,
+        )
+    });
+
+    group.finish();
+}
+
+fn mutate_and_flush(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mutate and flush");
+    group.sample_size(20);
+
+    let dir = tempfile::tempdir().unwrap();
+    let (mut mv, mut v) = populated(&dir);
+    let snapshot_path = dir.path().join("snapshot.bin");
+
+    group.bench_function("MmapedVec", |b| {
+        b.iter(|| {
+            for i in mutation_indexes() {
+                mv[i].flags ^= 1;
+            }
+            mv.flush().unwrap();
+        })
+    });
+
+    group.bench_function("Vec + bincode snapshot", |b| {
+        b.iter(|| {
+            for i in mutation_indexes() {
+                v[i].flags ^= 1;
+            }
+            snapshot(&snapshot_path, &v);
+        })
+    });
+
+    group.finish();
+}
+
+fn reopen(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reopen");
+
+    let dir = tempfile::tempdir().unwrap();
+    let (mv, v) = populated(&dir);
+    drop(mv);
+    let mmaped_path = dir.path().join("mmaped.bin");
+    let snapshot_path = dir.path().join("snapshot.bin");
+    snapshot(&snapshot_path, &v);
+
+    group.bench_function("MmapedVec", |b| b.iter(|| black_box(open(&mmaped_path))));
+
+    group.bench_function("bincode snapshot", |b| {
+        b.iter(|| {
+            let r = BufReader::new(File::open(&snapshot_path).unwrap());
+            let v: Vec<Element> = bincode::deserialize_from(r).unwrap();
+            black_box(v)
+        })
+    });
+
+    group.finish();
+}
+
+fn scan(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scan");
+    group.throughput(Throughput::Bytes(
+        (N * std::mem::size_of::<Element>()) as u64,
+    ));
+
+    let dir = tempfile::tempdir().unwrap();
+    let (mv, v) = populated(&dir);
+
+    group.bench_function("MmapedVec", |b| {
+        b.iter(|| black_box(mv.iter().map(|e| e.id).sum::<u64>()))
+    });
+
+    group.bench_function("Vec", |b| {
+        b.iter(|| black_box(v.iter().map(|e| e.id).sum::<u64>()))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, push, mutate_and_flush, reopen, scan);
+criterion_main!(benches);
@@ -0,0 +1,59 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+#ifndef PERSISTENCE_H
+#define PERSISTENCE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * Functions returning int return 0 on success and -1 on failure, after which
+ * persistence_last_error() describes what went wrong. See persistence-capi/src/lib.rs
+ * for the full documentation of each function.
+ */
+
+typedef struct PersistenceVec PersistenceVec;
+
+const char *persistence_last_error(void);
+
+int persistence_open(const char *path, const uint8_t magic_bytes[8],
+    const uint8_t data_contained_version[3], size_t elem_size, size_t elem_align,
+    const void *default_data, PersistenceVec **out);
+
+size_t persistence_len(const PersistenceVec *v);
+
+void *persistence_data(PersistenceVec *v);
+
+int persistence_push(PersistenceVec *v, const void *elem);
+
+int persistence_extend(PersistenceVec *v, const void *elems, size_t n);
+
+int persistence_get(const PersistenceVec *v, size_t index, void *out);
+
+int persistence_flush(PersistenceVec *v);
+
+int persistence_close(PersistenceVec *v);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PERSISTENCE_H */
@@ -0,0 +1,496 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! C API for using files created by the persistence crate from C and C++, declared in
+//! `include/persistence.h`.
+//!
+//! Since C has no generics, elements are opaque blobs of bytes with a size and alignment
+//! given when opening the file. These must match the size and alignment of the Rust type
+//! that other users of the file see, in the same way that the magic bytes and the data
+//! contained version must.
+//!
+//! Functions that can fail return 0 on success and -1 on failure, after which
+//! `persistence_last_error` describes what went wrong.
+//!
+//! Files are opened, grown and flushed by the same code as in the Rust library, so the
+//! header is kept up to date in the same way: the file is marked as open until it is
+//! closed, pushes are published to optimistic readers, and a checksum recorded in the
+//! header is refreshed on close.
+
+use memmap::MmapMut;
+use persistence::format;
+use persistence::MmapedVecBuilder;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::File;
+use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::{io, ptr, slice};
+
+/// Opaque handle to an open file, with elements whose size and alignment are only known at
+/// run time.
+pub struct PersistenceVec {
+    // NOTE: Declared before the file, so that it is unmapped before the lock is released.
+    mm: MmapMut,
+    file: File,
+    /// The lock file that the lock is held on, for files whose header says so.
+    _lock_file: Option<File>,
+    header_len: usize,
+    elem_size: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn report<T>(res: io::Result<T>) -> Result<T, c_int> {
+    res.map_err(|e| {
+        let msg = CString::new(e.to_string().replace('\0', "")).unwrap_or_default();
+        LAST_ERROR.with(|last| *last.borrow_mut() = Some(msg));
+        -1
+    })
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Description of the last error on the calling thread, or NULL if there has been none.
+/// Valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn persistence_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(msg) => msg.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Open or create the file at `path`, locking it in the same way as the Rust library does.
+///
+/// `default_data` points to `elem_size` bytes to store as the default data of a new file,
+/// or is NULL for a file without default data. It is ignored for existing files.
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string, `magic_bytes` must point to 8 bytes,
+/// `data_contained_version` to 3 bytes, `default_data` to `elem_size` bytes unless NULL,
+/// and `out` must be valid for writing a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn persistence_open(
+    path: *const c_char,
+    magic_bytes: *const u8,
+    data_contained_version: *const u8,
+    elem_size: usize,
+    elem_align: usize,
+    default_data: *const c_void,
+    out: *mut *mut PersistenceVec,
+) -> c_int {
+    if path.is_null() || magic_bytes.is_null() || data_contained_version.is_null() || out.is_null()
+    {
+        return report::<()>(Err(invalid_input("Required argument is NULL."))).unwrap_err();
+    }
+
+    let path = Path::new(OsStr::from_bytes(CStr::from_ptr(path).to_bytes()));
+    let mut magic = [0u8; 8];
+    magic.copy_from_slice(slice::from_raw_parts(magic_bytes, 8));
+    let mut dcv = [0u8; 3];
+    dcv.copy_from_slice(slice::from_raw_parts(data_contained_version, 3));
+    let default_data = match default_data.is_null() {
+        true => None,
+        false => Some(slice::from_raw_parts(default_data as *const u8, elem_size)),
+    };
+
+    match report(open(path, magic, dcv, elem_size, elem_align, default_data)) {
+        Ok(v) => {
+            *out = Box::into_raw(Box::new(v));
+            0
+        }
+        Err(rc) => rc,
+    }
+}
+
+/// Number of elements, or 0 if `v` is NULL.
+///
+/// # Safety
+///
+/// `v` must be NULL or a handle returned by `persistence_open` that has not been closed.
+#[no_mangle]
+pub unsafe extern "C" fn persistence_len(v: *const PersistenceVec) -> usize {
+    match v.is_null() {
+        true => 0,
+        false => (*v).len(),
+    }
+}
+
+/// Pointer to the first element, or NULL if `v` is NULL. Invalidated by `persistence_push`,
+/// `persistence_extend` and `persistence_close`. Writes through it are not published to
+/// optimistic readers.
+///
+/// # Safety
+///
+/// `v` must be NULL or a handle returned by `persistence_open` that has not been closed.
+#[no_mangle]
+pub unsafe extern "C" fn persistence_data(v: *mut PersistenceVec) -> *mut c_void {
+    if v.is_null() {
+        return ptr::null_mut();
+    }
+    let v = &mut *v;
+    v.mm[v.header_len..].as_mut_ptr() as *mut c_void
+}
+
+/// Append the `elem_size` bytes at `elem` as a new element.
+///
+/// # Safety
+///
+/// `v` must be a handle returned by `persistence_open` that has not been closed, and
+/// `elem` must point to `elem_size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn persistence_push(v: *mut PersistenceVec, elem: *const c_void) -> c_int {
+    persistence_extend(v, elem, 1)
+}
+
+/// Append the `n` elements of `elem_size` bytes each at `elems`, growing the file once to
+/// fit all of them.
+///
+/// # Safety
+///
+/// `v` must be a handle returned by `persistence_open` that has not been closed, and
+/// `elems` must point to `n` times `elem_size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn persistence_extend(
+    v: *mut PersistenceVec,
+    elems: *const c_void,
+    n: usize,
+) -> c_int {
+    if v.is_null() {
+        return report::<()>(Err(invalid_input("Handle is NULL."))).unwrap_err();
+    }
+    let v = &mut *v;
+    if elems.is_null() {
+        return report::<()>(Err(invalid_input("Element is NULL."))).unwrap_err();
+    }
+    let len_bytes = match n.checked_mul(v.elem_size) {
+        Some(len_bytes) => len_bytes,
+        None => return report::<()>(Err(invalid_input("Too many elements."))).unwrap_err(),
+    };
+
+    let elems = slice::from_raw_parts(elems as *const u8, len_bytes);
+    match report(v.extend(elems)) {
+        Ok(()) => 0,
+        Err(rc) => rc,
+    }
+}
+
+/// Copy the element at `index` into the `elem_size` bytes at `out`.
+///
+/// # Safety
+///
+/// `v` must be a handle returned by `persistence_open` that has not been closed, and
+/// `out` must be valid for writing `elem_size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn persistence_get(
+    v: *const PersistenceVec,
+    index: usize,
+    out: *mut c_void,
+) -> c_int {
+    if v.is_null() {
+        return report::<()>(Err(invalid_input("Handle is NULL."))).unwrap_err();
+    }
+    let v = &*v;
+    if out.is_null() {
+        return report::<()>(Err(invalid_input("Output is NULL."))).unwrap_err();
+    }
+
+    if index >= persistence_len(v) {
+        return report::<()>(Err(invalid_input("Index out of bounds."))).unwrap_err();
+    }
+
+    let start = v.header_len + index * v.elem_size;
+    ptr::copy_nonoverlapping(v.mm[start..].as_ptr(), out as *mut u8, v.elem_size);
+    0
+}
+
+/// Write all changes to the file.
+///
+/// # Safety
+///
+/// `v` must be a handle returned by `persistence_open` that has not been closed.
+#[no_mangle]
+pub unsafe extern "C" fn persistence_flush(v: *mut PersistenceVec) -> c_int {
+    if v.is_null() {
+        return report::<()>(Err(invalid_input("Handle is NULL."))).unwrap_err();
+    }
+    match report((*v).flush()) {
+        Ok(()) => 0,
+        Err(rc) => rc,
+    }
+}
+
+/// Flush, unmap and unlock the file, and free the handle, which is freed even if flushing
+/// fails. Closing NULL does nothing.
+///
+/// # Safety
+///
+/// `v` must be NULL or a handle returned by `persistence_open` that has not been closed.
+#[no_mangle]
+pub unsafe extern "C" fn persistence_close(v: *mut PersistenceVec) -> c_int {
+    if v.is_null() {
+        return 0;
+    }
+
+    let mut v = Box::from_raw(v);
+    match report(v.close()) {
+        Ok(()) => 0,
+        Err(rc) => rc,
+    }
+}
+
+impl PersistenceVec {
+    fn len(&self) -> usize {
+        (self.mm.len() - self.header_len) / self.elem_size
+    }
+
+    fn extend(&mut self, elems: &[u8]) -> io::Result<()> {
+        let old_len = self.mm.len();
+
+        format::begin_write(&self.mm);
+        let extended = format::resize(&self.file, &mut self.mm, (old_len + elems.len()) as u64)
+            .map(|()| self.mm[old_len..].copy_from_slice(elems));
+        format::end_write(&self.mm, self.len() as u64);
+
+        extended
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        format::flush(&self.mm, self.header_len)
+    }
+
+    /// Flush, then store the checksum and mark the file as closed cleanly, in that order,
+    /// as the Rust library does.
+    fn close(&mut self) -> io::Result<()> {
+        self.flush()?;
+        format::store_checksum(&mut self.mm)?;
+        format::set_dirty(&self.file, false)
+    }
+}
+
+fn open(
+    path: &Path,
+    magic_bytes: [u8; 8],
+    data_contained_version: [u8; 3],
+    elem_size: usize,
+    elem_align: usize,
+    default_data: Option<&[u8]>,
+) -> io::Result<PersistenceVec> {
+    if elem_size == 0 || !elem_align.is_power_of_two() || !elem_size.is_multiple_of(elem_align) {
+        return Err(invalid_input(
+            "Element size must be a non-zero multiple of the alignment, which must be a \
+             power of two.",
+        ));
+    }
+
+    let (file, lock_file) = format::open_locked(path)?;
+    let builder = MmapedVecBuilder::new(magic_bytes, data_contained_version);
+    let fh =
+        format::prepare_locked_file(&builder, &file, path, elem_size, elem_align, default_data)?;
+    let mm = unsafe { MmapMut::map_mut(&file)? };
+
+    let v = PersistenceVec {
+        mm,
+        file,
+        _lock_file: lock_file,
+        header_len: fh.header_len as usize,
+        elem_size,
+    };
+    // NOTE: Publishes the length, and ends any write that was in progress in a crash.
+    format::begin_write(&v.mm);
+    format::end_write(&v.mm, v.len() as u64);
+
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use persistence::{read_header, ChecksumAlgorithm};
+
+    const MAGIC_BYTES: [u8; 8] = *b"CAPITEST";
+    const DATA_CONTAINED_VERSION: [u8; 3] = [0, 0, 1];
+
+    fn try_open(
+        path: &Path,
+        magic_bytes: [u8; 8],
+        elem_size: usize,
+        elem_align: usize,
+    ) -> Result<*mut PersistenceVec, String> {
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let mut v = ptr::null_mut();
+        let rc = unsafe {
+            persistence_open(
+                c_path.as_ptr(),
+                magic_bytes.as_ptr(),
+                DATA_CONTAINED_VERSION.as_ptr(),
+                elem_size,
+                elem_align,
+                ptr::null(),
+                &mut v,
+            )
+        };
+        match rc {
+            0 => Ok(v),
+            _ => Err(unsafe { CStr::from_ptr(persistence_last_error()) }
+                .to_string_lossy()
+                .into_owned()),
+        }
+    }
+
+    fn open_u32(path: &Path) -> *mut PersistenceVec {
+        try_open(path, MAGIC_BYTES, 4, 4).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_with_rust() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("values.bin");
+        let mut builder = MmapedVecBuilder::new(MAGIC_BYTES, DATA_CONTAINED_VERSION);
+        builder.checksum(ChecksumAlgorithm::Crc32c);
+
+        let mut mv = builder.try_open::<u32>(&path)?;
+        mv.push(1)?;
+        mv.close()?;
+
+        let v = open_u32(&path);
+        assert!(read_header(&path)?.dirty);
+
+        let mut reader = builder.try_open_optimistic::<u32>(&path)?;
+        for value in [2u32, 3] {
+            let rc = unsafe { persistence_push(v, &value as *const u32 as *const c_void) };
+            assert_eq!(rc, 0);
+        }
+        assert_eq!(reader.len()?, 3);
+        assert_eq!(reader.read_range(0..3)?, vec![1, 2, 3]);
+
+        assert_eq!(unsafe { persistence_close(v) }, 0);
+        assert!(!read_header(&path)?.dirty);
+
+        // NOTE: Fails if the checksum was left as it was before the pushes.
+        let mv = builder.try_open::<u32>(&path)?;
+        assert_eq!(mv.recovered_from_crash(), None);
+        assert_eq!(&mv[..], &[1, 2, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_file() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("values.bin");
+        let mut builder = MmapedVecBuilder::new(MAGIC_BYTES, DATA_CONTAINED_VERSION);
+
+        let mut mv = builder.lock_file(true).try_open::<u32>(&path)?;
+        mv.push(1)?;
+        mv.close()?;
+
+        // NOTE: Writers lock the lock file, so the C API must lock it as well.
+        let v = open_u32(&path);
+        let err = builder.try_open::<u32>(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        assert_eq!(unsafe { persistence_close(v) }, 0);
+        builder.try_open::<u32>(&path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_what_rust_rejects() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("values.bin");
+        let builder = MmapedVecBuilder::new(MAGIC_BYTES, DATA_CONTAINED_VERSION);
+
+        let mut mv = builder.try_open::<u32>(&path)?;
+        mv.push(1)?;
+        mv.close()?;
+
+        // NOTE: The header is validated by the same code as in the library, so the errors
+        //       are the same too.
+        let err = try_open(&path, *b"OTHERMAG", 4, 4).err().unwrap();
+        let rust_err = MmapedVecBuilder::new(*b"OTHERMAG", DATA_CONTAINED_VERSION)
+            .try_open::<u32>(&path)
+            .err()
+            .unwrap();
+        assert_eq!(err, rust_err.to_string());
+        assert!(err.ends_with("Magic bytes mismatch."));
+
+        let err = try_open(&path, MAGIC_BYTES, 8, 8).err().unwrap();
+        let rust_err = builder.try_open::<u64>(&path).err().unwrap();
+        assert_eq!(err, rust_err.to_string());
+        assert!(err.contains("Header layout does not match the size and alignment"));
+
+        let v = open_u32(&path);
+        assert_eq!(unsafe { persistence_len(v) }, 1);
+        assert_eq!(unsafe { persistence_close(v) }, 0);
+
+        Ok(())
+    }
+    #[test]
+    fn test_extend() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("values.bin");
+
+        let v = open_u32(&path);
+        let values = [1u32, 2, 3];
+        let rc = unsafe { persistence_extend(v, values.as_ptr() as *const c_void, values.len()) };
+        assert_eq!(rc, 0);
+        assert_eq!(unsafe { persistence_extend(v, ptr::null(), 0) }, -1);
+        assert_eq!(
+            unsafe { persistence_extend(v, values.as_ptr() as *const c_void, usize::MAX) },
+            -1
+        );
+        assert_eq!(unsafe { persistence_len(v) }, 3);
+        assert_eq!(unsafe { persistence_close(v) }, 0);
+
+        let mv =
+            MmapedVecBuilder::new(MAGIC_BYTES, DATA_CONTAINED_VERSION).try_open::<u32>(&path)?;
+        assert_eq!(&mv[..], &[1, 2, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_null_handle() {
+        let value = 1u32;
+        let mut out = 0u32;
+        unsafe {
+            assert_eq!(persistence_len(ptr::null()), 0);
+            assert!(persistence_data(ptr::null_mut()).is_null());
+            assert_eq!(
+                persistence_push(ptr::null_mut(), &value as *const u32 as *const c_void),
+                -1
+            );
+            assert_eq!(
+                persistence_get(ptr::null(), 0, &mut out as *mut u32 as *mut c_void),
+                -1
+            );
+            assert_eq!(persistence_flush(ptr::null_mut()), -1);
+            assert_eq!(
+                CStr::from_ptr(persistence_last_error()).to_str(),
+                Ok("Handle is NULL.")
+            );
+        }
+    }
+}
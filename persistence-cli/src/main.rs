@@ -0,0 +1,754 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Command-line tool for operators who need to inspect, validate and dump files created by
+//! the persistence crate, without knowing the element type in Rust.
+//!
+//! Since the element type is not recorded in the file, subcommands that need to know how
+//! big the elements are take it as `--elem-size N`. Likewise, converting between
+//! endiannesses takes the layout of the elements as `--map`, a comma-separated list of the
+//! fields of an element, e.g. `u32,u16,u8,u8`. Fields may also be given as their width in
+//! bytes, and the fields must add up to the size of the element, padding included.
+
+use persistence::format::{
+    self, FileHeader, ENDIANNESS_MARKER, EXTENSION_TAG_CRITICAL, FILE_HEADER_LEN,
+    KNOWN_EXTENSION_TAGS, PERSISTENCE_FORMAT_VERSION,
+};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::ops::Deref;
+use std::os::unix::fs::FileExt as UnixFileExt;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "\
+Usage:
+    persistence inspect <FILE>
+    persistence check <FILE> [--elem-size N]
+    persistence dump <FILE> --elem-size N [--as hex|csv]
+    persistence truncate-to-valid <FILE> --elem-size N
+    persistence convert <FILE> <OUTPUT> --elem-size N [--map FIELDS] [--elem-align N]
+        [--target-endian little|big] [--from-version X.Y.Z] [--to-version X.Y.Z]";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let res = match args.first().map(String::as_str) {
+        Some("inspect") => Args::parse(&args[1..]).and_then(|a| inspect(&a)),
+        Some("check") => Args::parse(&args[1..]).and_then(|a| check(&a)),
+        Some("dump") => Args::parse(&args[1..]).and_then(|a| dump(&a)),
+        Some("truncate-to-valid") => Args::parse(&args[1..]).and_then(|a| truncate_to_valid(&a)),
+        Some("convert") => Args::parse(&args[1..]).and_then(|a| convert(&a)),
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            return;
+        }
+        _ => Err(usage_error("Missing or unknown subcommand.")),
+    };
+
+    if let Err(e) = res {
+        eprintln!("persistence: {}", e);
+        process::exit(1);
+    }
+}
+
+#[derive(Default)]
+struct Args {
+    path: String,
+    output: Option<String>,
+    elem_size: Option<usize>,
+    elem_align: Option<usize>,
+    dump_as: Option<String>,
+    map: Option<Vec<usize>>,
+    target_endian: Option<Endian>,
+    from_version: Option<[u8; 3]>,
+    to_version: Option<[u8; 3]>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Endian {
+    Little,
+    Big,
+}
+
+const HOST_ENDIAN: Endian = if cfg!(target_endian = "little") {
+    Endian::Little
+} else {
+    Endian::Big
+};
+
+impl Args {
+    fn parse(args: &[String]) -> io::Result<Self> {
+        let mut parsed = Self::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--elem-size" => {
+                    let n = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| usage_error("--elem-size takes a positive integer."))?;
+                    parsed.elem_size = Some(n);
+                }
+                "--elem-align" => {
+                    let n = args
+                        .next()
+                        .and_then(|n| n.parse::<usize>().ok())
+                        .filter(|n| n.is_power_of_two())
+                        .ok_or_else(|| usage_error("--elem-align takes a power of two."))?;
+                    parsed.elem_align = Some(n);
+                }
+                "--map" => {
+                    let map = args
+                        .next()
+                        .and_then(|m| parse_map(m))
+                        .ok_or_else(|| usage_error("--map takes fields like u32,u16,u8,u8."))?;
+                    parsed.map = Some(map);
+                }
+                "--target-endian" => {
+                    parsed.target_endian = Some(match args.next().map(String::as_str) {
+                        Some("little") => Endian::Little,
+                        Some("big") => Endian::Big,
+                        _ => return Err(usage_error("--target-endian takes little or big.")),
+                    });
+                }
+                "--from-version" | "--to-version" => {
+                    let v = args.next().and_then(|v| parse_version(v)).ok_or_else(|| {
+                        usage_error(&format!("{} takes a version like 0.0.7.", arg))
+                    })?;
+                    match arg.as_str() {
+                        "--from-version" => parsed.from_version = Some(v),
+                        _ => parsed.to_version = Some(v),
+                    }
+                }
+                "--as" => {
+                    parsed.dump_as = Some(
+                        args.next()
+                            .cloned()
+                            .ok_or_else(|| usage_error("--as takes hex or csv."))?,
+                    );
+                }
+                _ if arg.starts_with("--") => {
+                    return Err(usage_error(&format!("Unknown option `{}`.", arg)))
+                }
+                _ if parsed.path.is_empty() => parsed.path = arg.clone(),
+                _ if parsed.output.is_none() => parsed.output = Some(arg.clone()),
+                _ => return Err(usage_error("Too many files given.")),
+            }
+        }
+
+        if parsed.path.is_empty() {
+            return Err(usage_error("No file given."));
+        }
+
+        Ok(parsed)
+    }
+
+    fn elem_size(&self) -> io::Result<usize> {
+        self.elem_size
+            .ok_or_else(|| usage_error("This subcommand needs --elem-size."))
+    }
+}
+
+// TODO: Let --map name a plugin, i.e. a shared library exporting a conversion function, for
+//       element types that need more than the bytes of each field reversed, such as those
+//       containing pointers or variable-size data. The field list covers plain data for now.
+fn parse_map(map: &str) -> Option<Vec<usize>> {
+    map.split(',')
+        .map(|field| match field.trim() {
+            "u8" | "i8" | "bool" => Some(1),
+            "u16" | "i16" => Some(2),
+            "u32" | "i32" | "f32" | "char" => Some(4),
+            "u64" | "i64" | "f64" => Some(8),
+            "u128" | "i128" => Some(16),
+            n => n.parse().ok().filter(|&n| n > 0),
+        })
+        .collect()
+}
+
+fn parse_version(v: &str) -> Option<[u8; 3]> {
+    let parts: Vec<u8> = v
+        .split('.')
+        .map(|p| p.parse().ok())
+        .collect::<Option<_>>()?;
+    match parts[..] {
+        [major, minor, patch] => Some([major, minor, patch]),
+        _ => None,
+    }
+}
+
+fn usage_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}\n\n{}", msg, USAGE))
+}
+
+/// A file that is locked for as long as this is around.
+struct LockedFile {
+    file: File,
+    // NOTE: Holds the lock of files that are locked through their lock file.
+    _lock_file: Option<File>,
+}
+
+impl Deref for LockedFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+/// Open the file and take the same lock that the library does, on its lock file if it has
+/// one, so that we never look at a file while a MmapedVec has it open. Files opened for
+/// writing are locked exclusively, and others shared, as readers do.
+fn open_locked(path: &Path, write: bool) -> io::Result<LockedFile> {
+    let file = OpenOptions::new().read(true).write(write).open(path)?;
+    let lock_file = match write {
+        true => format::try_lock_exclusive_for(&file, path),
+        false => format::try_lock_shared_for(&file, path),
+    }
+    .map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "File `{:?}`: Could not lock file; is it in use? ({})",
+                path, e
+            ),
+        )
+    })?;
+    Ok(LockedFile {
+        file,
+        _lock_file: lock_file,
+    })
+}
+
+fn read_header(path: &Path, file: &File) -> io::Result<FileHeader> {
+    if file.metadata()?.len() < FILE_HEADER_LEN as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("File `{:?}`: Too short to contain a header.", path),
+        ));
+    }
+
+    FileHeader::read_from(file)
+}
+
+fn version(v: [u8; 3]) -> String {
+    format!("{}.{}.{}", v[0], v[1], v[2])
+}
+
+fn inspect(args: &Args) -> io::Result<()> {
+    let path = Path::new(&args.path);
+    let file = open_locked(path, false)?;
+    let fh = read_header(path, &file)?;
+    let flen = file.metadata()?.len();
+
+    println!("file length:                {}", flen);
+    println!(
+        "magic bytes:                {:02x?} ({:?})",
+        fh.magic_bytes,
+        String::from_utf8_lossy(&fh.magic_bytes)
+    );
+    println!(
+        "endianness:                 {}",
+        match fh.endianness {
+            ENDIANNESS_MARKER => "same as this host",
+            m if m == ENDIANNESS_MARKER.swap_bytes() => "opposite of this host",
+            _ => "invalid",
+        }
+    );
+    println!(
+        "persistence format version: {}",
+        version(fh.persistence_format_version)
+    );
+    println!(
+        "data contained version:     {}",
+        version(fh.data_contained_version)
+    );
+
+    if fh.persistence_format_version != PERSISTENCE_FORMAT_VERSION
+        || (fh.endianness != ENDIANNESS_MARKER && fh.endianness != ENDIANNESS_MARKER.swap_bytes())
+    {
+        println!("(remaining fields are not shown, since they cannot be decoded on this host)");
+        return Ok(());
+    }
+
+    let is_native = fh.endianness == ENDIANNESS_MARKER;
+    let fh = match is_native {
+        true => fh,
+        false => fh.swap_bytes(),
+    };
+
+    println!("flags:                      {:#010x}", fh.flags);
+    println!("left dirty by a crash:      {}", fh.is_dirty());
+    println!("default data offset:        {}", fh.default_data_offset);
+    println!("default data length:        {}", fh.default_data_len);
+    println!("extensions offset:          {}", fh.extensions_offset);
+    println!("header length:              {}", fh.header_len);
+    println!(
+        "body length:                {}",
+        flen.saturating_sub(fh.header_len)
+    );
+
+    if let Some(area) = extensions_area(&file, &fh)? {
+        let extensions = match is_native {
+            true => format::parse_extensions(&area),
+            false => format::parse_foreign_extensions(&area),
+        };
+        for (tag, range) in extensions.unwrap_or_default() {
+            println!(
+                "extension {:#06x}:           {} bytes",
+                tag,
+                range.end - range.start
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn extensions_area(file: &File, fh: &FileHeader) -> io::Result<Option<Vec<u8>>> {
+    if fh.extensions_offset == 0 || fh.extensions_offset as u64 > fh.header_len {
+        return Ok(None);
+    }
+
+    let mut area = vec![0u8; (fh.header_len - fh.extensions_offset as u64) as usize];
+    file.read_exact_at(&mut area, fh.extensions_offset as u64)?;
+    Ok(Some(area))
+}
+
+/// Everything that is wrong with the file, as far as can be told without knowing the
+/// element type.
+fn problems(file: &File, fh: &FileHeader, elem_size: Option<usize>) -> io::Result<Vec<String>> {
+    let mut problems = vec![];
+
+    if fh.endianness == ENDIANNESS_MARKER.swap_bytes() {
+        problems.push("written on a host with the opposite endianness".to_string());
+        return Ok(problems);
+    } else if fh.endianness != ENDIANNESS_MARKER {
+        problems.push("invalid endianness marker".to_string());
+        return Ok(problems);
+    }
+
+    if fh.persistence_format_version != PERSISTENCE_FORMAT_VERSION {
+        problems.push(format!(
+            "persistence format version {} is not {}",
+            version(fh.persistence_format_version),
+            version(PERSISTENCE_FORMAT_VERSION)
+        ));
+        return Ok(problems);
+    }
+
+    let flen = file.metadata()?.len();
+    let default_data_end = fh.default_data_offset as u64 + fh.default_data_len as u64;
+
+    if fh.header_len < FILE_HEADER_LEN as u64 || fh.header_len > flen {
+        problems.push(format!(
+            "header length {} is out of bounds for a file of {} bytes",
+            fh.header_len, flen
+        ));
+        return Ok(problems);
+    }
+
+    if fh.has_default_data()
+        && (fh.default_data_offset < FILE_HEADER_LEN as u32 || default_data_end > fh.header_len)
+    {
+        problems.push("default data lies outside of the header".to_string());
+    }
+
+    match extensions_area(file, fh)? {
+        None => problems.push("extensions area lies outside of the header".to_string()),
+        Some(area) => match format::parse_extensions(&area) {
+            None => problems.push("malformed header extensions".to_string()),
+            Some(extensions) => {
+                for (tag, _) in extensions {
+                    if tag & EXTENSION_TAG_CRITICAL != 0 && !KNOWN_EXTENSION_TAGS.contains(&tag) {
+                        problems.push(format!("unsupported critical extension {:#06x}", tag));
+                    }
+                }
+            }
+        },
+    }
+
+    if let Some(elem_size) = elem_size {
+        if fh.has_default_data() && fh.default_data_len as usize != elem_size {
+            problems.push(format!(
+                "default data is {} bytes, but elements are {} bytes",
+                fh.default_data_len, elem_size
+            ));
+        }
+
+        let trailing = (flen - fh.header_len) % elem_size as u64;
+        if trailing != 0 {
+            problems.push(format!(
+                "body ends with {} bytes of a partial element",
+                trailing
+            ));
+        }
+    }
+
+    Ok(problems)
+}
+
+fn check(args: &Args) -> io::Result<()> {
+    let path = Path::new(&args.path);
+    let file = open_locked(path, false)?;
+    let fh = read_header(path, &file)?;
+    let problems = problems(&file, &fh, args.elem_size)?;
+
+    if problems.is_empty() {
+        println!("{}: ok", args.path);
+        return Ok(());
+    }
+
+    for problem in &problems {
+        println!("{}: {}", args.path, problem);
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("File `{:?}`: {} problem(s) found.", path, problems.len()),
+    ))
+}
+
+/// Open the file for a subcommand that needs the body, refusing if the header is not sound.
+fn open_checked(args: &Args, write: bool) -> io::Result<(LockedFile, FileHeader)> {
+    let path = Path::new(&args.path);
+    let file = open_locked(path, write)?;
+    let fh = read_header(path, &file)?;
+
+    // A partial trailing element is fine here; it is what truncate-to-valid is for.
+    let problems = problems(&file, &fh, None)?;
+    if let Some(problem) = problems.first() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("File `{:?}`: {}.", path, problem),
+        ));
+    }
+
+    Ok((file, fh))
+}
+
+fn dump(args: &Args) -> io::Result<()> {
+    let elem_size = args.elem_size()?;
+    let csv = match args.dump_as.as_deref() {
+        None | Some("hex") => false,
+        Some("csv") => true,
+        Some(other) => return Err(usage_error(&format!("Cannot dump as `{}`.", other))),
+    };
+
+    let (file, fh) = open_checked(args, false)?;
+    let n = (file.metadata()?.len() - fh.header_len) / elem_size as u64;
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut elem = vec![0u8; elem_size];
+
+    if csv {
+        writeln!(out, "index,offset,bytes")?;
+    }
+
+    for i in 0..n {
+        let offset = fh.header_len + i * elem_size as u64;
+        file.read_exact_at(&mut elem, offset)?;
+
+        let hex: Vec<String> = elem.iter().map(|b| format!("{:02x}", b)).collect();
+        if csv {
+            writeln!(out, "{},{},{}", i, offset, hex.concat())?;
+        } else {
+            writeln!(out, "{:>10}  {:08x}  {}", i, offset, hex.join(" "))?;
+        }
+    }
+
+    out.flush()
+}
+
+fn truncate_to_valid(args: &Args) -> io::Result<()> {
+    let elem_size = args.elem_size()? as u64;
+    let (file, fh) = open_checked(args, true)?;
+    let flen = file.metadata()?.len();
+    let valid_len = flen - (flen - fh.header_len) % elem_size;
+
+    if valid_len == flen {
+        println!("{}: nothing to truncate", args.path);
+        return Ok(());
+    }
+
+    file.set_len(valid_len)?;
+    file.sync_all()?;
+
+    println!(
+        "{}: truncated from {} to {} bytes",
+        args.path, flen, valid_len
+    );
+
+    Ok(())
+}
+
+/// Convert a file to another endianness and/or persistence format version, writing the
+/// result to a new file and leaving the original as it is.
+fn convert(args: &Args) -> io::Result<()> {
+    let elem_size = args.elem_size()?;
+    let path = Path::new(&args.path);
+    let output = Path::new(
+        args.output
+            .as_deref()
+            .ok_or_else(|| usage_error("convert needs an output file."))?,
+    );
+
+    if let Some(map) = &args.map {
+        if map.iter().sum::<usize>() != elem_size {
+            return Err(usage_error(
+                "The fields given by --map must add up to --elem-size.",
+            ));
+        }
+    }
+
+    let file = open_locked(path, false)?;
+    let fh_raw = read_header(path, &file)?;
+    let flen = file.metadata()?.len();
+
+    let source_endian = match fh_raw.endianness {
+        ENDIANNESS_MARKER => HOST_ENDIAN,
+        m if m == ENDIANNESS_MARKER.swap_bytes() => match HOST_ENDIAN {
+            Endian::Little => Endian::Big,
+            Endian::Big => Endian::Little,
+        },
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("File `{:?}`: Invalid endianness marker.", path),
+            ))
+        }
+    };
+    let target_endian = args.target_endian.unwrap_or(source_endian);
+    let swap = source_endian != target_endian;
+
+    let source_version = fh_raw.persistence_format_version;
+    if let Some(from_version) = args.from_version {
+        if from_version != source_version {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "File `{:?}`: Has persistence format version {}, not {}.",
+                    path,
+                    version(source_version),
+                    version(from_version)
+                ),
+            ));
+        }
+    }
+
+    if let Some(to_version) = args.to_version {
+        if to_version != PERSISTENCE_FORMAT_VERSION {
+            return Err(usage_error(&format!(
+                "Can only convert to persistence format version {}.",
+                version(PERSISTENCE_FORMAT_VERSION)
+            )));
+        }
+    }
+
+    let field_widths = match (&args.map, swap) {
+        (Some(map), _) => map.clone(),
+        (None, false) => vec![elem_size],
+        (None, true) => return Err(usage_error("Converting endianness needs --map.")),
+    };
+
+    // Gather the parts of the source file, with the header in native byte order.
+    let (fh, old_header_len, default_data, mut extensions) = match source_version {
+        PERSISTENCE_FORMAT_VERSION => {
+            let fh = match source_endian == HOST_ENDIAN {
+                true => fh_raw,
+                false => fh_raw.swap_bytes(),
+            };
+
+            let mut default_data = vec![0u8; fh.default_data_len as usize];
+            file.read_exact_at(&mut default_data, fh.default_data_offset as u64)?;
+
+            let extensions = extensions_area(&file, &fh)?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "File `{:?}`: Extensions area lies outside of the header.",
+                        path
+                    ),
+                )
+            })?;
+
+            (fh, fh.header_len, default_data, extensions)
+        }
+        v if format::historical_format(v).is_some() => {
+            let layout = (format::historical_format(v).unwrap().layout)(elem_size);
+            let elem_align = args
+                .elem_align
+                .or_else(|| args.map.as_ref().and_then(|m| m.iter().copied().max()))
+                .ok_or_else(|| {
+                    usage_error(&format!(
+                        "Converting from {} needs --elem-align or --map.",
+                        version(v)
+                    ))
+                })?;
+
+            let mut default_data = vec![];
+            if let Some(offset) = layout.default_data_offset {
+                default_data.resize(elem_size, 0);
+                file.read_exact_at(&mut default_data, offset as u64)?;
+            }
+
+            let fh = FileHeader::with_layout(
+                fh_raw.magic_bytes,
+                fh_raw.data_contained_version,
+                elem_size,
+                elem_align,
+                layout.default_data_offset.is_some(),
+            );
+
+            (
+                fh,
+                layout.header_len,
+                default_data,
+                vec![0u8; (fh.header_len - fh.extensions_offset as u64) as usize],
+            )
+        }
+        v => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "File `{:?}`: Unsupported persistence format version {}.",
+                    path,
+                    version(v)
+                ),
+            ))
+        }
+    };
+
+    if flen < old_header_len || (flen - old_header_len) % elem_size as u64 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "File `{:?}`: Body is not a whole number of {}-byte elements.",
+                path, elem_size
+            ),
+        ));
+    }
+
+    if fh.has_default_data() && default_data.len() != elem_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "File `{:?}`: Default data is {} bytes, but elements are {} bytes.",
+                path,
+                default_data.len(),
+                elem_size
+            ),
+        ));
+    }
+
+    let mut default_data = default_data;
+    if swap {
+        if fh.has_default_data() {
+            format::swap_element_bytes(&mut default_data, &field_widths);
+        }
+
+        format::swap_extension_bytes(
+            &mut extensions,
+            fh.extensions_offset as usize,
+            source_endian == HOST_ENDIAN,
+        )
+        .map_err(|e| io::Error::new(e.kind(), format!("File `{:?}`: {}", path, e)))?;
+    }
+
+    let fh_out = match target_endian == HOST_ENDIAN {
+        true => fh,
+        false => fh.swap_bytes(),
+    };
+
+    let out = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(output)?;
+
+    let result = write_converted(
+        &file,
+        &out,
+        &fh_out,
+        &fh,
+        &default_data,
+        &extensions,
+        old_header_len,
+        &field_widths,
+        swap,
+    );
+
+    if result.is_err() {
+        drop(out);
+        let _ = std::fs::remove_file(output);
+    }
+
+    result?;
+
+    println!(
+        "{}: converted to {} ({}, persistence format version {})",
+        args.path,
+        output.display(),
+        match target_endian {
+            Endian::Little => "little-endian",
+            Endian::Big => "big-endian",
+        },
+        version(PERSISTENCE_FORMAT_VERSION)
+    );
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_converted(
+    file: &File,
+    out: &File,
+    fh_out: &FileHeader,
+    fh: &FileHeader,
+    default_data: &[u8],
+    extensions: &[u8],
+    old_header_len: u64,
+    field_widths: &[usize],
+    swap: bool,
+) -> io::Result<()> {
+    fh_out.write_to(&mut &*out)?;
+
+    if fh.has_default_data() {
+        out.write_all_at(default_data, fh.default_data_offset as u64)?;
+    }
+
+    out.write_all_at(extensions, fh.extensions_offset as u64)?;
+    out.set_len(fh.header_len)?;
+
+    let body_len = file.metadata()?.len() - old_header_len;
+    let chunk_len = field_widths.iter().sum::<usize>() * 4096;
+    let mut buf = vec![0u8; chunk_len];
+    let mut done = 0u64;
+
+    while done < body_len {
+        let n = chunk_len.min((body_len - done) as usize);
+        file.read_exact_at(&mut buf[..n], old_header_len + done)?;
+        if swap {
+            format::swap_element_bytes(&mut buf[..n], field_widths);
+        }
+        out.write_all_at(&buf[..n], fh.header_len + done)?;
+        done += n as u64;
+    }
+
+    out.sync_all()
+}
@@ -0,0 +1,259 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use persistence::format::{self, FileHeader, EXTENSION_TAG_CHECKSUM, EXTENSION_TAG_HINTS};
+use persistence::{ChecksumAlgorithm, MmapedVecBuilder};
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::process::{Command, Output};
+
+const MAGIC_BYTES: [u8; 8] = *b"CLI_TEST";
+const DATA_CONTAINED_VERSION: [u8; 3] = [0, 0, 1];
+
+fn persistence(args: &[&str], path: &Path) -> io::Result<Output> {
+    Command::new(env!("CARGO_BIN_EXE_persistence"))
+        .arg(args[0])
+        .arg(path)
+        .args(&args[1..])
+        .output()
+}
+
+#[test]
+pub fn test_lock_file_is_honored() -> Result<(), io::Error> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("values.bin");
+    let mut builder = MmapedVecBuilder::new(MAGIC_BYTES, DATA_CONTAINED_VERSION);
+
+    let mut mv = builder.lock_file(true).try_open::<u32>(&path)?;
+    mv.push(1)?;
+    mv.flush()?;
+
+    // NOTE: The writer holds the lock on the lock file, not on the file itself.
+    for args in [
+        &["inspect"][..],
+        &["check", "--elem-size", "4"],
+        &["truncate-to-valid", "--elem-size", "4"],
+    ] {
+        let out = persistence(args, &path)?;
+        assert!(!out.status.success(), "{:?} succeeded", args);
+        assert!(String::from_utf8_lossy(&out.stderr).contains("Could not lock file"));
+    }
+
+    mv.close()?;
+    assert!(persistence(&["check", "--elem-size", "4"], &path)?
+        .status
+        .success());
+
+    Ok(())
+}
+
+#[test]
+pub fn test_convert_endianness_round_trips() -> Result<(), io::Error> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("values.bin");
+    let (foreign, back) = (dir.path().join("foreign.bin"), dir.path().join("back.bin"));
+    let mut builder = MmapedVecBuilder::new(MAGIC_BYTES, DATA_CONTAINED_VERSION);
+    builder
+        .checksum(ChecksumAlgorithm::Crc32c)
+        .header_hints(true);
+
+    let mut mv = builder.try_open::<u32>(&path)?;
+    mv.extend([1, 2, 3])?;
+    let hints = mv.header_extension(EXTENSION_TAG_HINTS).unwrap().to_vec();
+    mv.close()?;
+
+    let to_foreign = [
+        "convert",
+        foreign.to_str().unwrap(),
+        "--elem-size",
+        "4",
+        "--map",
+        "u32",
+        "--target-endian",
+        match cfg!(target_endian = "little") {
+            true => "big",
+            false => "little",
+        },
+    ];
+    assert!(persistence(&to_foreign, &path)?.status.success());
+
+    // NOTE: The values of the extensions are converted too, and the checksum is dropped, as
+    //       it no longer holds for the body.
+    let file = File::open(&foreign)?;
+    let fh = FileHeader::read_from(&file)?.swap_bytes();
+    let mut area = vec![0u8; (fh.header_len - fh.extensions_offset as u64) as usize];
+    file.read_exact_at(&mut area, fh.extensions_offset as u64)?;
+    let extensions = format::parse_foreign_extensions(&area).unwrap();
+    assert!(!extensions
+        .iter()
+        .any(|(tag, _)| *tag == EXTENSION_TAG_CHECKSUM));
+    let (_, range) = extensions
+        .iter()
+        .find(|(tag, _)| *tag == EXTENSION_TAG_HINTS)
+        .unwrap();
+    assert_eq!(
+        area[range.start..range.start + 8],
+        4u64.swap_bytes().to_ne_bytes()
+    );
+
+    let to_native = [
+        "convert",
+        back.to_str().unwrap(),
+        "--elem-size",
+        "4",
+        "--map",
+        "u32",
+        "--target-endian",
+        match cfg!(target_endian = "little") {
+            true => "little",
+            false => "big",
+        },
+    ];
+    assert!(persistence(&to_native, &foreign)?.status.success());
+
+    let mv = builder.try_open::<u32>(&back)?;
+    assert_eq!(&mv[..], &[1, 2, 3]);
+    assert_eq!(mv.header_extension(EXTENSION_TAG_HINTS), Some(&hints[..]));
+
+    Ok(())
+}
+
+fn new_file_of_u32(path: &Path, values: &[u32]) -> Result<(), io::Error> {
+    let mut mv =
+        MmapedVecBuilder::new(MAGIC_BYTES, DATA_CONTAINED_VERSION).try_open::<u32>(path)?;
+    mv.extend(values.iter().copied())?;
+    mv.close()
+}
+
+fn stdout(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stdout).into_owned()
+}
+
+#[test]
+pub fn test_inspect() -> Result<(), io::Error> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("values.bin");
+    new_file_of_u32(&path, &[1, 2])?;
+
+    let out = persistence(&["inspect"], &path)?;
+    assert!(out.status.success());
+    let out = stdout(&out);
+    assert!(out.contains("\"CLI_TEST\""));
+    assert!(out.contains("endianness:                 same as this host"));
+    assert!(out.contains("data contained version:     0.0.1"));
+    assert!(out.contains("left dirty by a crash:      false"));
+    assert!(out.contains("extension 0x0001:"));
+
+    Ok(())
+}
+
+#[test]
+pub fn test_check_and_truncate_to_valid() -> Result<(), io::Error> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("values.bin");
+    new_file_of_u32(&path, &[1, 2])?;
+    let flen = path.metadata()?.len();
+
+    assert!(persistence(&["check", "--elem-size", "4"], &path)?
+        .status
+        .success());
+
+    // A write of a third element that was cut short.
+    File::options()
+        .append(true)
+        .open(&path)?
+        .write_all(&[3, 0])?;
+
+    let out = persistence(&["check", "--elem-size", "4"], &path)?;
+    assert!(!out.status.success());
+    assert!(stdout(&out).contains("body ends with 2 bytes of a partial element"));
+
+    assert!(!persistence(&["truncate-to-valid"], &path)?.status.success());
+    let out = persistence(&["truncate-to-valid", "--elem-size", "4"], &path)?;
+    assert!(out.status.success());
+    assert!(stdout(&out).contains(&format!("truncated from {} to {} bytes", flen + 2, flen)));
+    assert!(stdout(&persistence(
+        &["truncate-to-valid", "--elem-size", "4"],
+        &path
+    )?)
+    .contains("nothing to truncate"));
+
+    let mv = MmapedVecBuilder::new(MAGIC_BYTES, DATA_CONTAINED_VERSION).try_open::<u32>(&path)?;
+    assert_eq!(&mv[..], &[1, 2]);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_dump() -> Result<(), io::Error> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("values.bin");
+    new_file_of_u32(&path, &[1, 0x0a0b0c0d])?;
+
+    let out = stdout(&persistence(&["dump", "--elem-size", "4"], &path)?);
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].trim_start().starts_with("0  "));
+    assert!(lines[1].ends_with(match cfg!(target_endian = "little") {
+        true => "0d 0c 0b 0a",
+        false => "0a 0b 0c 0d",
+    }));
+
+    let out = stdout(&persistence(
+        &["dump", "--elem-size", "4", "--as", "csv"],
+        &path,
+    )?);
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines[0], "index,offset,bytes");
+    assert_eq!(lines.len(), 3);
+    assert!(lines[1].starts_with("0,"));
+
+    assert!(
+        !persistence(&["dump", "--elem-size", "4", "--as", "xml"], &path)?
+            .status
+            .success()
+    );
+
+    Ok(())
+}
+
+#[test]
+pub fn test_convert_leaves_existing_output_alone() -> Result<(), io::Error> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("values.bin");
+    let output = dir.path().join("output.bin");
+    new_file_of_u32(&path, &[1])?;
+    std::fs::write(&output, b"already here")?;
+
+    let convert = ["convert", output.to_str().unwrap(), "--elem-size", "4"];
+    assert!(!persistence(&convert, &path)?.status.success());
+    assert_eq!(std::fs::read(&output)?, b"already here");
+
+    let convert = [
+        "convert",
+        output.to_str().unwrap(),
+        "--elem-size",
+        "3",
+        "--map",
+        "u32",
+    ];
+    let out = persistence(&convert, &path)?;
+    assert!(String::from_utf8_lossy(&out.stderr).contains("must add up to --elem-size"));
+
+    Ok(())
+}
@@ -0,0 +1,277 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Python bindings for reading files created by the persistence crate, for analytics and
+//! debugging workflows.
+//!
+//! Files are opened read-only, with a shared lock, and expose their body through the
+//! buffer protocol without copying, so that `numpy.asarray` sees the elements in place:
+//!
+//! ```python
+//! import numpy, persistence
+//!
+//! with persistence.open("data.bin", b"EXAMPLE0", (0, 0, 1), 8, 4, "<If") as a:
+//!     elems = numpy.asarray(a)
+//! ```
+//!
+//! Without a struct format string for the elements, each element is exposed as a row of
+//! `elem_size` unsigned bytes.
+
+use memmap::Mmap;
+use persistence::format;
+use pyo3::exceptions::{PyBufferError, PyValueError};
+use pyo3::ffi;
+use pyo3::prelude::*;
+use pyo3::types::PyModule;
+use std::ffi::CString;
+use std::fs::File;
+use std::os::raw::{c_int, c_void};
+use std::path::PathBuf;
+use std::ptr;
+
+/// Read-only view of the elements of a file, exposed through the buffer protocol.
+#[pyclass(module = "persistence")]
+struct MmapedArray {
+    // NOTE: Declared before the file, so that it is unmapped before the lock is released.
+    mm: Option<Mmap>,
+    file: Option<File>,
+    /// The lock file that the lock is held on, for files whose header says so.
+    lock_file: Option<File>,
+    header_len: usize,
+    elem_size: usize,
+    format: CString,
+    ndim: c_int,
+    shape: [isize; 2],
+    strides: [isize; 2],
+    exports: usize,
+}
+
+impl MmapedArray {
+    fn mm(&self) -> PyResult<&Mmap> {
+        self.mm
+            .as_ref()
+            .ok_or_else(|| PyValueError::new_err("I/O operation on closed array."))
+    }
+}
+
+#[pymethods]
+impl MmapedArray {
+    fn __len__(&self) -> PyResult<usize> {
+        Ok((self.mm()?.len() - self.header_len) / self.elem_size)
+    }
+
+    #[getter]
+    fn elem_size(&self) -> usize {
+        self.elem_size
+    }
+
+    /// Unmap and unlock the file. Fails while buffers exported from the array are alive.
+    fn close(&mut self) -> PyResult<()> {
+        if self.exports > 0 {
+            return Err(PyBufferError::new_err(
+                "Cannot close an array while its buffer is exported.",
+            ));
+        }
+
+        self.mm = None;
+        self.file = None;
+        self.lock_file = None;
+        Ok(())
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    fn __exit__(
+        &mut self,
+        _exc_type: Py<PyAny>,
+        _exc_value: Py<PyAny>,
+        _traceback: Py<PyAny>,
+    ) -> PyResult<()> {
+        self.close()
+    }
+
+    unsafe fn __getbuffer__(
+        slf: Bound<'_, Self>,
+        view: *mut ffi::Py_buffer,
+        flags: c_int,
+    ) -> PyResult<()> {
+        if flags & ffi::PyBUF_WRITABLE == ffi::PyBUF_WRITABLE {
+            return Err(PyBufferError::new_err("Array is read-only."));
+        }
+
+        let mut this = slf.borrow_mut();
+        let body = &this.mm()?[this.header_len..];
+        let (buf, len) = (body.as_ptr(), body.len());
+        let structured = flags & ffi::PyBUF_ND == ffi::PyBUF_ND;
+
+        (*view).obj = slf.clone().into_any().into_ptr();
+        (*view).buf = buf as *mut c_void;
+        (*view).len = len as isize;
+        (*view).readonly = 1;
+        (*view).itemsize = match (structured, this.ndim) {
+            (true, 1) => this.elem_size as isize,
+            _ => 1,
+        };
+        (*view).format = match flags & ffi::PyBUF_FORMAT == ffi::PyBUF_FORMAT {
+            true if structured => this.format.as_ptr() as *mut _,
+            _ => ptr::null_mut(),
+        };
+        (*view).ndim = match structured {
+            true => this.ndim,
+            false => 1,
+        };
+        (*view).shape = match structured {
+            true => this.shape.as_mut_ptr(),
+            false => ptr::null_mut(),
+        };
+        (*view).strides = match flags & ffi::PyBUF_STRIDES == ffi::PyBUF_STRIDES {
+            true if structured => this.strides.as_mut_ptr(),
+            _ => ptr::null_mut(),
+        };
+        (*view).suboffsets = ptr::null_mut();
+        (*view).internal = ptr::null_mut();
+
+        this.exports += 1;
+        Ok(())
+    }
+
+    unsafe fn __releasebuffer__(&mut self, _view: *mut ffi::Py_buffer) {
+        self.exports -= 1;
+    }
+}
+
+/// Open the file at `path` read-only, taking a shared lock, so that it cannot be opened for
+/// writing by the Rust library until the array is closed.
+///
+/// `elem_size` and `elem_align` must match the size and alignment of the Rust type that the
+/// file contains. `format` is an optional struct format string for the elements, such as
+/// `"<If"`, whose size must be `elem_size`.
+#[pyfunction]
+#[pyo3(signature = (path, magic_bytes, data_contained_version, elem_size, elem_align, format=None))]
+fn open(
+    py: Python<'_>,
+    path: PathBuf,
+    magic_bytes: [u8; 8],
+    data_contained_version: [u8; 3],
+    elem_size: usize,
+    elem_align: usize,
+    format: Option<&str>,
+) -> PyResult<MmapedArray> {
+    if elem_size == 0 || !elem_align.is_power_of_two() || !elem_size.is_multiple_of(elem_align) {
+        return Err(PyValueError::new_err(
+            "Element size must be a non-zero multiple of the alignment, which must be a \
+             power of two.",
+        ));
+    }
+
+    let ndim = match format {
+        Some(format) => {
+            let calcsize: usize = PyModule::import(py, "struct")?
+                .call_method1("calcsize", (format,))?
+                .extract()?;
+            if calcsize != elem_size {
+                return Err(PyValueError::new_err(format!(
+                    "Format {:?} describes {} bytes, but elements are {} bytes.",
+                    format, calcsize, elem_size
+                )));
+            }
+            1
+        }
+        None => 2,
+    };
+    let format = CString::new(format.unwrap_or("B"))?;
+
+    let file = File::open(&path)?;
+    let lock_file = format::try_lock_shared_for(&file, &path)?;
+
+    let fh = format::check_existing_file(
+        &file,
+        &path,
+        magic_bytes,
+        data_contained_version,
+        elem_size,
+        elem_align,
+    )?;
+
+    let mm = unsafe { Mmap::map(&file)? };
+    let header_len = fh.header_len as usize;
+    let len = (mm.len() - header_len) / elem_size;
+
+    Ok(MmapedArray {
+        mm: Some(mm),
+        file: Some(file),
+        lock_file,
+        header_len,
+        elem_size,
+        format,
+        ndim,
+        shape: [len as isize, elem_size as isize],
+        strides: [elem_size as isize, 1],
+        exports: 0,
+    })
+}
+
+#[pymodule]
+#[pyo3(name = "persistence")]
+fn persistence_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<MmapedArray>()?;
+    m.add_function(wrap_pyfunction!(open, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use persistence::MmapedVecBuilder;
+
+    const MAGIC_BYTES: [u8; 8] = *b"PYTEST\0\0";
+    const DATA_CONTAINED_VERSION: [u8; 3] = [0, 0, 1];
+
+    #[test]
+    fn test_lock_file() -> PyResult<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("values.bin");
+        let mut builder = MmapedVecBuilder::new(MAGIC_BYTES, DATA_CONTAINED_VERSION);
+
+        let mut mv = builder.lock_file(true).try_open::<u32>(&path)?;
+        mv.extend([1, 2, 3])?;
+        mv.close()?;
+
+        Python::initialize();
+        Python::attach(|py| {
+            let mut array = open(
+                py,
+                path.clone(),
+                MAGIC_BYTES,
+                DATA_CONTAINED_VERSION,
+                4,
+                4,
+                None,
+            )?;
+            assert_eq!(array.__len__()?, 3);
+
+            // NOTE: Writers lock the lock file, so it must be locked by readers as well.
+            let err = builder.try_open::<u32>(&path).err().unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+
+            array.close()?;
+            builder.try_open::<u32>(&path)?;
+            Ok(())
+        })
+    }
+}
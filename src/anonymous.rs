@@ -0,0 +1,197 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::{check_element_type, MmapedVec, MmapedVecBuilder};
+use memmap::MmapMut;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::{mem, ptr, slice};
+
+/// Smallest capacity of an [`AnonymousVec`](AnonymousVec) in bytes, which is also the size
+/// of the first mapping.
+const MIN_CAPACITY_BYTES: usize = 4096;
+
+/// A vector of elements in anonymous memory, for workloads that only sometimes need to
+/// persist them, which can later be written out to a file and opened as a
+/// [`MmapedVec`](MmapedVec) with [`bind_to`](AnonymousVec::bind_to).
+///
+/// Until then, nothing touches the disk. The memory is mapped with `MAP_ANONYMOUS`, and is
+/// remapped to twice the size when full, like the buffer of a `Vec`.
+pub struct AnonymousVec<T> {
+    builder: MmapedVecBuilder,
+    mm: MmapMut,
+    len: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Sized + Default> MmapedVec<T> {
+    /// Start out in anonymous memory, with the options of a default builder for later
+    /// [binding](AnonymousVec::bind_to) to a file.
+    pub fn anonymous(
+        magic_bytes: [u8; 8],
+        data_contained_version: [u8; 3],
+    ) -> io::Result<AnonymousVec<T>> {
+        MmapedVecBuilder::new(magic_bytes, data_contained_version).anonymous()
+    }
+}
+
+impl MmapedVecBuilder {
+    /// Start out in anonymous memory, with the options of this builder for later
+    /// [binding](AnonymousVec::bind_to) to a file.
+    pub fn anonymous<T: Sized + Default>(&self) -> io::Result<AnonymousVec<T>> {
+        check_element_type::<T>(Path::new("anonymous"))?;
+
+        Ok(AnonymousVec {
+            builder: self.clone(),
+            mm: MmapMut::map_anon(MIN_CAPACITY_BYTES)?,
+            len: 0,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T: Sized + Default> AnonymousVec<T> {
+    /// Number of elements that fit before the memory is remapped.
+    pub fn capacity(&self) -> usize {
+        self.mm.len() / mem::size_of::<T>()
+    }
+
+    /// Make room for at least `additional` more elements.
+    pub fn reserve(&mut self, additional: usize) -> io::Result<()> {
+        let needed = self
+            .len
+            .checked_add(additional)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Capacity overflow."))?;
+        if needed <= self.capacity() {
+            return Ok(());
+        }
+
+        let bytes = (needed.max(self.capacity() * 2))
+            .checked_mul(mem::size_of::<T>())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Capacity overflow."))?;
+
+        // TODO: mremap() on Linux would move the pages rather than copy them.
+        let mut mm = MmapMut::map_anon(bytes)?;
+        let used = self.len * mem::size_of::<T>();
+        mm[..used].copy_from_slice(&self.mm[..used]);
+        self.mm = mm;
+        Ok(())
+    }
+
+    pub fn push(&mut self, value: T) -> io::Result<()> {
+        self.reserve(1)?;
+        unsafe { ptr::write((self.mm.as_mut_ptr() as *mut T).add(self.len), value) };
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) -> io::Result<()> {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0)?;
+        for value in iter {
+            self.push(value)?;
+        }
+        Ok(())
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    /// Write the elements to a new file at `path`, header first, and open it as a
+    /// [`MmapedVec`](MmapedVec) with the options of the builder that this was started with,
+    /// flushed to disk.
+    ///
+    /// The elements stay in anonymous memory too, so that binding can be retried on
+    /// failure; drop this once bound. Fails with
+    /// [`AlreadyExists`](io::ErrorKind::AlreadyExists) if a non-empty file is there already,
+    /// and removes the file again if writing it fails partway.
+    pub fn bind_to(&self, path: &Path) -> io::Result<MmapedVec<T>> {
+        let existed = path.exists();
+        let mut mv = self.builder.try_open::<T>(path)?;
+
+        if !mv.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("File `{:?}`: Already holds elements.", path),
+            ));
+        }
+
+        let bytes = &self.mm[..self.len * mem::size_of::<T>()];
+        let result = mv.apply_replicated(0, bytes).and_then(|()| mv.flush());
+
+        match result {
+            Ok(()) => Ok(mv),
+            Err(e) => {
+                drop(mv);
+                if !existed {
+                    let _ = fs::remove_file(path);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<T> Deref for AnonymousVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.mm.as_ptr() as *const T, self.len) }
+    }
+}
+
+impl<T> DerefMut for AnonymousVec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.mm.as_mut_ptr() as *mut T, self.len) }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+
+    #[test]
+    pub fn test_anonymous_vec_binds_to_file() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+
+        let mut av: AnonymousVec<u64> =
+            MmapedVec::anonymous(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)?;
+        av.extend(0..10_000)?;
+        av[0] = 42;
+        assert!(av.capacity() >= 10_000);
+        assert!(!path.exists());
+
+        let mv = av.bind_to(&path)?;
+        assert_eq!(mv.len(), 10_000);
+        assert_eq!(mv[0], 42);
+        assert_eq!(mv[9_999], 9_999);
+        drop(mv);
+
+        assert!(matches!(av.bind_to(&path), Err(e) if e.kind() == io::ErrorKind::AlreadyExists));
+
+        let mv: MmapedVec<u64> =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+                .try_open(&path)?;
+        assert_eq!(&mv[..], &av[..]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Arrays of atomics that several processes map and update at the same time, such as
+//! counters and flags for metrics that outlive the processes.
+
+use crate::{check_element_type, locking, MmapedVecBuilder};
+use memmap::MmapMut;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Deref;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::atomic::*;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// The atomic types of `std` that can be elements of [`SharedAtomics`](SharedAtomics).
+///
+/// These are lock-free on every platform that has them, so their operations are atomic
+/// across processes, not only across threads, as long as each process maps the file.
+pub trait AtomicElement: sealed::Sealed + Default + Send + Sync {}
+
+macro_rules! atomic_element {
+    ($($t:ty),*) => {
+        $(
+            impl sealed::Sealed for $t {}
+            impl AtomicElement for $t {}
+        )*
+    };
+}
+
+atomic_element!(AtomicBool, AtomicU8, AtomicI8, AtomicU16, AtomicI16, AtomicU32, AtomicI32);
+atomic_element!(AtomicUsize, AtomicIsize);
+#[cfg(target_has_atomic = "64")]
+atomic_element!(AtomicU64, AtomicI64);
+
+/// A fixed number of atomics in a file, mapped by any number of processes at once.
+///
+/// Takes a shared lock on the file, so there can be many at once, in this and other
+/// processes, but not while a [`MmapedVec`](crate::MmapedVec) has the file open. The
+/// atomics are accessed with the methods of their types, with whichever
+/// [`Ordering`](std::sync::atomic::Ordering) the caller asks for.
+///
+/// Updates are visible to the other processes at once, through the page cache, and reach
+/// the disk through the write-back of the kernel, or [`flush`](SharedAtomics::flush).
+pub struct SharedAtomics<A> {
+    path: PathBuf,
+    // NOTE: Must be dropped before the file, like the mapping of a MmapedVec.
+    mm: MmapMut,
+    file: File,
+    _lock_file: Option<File>,
+    header_len: usize,
+    len: usize,
+    _marker: PhantomData<A>,
+}
+
+impl MmapedVecBuilder {
+    /// Open the atomics in the file at `path`, creating it with `len` atomics at their
+    /// default value, zero, if it does not exist yet. Existing files keep their length.
+    pub fn try_open_atomic<A: AtomicElement>(
+        &self,
+        path: &Path,
+        len: usize,
+    ) -> io::Result<SharedAtomics<A>> {
+        check_element_type::<A>(path)?;
+
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        if !self.follow_symlinks {
+            options.custom_flags(libc::O_NOFOLLOW);
+        }
+
+        // NOTE: Of several processes creating the file at once, all but one fail to lock it.
+        //       Retrying opens the file that the winner created.
+        if !path.exists() {
+            let mut mv = self.try_open::<A>(path)?;
+            if mv.is_empty() {
+                mv.resize(len)?;
+            }
+            mv.close()?;
+        }
+
+        let file = options.open(path)?;
+        let lock_file = locking::try_lock_shared_for(&file, path)?;
+
+        let fh = self.check_existing_file::<A, _>(&file, path)?;
+        let mm = unsafe { MmapMut::map_mut(&file)? };
+        let header_len = fh.header_len as usize;
+        let len = (mm.len() - header_len) / mem::size_of::<A>();
+
+        Ok(SharedAtomics {
+            path: path.to_path_buf(),
+            mm,
+            file,
+            _lock_file: lock_file,
+            header_len,
+            len,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<A: AtomicElement> SharedAtomics<A> {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the atomics back to the file and wait for them to be on disk. What other
+    /// processes store at the same time may or may not be included.
+    pub fn flush(&self) -> io::Result<()> {
+        self.mm.flush()?;
+        self.file.sync_data()
+    }
+}
+
+impl<A: AtomicElement> Deref for SharedAtomics<A> {
+    type Target = [A];
+
+    fn deref(&self) -> &[A] {
+        unsafe {
+            slice::from_raw_parts(self.mm.as_ptr().add(self.header_len) as *const A, self.len)
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::MmapedVec;
+
+    #[test]
+    pub fn test_shared_atomics() -> Result<(), io::Error> {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let a = builder.try_open_atomic::<AtomicU64>(&path, 16)?;
+        let b = builder.try_open_atomic::<AtomicU64>(&path, 1)?;
+        assert_eq!((a.len(), b.len()), (16, 16));
+
+        std::thread::scope(|s| {
+            for counters in [&a, &b].iter().copied() {
+                s.spawn(move || {
+                    for _ in 0..1000 {
+                        counters[3].fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+        assert_eq!(a[3].load(Ordering::Acquire), 2000);
+        assert_eq!(b[3].load(Ordering::Acquire), 2000);
+
+        assert!(builder.try_open::<AtomicU64>(&path).is_err());
+        a.flush()?;
+        drop((a, b));
+
+        let mv: MmapedVec<AtomicU64> = builder.try_open(&path)?;
+        assert_eq!(mv[3].load(Ordering::Relaxed), 2000);
+
+        Ok(())
+    }
+}
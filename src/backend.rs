@@ -0,0 +1,174 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Storage that files in the persistence format can be kept on.
+//!
+//! The header layout, its validation and the arithmetic on elements only need to read and
+//! write bytes at offsets, so they go through [`StorageBackend`](StorageBackend) rather than
+//! [`File`](File). [`MmapedVec`](crate::MmapedVec) keeps using memory-mapped files, which
+//! remains the default on hosted platforms, but the same format can be laid down on other
+//! storage, such as a raw flash partition or a file in littlefs, by implementing the trait.
+
+// TODO: Embedded targets need the format and this module to build without std. That takes
+//       a default `std` feature gating memmap, fs2, libc and everything built on MmapedVec,
+//       and an error type of our own in place of io::Error, which is a breaking change that
+//       should be made together with the other changes for the stable format. The layout
+//       code in format only uses core and alloc already, so it can be moved as it is.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+pub trait StorageBackend {
+    /// Length of the stored data in bytes.
+    fn len_bytes(&self) -> io::Result<u64>;
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Grow or shrink the stored data, filling any new bytes with zeroes.
+    fn set_len_bytes(&mut self, len: u64) -> io::Result<()>;
+
+    /// Make all writes so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl StorageBackend for &File {
+    fn len_bytes(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        FileExt::read_exact_at(*self, buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        FileExt::write_all_at(*self, buf, offset)
+    }
+
+    fn set_len_bytes(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+impl StorageBackend for File {
+    fn len_bytes(&self) -> io::Result<u64> {
+        (&self).len_bytes()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        StorageBackend::read_at(&self, buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        StorageBackend::write_at(&mut &*self, buf, offset)
+    }
+
+    fn set_len_bytes(&mut self, len: u64) -> io::Result<()> {
+        StorageBackend::set_len_bytes(&mut &*self, len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        StorageBackend::sync(&mut &*self)
+    }
+}
+
+/// In-memory storage, for tests and for building images to write to flash.
+impl StorageBackend for Vec<u8> {
+    fn len_bytes(&self) -> io::Result<u64> {
+        Ok(<[u8]>::len(self) as u64)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let src = usize::try_from(offset)
+            .ok()
+            .and_then(|start| self.get(start..start.checked_add(buf.len())?))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let start = usize::try_from(offset).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        let end = start
+            .checked_add(buf.len())
+            .ok_or(io::ErrorKind::OutOfMemory)?;
+        if end > <[u8]>::len(self) {
+            self.resize(end, 0);
+        }
+        self[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn set_len_bytes(&mut self, len: u64) -> io::Result<()> {
+        let len = usize::try_from(len).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        self.resize(len, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        new_mmaped_vec_of_example_persisting_in_tempdir, Example, EXAMPLE_DATA_CONTAINED_VERSION,
+        EXAMPLE_MAGIC_BYTES,
+    };
+    use crate::{format, FileHeader};
+    use std::{fs, mem};
+
+    #[test]
+    pub fn test_storage_backend_lays_down_same_format() -> Result<(), io::Error> {
+        let (_dir, pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+        mv.push(Example { hello: 3, world: 4 })?;
+        drop(mv);
+
+        let mut image = Vec::new();
+        let fh =
+            FileHeader::new::<Example>(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION, true);
+        fh.write_to(&mut image)?;
+        image.write_at(&[1, 2], fh.default_data_offset as u64)?;
+        image.set_len_bytes(fh.header_len)?;
+        image.write_at(&[3, 4], fh.header_len)?;
+
+        let checked = format::check_existing_file(
+            &image,
+            &pathbuf,
+            EXAMPLE_MAGIC_BYTES,
+            EXAMPLE_DATA_CONTAINED_VERSION,
+            mem::size_of::<Example>(),
+            mem::align_of::<Example>(),
+        )?;
+        assert_eq!(checked, fh);
+
+        // NOTE: The extensions area differs, by the sequence that the MmapedVec publishes.
+        let mut file_image = fs::read(&pathbuf)?;
+        file_image[fh.extensions_offset as usize..fh.header_len as usize].fill(0);
+        assert_eq!(image, file_image);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::MmapedVec;
+use std::{io, ptr};
+
+enum Op<T> {
+    Push(T),
+    Set(usize, T),
+}
+
+/// Operations collected by [`batch`](MmapedVec::batch), to be applied all at once.
+pub struct Batch<T> {
+    len: usize,
+    pushed: usize,
+    ops: Vec<Op<T>>,
+}
+
+impl<T> Batch<T> {
+    /// Number of elements there will be once the batch has been applied.
+    pub fn len(&self) -> usize {
+        self.len + self.pushed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Append an element.
+    pub fn push(&mut self, value: T) {
+        self.pushed += 1;
+        self.ops.push(Op::Push(value));
+    }
+
+    /// Replace the element at `index`, which may be one pushed earlier in the batch.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn set(&mut self, index: usize, value: T) {
+        assert!(
+            index < self.len(),
+            "index {} out of bounds for batch of length {}",
+            index,
+            self.len()
+        );
+        self.ops.push(Op::Set(index, value));
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// Collect operations with `f`, then apply all of them with a single growth of the file
+    /// and a single [`flush`](MmapedVec::flush) at the end.
+    ///
+    /// Operations are applied in the order they were made, and nothing is written unless
+    /// `f` returns. Readers of the file may still see it grown before the pushed elements
+    /// have been written, as with [`extend`](MmapedVec::extend).
+    pub fn batch<F: FnOnce(&mut Batch<T>)>(&mut self, f: F) -> io::Result<()> {
+        self.check_poisoned()?;
+
+        let mut batch = Batch {
+            len: self.len(),
+            pushed: 0,
+            ops: vec![],
+        };
+        f(&mut batch);
+
+        if batch.ops.is_empty() {
+            return Ok(());
+        }
+
+        self.roll_if_due(batch.pushed)?;
+        // NOTE: Rolling may have emptied the file, which leaves any sets of elements that
+        //       were there before out of bounds.
+        let len = self.len();
+        if len < batch.len {
+            if let Some(index) = batch.ops.iter().find_map(|op| match op {
+                Op::Set(index, _) if *index < batch.len => Some(*index),
+                _ => None,
+            }) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "File `{:?}`: Rolled before the batch was applied, leaving index {} \
+          out of bounds.",
+                        self.path, index
+                    ),
+                ));
+            }
+        }
+        if batch.pushed > 0 {
+            self.grow(batch.pushed)?;
+            fail_point!(BetweenLengthCommitAndDataWrite)?;
+        }
+
+        let mut next = len;
+        let mut first_modified = len;
+        self.set_writable(true)?;
+        for op in batch.ops {
+            // NOTE: Pushed elements are uninitialized until written, so they must not be
+            //       dropped, whereas replaced elements are dropped as with slice assignment.
+            //       Sets only ever refer to elements that have been written by then.
+            match op {
+                Op::Push(value) => {
+                    unsafe { ptr::write(self.body_mut_ptr().add(next), value) };
+                    next += 1;
+                }
+                Op::Set(index, value) => {
+                    let index = index - (batch.len - len);
+                    first_modified = first_modified.min(index);
+                    unsafe { *self.body_mut_ptr().add(index) = value };
+                }
+            }
+        }
+        self.set_writable(false)?;
+
+        self.replicate_range(first_modified..self.len())?;
+        self.flush()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::MmapedVecBuilder;
+
+    #[test]
+    pub fn test_batch() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut mv = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .try_open::<u32>(&path)?;
+        mv.extend([1, 2])?;
+        let generation = mv.mapping_generation();
+
+        mv.batch(|b| {
+            b.push(3);
+            b.set(0, 10);
+            b.push(4);
+            b.set(2, 30);
+            assert_eq!(b.len(), 4);
+        })?;
+        assert_eq!(&mv[..], &[10, 2, 30, 4]);
+        assert_eq!(mv.mapping_generation(), generation + 1);
+
+        mv.batch(|_| {})?;
+        assert_eq!(mv.mapping_generation(), generation + 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Bloom filters over the keys of the elements, kept in a sidecar next to the file, so
+//! that looking for a key that is not there can skip scanning the body, with
+//! [`maybe_contains`](MmapedVec::maybe_contains).
+//!
+//! The sidecar starts with `BLOOM_MAGIC`, the number of hashes as a `u32` and four bytes of
+//! padding, and then the number of bits and the number of elements that the bits cover,
+//! as `u64`s in native byte order. Then come the bits, as `u64` words.
+
+use crate::MmapedVec;
+use memmap::MmapMut;
+use std::convert::TryInto;
+use std::f64::consts::LN_2;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::slice;
+
+/// Suffix of the sidecar that is kept next to files with a bloom filter.
+pub const BLOOM_SUFFIX: &str = ".bloom";
+
+pub(crate) const BLOOM_MAGIC: [u8; 8] = *b"PERSBLOM";
+const BLOOM_HEADER_LEN: usize = 32;
+
+/// Path of the bloom filter sidecar of the file at `path`.
+pub fn bloom_path(path: &Path) -> PathBuf {
+    let mut bloom_path = OsString::from(path.as_os_str());
+    bloom_path.push(BLOOM_SUFFIX);
+    PathBuf::from(bloom_path)
+}
+
+/// Two independent hashes of `key`, for double hashing. FNV-1a, which is stable across
+/// Rust versions, unlike the hasher of std, followed by the finalizer of SplitMix64.
+fn hash_key(key: &[u8]) -> (u64, u64) {
+    let mix = |mut z: u64| {
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    (mix(hash), mix(hash ^ 0x9e37_79b9_7f4a_7c15) | 1)
+}
+
+type KeyFn<T> = dyn Fn(&T) -> (u64, u64) + Send;
+
+/// The mapped sidecar of a file with a bloom filter, and how to get the key of an element.
+pub(crate) struct BloomFilter<T> {
+    // NOTE: Must be dropped before the file, like the mapping of a MmapedVec.
+    mm: MmapMut,
+    _file: File,
+    hashes: u32,
+    bits: u64,
+    key: Box<KeyFn<T>>,
+}
+
+impl<T> BloomFilter<T> {
+    fn words(&mut self) -> &mut [u64] {
+        let words = (self.bits / 64) as usize;
+        unsafe {
+            slice::from_raw_parts_mut(
+                self.mm.as_mut_ptr().add(BLOOM_HEADER_LEN) as *mut u64,
+                words,
+            )
+        }
+    }
+
+    fn bit_indices(&self, (h1, h2): (u64, u64)) -> impl Iterator<Item = u64> {
+        let bits = self.bits;
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bits)
+    }
+
+    pub(crate) fn insert(&mut self, elem: &T) {
+        let indices: Vec<u64> = self.bit_indices((self.key)(elem)).collect();
+        let words = self.words();
+        for i in indices {
+            words[(i / 64) as usize] |= 1 << (i % 64);
+        }
+    }
+
+    fn maybe_contains(&self, key: &[u8]) -> bool {
+        let words = unsafe {
+            slice::from_raw_parts(
+                self.mm.as_ptr().add(BLOOM_HEADER_LEN) as *const u64,
+                (self.bits / 64) as usize,
+            )
+        };
+        self.bit_indices(hash_key(key))
+            .all(|i| words[(i / 64) as usize] & (1 << (i % 64)) != 0)
+    }
+
+    fn covered_len(&self) -> u64 {
+        u64::from_ne_bytes(self.mm[24..32].try_into().unwrap())
+    }
+
+    /// Record that the bits cover `len` elements, and write them back to the sidecar.
+    pub(crate) fn commit(&mut self, len: usize) -> io::Result<()> {
+        self.mm.flush()?;
+        self.mm[24..32].copy_from_slice(&(len as u64).to_ne_bytes());
+        self.mm.flush_range(0, BLOOM_HEADER_LEN)
+    }
+
+    /// Create an empty sidecar of the same size at `path`, for
+    /// [`replace_sidecar`](BloomFilter::replace_sidecar).
+    pub(crate) fn create_empty(&self, path: &Path) -> io::Result<(MmapMut, File)> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.set_len(self.mm.len() as u64)?;
+
+        let mut mm = unsafe { MmapMut::map_mut(&file)? };
+        mm[..24].copy_from_slice(&self.mm[..24]);
+        mm.flush()?;
+        Ok((mm, file))
+    }
+
+    /// Use the sidecar made with [`create_empty`](BloomFilter::create_empty) from here on.
+    pub(crate) fn replace_sidecar(&mut self, (mm, file): (MmapMut, File)) {
+        self.mm = mm;
+        self._file = file;
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// Keep a bloom filter over the keys that `key` returns for the elements, in a sidecar
+    /// next to the file, sized for `expected_elems` elements at a rate of false positives of
+    /// `false_positive_rate`. The filter is rebuilt from the elements when the sidecar does
+    /// not match, such as after a crash.
+    ///
+    /// Elements are added to the filter as they are appended, and when recorded with
+    /// [`mark_modified`](MmapedVec::mark_modified). Removing or overwriting elements leaves
+    /// their keys in the filter, which only makes for more false positives.
+    ///
+    /// NOTE: Modifications made while the file is opened without the filter go unnoticed,
+    /// unless they change its length, so enable it every time the file is opened.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `false_positive_rate` is between zero and one.
+    pub fn bloom_filter<K, F>(
+        &mut self,
+        expected_elems: usize,
+        false_positive_rate: f64,
+        key: F,
+    ) -> io::Result<()>
+    where
+        K: AsRef<[u8]>,
+        F: Fn(&T) -> K + Send + 'static,
+    {
+        assert!(
+            false_positive_rate > 0.0 && false_positive_rate < 1.0,
+            "The rate of false positives must be between zero and one."
+        );
+
+        let n = expected_elems.max(1) as f64;
+        let bits = (-n * false_positive_rate.ln() / (LN_2 * LN_2)).ceil() as u64;
+        let bits = bits.max(64).div_ceil(64) * 64;
+        let hashes = ((bits as f64 / n) * LN_2).round().clamp(1.0, 32.0) as u32;
+
+        let path = bloom_path(&self.path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let mut header = [0u8; BLOOM_HEADER_LEN];
+        header[..8].copy_from_slice(&BLOOM_MAGIC);
+        header[8..12].copy_from_slice(&hashes.to_ne_bytes());
+        header[16..24].copy_from_slice(&bits.to_ne_bytes());
+
+        let sidecar_len = (BLOOM_HEADER_LEN + (bits / 8) as usize) as u64;
+        let mut mm = match file.metadata()?.len() == sidecar_len {
+            true => unsafe { MmapMut::map_mut(&file)? },
+            false => {
+                file.set_len(0)?;
+                file.set_len(sidecar_len)?;
+                unsafe { MmapMut::map_mut(&file)? }
+            }
+        };
+
+        let rebuild = mm[..24] != header[..24] || self.recovered_from_crash().is_some();
+        if rebuild {
+            mm[..BLOOM_HEADER_LEN].copy_from_slice(&header);
+        }
+
+        let mut filter = BloomFilter {
+            mm,
+            _file: file,
+            hashes,
+            bits,
+            key: Box::new(move |elem: &T| hash_key(key(elem).as_ref())),
+        };
+
+        if rebuild || filter.covered_len() != self.len() as u64 {
+            filter.mm[BLOOM_HEADER_LEN..].fill(0);
+            for elem in self.iter() {
+                filter.insert(elem);
+            }
+            filter.commit(self.len())?;
+        }
+
+        self.bloom = Some(filter);
+        Ok(())
+    }
+
+    /// Whether an element with `key` may be in the file, as far as the
+    /// [bloom filter](MmapedVec::bloom_filter) knows. False means that there is none, so the
+    /// body need not be searched. Always true without a filter.
+    pub fn maybe_contains<K: AsRef<[u8]>>(&self, key: K) -> bool {
+        self.bloom
+            .as_ref()
+            .is_none_or(|filter| filter.maybe_contains(key.as_ref()))
+    }
+
+    /// Add the elements in `range` to the bloom filter, if there is one.
+    pub(crate) fn bloom_insert(&mut self, range: std::ops::Range<usize>) {
+        if let Some(filter) = self.bloom.as_mut() {
+            let body = unsafe {
+                slice::from_raw_parts(
+                    self.mm.as_ptr().add(self.header_len) as *const T,
+                    (self.mm.len() - self.header_len) / mem::size_of::<T>(),
+                )
+            };
+            for elem in &body[range.start.min(body.len())..range.end.min(body.len())] {
+                filter.insert(elem);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::MmapedVecBuilder;
+
+    #[test]
+    pub fn test_bloom_filter() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        let key = |v: &u64| v.to_le_bytes();
+
+        let mut mv: MmapedVec<u64> = builder.try_open(&path)?;
+        mv.extend((0..500).map(|i| i * 2))?;
+        assert!(mv.maybe_contains(3u64.to_le_bytes()));
+
+        mv.bloom_filter(10_000, 0.01, key)?;
+        mv.extend((500..1000).map(|i| i * 2))?;
+        assert!((0..1000).all(|i| mv.maybe_contains((i * 2u64).to_le_bytes())));
+        let false_positives = (0..1000)
+            .filter(|i| mv.maybe_contains((i * 2u64 + 1).to_le_bytes()))
+            .count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+        drop(mv);
+        assert!(bloom_path(&path).exists());
+
+        let mut mv: MmapedVec<u64> = builder.try_open(&path)?;
+        mv.push(7)?;
+        mv.bloom_filter(10_000, 0.01, key)?;
+        assert!(mv.maybe_contains(7u64.to_le_bytes()));
+        assert!(mv.maybe_contains(1998u64.to_le_bytes()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::backend::StorageBackend;
+use crate::format::{self, FileHeader};
+use crate::{check_element_type, MmapedVecBuilder};
+use std::ops::{Deref, DerefMut, Range};
+use std::path::{Path, PathBuf};
+use std::{cmp, io, mem, slice};
+
+/// A vector of elements loaded from storage into memory, for targets without `mmap`, such
+/// as WASI, that writes back only the elements that changed when flushed.
+///
+/// Files are in the same format as those of [`MmapedVec`](crate::MmapedVec), so the two can
+/// be used on the same files. Unlike a [`MmapedVec`](crate::MmapedVec), nothing reaches
+/// storage until [`flush`](BufferedVec::flush) is called, which also happens on drop.
+///
+/// The storage is not locked, so it is up to the caller to make sure no one else uses it
+/// at the same time.
+// TODO: Select this in place of MmapedVec for target_family = "wasm", once the rest of the
+//       crate builds without memmap, fs2 and the unix extensions of std. See the TODO in
+//       backend about building without std, which the same feature gates would cover.
+pub struct BufferedVec<T: Copy, B: StorageBackend> {
+    path: PathBuf,
+    backend: B,
+    header_len: u64,
+    elems: Vec<T>,
+    persisted_len: usize,
+    dirty: Option<Range<usize>>,
+}
+
+impl MmapedVecBuilder {
+    /// Load the elements kept on `backend` into memory, laying down a new header first if
+    /// the backend is empty. `path` names the storage in errors.
+    pub fn try_open_buffered<T: Copy, B: StorageBackend>(
+        &self,
+        mut backend: B,
+        path: &Path,
+        default_data: Option<T>,
+    ) -> io::Result<BufferedVec<T, B>> {
+        check_element_type::<T>(path)?;
+
+        let fh = if backend.len_bytes()? == 0 {
+            let fh = FileHeader::new::<T>(
+                self.magic_bytes,
+                self.data_contained_version,
+                default_data.is_some(),
+            );
+
+            fh.write_to(&mut backend)?;
+            if let Some(default_data) = default_data {
+                backend.write_at(
+                    format::as_bytes(&default_data),
+                    fh.default_data_offset as u64,
+                )?;
+            }
+            backend.set_len_bytes(fh.header_len)?;
+            fh
+        } else {
+            self.check_existing_file::<T, _>(&backend, path)?
+        };
+
+        let len = ((backend.len_bytes()? - fh.header_len) / mem::size_of::<T>() as u64) as usize;
+
+        let mut elems = Vec::<T>::with_capacity(len);
+        unsafe {
+            let bytes =
+                slice::from_raw_parts_mut(elems.as_mut_ptr() as *mut u8, len * mem::size_of::<T>());
+            backend.read_at(bytes, fh.header_len)?;
+            elems.set_len(len);
+        }
+
+        Ok(BufferedVec {
+            path: path.to_path_buf(),
+            backend,
+            header_len: fh.header_len,
+            elems,
+            persisted_len: len,
+            dirty: None,
+        })
+    }
+}
+
+impl<T: Copy, B: StorageBackend> BufferedVec<T, B> {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.elems.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elems.is_empty()
+    }
+
+    /// Whether there are changes that have not been flushed.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.is_some() || self.elems.len() != self.persisted_len
+    }
+
+    pub fn push(&mut self, value: T) {
+        self.elems.push(value);
+        self.mark_dirty(self.elems.len() - 1..self.elems.len());
+    }
+
+    pub fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let start = self.elems.len();
+        self.elems.extend(iter);
+        self.mark_dirty(start..self.elems.len());
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.elems.truncate(len);
+        if let Some(dirty) = &mut self.dirty {
+            dirty.end = cmp::min(dirty.end, len);
+            if dirty.start >= dirty.end {
+                self.dirty = None;
+            }
+        }
+    }
+
+    /// Overwrite the element at `index`, marking only it as changed, where writing through
+    /// [`DerefMut`](DerefMut) marks every element as changed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn set(&mut self, index: usize, value: T) {
+        self.elems[index] = value;
+        self.mark_dirty(index..index + 1);
+    }
+
+    /// Write the changed elements and the new length to storage, and make them durable.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.elems.len() != self.persisted_len {
+            self.backend
+                .set_len_bytes(self.header_len + (self.elems.len() * mem::size_of::<T>()) as u64)?;
+            self.persisted_len = self.elems.len();
+        }
+
+        if let Some(dirty) = self.dirty.take() {
+            let bytes = unsafe {
+                slice::from_raw_parts(
+                    self.elems[dirty.clone()].as_ptr() as *const u8,
+                    dirty.len() * mem::size_of::<T>(),
+                )
+            };
+            let offset = self.header_len + (dirty.start * mem::size_of::<T>()) as u64;
+
+            if let Err(e) = self.backend.write_at(bytes, offset) {
+                self.dirty = Some(dirty);
+                return Err(e);
+            }
+        }
+
+        self.backend.sync()
+    }
+
+    /// Flush and hand back the storage.
+    pub fn into_backend(mut self) -> io::Result<B> {
+        self.flush()?;
+
+        let mut this = mem::ManuallyDrop::new(self);
+        // SAFETY: Every other field is dropped here, and `this` is never used again.
+        unsafe {
+            std::ptr::drop_in_place(&mut this.path);
+            std::ptr::drop_in_place(&mut this.elems);
+            Ok(std::ptr::read(&this.backend))
+        }
+    }
+
+    fn mark_dirty(&mut self, range: Range<usize>) {
+        self.dirty = Some(match self.dirty.take() {
+            Some(dirty) => cmp::min(dirty.start, range.start)..cmp::max(dirty.end, range.end),
+            None => range,
+        });
+    }
+}
+
+impl<T: Copy, B: StorageBackend> Drop for BufferedVec<T, B> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl<T: Copy, B: StorageBackend> Deref for BufferedVec<T, B> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.elems
+    }
+}
+
+impl<T: Copy, B: StorageBackend> DerefMut for BufferedVec<T, B> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        if !self.elems.is_empty() {
+            self.mark_dirty(0..self.elems.len());
+        }
+        &mut self.elems
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        tempdir_and_tempfile, Example, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES,
+    };
+    use std::fs::{self, OpenOptions};
+
+    #[test]
+    pub fn test_buffered_vec_writes_back_changes() -> Result<(), io::Error> {
+        let (_dir, pathbuf) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&pathbuf)?;
+        let mut bv = builder.try_open_buffered(file, &pathbuf, Some(Example::default()))?;
+        bv.extend(vec![Example { hello: 1, world: 2 }; 3]);
+        bv.flush()?;
+        assert!(!bv.is_dirty());
+
+        bv.set(1, Example { hello: 5, world: 6 });
+        bv.push(Example { hello: 7, world: 8 });
+        drop(bv);
+
+        let mut mv = builder.try_open::<Example>(&pathbuf)?;
+        assert_eq!(mv.len(), 4);
+        assert_eq!((mv[1].hello, mv[1].world), (5, 6));
+        mv.truncate(2)?;
+        drop(mv);
+
+        let image = fs::read(&pathbuf)?;
+        let bv = builder.try_open_buffered::<Example, _>(image.clone(), &pathbuf, None)?;
+        assert_eq!(bv.len(), 2);
+        assert_eq!(bv.into_backend()?, image);
+
+        Ok(())
+    }
+}
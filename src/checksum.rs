@@ -0,0 +1,267 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Checksums of the body, stored in a header extension when a file is closed cleanly, and
+//! verified when it is opened again. See
+//! [`EXTENSION_TAG_CHECKSUM`](crate::format::EXTENSION_TAG_CHECKSUM).
+
+use crate::format::{self, FileHeader, EXTENSION_TAG_CHECKSUM};
+use crate::{MmapedVec, MmapedVecBuilder};
+use memmap::Mmap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// How to checksum the body of a file, chosen with
+/// [`checksum`](MmapedVecBuilder::checksum) and recorded in the header along with the
+/// checksum, so that the file says how to verify it.
+///
+/// CRC32C is always available, and is the fastest of them. xxHash64 and BLAKE3 need the
+/// `checksum-xxhash64` and `checksum-blake3` features, respectively. Only BLAKE3 is of use
+/// against deliberate tampering; the others only catch accidental corruption.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChecksumAlgorithm {
+    Crc32c,
+    XxHash64,
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    /// The id of the algorithm in the header.
+    pub fn id(self) -> u8 {
+        match self {
+            ChecksumAlgorithm::Crc32c => 1,
+            ChecksumAlgorithm::XxHash64 => 2,
+            ChecksumAlgorithm::Blake3 => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(ChecksumAlgorithm::Crc32c),
+            2 => Some(ChecksumAlgorithm::XxHash64),
+            3 => Some(ChecksumAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    /// Whether this build of the library can compute the checksum.
+    pub fn is_available(self) -> bool {
+        match self {
+            ChecksumAlgorithm::Crc32c => true,
+            ChecksumAlgorithm::XxHash64 => cfg!(feature = "checksum-xxhash64"),
+            ChecksumAlgorithm::Blake3 => cfg!(feature = "checksum-blake3"),
+        }
+    }
+
+    /// The digest of `data`, in little-endian byte order where that matters, so that it
+    /// reads the same on every host.
+    pub fn digest(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            ChecksumAlgorithm::Crc32c => Ok(crc32c(data).to_le_bytes().to_vec()),
+            #[cfg(feature = "checksum-xxhash64")]
+            ChecksumAlgorithm::XxHash64 => {
+                Ok(xxhash_rust::xxh64::xxh64(data, 0).to_le_bytes().to_vec())
+            }
+            #[cfg(feature = "checksum-blake3")]
+            ChecksumAlgorithm::Blake3 => Ok(blake3::hash(data).as_bytes().to_vec()),
+            #[allow(unreachable_patterns)]
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Checksum algorithm {:?} is not enabled in this build.",
+                    self
+                ),
+            )),
+        }
+    }
+}
+
+/// CRC32C (Castagnoli), computed a byte at a time.
+// TODO: Use the SSE 4.2 and ARMv8 CRC instructions where available.
+pub(crate) fn crc32c(data: &[u8]) -> u32 {
+    const POLY: u32 = 0x82f6_3b78;
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = match crc & 1 {
+                    1 => (crc >> 1) ^ POLY,
+                    _ => crc >> 1,
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    !data.iter().fold(!0u32, |crc, &b| {
+        TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+impl MmapedVecBuilder {
+    /// Store a checksum of the body computed with `algorithm` when the file is closed
+    /// cleanly, and verify the checksum when opening a file that was closed cleanly, failing
+    /// with [`InvalidData`](io::ErrorKind::InvalidData) if it does not match.
+    ///
+    /// A file is verified with the algorithm recorded in it, whatever `algorithm` is, and
+    /// checksummed with `algorithm` when closed. Verifying reads the whole body.
+    pub fn checksum(&mut self, algorithm: ChecksumAlgorithm) -> &mut Self {
+        self.checksum = Some(algorithm);
+        self
+    }
+
+    /// Verify the checksum of an existing file that was closed cleanly, if it has one and we
+    /// have been asked to.
+    pub(crate) fn verify_checksum(
+        &self,
+        file: &File,
+        path: &Path,
+        fh: &FileHeader,
+    ) -> io::Result<()> {
+        if self.checksum.is_none() || fh.is_dirty() {
+            return Ok(());
+        }
+
+        let mut area = vec![0u8; (fh.header_len - fh.extensions_offset as u64) as usize];
+        file.read_exact_at(&mut area, fh.extensions_offset as u64)?;
+        let stored = match format::parse_extensions(&area)
+            .unwrap_or_default()
+            .into_iter()
+            .find(|(tag, _)| *tag == EXTENSION_TAG_CHECKSUM)
+        {
+            Some((_, range)) => &area[range],
+            None => return Ok(()),
+        };
+
+        let algorithm = stored
+            .first()
+            .and_then(|&id| ChecksumAlgorithm::from_id(id));
+        let algorithm = algorithm.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("File `{:?}`: Unknown checksum algorithm.", path),
+            )
+        })?;
+
+        // NOTE: Mapping an empty range fails, so the body is only mapped if there is one.
+        let digest = match file.metadata()?.len() > fh.header_len {
+            true => {
+                let mm = unsafe { Mmap::map(file)? };
+                algorithm.digest(&mm[fh.header_len as usize..])?
+            }
+            false => algorithm.digest(&[])?,
+        };
+
+        if digest != stored[1..] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "File `{:?}`: The body does not match its {:?} checksum.",
+                    path, algorithm
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// The algorithm of the checksum recorded in the header, if there is one. The checksum
+    /// itself is only up to date once the file has been closed cleanly.
+    pub fn checksum_algorithm(&self) -> Option<ChecksumAlgorithm> {
+        self.header_extension(EXTENSION_TAG_CHECKSUM)
+            .and_then(|value| value.first())
+            .and_then(|&id| ChecksumAlgorithm::from_id(id))
+    }
+
+    /// Make room for the checksum of the algorithm we were opened with, so that a header
+    /// without room for it fails to open rather than to close cleanly.
+    pub(crate) fn reserve_checksum(&mut self) -> io::Result<()> {
+        let algorithm = match self.checksum {
+            Some(algorithm) => algorithm,
+            None => return Ok(()),
+        };
+
+        let digest = algorithm.digest(&[])?;
+        let mut value = vec![algorithm.id()];
+        value.resize(1 + digest.len(), 0);
+        self.rewrite_extensions(EXTENSION_TAG_CHECKSUM, Some(&value))
+    }
+
+    /// Store the checksum of the body, with the algorithm we were opened with, or else with
+    /// the one already recorded so that the file does not keep a stale checksum.
+    pub(crate) fn store_checksum(&mut self) -> io::Result<()> {
+        let algorithm = match self.checksum.or_else(|| self.checksum_algorithm()) {
+            Some(algorithm) => algorithm,
+            None => return Ok(()),
+        };
+
+        let mut value = vec![algorithm.id()];
+        match algorithm.digest(&self.mm[self.header_len..]) {
+            Ok(digest) => value.extend_from_slice(&digest),
+            // NOTE: A checksum we cannot compute would only go stale, so it is dropped.
+            Err(_) => return self.rewrite_extensions(EXTENSION_TAG_CHECKSUM, None),
+        }
+        self.rewrite_extensions(EXTENSION_TAG_CHECKSUM, Some(&value))?;
+
+        self.mm.flush_range(0, self.header_len)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use std::fs::OpenOptions;
+
+    #[test]
+    pub fn test_checksum_detects_corruption() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        builder.checksum(ChecksumAlgorithm::Crc32c);
+
+        let mut mv = builder.try_open::<u32>(&path)?;
+        mv.extend([1, 2, 3])?;
+        mv.close()?;
+
+        let mv = builder.try_open::<u32>(&path)?;
+        assert_eq!(mv.checksum_algorithm(), Some(ChecksumAlgorithm::Crc32c));
+        assert_eq!(&mv[..], &[1, 2, 3]);
+        let header_len = mv.header_len;
+        mv.close()?;
+
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.write_all_at(&[9], header_len as u64)?;
+
+        let err = builder.try_open::<u32>(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("checksum"));
+
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::MmapedVec;
+use std::{io, mem, slice};
+
+impl<T> MmapedVec<T> {
+    /// Call `f` with consecutive chunks of up to `chunk_elems` elements, covering the body
+    /// from start to end.
+    ///
+    /// With `release_behind`, pages that the scan has moved past are written back and then
+    /// dropped both from the mapping (`MADV_DONTNEED`) and, where supported, from the page
+    /// cache (`POSIX_FADV_DONTNEED`), so that scanning a file larger than RAM does not evict
+    /// the page cache of everything else on the system.
+    ///
+    /// Elements modified through the chunks are not passed on to the
+    /// [`ReplicationSink`](crate::ReplicationSink); replicate those with
+    /// [`replicate_range`](MmapedVec::replicate_range).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_elems` is zero.
+    pub fn for_each_chunk<F>(
+        &mut self,
+        chunk_elems: usize,
+        release_behind: bool,
+        mut f: F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(&mut [T]),
+    {
+        assert!(chunk_elems > 0, "Chunks must hold at least one element.");
+
+        self.check_poisoned()?;
+
+        let len = self.len();
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut released_up_to = self.header_len / page_size * page_size;
+
+        let mut start = 0;
+        while start < len {
+            let end = len.min(start + chunk_elems);
+
+            self.set_writable(true)?;
+            let chunk =
+                unsafe { slice::from_raw_parts_mut(self.body_mut_ptr().add(start), end - start) };
+            f(chunk);
+            self.set_writable(false)?;
+
+            if release_behind {
+                let end_bytes = match end == len {
+                    true => self.mm.len(),
+                    false => (self.header_len + end * mem::size_of::<T>()) / page_size * page_size,
+                };
+
+                if end_bytes > released_up_to {
+                    self.release(released_up_to, end_bytes - released_up_to)?;
+                    released_up_to = end_bytes;
+                }
+            }
+
+            start = end;
+        }
+
+        Ok(())
+    }
+
+    /// Write back the pages in `len` bytes from `offset`, and drop them from memory.
+    fn release(&self, offset: usize, len: usize) -> io::Result<()> {
+        // Dirty pages are not dropped from the page cache, so write them back first.
+        self.mm.flush_range(offset, len)?;
+
+        let ret = unsafe {
+            libc::madvise(
+                self.mm.as_ptr().add(offset) as *mut libc::c_void,
+                len,
+                libc::MADV_DONTNEED,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            let ret = unsafe {
+                libc::posix_fadvise(
+                    self.file.as_raw_fd(),
+                    offset as libc::off_t,
+                    len as libc::off_t,
+                    libc::POSIX_FADV_DONTNEED,
+                )
+            };
+            if ret != 0 {
+                return Err(io::Error::from_raw_os_error(ret));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{new_mmaped_vec_of_example_persisting_in_tempdir, Example};
+
+    #[test]
+    pub fn test_for_each_chunk() -> Result<(), io::Error> {
+        let (_dir, _pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        mv.extend((0..10000).map(|i| Example {
+            hello: i as u8,
+            world: 0,
+        }))?;
+
+        for release_behind in [false, true] {
+            let mut seen = 0;
+            mv.for_each_chunk(3000, release_behind, |chunk| {
+                assert!(chunk.len() <= 3000);
+                for e in chunk.iter_mut() {
+                    e.world += 1;
+                }
+                seen += chunk.len();
+            })?;
+            assert_eq!(seen, 10000);
+        }
+
+        assert!(mv
+            .iter()
+            .enumerate()
+            .all(|(i, e)| e.hello == i as u8 && e.world == 2));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::format::{self, FILE_HEADER_LEN};
+use crate::MmapedVec;
+use std::io::{self, Write};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+
+/// Bytes shown on each line of a dump.
+const BYTES_PER_LINE: usize = 16;
+
+impl<T> MmapedVec<T> {
+    /// Write a hexdump of the header and of the elements in `range` to `writer`, with each
+    /// line annotated with its offset into the file and the header field or element index
+    /// that it shows. Meant for support and for triaging corrupted files.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is out of bounds, like slicing does.
+    pub fn debug_dump<R, W>(&self, range: R, writer: &mut W) -> io::Result<()>
+    where
+        R: RangeBounds<usize>,
+        W: Write + ?Sized,
+    {
+        let fh = self.header();
+        let start = match range.start_bound() {
+            Bound::Included(&n) => n,
+            Bound::Excluded(&n) => n + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&n) => n + 1,
+            Bound::Excluded(&n) => n,
+            Bound::Unbounded => self.len(),
+        };
+
+        assert!(
+            start <= end && end <= self.len(),
+            "Range {}..{} out of bounds for MmapedVec of length {}.",
+            start,
+            end,
+            self.len()
+        );
+
+        writeln!(
+            writer,
+            "{:?}: header ({} bytes)",
+            self.path, self.header_len
+        )?;
+
+        let h = &self.mm[..FILE_HEADER_LEN];
+        let fields: [(&str, usize, usize); 9] = [
+            ("magic_bytes", format::OFFSET_MAGIC_BYTES, 8),
+            ("endianness", format::OFFSET_ENDIANNESS, 2),
+            (
+                "persistence_format_version",
+                format::OFFSET_PERSISTENCE_FORMAT_VERSION,
+                3,
+            ),
+            (
+                "data_contained_version",
+                format::OFFSET_DATA_CONTAINED_VERSION,
+                3,
+            ),
+            ("flags", format::OFFSET_FLAGS, 4),
+            ("default_data_offset", format::OFFSET_DEFAULT_DATA_OFFSET, 4),
+            ("default_data_len", format::OFFSET_DEFAULT_DATA_LEN, 4),
+            ("extensions_offset", format::OFFSET_EXTENSIONS_OFFSET, 4),
+            ("header_len", format::OFFSET_HEADER_LEN, 8),
+        ];
+
+        for (name, offset, len) in fields.iter() {
+            dump_lines(writer, *offset, name, &h[*offset..offset + len])?;
+        }
+
+        if fh.has_default_data() {
+            let offset = fh.default_data_offset as usize;
+            let len = fh.default_data_len as usize;
+            dump_lines(
+                writer,
+                offset,
+                "default_data",
+                &self.mm[offset..offset + len],
+            )?;
+        }
+
+        let area = self.extensions_area();
+        for (tag, range) in format::parse_extensions(area).unwrap_or_default() {
+            dump_lines(
+                writer,
+                fh.extensions_offset as usize + range.start,
+                &format!("extension {:#06x}", tag),
+                &area[range],
+            )?;
+        }
+
+        writeln!(
+            writer,
+            "{:?}: elements {}..{} of {} ({} bytes each)",
+            self.path,
+            start,
+            end,
+            self.len(),
+            mem::size_of::<T>()
+        )?;
+
+        for i in start..end {
+            let offset = self.header_len + i * mem::size_of::<T>();
+            dump_lines(
+                writer,
+                offset,
+                &format!("[{}]", i),
+                &self.mm[offset..offset + mem::size_of::<T>()],
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Write `bytes`, found at `offset` into the file, labelled with `label` on the first line.
+fn dump_lines<W: Write + ?Sized>(
+    writer: &mut W,
+    offset: usize,
+    label: &str,
+    bytes: &[u8],
+) -> io::Result<()> {
+    for (n, chunk) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| match b {
+                0x20..=0x7e => b as char,
+                _ => '.',
+            })
+            .collect();
+
+        writeln!(
+            writer,
+            "  {:08x}  {:<28}  {:<47}  |{}|",
+            offset + n * BYTES_PER_LINE,
+            match n {
+                0 => label,
+                _ => "",
+            },
+            hex.join(" "),
+            ascii
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{new_mmaped_vec_of_example_persisting_in_tempdir, Example};
+
+    #[test]
+    pub fn test_debug_dump() -> Result<(), io::Error> {
+        let (_dir, _pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        mv.push(Example { hello: 3, world: 4 })?;
+        mv.push(Example { hello: 5, world: 6 })?;
+        mv.set_header_extension(0x4001, b"abc")?;
+
+        let mut out = vec![];
+        mv.debug_dump(1.., &mut out)?;
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("00000000  magic_bytes"));
+        assert!(out.contains("default_data                  01 02"));
+        assert!(out.contains("extension 0x4001              61 62 63"));
+        assert!(out.contains("elements 1..2 of 2"));
+        assert!(out.contains("00001002  [1]                           05 06"));
+        assert!(!out.contains("[0]"));
+
+        Ok(())
+    }
+}
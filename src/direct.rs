@@ -0,0 +1,292 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Direct I/O mode, in which flushes write the parts of the mapping that were marked modified
+//! through a second descriptor of the file opened with `O_DIRECT`, for applications that
+//! manage their own caching and do not want the page cache to hold on to what they flush.
+//! Reads keep going through the mapping, and flushes still `msync` it afterwards, for
+//! whatever was modified without being marked modified.
+
+use crate::{FlushOrder, MmapedVec, MmapedVecBuilder};
+use std::alloc::{self, Layout};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::ops::Range;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+
+/// Alignment of the offsets, lengths and buffers of direct writes. A multiple of the
+/// logical block size of any device we expect to run on.
+const DIRECT_IO_ALIGN: usize = 4096;
+
+/// Most bytes copied into the aligned buffer per write, bounding the memory it takes.
+const DIRECT_IO_CHUNK: usize = 1024 * 1024;
+
+/// A buffer aligned for direct I/O.
+struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        let layout = Layout::from_size_align(len, DIRECT_IO_ALIGN).unwrap();
+        let ptr = unsafe { alloc::alloc(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Self { ptr, layout }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// The `O_DIRECT` descriptor of a file in direct I/O mode, and the ranges of elements
+/// modified since the last flush.
+pub(crate) struct DirectWriter {
+    file: File,
+    pending: Vec<Range<usize>>,
+}
+
+/// Open `file` again with `O_DIRECT`, through `/proc` rather than by its path, so that it is
+/// the same file even if the path has been renamed over since.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn reopen_direct(file: &File, path: &Path) -> io::Result<File> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(format!("/proc/self/fd/{}", file.as_raw_fd()))
+        .map_err(|e| match e.raw_os_error() {
+            Some(libc::EINVAL) => io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "File `{:?}`: The file system does not support O_DIRECT.",
+                    path
+                ),
+            ),
+            _ => e,
+        })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn reopen_direct(_file: &File, path: &Path) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("File `{:?}`: Direct I/O is only supported on Linux.", path),
+    ))
+}
+
+fn same_file(a: &File, b: &File) -> io::Result<bool> {
+    let (a, b) = (a.metadata()?, b.metadata()?);
+    Ok((a.dev(), a.ino()) == (b.dev(), b.ino()))
+}
+
+impl DirectWriter {
+    pub(crate) fn open(file: &File, path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: reopen_direct(file, path)?,
+            pending: vec![],
+        })
+    }
+
+    pub(crate) fn modified(&mut self, range: Range<usize>) {
+        if range.start < range.end {
+            self.pending.push(range);
+        }
+    }
+
+    /// Write `range` of `mm` to the file, directly where it is aligned, and through `file`
+    /// for the partial block at the end of the file, if any.
+    fn write_range(&self, mm: &[u8], file: &File, range: Range<usize>) -> io::Result<()> {
+        let start = range.start / DIRECT_IO_ALIGN * DIRECT_IO_ALIGN;
+        let end = (range.end.div_ceil(DIRECT_IO_ALIGN) * DIRECT_IO_ALIGN)
+            .min(mm.len() / DIRECT_IO_ALIGN * DIRECT_IO_ALIGN);
+
+        let mut buf = AlignedBuf::new(DIRECT_IO_CHUNK);
+        let mut offset = start;
+        while offset < end {
+            let len = (end - offset).min(DIRECT_IO_CHUNK);
+            let chunk = &mut buf.as_mut_slice()[..len];
+            chunk.copy_from_slice(&mm[offset..offset + len]);
+            self.file.write_all_at(chunk, offset as u64)?;
+            offset += len;
+        }
+
+        // NOTE: A direct write of the whole last block would extend the file past its end.
+        if range.end > end.max(start) {
+            let tail = end.max(start)..range.end;
+            file.write_all_at(&mm[tail.clone()], tail.start as u64)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the header and the modified elements of `mm`, with its body starting at
+    /// `header_len`, in `order`, and make them durable. `file` is the file that is mapped,
+    /// which is opened again if it has been replaced, as by rolling.
+    pub(crate) fn commit(
+        &mut self,
+        mm: &[u8],
+        file: &File,
+        path: &Path,
+        header_len: usize,
+        elem_size: usize,
+        order: FlushOrder,
+    ) -> io::Result<()> {
+        if !same_file(&self.file, file)? {
+            self.file = reopen_direct(file, path)?;
+        }
+
+        let mut ranges = mem::take(&mut self.pending);
+        ranges.sort_by_key(|range| range.start);
+
+        let mut body: Vec<Range<usize>> = vec![];
+        for range in &ranges {
+            let start = header_len + range.start * elem_size;
+            let end = (header_len + range.end * elem_size).min(mm.len());
+            if start >= end {
+                continue;
+            }
+            match body.last_mut() {
+                Some(last) if start <= last.end => last.end = last.end.max(end),
+                _ => body.push(start..end),
+            }
+        }
+
+        let result = (|| {
+            let write_body = || -> io::Result<()> {
+                for range in &body {
+                    self.write_range(mm, file, range.clone())?;
+                }
+                Ok(())
+            };
+            let write_header = || self.write_range(mm, file, 0..header_len);
+
+            match order {
+                FlushOrder::DataFirst => {
+                    write_body()?;
+                    self.file.sync_data()?;
+                    write_header()?;
+                }
+                FlushOrder::HeaderFirst => {
+                    write_header()?;
+                    self.file.sync_data()?;
+                    write_body()?;
+                }
+                FlushOrder::Unordered => {
+                    write_header()?;
+                    write_body()?;
+                }
+            }
+            self.file.sync_data()
+        })();
+
+        if result.is_err() {
+            self.pending = ranges;
+        }
+        result
+    }
+}
+
+impl MmapedVecBuilder {
+    /// Make synchronous flushes write the header and the elements
+    /// [marked modified](MmapedVec::mark_modified) to the file with direct I/O, bypassing the
+    /// page cache. Off by default, and only supported on Linux, on file systems that support
+    /// `O_DIRECT`.
+    ///
+    /// Flushes still `msync` the mapping afterwards, so that modifications made through
+    /// dereferencing the [`MmapedVec`](MmapedVec) mutably without marking them modified are
+    /// made durable too, as they would be without direct I/O.
+    ///
+    /// NOTE: The kernel writes back any pages of the mapping that are dirty before writing
+    /// directly over them, so this saves memory in the page cache, not writes to the disk.
+    pub fn direct_io(&mut self, direct_io: bool) -> &mut Self {
+        self.direct_io = direct_io;
+        self
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// Whether flushes go through direct I/O. See
+    /// [`direct_io`](MmapedVecBuilder::direct_io).
+    pub fn is_direct_io(&self) -> bool {
+        self.direct.is_some()
+    }
+
+    /// Write what was marked modified through direct I/O, if in that mode.
+    pub(crate) fn flush_direct(&mut self) -> io::Result<()> {
+        match self.direct.as_mut() {
+            Some(direct) => direct.commit(
+                &self.mm,
+                &self.file,
+                &self.path,
+                self.header_len,
+                mem::size_of::<T>(),
+                self.flush_order,
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use std::fs;
+
+    #[test]
+    pub fn test_direct_io_flushes() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        builder.direct_io(true);
+
+        let mut mv: MmapedVec<u64> = match builder.try_open(&path) {
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(()),
+            result => result?,
+        };
+        assert!(mv.is_direct_io());
+
+        mv.extend(0..3000)?;
+        mv.flush()?;
+        mv[1234] = 42;
+        mv.mark_modified(1234..1235);
+        mv.flush()?;
+        drop(mv);
+
+        let body = fs::read(&path)?;
+        let mv: MmapedVec<u64> = builder.direct_io(false).try_open(&path)?;
+        assert_eq!(mv[1234], 42);
+        assert_eq!(mv[2999], 2999);
+        assert_eq!(body.len(), mv.header_len() + 3000 * 8);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Grace periods for mappings that are replaced while they are pinned, so that readers in
+//! this process can finish with the old mapping before it goes away.
+
+use crate::MmapedVec;
+use memmap::MmapMut;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A mapping that has been replaced, kept until the pins taken on it are dropped.
+pub(crate) struct Retired {
+    mapping_generation: u64,
+    mm: MmapMut,
+    pins: Arc<AtomicUsize>,
+}
+
+impl<T> MmapedVec<T> {
+    /// Replace the mapping with `mm`, as the next mapping generation. If the current one is
+    /// pinned, it is retired rather than unmapped, and the pins stay with it.
+    pub(crate) fn replace_mapping(&mut self, mm: MmapMut) {
+        let old = mem::replace(&mut self.mm, mm);
+
+        if self.is_pinned() {
+            let pins = mem::replace(&mut self.pins, Arc::new(AtomicUsize::new(0)));
+            self.retired.push(Retired {
+                mapping_generation: self.mapping_generation,
+                mm: old,
+                pins,
+            });
+        }
+
+        self.mapping_generation += 1;
+        self.reclaim_retired();
+    }
+
+    /// Unmap the retired mappings whose pins have all been dropped, returning how many are
+    /// left. This happens by itself whenever a mapping is replaced.
+    pub fn reclaim_retired(&mut self) -> usize {
+        self.retired
+            .retain(|retired| retired.pins.load(Ordering::Acquire) > 0);
+        self.retired.len()
+    }
+
+    /// The mapping generations of the retired mappings that are still pinned, oldest first.
+    /// A [`PinnedSlice`](crate::PinnedSlice) of an older generation than the current
+    /// [`mapping_generation`](MmapedVec::mapping_generation) should be dropped, and taken
+    /// again, as soon as the reader is done with it.
+    pub fn retired_generations(&self) -> Vec<u64> {
+        self.retired
+            .iter()
+            .filter(|retired| retired.pins.load(Ordering::Acquire) > 0)
+            .map(|retired| retired.mapping_generation)
+            .collect()
+    }
+
+    /// Leak the retired mappings that are still pinned, since they must stay valid.
+    pub(crate) fn leak_retired(&mut self) {
+        self.reclaim_retired();
+        for retired in self.retired.drain(..) {
+            mem::forget(retired.mm);
+        }
+    }
+}
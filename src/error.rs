@@ -0,0 +1,175 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Error returned when growing a [`MmapedVec`](crate::MmapedVec) would exceed the limits
+/// set with [`max_len_bytes`](crate::MmapedVecBuilder::max_len_bytes) or
+/// [`max_elements`](crate::MmapedVecBuilder::max_elements).
+///
+/// It is wrapped in an [`io::Error`](std::io::Error), from which it can be recovered
+/// through [`get_ref`](std::io::Error::get_ref) and `downcast_ref`.
+#[derive(Debug)]
+pub struct CapacityExceeded {
+    pub requested_elements: usize,
+    pub requested_len_bytes: u64,
+    pub max_elements: Option<usize>,
+    pub max_len_bytes: Option<u64>,
+}
+
+impl fmt::Display for CapacityExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Capacity exceeded: {} elements ({} bytes) requested, limits are {:?} elements \
+      and {:?} bytes.",
+            self.requested_elements,
+            self.requested_len_bytes,
+            self.max_elements,
+            self.max_len_bytes
+        )
+    }
+}
+
+impl Error for CapacityExceeded {}
+
+/// Error returned when growing a [`MmapedVec`](crate::MmapedVec) with
+/// [`check_free_space`](crate::MmapedVecBuilder::check_free_space) enabled, and the
+/// file system that the file resides on does not have room for the growth.
+///
+/// It is wrapped in an [`io::Error`](std::io::Error) in the same way as
+/// [`CapacityExceeded`](CapacityExceeded).
+#[derive(Debug)]
+pub struct InsufficientSpace {
+    pub path: PathBuf,
+    pub required_bytes: u64,
+    pub available_bytes: u64,
+}
+
+impl fmt::Display for InsufficientSpace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "File `{:?}`: Insufficient space, growing requires {} bytes but only {} bytes \
+      are available on the file system. Free up disk space, or move the file to a file \
+      system with more room.",
+            self.path, self.required_bytes, self.available_bytes
+        )
+    }
+}
+
+impl Error for InsufficientSpace {}
+
+/// Error returned by operations on a [`MmapedVec`](crate::MmapedVec) that has been
+/// poisoned by a panic while a [`WriteGuard`](crate::WriteGuard) was held.
+///
+/// It is wrapped in an [`io::Error`](std::io::Error) in the same way as
+/// [`CapacityExceeded`](CapacityExceeded).
+#[derive(Debug)]
+pub struct Poisoned {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for Poisoned {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "File `{:?}`: Poisoned by a panic during mutation. Verify the elements, and call \
+      clear_poison() to continue using it.",
+            self.path
+        )
+    }
+}
+
+impl Error for Poisoned {}
+
+/// Error returned when opening a file on a platform where advisory locks were found to
+/// provide no exclusion between processes, so that a file could be corrupted by being
+/// opened twice.
+///
+/// It is wrapped in an [`io::Error`](std::io::Error) in the same way as
+/// [`CapacityExceeded`](CapacityExceeded).
+#[derive(Debug)]
+pub struct UnsupportedPlatform {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for UnsupportedPlatform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "File `{:?}`: Advisory locks on this platform do not exclude other processes,       so the file cannot be opened safely.",
+            self.path
+        )
+    }
+}
+
+impl Error for UnsupportedPlatform {}
+
+/// Error returned by [`check_compatibility`](crate::Store::check_compatibility) when files
+/// in a [`Store`](crate::Store) do not match what the application expects of them, listing
+/// every such file by name along with what is wrong with it.
+///
+/// It is wrapped in an [`io::Error`](std::io::Error) in the same way as
+/// [`CapacityExceeded`](CapacityExceeded).
+#[derive(Debug)]
+pub struct Incompatible {
+    pub dir: PathBuf,
+    pub problems: Vec<(String, String)>,
+}
+
+impl fmt::Display for Incompatible {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Store `{:?}`: {} file(s) incompatible:",
+            self.dir,
+            self.problems.len()
+        )?;
+        for (name, problem) in &self.problems {
+            write!(f, " `{}`: {}", name, problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Incompatible {}
+
+/// Error returned when opening a file for writing that is on a read-only file system.
+///
+/// It is wrapped in an [`io::Error`](std::io::Error) of kind
+/// [`ReadOnlyFilesystem`](std::io::ErrorKind::ReadOnlyFilesystem), in the same way as
+/// [`CapacityExceeded`](CapacityExceeded).
+#[derive(Debug)]
+pub struct ReadOnlyFileSystem {
+    pub path: PathBuf,
+}
+
+impl fmt::Display for ReadOnlyFileSystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "File `{:?}`: On a read-only file system, so it cannot be opened for writing. \
+      Remount the file system read-write, or copy the file to one that is writable, or open \
+      it with MmapedVecBuilder::try_open_read_only() if reading is all that is needed.",
+            self.path
+        )
+    }
+}
+
+impl Error for ReadOnlyFileSystem {}
@@ -0,0 +1,150 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Expiry of elements, for persistent caches and session stores.
+
+use crate::MmapedVec;
+use std::io;
+use std::ptr;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Elements that expire, which [`reap_expired`](MmapedVec::reap_expired) removes once they
+/// have.
+pub trait HasExpiry {
+    /// When the element expires, or `None` if it never does.
+    fn expires_at(&self) -> Option<SystemTime>;
+}
+
+impl<T: HasExpiry> MmapedVec<T> {
+    /// Remove the elements that have expired by now, moving the rest down to close the
+    /// gaps, in order, and shrinking the file. Returns how many were removed.
+    pub fn reap_expired(&mut self) -> io::Result<usize> {
+        self.reap_expired_at(SystemTime::now())
+    }
+
+    /// Like [`reap_expired`](MmapedVec::reap_expired), but as of `now`.
+    pub fn reap_expired_at(&mut self, now: SystemTime) -> io::Result<usize> {
+        self.check_poisoned()?;
+
+        let len = self.len();
+        let is_expired = |elem: &T| elem.expires_at().is_some_and(|at| at <= now);
+
+        let first = match self.iter().position(is_expired) {
+            Some(first) => first,
+            None => return Ok(0),
+        };
+
+        self.set_writable(true)?;
+        let body = self.body_mut_ptr();
+        let mut kept = first;
+        for i in first + 1..len {
+            // NOTE: Elements are moved as bytes, as everywhere else; nothing is dropped.
+            if !is_expired(unsafe { &*body.add(i) }) {
+                unsafe { ptr::copy_nonoverlapping(body.add(i), body.add(kept), 1) };
+                kept += 1;
+            }
+        }
+        self.set_writable(false)?;
+
+        self.mark_modified(first..kept);
+        self.truncate(kept)?;
+        Ok(len - kept)
+    }
+}
+
+/// Reaps a [`MmapedVec`](MmapedVec) of expiring elements at most once per interval, when
+/// [`run`](ExpiryReaper::run) is called.
+///
+/// Like the [`MaintenanceScheduler`](crate::MaintenanceScheduler), nothing runs in the
+/// background; call [`run`](ExpiryReaper::run) from wherever the application does its
+/// housekeeping, as often as it likes.
+#[derive(Clone, Debug)]
+pub struct ExpiryReaper {
+    min_interval: Duration,
+    last_run: Option<Instant>,
+}
+
+impl ExpiryReaper {
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            last_run: None,
+        }
+    }
+
+    /// Reap `mv` unless it was reaped in the last `min_interval`, returning how many
+    /// elements were removed, or `None` if it was not time yet.
+    pub fn run<T: HasExpiry>(&mut self, mv: &mut MmapedVec<T>) -> io::Result<Option<usize>> {
+        if self
+            .last_run
+            .is_some_and(|last| last.elapsed() < self.min_interval)
+        {
+            return Ok(None);
+        }
+
+        self.last_run = Some(Instant::now());
+        mv.reap_expired().map(Some)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::MmapedVecBuilder;
+
+    #[test]
+    pub fn test_reap_expired() -> Result<(), io::Error> {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        #[derive(Clone, Copy, Debug, Default, PartialEq)]
+        struct Session {
+            id: u32,
+            expires_at_secs: u64,
+        }
+
+        impl HasExpiry for Session {
+            fn expires_at(&self) -> Option<SystemTime> {
+                match self.expires_at_secs {
+                    0 => None,
+                    secs => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+                }
+            }
+        }
+
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut mv: MmapedVec<Session> =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+                .try_open(&path)?;
+        mv.extend((0..10).map(|id| Session {
+            id,
+            expires_at_secs: [0, 100, 200][id as usize % 3],
+        }))?;
+
+        let now = UNIX_EPOCH + Duration::from_secs(150);
+        assert_eq!(mv.reap_expired_at(now)?, 3);
+        let ids: Vec<u32> = mv.iter().map(|s| s.id).collect();
+        assert_eq!(ids, [0, 2, 3, 5, 6, 8, 9]);
+        assert_eq!(mv.reap_expired_at(now)?, 0);
+
+        let mut reaper = ExpiryReaper::new(Duration::from_secs(3600));
+        assert_eq!(reaper.run(&mut mv)?, Some(3));
+        assert_eq!(reaper.run(&mut mv)?, None);
+        assert!(mv.iter().all(|s| s.expires_at_secs == 0));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::format::{
+    self, EXTENSION_ENTRY_HEADER_LEN, EXTENSION_TAG_CHECKSUM, EXTENSION_TAG_END,
+    EXTENSION_TAG_HINTS, EXTENSION_TAG_LOCK_FILE, EXTENSION_TAG_SEQUENCE, EXTENSION_TAG_STRIDE,
+    SEQUENCE_VALUE_LEN,
+};
+use crate::MmapedVec;
+use std::io;
+
+impl<T> MmapedVec<T> {
+    /// The value of the header extension with the given `tag`, if the header has one.
+    pub fn header_extension(&self, tag: u16) -> Option<&[u8]> {
+        let area = self.extensions_area();
+
+        format::parse_extensions(area)?
+            .into_iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, range)| &area[range])
+    }
+
+    /// Store `value` as the header extension with the given `tag`, replacing any existing
+    /// value for it.
+    ///
+    /// Extensions live in the padding between the header and the body, so there is only
+    /// limited room for them. Tags below `0x4000` and between `0x8000` and `0xC000` are
+    /// reserved for use by this library. Tags with the `0x8000` bit set are critical, and
+    /// make versions of this library that do not understand them refuse to open the file.
+    pub fn set_header_extension(&mut self, tag: u16, value: &[u8]) -> io::Result<()> {
+        check_tag(tag)?;
+        self.rewrite_extensions(tag, Some(value))
+    }
+
+    pub fn remove_header_extension(&mut self, tag: u16) -> io::Result<()> {
+        check_tag(tag)?;
+        self.rewrite_extensions(tag, None)
+    }
+
+    /// Add the sequence extension for optimistic readers if the file does not have it yet
+    /// and there is room for it. Files without room are left without it.
+    pub(crate) fn ensure_sequence_extension(&mut self) -> io::Result<()> {
+        if self.header_extension(EXTENSION_TAG_SEQUENCE).is_none() {
+            self.add_sequence_extension()?;
+        }
+
+        // NOTE: Publishes the length, and ends any write that was in progress in a crash.
+        self.set_writable(true)?;
+        self.set_writable(false)
+    }
+
+    fn add_sequence_extension(&mut self) -> io::Result<()> {
+        let area = self.extensions_area();
+        let used: usize = format::parse_extensions(area)
+            .unwrap_or_default()
+            .iter()
+            .map(|(_, range)| EXTENSION_ENTRY_HEADER_LEN + range.len())
+            .sum();
+
+        if used + EXTENSION_ENTRY_HEADER_LEN + SEQUENCE_VALUE_LEN > area.len() {
+            return Ok(());
+        }
+
+        self.rewrite_extensions(EXTENSION_TAG_SEQUENCE, Some(&[0; SEQUENCE_VALUE_LEN]))
+    }
+
+    pub(crate) fn extensions_area(&self) -> &[u8] {
+        let fh = self.header();
+        &self.mm[fh.extensions_offset as usize..fh.header_len as usize]
+    }
+
+    pub(crate) fn rewrite_extensions(&mut self, tag: u16, value: Option<&[u8]>) -> io::Result<()> {
+        self.check_poisoned()?;
+
+        let (buf, keep) = rewrite_area(self.extensions_area(), tag, value).map_err(|needed| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "File `{:?}`: Header extensions need {} bytes, but there is only room for {}.",
+                    self.path,
+                    needed,
+                    self.extensions_area().len()
+                ),
+            )
+        })?;
+
+        let fh = self.header();
+        self.set_writable(true)?;
+        self.mm[fh.extensions_offset as usize + keep..fh.header_len as usize]
+            .copy_from_slice(&buf[keep..]);
+        self.set_writable(false)
+    }
+}
+
+/// The extensions area `area` with the extension `tag` set to `value`, or removed, and how
+/// many bytes at the start of it must be left as they are when writing it back. Fails with
+/// the number of bytes needed if the extensions do not fit.
+pub(crate) fn rewrite_area(
+    area: &[u8],
+    tag: u16,
+    value: Option<&[u8]>,
+) -> Result<(Vec<u8>, usize), usize> {
+    let mut extensions: Vec<(u16, &[u8])> = format::parse_extensions(area)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(t, _)| *t != tag)
+        .map(|(t, range)| (t, &area[range]))
+        .collect();
+
+    if let Some(value) = value {
+        extensions.push((tag, value));
+    }
+
+    // NOTE: The sequence extension is kept first, so that it stays where optimistic
+    //       readers found it, and its value is left as it is, since it is live.
+    extensions.sort_by_key(|(t, _)| *t != EXTENSION_TAG_SEQUENCE);
+    let keep = match format::sequence_offset(0, area) {
+        Some(_) => EXTENSION_ENTRY_HEADER_LEN + SEQUENCE_VALUE_LEN,
+        None => 0,
+    };
+
+    let mut buf = format::encode_extensions(&extensions);
+    if buf.len() > area.len() {
+        return Err(buf.len());
+    }
+    buf.resize(area.len(), 0);
+
+    Ok((buf, keep))
+}
+
+fn check_tag(tag: u16) -> io::Result<()> {
+    match tag {
+        EXTENSION_TAG_END => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Header extension tag zero is reserved to end the list of extensions.",
+        )),
+        EXTENSION_TAG_SEQUENCE => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Header extension tag one is reserved for the sequence of optimistic reads.",
+        )),
+        EXTENSION_TAG_CHECKSUM => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Header extension tag two is reserved for the checksum of the body.",
+        )),
+        EXTENSION_TAG_STRIDE => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Header extension tag three is reserved for the stride of padded elements.",
+        )),
+        EXTENSION_TAG_LOCK_FILE => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Header extension tag four is reserved to record that there is a lock file.",
+        )),
+        EXTENSION_TAG_HINTS => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Header extension tag five is reserved for the options the file was created with.",
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        new_mmaped_vec_of_example_persisting_in_tempdir, Example, EXAMPLE_DATA_CONTAINED_VERSION,
+        EXAMPLE_MAGIC_BYTES,
+    };
+
+    #[test]
+    pub fn test_header_extensions_persist_and_unknown_critical_is_refused() -> Result<(), io::Error>
+    {
+        let (_dir, pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        mv.set_header_extension(0x4001, b"hello")?;
+        mv.set_header_extension(0x4002, b"world")?;
+        mv.set_header_extension(0x4001, b"HELLO")?;
+        mv.remove_header_extension(0x4002)?;
+        drop(mv);
+
+        let mut mv = MmapedVec::<Example>::try_new(
+            pathbuf.as_path(),
+            EXAMPLE_MAGIC_BYTES,
+            EXAMPLE_DATA_CONTAINED_VERSION,
+        )?;
+
+        assert_eq!(mv.header_extension(0x4001), Some(&b"HELLO"[..]));
+        assert_eq!(mv.header_extension(0x4002), None);
+
+        mv.set_header_extension(0xC001, b"from the future")?;
+        drop(mv);
+
+        let mv_err = MmapedVec::<Example>::try_new(
+            pathbuf.as_path(),
+            EXAMPLE_MAGIC_BYTES,
+            EXAMPLE_DATA_CONTAINED_VERSION,
+        )
+        .err()
+        .unwrap();
+
+        assert!(mv_err
+            .to_string()
+            .contains("Requires header extension 0xc001"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Fault injection for crash-consistency testing, enabled with the `failpoints` feature.
+//!
+//! Arming a [`Failpoint`](Failpoint) makes the next operation that reaches it fail in the
+//! way given by its [`Action`](Action). Failpoints are armed per thread, so that tests
+//! running in parallel do not trip each other's failpoints.
+//!
+//! This feature is meant for tests only, and should never be enabled in production builds.
+
+use std::cell::RefCell;
+use std::{io, process};
+
+/// Places in the library where faults can be injected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failpoint {
+    /// After the header of a new or upgraded file has been written, before the rest of the
+    /// header and the length of the file are.
+    AfterHeaderWrite,
+    /// After the file has been grown, before it has been mapped again.
+    MidRemap,
+    /// After the length of the file has been increased to make room for new elements,
+    /// before the new elements have been written.
+    BetweenLengthCommitAndDataWrite,
+    /// Before the mapping is synced to disk.
+    DuringMsync,
+    /// After the empty file has been renamed into place when rolling, before it has been
+    /// mapped.
+    AfterRollRename,
+}
+
+/// What happens when an armed failpoint is reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// The operation fails with an [`io::Error`](io::Error).
+    Error,
+    /// The thread panics.
+    Panic,
+    /// The process aborts, as it would if it crashed or lost power at this point.
+    Abort,
+}
+
+struct Armed {
+    point: Failpoint,
+    skip: usize,
+    action: Action,
+}
+
+thread_local! {
+    static ARMED: RefCell<Vec<Armed>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Arm `point` on this thread, so that it triggers `action` the next time it is reached.
+pub fn set(point: Failpoint, action: Action) {
+    set_after(point, 0, action)
+}
+
+/// Arm `point` on this thread, so that it triggers `action` after having been reached
+/// `skip` times without triggering.
+pub fn set_after(point: Failpoint, skip: usize, action: Action) {
+    clear(point);
+    ARMED.with(|armed| {
+        armed.borrow_mut().push(Armed {
+            point,
+            skip,
+            action,
+        })
+    });
+}
+
+/// Disarm `point` on this thread.
+pub fn clear(point: Failpoint) {
+    ARMED.with(|armed| armed.borrow_mut().retain(|a| a.point != point));
+}
+
+/// Disarm all failpoints on this thread.
+pub fn clear_all() {
+    ARMED.with(|armed| armed.borrow_mut().clear());
+}
+
+/// Called by the library when reaching `point`. Failpoints are disarmed once triggered.
+pub(crate) fn hit(point: Failpoint) -> io::Result<()> {
+    let action = ARMED.with(|armed| {
+        let mut armed = armed.borrow_mut();
+        let i = armed.iter().position(|a| a.point == point)?;
+
+        match armed[i].skip {
+            0 => Some(armed.remove(i).action),
+            _ => {
+                armed[i].skip -= 1;
+                None
+            }
+        }
+    });
+
+    match action {
+        None => Ok(()),
+        Some(Action::Error) => Err(io::Error::other(format!(
+            "Failpoint {:?} triggered.",
+            point
+        ))),
+        Some(Action::Panic) => panic!("Failpoint {:?} triggered.", point),
+        Some(Action::Abort) => process::abort(),
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{new_mmaped_vec_of_example_persisting_in_tempdir, Example};
+    use std::{fs, mem};
+
+    #[test]
+    pub fn test_failpoints() -> Result<(), io::Error> {
+        let (_dir, pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        set(Failpoint::MidRemap, Action::Error);
+        assert!(mv.push(Example { hello: 3, world: 4 }).is_err());
+        assert_eq!(mv.len(), 0);
+        assert_eq!(fs::metadata(&pathbuf)?.len(), mv.header_len as u64);
+
+        set_after(Failpoint::DuringMsync, 1, Action::Error);
+        mv.flush()?;
+        assert!(mv.flush().is_err());
+        mv.flush()?;
+
+        set(Failpoint::BetweenLengthCommitAndDataWrite, Action::Error);
+        assert!(mv.push(Example { hello: 3, world: 4 }).is_err());
+        assert_eq!(mv.len(), 1);
+
+        set(Failpoint::AfterRollRename, Action::Error);
+        assert!(mv.roll().is_err());
+        assert_eq!(fs::read_dir(pathbuf.parent().unwrap())?.count(), 1);
+        mv.push(Example { hello: 5, world: 6 })?;
+        mv.flush()?;
+        assert_eq!(
+            fs::metadata(&pathbuf)?.len(),
+            (mv.header_len + 2 * mem::size_of::<Example>()) as u64
+        );
+
+        clear_all();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::MmapedVec;
+use std::ops::Range;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+/// What happened to a range of elements, as reported by [`subscribe`](MmapedVec::subscribe).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Push,
+    Overwrite,
+    Remove,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub kind: ChangeKind,
+    pub range: Range<usize>,
+    /// Number of the flush that committed the change, counting from one for each
+    /// [`MmapedVec`](MmapedVec), and shared by all changes committed by the same flush.
+    pub generation: u64,
+}
+
+/// Changes made since the last flush, and who to send them to once it comes.
+pub(crate) struct ChangeFeed {
+    subscribers: Vec<Sender<ChangeEvent>>,
+    pending: Vec<(ChangeKind, Range<usize>)>,
+    len: usize,
+    generation: u64,
+}
+
+impl ChangeFeed {
+    pub(crate) fn modified(&mut self, range: Range<usize>) {
+        let overwritten = range.start..range.end.min(self.len);
+        let pushed = range.start.max(self.len)..range.end;
+
+        if !overwritten.is_empty() {
+            self.pending.push((ChangeKind::Overwrite, overwritten));
+        }
+        if !pushed.is_empty() {
+            self.len = pushed.end;
+            self.pending.push((ChangeKind::Push, pushed));
+        }
+    }
+
+    pub(crate) fn removed(&mut self, len: usize) {
+        if len < self.len {
+            self.pending.push((ChangeKind::Remove, len..self.len));
+            self.len = len;
+        }
+    }
+
+    pub(crate) fn commit(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        self.generation += 1;
+
+        for (kind, range) in self.pending.drain(..) {
+            let event = ChangeEvent {
+                kind,
+                range,
+                generation: self.generation,
+            };
+            // NOTE: Subscribers that have dropped their receiver are forgotten.
+            self.subscribers
+                .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        }
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// Receive the changes made to the elements, as they are committed by each
+    /// [`flush`](MmapedVec::flush), for other components of this process to react to.
+    ///
+    /// Changes made by the methods of the [`MmapedVec`](MmapedVec) are reported as they are.
+    /// Those made through dereferencing it mutably are reported once recorded with
+    /// [`mark_modified`](MmapedVec::mark_modified), and those made through a
+    /// [`WriteGuard`](crate::WriteGuard) as changes to the elements it covers once it is dropped.
+    /// Changes are only collected while there are subscribers.
+    pub fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::channel();
+        let len = self.len();
+
+        self.change_feed
+            .get_or_insert_with(|| ChangeFeed {
+                subscribers: vec![],
+                pending: vec![],
+                len,
+                generation: 0,
+            })
+            .subscribers
+            .push(sender);
+
+        receiver
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use std::io;
+
+    #[test]
+    pub fn test_change_feed() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut mv =
+            MmapedVec::<u32>::try_new(&path, EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)?;
+        mv.extend([1, 2, 3])?;
+
+        let changes = mv.subscribe();
+        mv.push(4)?;
+        mv.copy_within(0..2, 2)?;
+        assert!(changes.try_recv().is_err());
+
+        mv.flush()?;
+        mv.truncate(1)?;
+        mv.flush()?;
+
+        let events: Vec<_> = changes
+            .try_iter()
+            .map(|e| (e.kind, e.range, e.generation))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (ChangeKind::Push, 3..4, 1),
+                (ChangeKind::Overwrite, 2..4, 1),
+                (ChangeKind::Remove, 1..4, 2),
+            ]
+        );
+
+        mv.close()?;
+        assert_eq!(
+            changes.recv_timeout(std::time::Duration::from_secs(1)),
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected)
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::{locking, MmapedVec};
+use memmap::MmapMut;
+use std::fs::{File, OpenOptions};
+use std::{io, mem, process};
+
+#[cfg(feature = "fork-detection")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "fork-detection")]
+static FORK_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(feature = "fork-detection")]
+extern "C" fn on_fork_in_child() {
+    FORK_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of times this process is a forked child of the process that loaded the library,
+/// counted by a `pthread_atfork` handler that is installed on first use. Always zero
+/// without the `fork-detection` feature.
+pub(crate) fn generation() -> u64 {
+    #[cfg(feature = "fork-detection")]
+    {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| unsafe {
+            libc::pthread_atfork(None, None, Some(on_fork_in_child));
+        });
+        FORK_GENERATION.load(Ordering::Relaxed)
+    }
+    #[cfg(not(feature = "fork-detection"))]
+    0
+}
+
+impl<T> MmapedVec<T> {
+    /// Whether this handle was inherited from the process that opened it, across `fork()`.
+    pub fn is_inherited_across_fork(&self) -> bool {
+        self.owner_pid != process::id()
+    }
+
+    /// Make a handle inherited across `fork()` usable in the child, by opening and locking
+    /// the file anew, which fails for as long as the parent still has it open. Does nothing
+    /// in the process that opened the file.
+    ///
+    /// A forked child shares the open file description of the parent, and with it the lock,
+    /// so without this, parent and child could both write to the file believing they hold
+    /// the lock alone. The inherited descriptors and mapping are replaced before locking
+    /// anew, which leaves the parent's lock in place. Fails with
+    /// [`ResourceBusy`](io::ErrorKind::ResourceBusy) while any mapping is pinned, as it would
+    /// hold on to that lock.
+    pub fn reinit_after_fork(&mut self) -> io::Result<()> {
+        if !self.is_inherited_across_fork() {
+            return Ok(());
+        }
+
+        self.check_not_pinned()?;
+        if self.reclaim_retired() > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!(
+                    "File `{:?}`: A retired mapping is still pinned, and holds on to the lock \
+          shared with the parent.",
+                    self.path
+                ),
+            ));
+        }
+
+        // NOTE: Locks belong to the open file description, which the inherited descriptors
+        //       share with the parent, and so does the inherited mapping. The new lock would
+        //       conflict with them for as long as we keep them, so they are replaced by ones
+        //       of a description of our own first. Closing them leaves the parent's lock in
+        //       place, where unlocking them would release it.
+        let file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        let mm = self.map_anew(&file)?;
+        drop(mem::replace(&mut self.mm, mm));
+        drop(mem::replace(&mut self.file, file));
+        drop(self.lock_file.take());
+
+        let lock_file = locking::try_lock_exclusive_for(&self.file, &self.path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "File `{:?}`: Could not lock file anew after fork; is the parent still \
+          using it? ({})",
+                    self.path, e
+                ),
+            )
+        })?;
+
+        // NOTE: Mapped again, as the parent may have resized the file before we got the lock.
+        let mm = self.map_anew(&self.file)?;
+        self.mm = mm;
+        self.lock_file = lock_file;
+        self.synced_len_bytes = 0;
+        self.mapping_generation += 1;
+        self.owner_pid = process::id();
+        self.fork_generation = generation();
+
+        Ok(())
+    }
+
+    fn map_anew(&self, file: &File) -> io::Result<MmapMut> {
+        let mm = unsafe { MmapMut::map_mut(file)? };
+        if mm.len() < self.header_len
+            || !(mm.len() - self.header_len).is_multiple_of(mem::size_of::<T>())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "File `{:?}`: Changed into an invalid length after fork.",
+                    self.path
+                ),
+            ));
+        }
+        Ok(mm)
+    }
+
+    /// Fail operations on a handle inherited across `fork()` until
+    /// [`reinit_after_fork`](MmapedVec::reinit_after_fork) has been called. The
+    /// `fork-detection` feature also catches children whose process ID is that of the
+    /// process that opened the file, as in a new PID namespace.
+    pub(crate) fn check_not_forked(&self) -> io::Result<()> {
+        if !self.is_inherited_across_fork() && self.fork_generation == generation() {
+            return Ok(());
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::ResourceBusy,
+            format!(
+                "File `{:?}`: Handle was inherited across fork, and shares the lock with the \
+          parent. Call reinit_after_fork() before using it.",
+                self.path
+            ),
+        ))
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        new_mmaped_vec_of_example_persisting_in_tempdir, Example, EXAMPLE_DATA_CONTAINED_VERSION,
+        EXAMPLE_MAGIC_BYTES,
+    };
+    use crate::{read_header, MmapedVecBuilder};
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    pub fn test_reinit_after_fork_fails_while_parent_holds_lock() -> Result<(), io::Error> {
+        let (_dir, _pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+        assert!(!mv.is_inherited_across_fork());
+        mv.reinit_after_fork()?;
+
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => {
+                let reinit_refused = mv.is_inherited_across_fork()
+                    && mv.reinit_after_fork().is_err()
+                    && mv.push(Example::default()).is_err();
+                unsafe { libc::_exit(if reinit_refused { 0 } else { 1 }) }
+            }
+            pid => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                assert!(libc::WIFEXITED(status));
+                assert_eq!(libc::WEXITSTATUS(status), 0);
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_inherited_handle_leaves_parent_file_alone() -> Result<(), io::Error> {
+        let (_dir, pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+        mv.push(Example { hello: 1, world: 2 })?;
+        mv.flush()?;
+        let reader = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .try_open_optimistic::<Example>(&pathbuf)?;
+        let sequence = reader.begin();
+
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => {
+                let refused = mv.flush().is_err() && mv.push(Example::default()).is_err();
+                drop(mv);
+                unsafe { libc::_exit(if refused { 0 } else { 1 }) }
+            }
+            pid => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                assert!(libc::WIFEXITED(status));
+                assert_eq!(libc::WEXITSTATUS(status), 0);
+
+                // NOTE: Still open here, so it must still be marked as such.
+                assert!(read_header(&pathbuf)?.dirty);
+                assert_eq!(reader.begin(), sequence);
+                assert_eq!(mv.len(), 1);
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_reinit_after_fork_once_parent_closed() -> Result<(), io::Error> {
+        let (_dir, pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+        mv.push(Example { hello: 1, world: 2 })?;
+        let (parent_end, mut child_end) = UnixStream::pair()?;
+
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => {
+                // Wait for the parent to close the file.
+                drop(parent_end);
+                let _ = child_end.read(&mut [0]);
+
+                // NOTE: Retried, as children forked by other tests in the meantime may hold
+                //       on to the parent's lock for a little while longer.
+                let mut reinit = mv.reinit_after_fork();
+                for _ in 0..100 {
+                    if reinit.is_ok() {
+                        break;
+                    }
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    reinit = mv.reinit_after_fork();
+                }
+
+                let reinit_done = reinit.is_ok()
+                    && !mv.is_inherited_across_fork()
+                    && mv.push(Example { hello: 3, world: 4 }).is_ok()
+                    && mv.close().is_ok();
+                unsafe { libc::_exit(if reinit_done { 0 } else { 1 }) }
+            }
+            pid => {
+                drop(child_end);
+                drop(mv);
+                drop(parent_end);
+
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                assert!(libc::WIFEXITED(status));
+                assert_eq!(libc::WEXITSTATUS(status), 0);
+
+                let mv = MmapedVec::<Example>::try_new(
+                    &pathbuf,
+                    EXAMPLE_MAGIC_BYTES,
+                    EXAMPLE_DATA_CONTAINED_VERSION,
+                )?;
+                assert_eq!(mv.recovered_from_crash(), None);
+                assert_eq!(mv.len(), 2);
+                assert_eq!((mv[1].hello, mv[1].world), (3, 4));
+                Ok(())
+            }
+        }
+    }
+}
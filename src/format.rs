@@ -0,0 +1,901 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! The on-disk format of the files, for tools that need to read them without going through
+//! [`MmapedVec`](crate::MmapedVec), or that are written in other languages.
+//!
+//! This module is only public with the `unstable-format` feature. It follows the format
+//! version rather than semver, and may change in any release until the format is stable.
+//!
+//! A file consists of a header followed by the body, which holds the elements back to back
+//! with no padding in between, starting at `header_len` bytes into the file.
+
+use crate::backend::StorageBackend;
+use crate::{extensions, ChecksumAlgorithm, MmapedVecBuilder};
+use core::ops::Range;
+use core::{cmp, mem, ptr, slice};
+use memmap::MmapMut;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Bumped to match crate version when changes are made to format itself.
+pub const PERSISTENCE_FORMAT_VERSION: [u8; 3] = [0, 0, 7];
+
+/// Historical versions of the format that files can be upgraded from.
+pub const PERSISTENCE_FORMAT_VERSION_0_0_5: [u8; 3] = [0, 0, 5];
+
+/// Written in native byte order, so that reading it back byte-swapped reveals a file that
+/// was written on a host with the opposite endianness.
+pub const ENDIANNESS_MARKER: u16 = 0x1234;
+
+/// The body starts at a multiple of this many bytes into the file, or at a multiple of the
+/// alignment of the element type for over-aligned types.
+pub const MIN_BODY_ALIGNMENT: usize = 4096;
+
+// TODO: The elements of `SharedAtomics`, with their futex waits and wakes, should go through
+//       the internal `sync` module too, as the sequence and length published to
+//       `OptimisticReader`s do, with loom tests of their own.
+/// Minimum number of bytes set aside for extensions in the header of a new file.
+pub const MIN_EXTENSIONS_AREA_LEN: usize = 256;
+
+/// Extension tags with this bit set are critical: a reader that does not know the tag
+/// must refuse to open the file. Other unknown extensions are skipped.
+pub const EXTENSION_TAG_CRITICAL: u16 = 0x8000;
+
+/// Ends the list of extensions, as does the end of the extensions area.
+pub const EXTENSION_TAG_END: u16 = 0;
+
+/// Each extension starts with its tag (`u16`) and the length of its value (`u32`).
+pub const EXTENSION_ENTRY_HEADER_LEN: usize = 6;
+
+/// Extension holding the sequence number and length that a writer publishes to
+/// [`OptimisticReader`](crate::OptimisticReader)s, in the manner of a seqlock.
+///
+/// It is always the first extension. Its value is `SEQUENCE_VALUE_LEN` bytes, and holds
+/// two `u64`s, the sequence number and then the number of elements, starting at the first
+/// offset into the file within the value that is a multiple of 8. The sequence number is
+/// odd while a write is in progress, and the length is only valid while it is even.
+pub const EXTENSION_TAG_SEQUENCE: u16 = 1;
+
+/// Length of the value of the sequence extension, which leaves room to align its fields.
+pub const SEQUENCE_VALUE_LEN: usize = 7 + 2 * 8;
+
+/// Extension holding a checksum of the body as of when the file was last closed cleanly,
+/// stored with [`checksum`](crate::MmapedVecBuilder::checksum).
+///
+/// Its value is the id of the [`ChecksumAlgorithm`](crate::ChecksumAlgorithm) followed by
+/// the digest, which only holds while the file is not marked dirty.
+pub const EXTENSION_TAG_CHECKSUM: u16 = 2;
+
+/// Extension recording that each element is padded out to a stride, as with
+/// [`CachePadded`](crate::CachePadded), for readers that do not know the element type.
+///
+/// Its value is two `u64`s, the stride and then the size of the element within it. A file
+/// with it can only be opened with element types whose size is the stride.
+pub const EXTENSION_TAG_STRIDE: u16 = 3;
+
+/// Extension recording that the lock of the file is taken on the lock file next to it,
+/// [`lock_path`](crate::lock_path), rather than on the file itself, set with
+/// [`lock_file`](crate::MmapedVecBuilder::lock_file).
+///
+/// Its value is empty. It is critical, so that versions of this library that would lock the
+/// file itself refuse to open it instead.
+pub const EXTENSION_TAG_LOCK_FILE: u16 = EXTENSION_TAG_CRITICAL | 4;
+
+/// Extension recording the options that the file was created with, set with
+/// [`header_hints`](crate::MmapedVecBuilder::header_hints), so that it can be opened
+/// without repeating them.
+///
+/// Its value is `HINTS_VALUE_LEN` bytes: the size and the alignment of the element type,
+/// the [`max_elements`](crate::MmapedVecBuilder::max_elements) and the
+/// [`max_len_bytes`](crate::MmapedVecBuilder::max_len_bytes), as `u64`s with `u64::MAX` for
+/// none, followed by a byte of flags, `HINT_PREALLOCATE` and `HINT_CHECK_FREE_SPACE`.
+pub const EXTENSION_TAG_HINTS: u16 = 5;
+
+/// Length of the value of the hints extension.
+pub const HINTS_VALUE_LEN: usize = 4 * 8 + 1;
+
+/// Set in the flags of the hints of files created with
+/// [`preallocate`](crate::MmapedVecBuilder::preallocate).
+pub const HINT_PREALLOCATE: u8 = 1 << 0;
+
+/// Set in the flags of the hints of files created with
+/// [`check_free_space`](crate::MmapedVecBuilder::check_free_space).
+pub const HINT_CHECK_FREE_SPACE: u8 = 1 << 1;
+
+/// Extension tags that this version of the library understands.
+pub const KNOWN_EXTENSION_TAGS: &[u16] = &[
+    EXTENSION_TAG_SEQUENCE,
+    EXTENSION_TAG_CHECKSUM,
+    EXTENSION_TAG_STRIDE,
+    EXTENSION_TAG_LOCK_FILE,
+    EXTENSION_TAG_HINTS,
+];
+
+/// Set in the flags of files that store default data in their header.
+pub const FLAG_HAS_DEFAULT_DATA: u32 = 1 << 0;
+
+/// Set in the flags of files while they are open for writing, and cleared again when they
+/// are closed cleanly, so that a file that is found with it set but not locked was left
+/// behind by a crash.
+pub const FLAG_DIRTY: u32 = 1 << 1;
+
+/// The part of the header that does not depend on the element type.
+///
+/// If the file has default data, this is followed by a `T` stored at `default_data_offset`,
+/// which is aligned for `T`. Files without default data have zero as their default data
+/// offset and length.
+///
+/// Next, from `extensions_offset` up until `header_len`, where the body starts, there is a
+/// TLV-style list of extensions, allowing fields to be added to the header in the future
+/// without breaking compatibility. Each extension is a tag, the length of the value, and the
+/// value itself. Unknown extensions are skipped unless they are critical. The list ends at
+/// a tag of zero, which zeroed padding provides for free.
+///
+/// The header is always read and written through byte arrays, field by field, so that
+/// no references to fields at unaligned addresses are ever made.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileHeader {
+    pub magic_bytes: [u8; 8],
+    pub endianness: u16,
+    pub persistence_format_version: [u8; 3],
+    pub data_contained_version: [u8; 3],
+    pub flags: u32,
+    pub default_data_offset: u32,
+    pub default_data_len: u32,
+    pub extensions_offset: u32,
+    pub header_len: u64,
+}
+
+/// Length of the part of the header that does not depend on the element type, in bytes.
+pub const FILE_HEADER_LEN: usize = mem::size_of::<FileHeader>();
+
+impl FileHeader {
+    /// The header of a new file containing elements of type `T`.
+    pub fn new<T>(
+        magic_bytes: [u8; 8],
+        data_contained_version: [u8; 3],
+        has_default_data: bool,
+    ) -> Self {
+        Self::with_layout(
+            magic_bytes,
+            data_contained_version,
+            mem::size_of::<T>(),
+            mem::align_of::<T>(),
+            has_default_data,
+        )
+    }
+
+    /// The header of a new file containing elements of the given size and alignment, for
+    /// when the element type is not known at compile time.
+    pub fn with_layout(
+        magic_bytes: [u8; 8],
+        data_contained_version: [u8; 3],
+        elem_size: usize,
+        elem_align: usize,
+        has_default_data: bool,
+    ) -> Self {
+        let body_alignment = cmp::max(MIN_BODY_ALIGNMENT, elem_align);
+
+        let (flags, default_data_offset, default_data_len) = match has_default_data {
+            true => (
+                FLAG_HAS_DEFAULT_DATA,
+                round_up(FILE_HEADER_LEN, elem_align),
+                elem_size,
+            ),
+            false => (0, 0, 0),
+        };
+
+        let extensions_offset = round_up(
+            cmp::max(FILE_HEADER_LEN, default_data_offset + default_data_len),
+            8,
+        );
+        let header_len = round_up(extensions_offset + MIN_EXTENSIONS_AREA_LEN, body_alignment);
+
+        Self {
+            magic_bytes,
+            endianness: ENDIANNESS_MARKER,
+            persistence_format_version: PERSISTENCE_FORMAT_VERSION,
+            data_contained_version,
+            flags,
+            default_data_offset: default_data_offset as u32,
+            default_data_len: default_data_len as u32,
+            extensions_offset: extensions_offset as u32,
+            header_len: header_len as u64,
+        }
+    }
+
+    /// The header with the byte order of all multi-byte fields reversed, for converting
+    /// headers written on hosts with the opposite endianness.
+    pub fn swap_bytes(self) -> Self {
+        Self {
+            endianness: self.endianness.swap_bytes(),
+            flags: self.flags.swap_bytes(),
+            default_data_offset: self.default_data_offset.swap_bytes(),
+            default_data_len: self.default_data_len.swap_bytes(),
+            extensions_offset: self.extensions_offset.swap_bytes(),
+            header_len: self.header_len.swap_bytes(),
+            ..self
+        }
+    }
+
+    pub fn has_default_data(&self) -> bool {
+        self.flags & FLAG_HAS_DEFAULT_DATA != 0
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.flags & FLAG_DIRTY != 0
+    }
+
+    pub fn to_bytes(self) -> [u8; FILE_HEADER_LEN] {
+        let mut buf = [0u8; FILE_HEADER_LEN];
+        buf[OFFSET_MAGIC_BYTES..][..8].copy_from_slice(&self.magic_bytes);
+        buf[OFFSET_ENDIANNESS..][..2].copy_from_slice(&self.endianness.to_ne_bytes());
+        buf[OFFSET_PERSISTENCE_FORMAT_VERSION..][..3]
+            .copy_from_slice(&self.persistence_format_version);
+        buf[OFFSET_DATA_CONTAINED_VERSION..][..3].copy_from_slice(&self.data_contained_version);
+        buf[OFFSET_FLAGS..][..4].copy_from_slice(&self.flags.to_ne_bytes());
+        buf[OFFSET_DEFAULT_DATA_OFFSET..][..4]
+            .copy_from_slice(&self.default_data_offset.to_ne_bytes());
+        buf[OFFSET_DEFAULT_DATA_LEN..][..4].copy_from_slice(&self.default_data_len.to_ne_bytes());
+        buf[OFFSET_EXTENSIONS_OFFSET..][..4].copy_from_slice(&self.extensions_offset.to_ne_bytes());
+        buf[OFFSET_HEADER_LEN..][..8].copy_from_slice(&self.header_len.to_ne_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; FILE_HEADER_LEN]) -> Self {
+        fn field<const N: usize>(buf: &[u8], offset: usize) -> [u8; N] {
+            let mut field = [0u8; N];
+            field.copy_from_slice(&buf[offset..][..N]);
+            field
+        }
+
+        Self {
+            magic_bytes: field(buf, OFFSET_MAGIC_BYTES),
+            endianness: u16::from_ne_bytes(field(buf, OFFSET_ENDIANNESS)),
+            persistence_format_version: field(buf, OFFSET_PERSISTENCE_FORMAT_VERSION),
+            data_contained_version: field(buf, OFFSET_DATA_CONTAINED_VERSION),
+            flags: u32::from_ne_bytes(field(buf, OFFSET_FLAGS)),
+            default_data_offset: u32::from_ne_bytes(field(buf, OFFSET_DEFAULT_DATA_OFFSET)),
+            default_data_len: u32::from_ne_bytes(field(buf, OFFSET_DEFAULT_DATA_LEN)),
+            extensions_offset: u32::from_ne_bytes(field(buf, OFFSET_EXTENSIONS_OFFSET)),
+            header_len: u64::from_ne_bytes(field(buf, OFFSET_HEADER_LEN)),
+        }
+    }
+
+    /// Read the header from the start of `backend`, without regard for any current offset.
+    pub fn read_from<B: StorageBackend + ?Sized>(backend: &B) -> io::Result<Self> {
+        let mut buf = [0u8; FILE_HEADER_LEN];
+        backend.read_at(&mut buf, 0)?;
+        Ok(Self::from_bytes(&buf))
+    }
+
+    /// Write the header to the start of `backend`, without regard for any current offset.
+    pub fn write_to<B: StorageBackend + ?Sized>(self, backend: &mut B) -> io::Result<()> {
+        backend.write_at(&self.to_bytes(), 0)
+    }
+}
+
+/// Validate the header of an existing, non-empty file against what the caller expects it to
+/// contain, returning the header if the file can be used. `path` is only used in errors.
+pub fn check_existing_file<B: StorageBackend + ?Sized>(
+    file: &B,
+    path: &Path,
+    magic_bytes: [u8; 8],
+    data_contained_version: [u8; 3],
+    elem_size: usize,
+    elem_align: usize,
+) -> io::Result<FileHeader> {
+    let flen = file.len_bytes()?;
+
+    if flen < FILE_HEADER_LEN as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "File `{:?}` has non-zero size ({} bytes), but it is shorter than \
+      the expected header size ({} bytes).",
+                path, flen, FILE_HEADER_LEN
+            ),
+        ));
+    }
+
+    let fh_file = FileHeader::read_from(file)?;
+
+    if fh_file.magic_bytes != magic_bytes {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("File `{:?}`: Magic bytes mismatch.", path),
+        ));
+    }
+
+    if fh_file.endianness != ENDIANNESS_MARKER {
+        if fh_file.endianness.swap_bytes() != ENDIANNESS_MARKER {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("File `{:?}`: Endianness-marker invalid.", path),
+            ));
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("File `{:?}`: Wrong endianness.", path),
+            ));
+        }
+    }
+
+    if historical_format(fh_file.persistence_format_version).is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "File `{:?}`: Persistence format version {:?} is no longer supported \
+      directly. Upgrade the file with MmapedVecBuilder::upgrade_format().",
+                path, fh_file.persistence_format_version
+            ),
+        ));
+    }
+
+    if fh_file.persistence_format_version != PERSISTENCE_FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "File `{:?}`: Unsupported persistence format version {:?}.",
+                path, fh_file.persistence_format_version
+            ),
+        ));
+    }
+
+    let fh = FileHeader::with_layout(
+        magic_bytes,
+        data_contained_version,
+        elem_size,
+        elem_align,
+        fh_file.has_default_data(),
+    );
+
+    if fh_file.default_data_offset != fh.default_data_offset
+        || fh_file.default_data_len != fh.default_data_len
+        || fh_file.extensions_offset != fh.extensions_offset
+        || fh_file.header_len != fh.header_len
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "File `{:?}`: Header layout does not match the size and alignment of \
+      the data type that the file supposedly contains.",
+                path
+            ),
+        ));
+    }
+
+    if flen < fh.header_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "File `{:?}` is shorter ({} bytes) than its header and padding ({} bytes).",
+                path, flen, fh.header_len
+            ),
+        ));
+    }
+
+    let mut extensions_area =
+        vec![0u8; (fh_file.header_len - fh_file.extensions_offset as u64) as usize];
+    file.read_at(&mut extensions_area, fh_file.extensions_offset as u64)?;
+
+    match parse_extensions(&extensions_area) {
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("File `{:?}`: Malformed header extensions.", path),
+            ));
+        }
+        Some(extensions) => {
+            if let Some((tag, _)) = extensions.iter().find(|(tag, _)| {
+                tag & EXTENSION_TAG_CRITICAL != 0 && !KNOWN_EXTENSION_TAGS.contains(tag)
+            }) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "File `{:?}`: Requires header extension {:#06x}, which this \
+      version of the library does not support.",
+                        path, tag
+                    ),
+                ));
+            }
+        }
+    }
+
+    // TODO: Validate remaining fields
+
+    if !(flen - fh.header_len).is_multiple_of(elem_size as u64) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "File `{:?}` has non-zero size, but file size minus header size and padding \
+      bytes is not an integer multiple of the size of the data type that the file supposedly \
+      contains. This indicates that the file might be corrupt, incorrectly versioned or \
+      malformed.",
+                path
+            ),
+        ));
+    }
+
+    Ok(fh)
+}
+
+/// Mark the file as open for writing, or as cleanly closed, durably, as
+/// [`MmapedVec`](crate::MmapedVec) does when it opens and closes a file. A file that is
+/// still marked as open when it is opened again was not closed cleanly.
+///
+/// This and the functions below are for writers that map files themselves, such as the C
+/// API, to keep the header in the state that [`MmapedVec`](crate::MmapedVec) keeps it in.
+pub fn set_dirty(file: &File, dirty: bool) -> io::Result<()> {
+    crate::recovery::set_dirty(file, dirty)
+}
+
+/// Take the exclusive lock of the file at `path` that `file` is open on, as
+/// [`MmapedVec`](crate::MmapedVec) does: on its lock file if its header has
+/// [`EXTENSION_TAG_LOCK_FILE`], in which case the lock file is returned, and the lock is
+/// held for as long as it is open.
+pub fn try_lock_exclusive_for(file: &File, path: &Path) -> io::Result<Option<File>> {
+    crate::locking::try_lock_exclusive_for(file, path)
+}
+
+/// Like [`try_lock_exclusive_for`], but taking a shared lock, as readers do.
+pub fn try_lock_shared_for(file: &File, path: &Path) -> io::Result<Option<File>> {
+    crate::locking::try_lock_shared_for(file, path)
+}
+
+/// Open the file at `path` for writing, creating it if there is none, and take its exclusive
+/// lock, as [`MmapedVec`](crate::MmapedVec) does. Returns the lock file too, if the lock is
+/// held on one.
+pub fn open_locked(path: &Path) -> io::Result<(File, Option<File>)> {
+    crate::open_locked(path, true)
+}
+
+/// Write the header of a new, empty `file` at `path`, with the `elem_size` bytes of
+/// `default_data` if any, or recover and validate an existing one, as `builder` does when
+/// opening it, for elements of `elem_size` bytes aligned to `elem_align`. Then mark it as
+/// open. The file must be locked, as by [`open_locked`].
+pub fn prepare_locked_file(
+    builder: &MmapedVecBuilder,
+    file: &File,
+    path: &Path,
+    elem_size: usize,
+    elem_align: usize,
+    default_data: Option<&[u8]>,
+) -> io::Result<FileHeader> {
+    builder
+        .prepare_locked_file(file, path, elem_size, elem_align, default_data)
+        .map(|prepared| prepared.fh)
+}
+
+/// Resize the file that `mm` maps to `len_bytes`, header included, and map it anew, as
+/// [`MmapedVec`](crate::MmapedVec) does when growing. If mapping it fails, the file is left
+/// at the length it had.
+pub fn resize(file: &File, mm: &mut MmapMut, len_bytes: u64) -> io::Result<()> {
+    crate::resize_mapped(file, mm, len_bytes, false)
+}
+
+/// Write back the modifications of `mm`, whose body starts `header_len` bytes in, as
+/// [`flush`](crate::MmapedVec::flush) does by default.
+pub fn flush(mm: &MmapMut, header_len: usize) -> io::Result<()> {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let order = crate::msync::resolve_flush_order(None, header_len, page_size)
+        .unwrap_or(crate::FlushOrder::Unordered);
+    crate::msync::msync(mm, header_len, crate::FlushMode::Sync, order)
+}
+
+/// Make the sequence number of the sequence extension odd before changing the elements or
+/// the length of the file that `mm` maps, unless a write is already in progress. Does
+/// nothing for files without the extension.
+pub fn begin_write(mm: &MmapMut) {
+    crate::seqlock::begin_write(mm)
+}
+
+/// Publish `len` as the number of elements, and make the sequence number even again, if a
+/// write is in progress. See [`begin_write`].
+pub fn end_write(mm: &MmapMut, len: u64) {
+    crate::seqlock::end_write(mm, len)
+}
+
+/// Store the checksum of the body of the file that `mm` maps, with the algorithm recorded
+/// in its checksum extension, as closing a file cleanly does. Does nothing for files
+/// without the extension, and removes it if the checksum cannot be computed in this build.
+pub fn store_checksum(mm: &mut MmapMut) -> io::Result<()> {
+    let mut fh_buf = [0u8; FILE_HEADER_LEN];
+    fh_buf.copy_from_slice(&mm[..FILE_HEADER_LEN]);
+    let fh = FileHeader::from_bytes(&fh_buf);
+    let (start, end) = (fh.extensions_offset as usize, fh.header_len as usize);
+
+    let area = &mm[start..end];
+    let algorithm = match parse_extensions(area)
+        .unwrap_or_default()
+        .into_iter()
+        .find(|(tag, _)| *tag == EXTENSION_TAG_CHECKSUM)
+    {
+        Some((_, range)) => area[range].first().copied(),
+        None => return Ok(()),
+    };
+
+    let value = algorithm
+        .and_then(ChecksumAlgorithm::from_id)
+        .and_then(|algorithm| {
+            let digest = algorithm.digest(&mm[end..]).ok()?;
+            Some([&[algorithm.id()], &digest[..]].concat())
+        });
+    // NOTE: A checksum we cannot compute would only go stale, so it is dropped.
+    let (buf, keep) = extensions::rewrite_area(area, EXTENSION_TAG_CHECKSUM, value.as_deref())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "No room for the checksum."))?;
+    mm[start + keep..end].copy_from_slice(&buf[keep..]);
+
+    mm.flush_range(0, end)
+}
+
+/// Offsets of the fields of [`FileHeader`] from the start of the file, in bytes.
+/// All multi-byte fields are stored in the byte order of the host that wrote the file.
+pub const OFFSET_MAGIC_BYTES: usize = 0;
+pub const OFFSET_ENDIANNESS: usize = 8;
+pub const OFFSET_PERSISTENCE_FORMAT_VERSION: usize = 10;
+pub const OFFSET_DATA_CONTAINED_VERSION: usize = 13;
+pub const OFFSET_FLAGS: usize = 16;
+pub const OFFSET_DEFAULT_DATA_OFFSET: usize = 20;
+pub const OFFSET_DEFAULT_DATA_LEN: usize = 24;
+pub const OFFSET_EXTENSIONS_OFFSET: usize = 28;
+pub const OFFSET_HEADER_LEN: usize = 32;
+
+/// Where the default data and the body are in a file of an earlier version of the
+/// persistence format, with elements of a given size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HistoricalLayout {
+    /// Offset of the default data, if the file has any.
+    pub default_data_offset: Option<usize>,
+    /// Length of the header and the padding after it, in bytes.
+    pub header_len: u64,
+}
+
+/// An earlier version of the persistence format that files can be upgraded from.
+#[derive(Clone, Copy, Debug)]
+pub struct HistoricalFormat {
+    pub version: [u8; 3],
+    /// The layout of files with elements of the given size.
+    pub layout: fn(elem_size: usize) -> HistoricalLayout,
+}
+
+/// Earlier versions of the persistence format that files can be upgraded from, oldest
+/// first. All of them start with the magic bytes, the endianness marker and the two
+/// versions, at the same offsets as the current format, so that is all there is to add for
+/// the next version.
+pub const HISTORICAL_FORMATS: &[HistoricalFormat] = &[HistoricalFormat {
+    version: PERSISTENCE_FORMAT_VERSION_0_0_5,
+    layout: layout_v0_0_5,
+}];
+
+/// The earlier version of the persistence format `version`, if files can be upgraded from
+/// it.
+pub fn historical_format(version: [u8; 3]) -> Option<&'static HistoricalFormat> {
+    HISTORICAL_FORMATS
+        .iter()
+        .find(|format| format.version == version)
+}
+
+/// Offset of the default data in files of persistence format version 0.0.5.
+pub const DEFAULT_DATA_OFFSET_V0_0_5: usize = 16;
+
+/// Length of the header and the padding after it in files of persistence format version
+/// 0.0.5 with elements of `elem_size` bytes.
+pub fn header_len_v0_0_5(elem_size: usize) -> usize {
+    round_up(DEFAULT_DATA_OFFSET_V0_0_5 + elem_size + 2, 4096)
+}
+
+/// In version 0.0.5 the header was packed, and held the magic bytes, the endianness marker,
+/// the two versions, the default data, unaligned, and a `u16` that was not used, followed
+/// by padding up to a multiple of 4096 bytes. Every file had default data.
+fn layout_v0_0_5(elem_size: usize) -> HistoricalLayout {
+    HistoricalLayout {
+        default_data_offset: Some(DEFAULT_DATA_OFFSET_V0_0_5),
+        header_len: header_len_v0_0_5(elem_size) as u64,
+    }
+}
+
+/// Find the extensions in an extensions area, as tags and the ranges of their values.
+///
+/// Returns `None` if an extension runs past the end of the area.
+pub fn parse_extensions(area: &[u8]) -> Option<Vec<(u16, Range<usize>)>> {
+    parse_extensions_with(area, false)
+}
+
+/// Like [`parse_extensions`], but for an extensions area that was written on a host with the
+/// opposite endianness.
+pub fn parse_foreign_extensions(area: &[u8]) -> Option<Vec<(u16, Range<usize>)>> {
+    parse_extensions_with(area, true)
+}
+
+fn parse_extensions_with(area: &[u8], swapped: bool) -> Option<Vec<(u16, Range<usize>)>> {
+    let mut extensions = vec![];
+    let mut pos = 0;
+
+    while pos + EXTENSION_ENTRY_HEADER_LEN <= area.len() {
+        let mut tag = u16::from_ne_bytes([area[pos], area[pos + 1]]);
+        let mut len =
+            u32::from_ne_bytes([area[pos + 2], area[pos + 3], area[pos + 4], area[pos + 5]]);
+        if swapped {
+            tag = tag.swap_bytes();
+            len = len.swap_bytes();
+        }
+
+        if tag == EXTENSION_TAG_END {
+            break;
+        }
+
+        let start = pos + EXTENSION_ENTRY_HEADER_LEN;
+        let end = start.checked_add(len as usize)?;
+
+        if end > area.len() {
+            return None;
+        }
+
+        extensions.push((tag, start..end));
+        pos = end;
+    }
+
+    Some(extensions)
+}
+
+/// Offset into the file of the fields of the sequence extension, given the extensions area
+/// and the offset into the file that it starts at, if the area starts with the extension.
+pub fn sequence_offset(extensions_offset: usize, area: &[u8]) -> Option<usize> {
+    let header = area.get(..EXTENSION_ENTRY_HEADER_LEN)?;
+    let tag = u16::from_ne_bytes([header[0], header[1]]);
+    let len = u32::from_ne_bytes([header[2], header[3], header[4], header[5]]);
+
+    match tag == EXTENSION_TAG_SEQUENCE
+        && len as usize == SEQUENCE_VALUE_LEN
+        && area.len() >= EXTENSION_ENTRY_HEADER_LEN + SEQUENCE_VALUE_LEN
+    {
+        true => Some(round_up(extensions_offset + EXTENSION_ENTRY_HEADER_LEN, 8)),
+        false => None,
+    }
+}
+
+/// Encode extensions for writing to an extensions area.
+pub fn encode_extensions(extensions: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut buf = vec![];
+
+    for (tag, value) in extensions {
+        buf.extend_from_slice(&tag.to_ne_bytes());
+        buf.extend_from_slice(&(value.len() as u32).to_ne_bytes());
+        buf.extend_from_slice(value);
+    }
+
+    buf
+}
+
+/// Reverse the byte order of an extensions area, for converting between hosts with
+/// different endianness: the tags and lengths, and the fields of the values of the
+/// extensions that this library knows. `extensions_offset` is the offset into the file that
+/// the area starts at, which the fields of the sequence extension are aligned to, and
+/// `is_native` tells whether the area is currently in the byte order of this host.
+///
+/// The checksum extension is dropped, since the body that it covers is converted too, and
+/// its digest would no longer hold. Fails with [`InvalidData`](io::ErrorKind::InvalidData)
+/// if an extension runs past the end of the area, if a known extension has a value of the
+/// wrong length, or if an unknown extension has a value, since only whoever wrote it knows
+/// its layout.
+pub fn swap_extension_bytes(
+    area: &mut [u8],
+    extensions_offset: usize,
+    is_native: bool,
+) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let u64s = |value: &mut [u8], start: usize, n: usize| {
+        for field in value[start..start + 8 * n].chunks_exact_mut(8) {
+            field.reverse();
+        }
+    };
+
+    let extensions = parse_extensions_with(area, !is_native)
+        .ok_or_else(|| invalid("Malformed header extensions.".to_string()))?;
+
+    let mut checksum = None;
+    for (tag, range) in extensions {
+        let len = range.len();
+        let expected_len = match tag {
+            EXTENSION_TAG_SEQUENCE => Some(SEQUENCE_VALUE_LEN),
+            EXTENSION_TAG_STRIDE => Some(16),
+            EXTENSION_TAG_LOCK_FILE => Some(0),
+            EXTENSION_TAG_HINTS => Some(HINTS_VALUE_LEN),
+            _ => None,
+        };
+        if expected_len.is_some_and(|expected| expected != len) {
+            return Err(invalid(format!(
+                "Header extension {:#06x} has a value of {} bytes.",
+                tag, len
+            )));
+        }
+
+        let value = &mut area[range.clone()];
+        match tag {
+            EXTENSION_TAG_SEQUENCE => {
+                let start = extensions_offset + range.start;
+                u64s(value, round_up(start, 8) - start, 2);
+            }
+            EXTENSION_TAG_STRIDE => u64s(value, 0, 2),
+            EXTENSION_TAG_HINTS => u64s(value, 0, 4),
+            EXTENSION_TAG_CHECKSUM => {
+                checksum = Some(range.start - EXTENSION_ENTRY_HEADER_LEN..range.end)
+            }
+            EXTENSION_TAG_LOCK_FILE => {}
+            _ if len == 0 => {}
+            _ => {
+                return Err(invalid(format!(
+                    "Header extension {:#06x} has a value whose byte order is unknown.",
+                    tag
+                )))
+            }
+        }
+
+        let pos = range.start - EXTENSION_ENTRY_HEADER_LEN;
+        area[pos..pos + 2].reverse();
+        area[pos + 2..pos + 6].reverse();
+    }
+
+    if let Some(checksum) = checksum {
+        let n = checksum.len();
+        area.copy_within(checksum.end.., checksum.start);
+        let end = area.len();
+        area[end - n..].fill(0);
+    }
+
+    Ok(())
+}
+
+/// Reverse the byte order of each field of each element in `elems`, where the elements
+/// consist of fields of the widths in `field_widths`, in order and without padding.
+///
+/// # Panics
+///
+/// Panics if `elems` does not hold a whole number of elements.
+pub fn swap_element_bytes(elems: &mut [u8], field_widths: &[usize]) {
+    let elem_size: usize = field_widths.iter().sum();
+
+    assert!(
+        elem_size > 0 && elems.len().is_multiple_of(elem_size),
+        "Not a whole number of elements."
+    );
+
+    for elem in elems.chunks_exact_mut(elem_size) {
+        let mut pos = 0;
+        for &width in field_widths {
+            elem[pos..pos + width].reverse();
+            pos += width;
+        }
+    }
+}
+
+fn round_up(n: usize, multiple: usize) -> usize {
+    n.div_ceil(multiple) * multiple
+}
+
+/// Copy a `T` out of the start of `bytes`, which need not be aligned for it.
+///
+/// # Safety
+///
+/// The bytes must be a valid `T`.
+///
+/// # Panics
+///
+/// Panics if `bytes` is shorter than a `T`.
+pub(crate) unsafe fn read_value<T>(bytes: &[u8]) -> T {
+    assert!(bytes.len() >= mem::size_of::<T>());
+    ptr::read_unaligned(bytes.as_ptr() as *const T)
+}
+
+/// The bytes of `value`, for writing it to the file.
+pub(crate) fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use memoffset::offset_of;
+
+    #[test]
+    pub fn test_documented_header_offsets_match_layout() {
+        assert_eq!(offset_of!(FileHeader, magic_bytes), OFFSET_MAGIC_BYTES);
+        assert_eq!(offset_of!(FileHeader, endianness), OFFSET_ENDIANNESS);
+        assert_eq!(
+            offset_of!(FileHeader, persistence_format_version),
+            OFFSET_PERSISTENCE_FORMAT_VERSION
+        );
+        assert_eq!(
+            offset_of!(FileHeader, data_contained_version),
+            OFFSET_DATA_CONTAINED_VERSION
+        );
+        assert_eq!(offset_of!(FileHeader, flags), OFFSET_FLAGS);
+        assert_eq!(
+            offset_of!(FileHeader, default_data_offset),
+            OFFSET_DEFAULT_DATA_OFFSET
+        );
+        assert_eq!(
+            offset_of!(FileHeader, default_data_len),
+            OFFSET_DEFAULT_DATA_LEN
+        );
+        assert_eq!(
+            offset_of!(FileHeader, extensions_offset),
+            OFFSET_EXTENSIONS_OFFSET
+        );
+        assert_eq!(offset_of!(FileHeader, header_len), OFFSET_HEADER_LEN);
+        assert_eq!(OFFSET_HEADER_LEN + 8, FILE_HEADER_LEN);
+    }
+
+    #[test]
+    pub fn test_byte_swapping_round_trips() {
+        let fh = FileHeader::new::<u64>(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION, true);
+        assert_eq!(fh.swap_bytes().endianness, ENDIANNESS_MARKER.swap_bytes());
+        assert_eq!(fh.swap_bytes().swap_bytes(), fh);
+
+        let mut sequence = [0u8; SEQUENCE_VALUE_LEN];
+        sequence[2..10].copy_from_slice(&3u64.to_ne_bytes());
+        let stride: Vec<u8> = [64u64, 12].iter().flat_map(|n| n.to_ne_bytes()).collect();
+        let with = |checksum: &[(u16, &[u8])]| {
+            let mut extensions = vec![
+                (EXTENSION_TAG_SEQUENCE, &sequence[..]),
+                (EXTENSION_TAG_STRIDE, &stride[..]),
+            ];
+            extensions.extend_from_slice(checksum);
+            extensions.push((EXTENSION_TAG_LOCK_FILE, b""));
+            extensions.push((0x4002, b""));
+            let mut area = encode_extensions(&extensions);
+            area.resize(128, 0);
+            area
+        };
+
+        // NOTE: At offset 16 into the file, the sequence fields start 2 bytes into the value.
+        let orig = with(&[]);
+        let mut area = with(&[(EXTENSION_TAG_CHECKSUM, b"\x01abcd")]);
+        swap_extension_bytes(&mut area, 16, true).unwrap();
+        let foreign = parse_foreign_extensions(&area).unwrap();
+        assert_eq!(foreign.len(), 4);
+        assert_eq!(
+            area[foreign[0].1.start + 2..][..8],
+            3u64.swap_bytes().to_ne_bytes()
+        );
+        assert_eq!(
+            area[foreign[1].1.clone()][..8],
+            64u64.swap_bytes().to_ne_bytes()
+        );
+        swap_extension_bytes(&mut area, 16, false).unwrap();
+        assert_eq!(area, orig);
+
+        let mut area = encode_extensions(&[(0x4001, b"abc")]);
+        assert!(swap_extension_bytes(&mut area, 16, true).is_err());
+
+        let mut elems = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        swap_element_bytes(&mut elems, &[2, 1, 1]);
+        assert_eq!(elems, vec![2, 1, 3, 4, 6, 5, 7, 8]);
+    }
+
+    #[test]
+    pub fn test_read_value_at_unaligned_offset() {
+        let mut bytes = [0u8; 1 + 8];
+        bytes[1..].copy_from_slice(&0x0102_0304_0506_0708u64.to_ne_bytes());
+        let value: u64 = unsafe { read_value(&bytes[1..]) };
+        assert_eq!(value, 0x0102_0304_0506_0708);
+    }
+}
@@ -0,0 +1,299 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Slots freed by [`vacate`](MmapedVec::vacate), kept in a sidecar next to the file and
+//! reused by [`insert_any`](MmapedVec::insert_any), for elements whose index is referenced
+//! from elsewhere and so must not move when others come and go.
+//!
+//! The lowest free slot is always reused first, so that the free slots gather at the end,
+//! where vacating the last slot cuts them off the file.
+//!
+//! The sidecar starts with `FREE_MAGIC`, and then, in native byte order, the length of the
+//! file and the number of free slots, and then the index of each free slot, as `u64`s.
+
+use crate::{locking, MmapedVec};
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::{mem, ptr};
+
+/// Suffix of the sidecar that is kept next to files with a free list.
+pub const FREE_SUFFIX: &str = ".free";
+
+pub(crate) const FREE_MAGIC: [u8; 8] = *b"PERSFREE";
+const FREE_HEADER_LEN: usize = 24;
+
+/// Path of the free list sidecar of the file at `path`.
+pub fn free_path(path: &Path) -> PathBuf {
+    let mut free_path = OsString::from(path.as_os_str());
+    free_path.push(FREE_SUFFIX);
+    PathBuf::from(free_path)
+}
+
+/// The free slots of a file.
+pub(crate) struct FreeList {
+    path: PathBuf,
+    slots: BTreeSet<usize>,
+    committed: bool,
+}
+
+impl FreeList {
+    pub(crate) fn contains(&self, index: usize) -> bool {
+        self.slots.contains(&index)
+    }
+
+    /// Forget all free slots, such as once they are vacuumed away, and write that down.
+    pub(crate) fn clear(&mut self, len: usize) -> io::Result<()> {
+        self.slots.clear();
+        self.committed = false;
+        self.commit(len)
+    }
+
+    /// Write the free slots to the sidecar if they changed since it was last written.
+    pub(crate) fn commit(&mut self, len: usize) -> io::Result<()> {
+        // NOTE: Slots past the end were cut off by truncating the file.
+        if !self.slots.split_off(&len).is_empty() {
+            self.committed = false;
+        }
+        if self.committed {
+            return Ok(());
+        }
+
+        let mut buf = FREE_MAGIC.to_vec();
+        buf.extend_from_slice(&(len as u64).to_ne_bytes());
+        buf.extend_from_slice(&(self.slots.len() as u64).to_ne_bytes());
+        for slot in &self.slots {
+            buf.extend_from_slice(&(*slot as u64).to_ne_bytes());
+        }
+
+        // NOTE: Written aside and renamed into place, so that a crash leaves either sidecar.
+        let mut tmp_name = OsString::from(self.path.as_os_str());
+        tmp_name.push(".tmp");
+        let tmp = PathBuf::from(tmp_name);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)?;
+        locking::try_lock_exclusive(&file, &tmp)?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.committed = true;
+        Ok(())
+    }
+}
+
+/// The free slots in `buf` that are below `len`.
+fn parse_slots(buf: &[u8], len: usize) -> Option<BTreeSet<usize>> {
+    let field = |i: usize| -> Option<[u8; 8]> { buf.get(i * 8..i * 8 + 8)?.try_into().ok() };
+
+    if buf.get(..8)? != FREE_MAGIC {
+        return None;
+    }
+    let recorded_len = u64::from_ne_bytes(field(1)?) as usize;
+    let count = u64::from_ne_bytes(field(2)?) as usize;
+
+    // NOTE: Slots past either length may have been cut off and appended to again since.
+    (0..count)
+        .map(|i| Some(u64::from_ne_bytes(field(FREE_HEADER_LEN / 8 + i)?) as usize))
+        .filter(|slot| slot.is_none_or(|slot| slot < len.min(recorded_len)))
+        .collect()
+}
+
+impl<T> MmapedVec<T> {
+    /// Keep a free list in a sidecar next to the file, so that slots freed by
+    /// [`vacate`](MmapedVec::vacate) are reused by [`insert_any`](MmapedVec::insert_any).
+    /// The free list is written on each flush, before the body, so that a crash in between
+    /// can leak a slot that was reused, but never hand out a slot that holds an element.
+    ///
+    /// NOTE: Elements removed and appended while the file is opened without the free list
+    /// go unnoticed, so enable it every time the file is opened.
+    pub fn free_list(&mut self) -> io::Result<()> {
+        let path = free_path(&self.path);
+        let slots = match fs::read(&path) {
+            Ok(buf) => parse_slots(&buf, self.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        let mut free = FreeList {
+            path,
+            slots: slots.unwrap_or_default(),
+            committed: false,
+        };
+        free.commit(self.len())?;
+
+        self.free = Some(free);
+        Ok(())
+    }
+
+    /// Free the slot at `index` for [`insert_any`](MmapedVec::insert_any) to reuse, and
+    /// return the element in it. The other elements keep their indices. Vacating the last
+    /// slot cuts it off the file, along with any free slots right before it.
+    ///
+    /// Fails with [`InvalidInput`](io::ErrorKind::InvalidInput) if the slot is already free,
+    /// and with [`Unsupported`](io::ErrorKind::Unsupported) without a
+    /// [`free_list`](MmapedVec::free_list).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn vacate(&mut self, index: usize) -> io::Result<T> {
+        self.check_poisoned()?;
+        assert!(index < self.len(), "Index out of bounds.");
+
+        let len = self.len();
+        let free = self.free_list_mut()?;
+        if free.slots.contains(&index) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The slot is already free.",
+            ));
+        }
+
+        let mut new_len = len;
+        if index == len - 1 {
+            new_len = index;
+            while new_len > 0 && free.slots.contains(&(new_len - 1)) {
+                new_len -= 1;
+            }
+        }
+
+        let value = unsafe { ptr::read(self.as_ptr().add(index)) };
+        if let Err(e) = self.truncate(new_len) {
+            // NOTE: The element is still in the file, which owns it.
+            mem::forget(value);
+            return Err(e);
+        }
+
+        let free = self.free_list_mut()?;
+        free.slots.insert(index);
+        free.slots.split_off(&new_len);
+        free.committed = false;
+
+        Ok(value)
+    }
+
+    /// Store `value` in the lowest free slot, or append it if there is none, and return its
+    /// index.
+    ///
+    /// Fails with [`Unsupported`](io::ErrorKind::Unsupported) without a
+    /// [`free_list`](MmapedVec::free_list).
+    pub fn insert_any(&mut self, value: T) -> io::Result<usize> {
+        self.check_poisoned()?;
+
+        let len = self.len();
+        let index = match self.free_list_mut()?.slots.range(..len).next().copied() {
+            Some(index) => index,
+            None => {
+                self.push(value)?;
+                return Ok(len);
+            }
+        };
+
+        self.set_writable(true)?;
+        unsafe { ptr::write(self.body_mut_ptr().add(index), value) };
+        let free = self.free_list_mut()?;
+        free.slots.remove(&index);
+        free.committed = false;
+        self.set_writable(false)?;
+        self.replicate_range(index..index + 1)?;
+
+        Ok(index)
+    }
+
+    fn free_list_mut(&mut self) -> io::Result<&mut FreeList> {
+        let path = &self.path;
+        self.free.as_mut().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("File `{:?}`: No free list.", path),
+            )
+        })
+    }
+
+    /// Whether the slot at `index` is free, as far as the [free list](MmapedVec::free_list)
+    /// knows.
+    pub fn is_free(&self, index: usize) -> bool {
+        self.free
+            .as_ref()
+            .is_some_and(|free| index < self.len() && free.slots.contains(&index))
+    }
+
+    /// The number of free slots, which is zero without a [free list](MmapedVec::free_list).
+    pub fn free_slots(&self) -> usize {
+        let len = self.len();
+        self.free
+            .as_ref()
+            .map_or(0, |free| free.slots.range(..len).count())
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::MmapedVecBuilder;
+
+    #[test]
+    pub fn test_free_list() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let mut mv: MmapedVec<u64> = builder.try_open(&path)?;
+        assert_eq!(
+            mv.insert_any(0).unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+        mv.free_list()?;
+        for i in 0..10 {
+            assert_eq!(mv.insert_any(i * 10)?, i as usize);
+        }
+        assert_eq!(mv.vacate(7)?, 70);
+        assert_eq!(mv.vacate(3)?, 30);
+        assert_eq!(
+            mv.vacate(3).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert!(mv.is_free(3));
+        assert_eq!(mv.free_slots(), 2);
+        mv.flush()?;
+        drop(mv);
+        assert!(free_path(&path).exists());
+
+        let mut mv: MmapedVec<u64> = builder.try_open(&path)?;
+        mv.free_list()?;
+        assert_eq!(mv.free_slots(), 2);
+        assert_eq!(mv.insert_any(33)?, 3);
+        assert_eq!(mv[3], 33);
+        let pinned = mv.pin();
+        assert!(mv.vacate(9).is_err());
+        assert!(!mv.is_free(9));
+        drop(pinned);
+        assert_eq!(mv.vacate(9)?, 90);
+        assert_eq!(mv.vacate(8)?, 80);
+        assert_eq!(mv.len(), 7);
+        assert_eq!(mv.free_slots(), 0);
+        assert_eq!(mv.insert_any(77)?, 7);
+
+        Ok(())
+    }
+}
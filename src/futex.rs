@@ -0,0 +1,125 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Waiting on and waking elements of [`SharedAtomics`](SharedAtomics) with futexes, for
+//! producers and consumers in different processes to coordinate through the file itself.
+
+use crate::SharedAtomics;
+use std::io;
+use std::ptr;
+use std::sync::atomic::AtomicU32;
+use std::time::Duration;
+
+impl SharedAtomics<AtomicU32> {
+    /// Sleep until woken by [`wake`](SharedAtomics::wake) on the same element, in this or
+    /// another process, or until `timeout` has passed, unless the element at `index` no
+    /// longer holds `expected` to begin with. Returns whether it was woken, rather than
+    /// timed out.
+    ///
+    /// As with any futex, wake-ups can be spurious, so check the element again afterwards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn wait_on(
+        &self,
+        index: usize,
+        expected: u32,
+        timeout: Option<Duration>,
+    ) -> io::Result<bool> {
+        let timespec = timeout.map(|timeout| libc::timespec {
+            tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as _,
+        });
+
+        // NOTE: Not FUTEX_PRIVATE_FLAG, since the waker may be another process.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                self[index].as_ptr(),
+                libc::FUTEX_WAIT,
+                expected,
+                timespec
+                    .as_ref()
+                    .map_or(ptr::null(), |t| t as *const libc::timespec),
+            )
+        };
+
+        if ret == 0 {
+            return Ok(true);
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EAGAIN) | Some(libc::EINTR) => Ok(true),
+            Some(libc::ETIMEDOUT) => Ok(false),
+            _ => Err(e),
+        }
+    }
+
+    /// Wake up to `n` waiters on the element at `index`, returning how many were woken.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn wake(&self, index: usize, n: u32) -> io::Result<usize> {
+        let n = n.min(i32::MAX as u32) as libc::c_int;
+        let ret =
+            unsafe { libc::syscall(libc::SYS_futex, self[index].as_ptr(), libc::FUTEX_WAKE, n) };
+
+        match ret {
+            -1 => Err(io::Error::last_os_error()),
+            woken => Ok(woken as usize),
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::MmapedVecBuilder;
+
+    #[test]
+    pub fn test_futex_wait_and_wake() -> Result<(), io::Error> {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::time::Duration;
+
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let producer = builder.try_open_atomic::<AtomicU32>(&path, 4)?;
+        let consumer = builder.try_open_atomic::<AtomicU32>(&path, 4)?;
+
+        assert!(!consumer.wait_on(1, 0, Some(Duration::from_millis(10)))?);
+        assert!(consumer.wait_on(1, 7, None)?);
+
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| -> io::Result<u32> {
+                while consumer[1].load(Ordering::Acquire) == 0 {
+                    consumer.wait_on(1, 0, Some(Duration::from_secs(10)))?;
+                }
+                Ok(consumer[1].load(Ordering::Acquire))
+            });
+
+            std::thread::sleep(Duration::from_millis(20));
+            producer[1].store(5, Ordering::Release);
+            producer.wake(1, u32::MAX)?;
+
+            assert_eq!(waiter.join().unwrap()?, 5);
+            Ok(())
+        })
+    }
+}
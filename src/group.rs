@@ -0,0 +1,350 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Groups of files that are committed together, so that a crash leaves them as of the
+//! same commit.
+//!
+//! The group file starts with `GROUP_MAGIC` and the generation of the last commit, followed
+//! by the number of members and, for each member, the length of its name as a `u32`, the
+//! name and the number of elements it had at that commit, as a `u64`, in native byte order.
+
+// TODO: Only the lengths are committed, which is enough for files that are only appended
+//       to. Rolling back elements overwritten in place after the last commit would need
+//       the members to be in WAL mode, and replaying their logs up to the commit.
+
+use crate::MmapedVec;
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const GROUP_MAGIC: [u8; 8] = *b"PERSGRP\0";
+
+/// What a [`CommitGroup`](CommitGroup) needs of its members, whatever their element type.
+trait Member {
+    fn len(&self) -> usize;
+    fn flush(&mut self) -> io::Result<()>;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: 'static> Member for MmapedVec<T> {
+    fn len(&self) -> usize {
+        MmapedVec::len(self)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        MmapedVec::flush(self)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Several [`MmapedVec`](MmapedVec)s that belong together, such as the columns of a table,
+/// or data and an index into it, committed with [`commit_all`](CommitGroup::commit_all),
+/// so that they are never found out of sync after a crash.
+///
+/// Each commit flushes the members, and then replaces the group file with one that records
+/// the next generation and the length of each member. When a member is
+/// [added](CommitGroup::add), it is truncated to its length as of the last commit, which
+/// throws away elements appended after it, whether the file was closed cleanly or not.
+/// Members are borrowed by the group, and modified through
+/// [`member_mut`](CommitGroup::member_mut) meanwhile.
+///
+/// A member that is not added to the group keeps the length it was last committed with,
+/// until it is [removed](CommitGroup::remove).
+pub struct CommitGroup<'a> {
+    path: PathBuf,
+    generation: u64,
+    committed: BTreeMap<String, usize>,
+    removed: BTreeSet<String>,
+    members: Vec<(String, &'a mut dyn Member)>,
+}
+
+impl<'a> CommitGroup<'a> {
+    /// Open the group whose group file is at `path`, creating it at generation zero, with
+    /// no members, if there is none.
+    ///
+    /// In the directory of a [`Store`](crate::Store), give the group file a name that starts
+    /// with `.`, which the store leaves alone.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let (generation, committed) = match fs::read(path) {
+            Ok(buf) => parse(&buf).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("File `{:?}`: Not a valid group file.", path),
+                )
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, BTreeMap::new()),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            generation,
+            committed,
+            removed: BTreeSet::new(),
+            members: vec![],
+        })
+    }
+
+    /// The generation of the last commit.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Add `mv` to the group by the name of `name`, truncating it to its length as of the
+    /// last commit, if it was a member then.
+    ///
+    /// Fails if the file is shorter than it was at the last commit, since that means that
+    /// committed elements were lost, or if there is already a member by that name.
+    pub fn add<T: 'static>(&mut self, name: &str, mv: &'a mut MmapedVec<T>) -> io::Result<()> {
+        if self.members.iter().any(|(n, _)| n == name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("File `{:?}`: Member {:?} added twice.", self.path, name),
+            ));
+        }
+
+        if let Some(&committed) = self.committed.get(name) {
+            if mv.len() < committed {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "File `{:?}`: Member {:?} has {} elements, but {} were committed.",
+                        self.path,
+                        name,
+                        mv.len(),
+                        committed
+                    ),
+                ));
+            }
+            if mv.len() > committed {
+                mv.truncate(committed)?;
+                mv.flush()?;
+            }
+        }
+
+        self.removed.remove(name);
+        self.members.push((name.to_string(), mv));
+        Ok(())
+    }
+
+    /// Remove the member by the name of `name` from the group, as of the next commit, whether
+    /// it is added to the group or not. Returns whether there was such a member.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let added = self.members.len();
+        self.members.retain(|(n, _)| n != name);
+        let found = self.members.len() < added || self.committed.contains_key(name);
+        if found {
+            self.removed.insert(name.to_string());
+        }
+        found
+    }
+
+    /// The member by the name of `name`, if it has elements of type `T`, for modifying it
+    /// while it is in the group.
+    pub fn member_mut<T: 'static>(&mut self, name: &str) -> Option<&mut MmapedVec<T>> {
+        self.members
+            .iter_mut()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, member)| member.as_any_mut().downcast_mut())
+    }
+
+    /// Commit the members as they are now, as the next generation: flush each of them, and
+    /// then atomically replace the group file.
+    ///
+    /// If anything fails, the last commit stands, and the members are rolled back to it the
+    /// next time they are added to the group.
+    pub fn commit_all(&mut self) -> io::Result<u64> {
+        for (_, member) in self.members.iter_mut() {
+            member.flush()?;
+        }
+
+        let generation = self.generation + 1;
+        // NOTE: Members that are not added keep the length they were last committed with.
+        let mut committed = self.committed.clone();
+        committed.retain(|name, _| !self.removed.contains(name));
+        for (name, member) in &self.members {
+            committed.insert(name.clone(), member.len());
+        }
+
+        let mut buf = GROUP_MAGIC.to_vec();
+        buf.extend_from_slice(&generation.to_ne_bytes());
+        buf.extend_from_slice(&(committed.len() as u64).to_ne_bytes());
+        for (name, len) in &committed {
+            buf.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+            buf.extend_from_slice(name.as_bytes());
+            buf.extend_from_slice(&(*len as u64).to_ne_bytes());
+        }
+
+        let mut tmp_name = OsString::from(self.path.as_os_str());
+        tmp_name.push(".tmp");
+        let tmp = PathBuf::from(tmp_name);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &self.path)?;
+
+        // NOTE: The rename is only durable once the directory is.
+        if let Some(dir) = self.path.parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            File::open(dir)?.sync_all()?;
+        }
+
+        self.generation = generation;
+        self.committed = committed;
+        self.removed.clear();
+        Ok(generation)
+    }
+}
+
+fn parse(buf: &[u8]) -> Option<(u64, BTreeMap<String, usize>)> {
+    let u64_at = |pos: usize| -> Option<u64> {
+        Some(u64::from_ne_bytes(buf.get(pos..pos + 8)?.try_into().ok()?))
+    };
+
+    if buf.get(..8)? != GROUP_MAGIC {
+        return None;
+    }
+    let generation = u64_at(8)?;
+    let count = u64_at(16)?;
+
+    let mut committed = BTreeMap::new();
+    let mut pos = 24;
+    for _ in 0..count {
+        let name_len = u32::from_ne_bytes(buf.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        pos += 4;
+        let name = std::str::from_utf8(buf.get(pos..pos.checked_add(name_len)?)?).ok()?;
+        pos += name_len;
+        committed.insert(name.to_string(), u64_at(pos)? as usize);
+        pos += 8;
+    }
+
+    Some((generation, committed))
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::MmapedVecBuilder;
+
+    #[test]
+    pub fn test_commit_group_rolls_back_uncommitted_appends() -> Result<(), io::Error> {
+        let (dir, path) = tempdir_and_tempfile()?;
+        let group_path = dir.path().join("group");
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        let mut data = builder.try_open::<u64>(&path)?;
+        let mut index = builder.try_open::<u32>(&path.with_extension("index"))?;
+        let mut other = builder.try_open::<u32>(&path.with_extension("other"))?;
+
+        {
+            let mut group = CommitGroup::open(&group_path)?;
+            group.add("data", &mut data)?;
+            group.add("index", &mut index)?;
+            assert!(group.add("index", &mut other).is_err());
+            assert_eq!(group.commit_all()?, 1);
+        }
+
+        {
+            let mut group = CommitGroup::open(&group_path)?;
+            group.add("data", &mut data)?;
+            group.add("index", &mut index)?;
+            group.member_mut::<u64>("data").unwrap().extend([1, 2])?;
+            group.member_mut::<u32>("index").unwrap().push(0)?;
+            assert!(group.member_mut::<u64>("index").is_none());
+            assert_eq!(group.commit_all()?, 2);
+
+            // Appended to one member, but not committed, as in a crash between the two.
+            group.member_mut::<u64>("data").unwrap().push(3)?;
+        }
+        drop(data);
+        drop(index);
+
+        let mut data = builder.try_open::<u64>(&path)?;
+        let mut index = builder.try_open::<u32>(&path.with_extension("index"))?;
+        let mut group = CommitGroup::open(&group_path)?;
+        assert_eq!(group.generation(), 2);
+        group.add("data", &mut data)?;
+        group.add("index", &mut index)?;
+        drop(group);
+        assert_eq!((&data[..], &index[..]), (&[1, 2][..], &[0][..]));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_commit_group_keeps_absent_members() -> Result<(), io::Error> {
+        let (dir, path) = tempdir_and_tempfile()?;
+        let group_path = dir.path().join("group");
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        let mut data = builder.try_open::<u64>(&path)?;
+        let mut index = builder.try_open::<u32>(&path.with_extension("index"))?;
+        data.extend([1, 2])?;
+        index.push(0)?;
+
+        {
+            let mut group = CommitGroup::open(&group_path)?;
+            group.add("data", &mut data)?;
+            group.add("index", &mut index)?;
+            group.commit_all()?;
+        }
+
+        // Committed without the index, which keeps its length as of the last commit.
+        {
+            let mut group = CommitGroup::open(&group_path)?;
+            group.add("data", &mut data)?;
+            group.member_mut::<u64>("data").unwrap().push(3)?;
+            group.commit_all()?;
+        }
+        index.push(1)?;
+        {
+            let mut group = CommitGroup::open(&group_path)?;
+            group.add("index", &mut index)?;
+        }
+        assert_eq!(&index[..], &[0][..]);
+
+        // Removed explicitly, after which it is no longer rolled back.
+        {
+            let mut group = CommitGroup::open(&group_path)?;
+            assert!(group.remove("index"));
+            assert!(!group.remove("nonexistent"));
+            group.commit_all()?;
+        }
+        index.push(1)?;
+        {
+            let mut group = CommitGroup::open(&group_path)?;
+            group.add("data", &mut data)?;
+            group.add("index", &mut index)?;
+        }
+        assert_eq!((&data[..], &index[..]), (&[1, 2, 3][..], &[0, 1][..]));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::MmapedVec;
+use std::ops::Range;
+use std::{io, ptr};
+
+impl<T> MmapedVec<T> {
+    /// Remove consecutive elements for which `same_bucket(a, b)` returns true, where `b` is
+    /// the element kept before `a`, like [`Vec::dedup_by`](Vec::dedup_by). The kept elements
+    /// are moved down in place and the file is then shrunk. Removed elements are not
+    /// dropped, just as with [`truncate`](MmapedVec::truncate).
+    pub fn dedup_by<F>(&mut self, mut same_bucket: F) -> io::Result<()>
+    where
+        F: FnMut(&mut T, &mut T) -> bool,
+    {
+        self.check_poisoned()?;
+
+        let len = self.len();
+        if len < 2 {
+            return Ok(());
+        }
+
+        self.set_writable(true)?;
+        let body = self.body_mut_ptr();
+
+        // Elements before `first_moved` are where they were; only those after need
+        // replicating.
+        let mut kept = 1;
+        let mut first_moved = None;
+
+        for read in 1..len {
+            let same = unsafe { same_bucket(&mut *body.add(read), &mut *body.add(kept - 1)) };
+
+            if !same {
+                if read != kept {
+                    unsafe { ptr::copy_nonoverlapping(body.add(read), body.add(kept), 1) };
+                    first_moved.get_or_insert(kept);
+                }
+                kept += 1;
+            }
+        }
+
+        self.set_writable(false)?;
+        self.truncate(kept)?;
+
+        match first_moved {
+            Some(start) => self.replicate_range(start..kept),
+            None => Ok(()),
+        }
+    }
+
+    /// Remove consecutive elements that have the same key, keeping the first of each run.
+    pub fn dedup_by_key<K, F>(&mut self, mut key: F) -> io::Result<()>
+    where
+        K: PartialEq,
+        F: FnMut(&mut T) -> K,
+    {
+        self.dedup_by(|a, b| key(a) == key(b))
+    }
+
+    /// The key and the range of indexes of each run of consecutive elements with the same
+    /// key. Over sorted data, these are the groups of a group-by.
+    pub fn group_ranges_by_key<K, F>(&self, key: F) -> GroupRangesByKey<'_, T, F>
+    where
+        K: PartialEq,
+        F: FnMut(&T) -> K,
+    {
+        GroupRangesByKey {
+            elements: self,
+            start: 0,
+            key,
+        }
+    }
+}
+
+/// Iterator returned by [`group_ranges_by_key`](MmapedVec::group_ranges_by_key).
+pub struct GroupRangesByKey<'a, T, F> {
+    elements: &'a [T],
+    start: usize,
+    key: F,
+}
+
+impl<T, K, F> Iterator for GroupRangesByKey<'_, T, F>
+where
+    K: PartialEq,
+    F: FnMut(&T) -> K,
+{
+    type Item = (K, Range<usize>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.start;
+        let k = (self.key)(self.elements.get(start)?);
+
+        let mut end = start + 1;
+        while end < self.elements.len() && (self.key)(&self.elements[end]) == k {
+            end += 1;
+        }
+
+        self.start = end;
+        Some((k, start..end))
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{new_mmaped_vec_of_example_persisting_in_tempdir, Example};
+
+    #[test]
+    pub fn test_dedup_and_group_ranges() -> Result<(), io::Error> {
+        let (_dir, _pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        for (hello, world) in [(1, 0), (1, 1), (2, 2), (2, 3), (2, 4), (3, 5), (1, 6)] {
+            mv.push(Example { hello, world })?;
+        }
+
+        let groups: Vec<(u8, Range<usize>)> = mv.group_ranges_by_key(|e| e.hello).collect();
+        assert_eq!(groups, vec![(1, 0..2), (2, 2..5), (3, 5..6), (1, 6..7)]);
+
+        mv.dedup_by_key(|e| e.hello)?;
+        let kept: Vec<(u8, u8)> = mv.iter().map(|e| (e.hello, e.world)).collect();
+        assert_eq!(kept, vec![(1, 0), (2, 2), (3, 5), (1, 6)]);
+
+        mv.dedup_by(|a, b| a.world.abs_diff(b.world) < 4)?;
+        let kept: Vec<u8> = mv.iter().map(|e| e.world).collect();
+        assert_eq!(kept, vec![0, 5]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::{MmapedVec, Poisoned};
+use std::ops::{Deref, DerefMut, Range};
+use std::{io, slice, thread};
+
+/// Mutable access to a range of the elements of a [`MmapedVec`](MmapedVec).
+///
+/// For a [`MmapedVec`](MmapedVec) opened with [`harden`](crate::MmapedVecBuilder::harden),
+/// the mapping is writable only for as long as the guard is held. Otherwise the guard is
+/// equivalent to dereferencing the range of the [`MmapedVec`](MmapedVec) mutably, except
+/// that once the guard has been dereferenced mutably, the elements in the range are
+/// recorded as modified with [`mark_modified`](MmapedVec::mark_modified) when it is dropped.
+pub struct WriteGuard<'a, T> {
+    mv: &'a mut MmapedVec<T>,
+    range: Range<usize>,
+    written: bool,
+}
+
+impl<T> MmapedVec<T> {
+    /// A [`WriteGuard`](WriteGuard) over every element.
+    pub fn write_guard(&mut self) -> io::Result<WriteGuard<'_, T>> {
+        let len = self.len();
+        self.write_guard_range(0..len)
+    }
+
+    /// A [`WriteGuard`](WriteGuard) over the elements in `range` only, so that no more than
+    /// those are recorded as modified when it is dropped.
+    ///
+    /// Fails with [`InvalidInput`](io::ErrorKind::InvalidInput) if `range` is out of bounds.
+    pub fn write_guard_range(&mut self, range: Range<usize>) -> io::Result<WriteGuard<'_, T>> {
+        if range.start > range.end || range.end > self.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "File `{:?}`: Range {}..{} out of bounds for {} elements.",
+                    self.path,
+                    range.start,
+                    range.end,
+                    self.len()
+                ),
+            ));
+        }
+
+        self.check_poisoned()?;
+        self.set_writable(true)?;
+        Ok(WriteGuard {
+            mv: self,
+            range,
+            written: false,
+        })
+    }
+
+    /// Whether a panic occurred while a [`WriteGuard`](WriteGuard) was held, in the manner of
+    /// [`Mutex::is_poisoned`](std::sync::Mutex::is_poisoned).
+    ///
+    /// While poisoned, operations on the [`MmapedVec`](MmapedVec) fail with
+    /// [`Poisoned`](Poisoned), since the elements may have been left half-modified.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// Clear the poisoned state, once the elements have been verified or repaired.
+    pub fn clear_poison(&mut self) {
+        self.poisoned = false;
+    }
+
+    pub(crate) fn check_poisoned(&self) -> io::Result<()> {
+        if self.poisoned {
+            return Err(io::Error::other(Poisoned {
+                path: self.path.clone(),
+            }));
+        }
+
+        Ok(())
+    }
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.mv[self.range.clone()]
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.written = true;
+        // NOTE: Bypasses DerefMut of the MmapedVec, which would unprotect a hardened mapping
+        //       until the next flush rather than only while the guard is held.
+        unsafe {
+            slice::from_raw_parts_mut(
+                self.mv.body_mut_ptr().add(self.range.start),
+                self.range.len(),
+            )
+        }
+    }
+}
+
+impl<T> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.mv.poisoned = true;
+        }
+
+        if self.written {
+            self.mv.mark_modified(self.range.clone());
+        }
+
+        // XXX: Failing to make a mapping that we own read-only again should not happen.
+        let _ = self.mv.set_writable(false);
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{new_mmaped_vec_of_example_persisting_in_tempdir, Example};
+    use std::panic;
+
+    #[test]
+    pub fn test_panic_while_holding_write_guard_poisons() -> Result<(), io::Error> {
+        let (_dir, _pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        mv.push(Example::default())?;
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let mut guard = mv.write_guard().unwrap();
+            guard[0].hello = 7;
+            panic!("interrupted mid-mutation");
+        }));
+
+        assert!(result.is_err());
+        assert!(mv.is_poisoned());
+        assert!(mv
+            .push(Example::default())
+            .err()
+            .unwrap()
+            .get_ref()
+            .unwrap()
+            .is::<Poisoned>());
+
+        mv.clear_poison();
+        mv.push(Example::default())?;
+        assert_eq!(mv.len(), 2);
+
+        Ok(())
+    }
+}
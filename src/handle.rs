@@ -0,0 +1,150 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::MmapedVec;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// A reference to an element by its index rather than its address, so that it stays valid
+/// when the file is grown and remapped, unlike `&T`.
+///
+/// A handle refers to a position, not to the element that was there when it was made. It
+/// resolves to nothing once the vector has been truncated to below it, and to a different
+/// element after the elements have been moved around, such as by sorting.
+pub struct ElemHandle<T> {
+    index: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> ElemHandle<T> {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+// Implemented by hand, since deriving would require T to implement these too.
+impl<T> Clone for ElemHandle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ElemHandle<T> {}
+
+impl<T> PartialEq for ElemHandle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl<T> Eq for ElemHandle<T> {}
+
+impl<T> fmt::Debug for ElemHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ElemHandle").field(&self.index).finish()
+    }
+}
+
+/// A position for reading through the elements that stays valid across growth, and sees
+/// elements appended after it was made. Useful for following a vector as it is appended to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Cursor {
+    pos: usize,
+}
+
+impl Cursor {
+    /// A cursor at `pos`, which may be past the end.
+    pub fn at(pos: usize) -> Self {
+        Self { pos }
+    }
+
+    /// Index of the next element to be read.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// The next element of `mv`, if there is one yet, advancing past it.
+    pub fn next<'a, T>(&mut self, mv: &'a MmapedVec<T>) -> Option<&'a T> {
+        let e = mv.get(self.pos)?;
+        self.pos += 1;
+        Some(e)
+    }
+
+    /// All elements of `mv` from the cursor to the end, advancing past them.
+    pub fn remaining<'a, T>(&mut self, mv: &'a MmapedVec<T>) -> &'a [T] {
+        let start = self.pos.min(mv.len());
+        self.pos = self.pos.max(mv.len());
+        &mv[start..]
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// A handle to the element at `index`, or `None` if it is out of bounds.
+    pub fn handle(&self, index: usize) -> Option<ElemHandle<T>> {
+        match index < self.len() {
+            true => Some(ElemHandle {
+                index,
+                _marker: PhantomData,
+            }),
+            false => None,
+        }
+    }
+
+    /// The element that `handle` refers to, or `None` if the vector has been truncated to
+    /// below it.
+    pub fn resolve(&self, handle: ElemHandle<T>) -> Option<&T> {
+        self.get(handle.index)
+    }
+
+    /// Like [`resolve`](MmapedVec::resolve), but mutable.
+    pub fn resolve_mut(&mut self, handle: ElemHandle<T>) -> Option<&mut T> {
+        self.get_mut(handle.index)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{new_mmaped_vec_of_example_persisting_in_tempdir, Example};
+    use std::io;
+
+    #[test]
+    pub fn test_handles_and_cursors_survive_growth() -> Result<(), io::Error> {
+        let (_dir, _pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        mv.push(Example { hello: 7, world: 0 })?;
+        let handle = mv.handle(0).unwrap();
+        assert!(mv.handle(1).is_none());
+
+        let mut cursor = Cursor::default();
+        assert_eq!(cursor.next(&mv).map(|e| e.hello), Some(7));
+        assert!(cursor.next(&mv).is_none());
+
+        mv.extend((0..10000).map(|_| Example { hello: 1, world: 1 }))?;
+
+        assert_eq!(mv.resolve(handle).map(|e| e.hello), Some(7));
+        mv.resolve_mut(handle).unwrap().hello = 8;
+        assert_eq!(mv[0].hello, 8);
+
+        assert_eq!(cursor.remaining(&mv).len(), 10000);
+        assert_eq!(cursor.position(), 10001);
+
+        mv.truncate(0)?;
+        assert!(mv.resolve(handle).is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::{lock_path, locking, MmapedVec, MmapedVecBuilder};
+use std::ffi::OsString;
+use std::fs::File;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::{io, mem, ptr};
+
+impl<T> MmapedVec<T> {
+    /// Hand this [`MmapedVec`](MmapedVec) off to another process over a Unix domain socket,
+    /// by passing the file descriptor with `SCM_RIGHTS`.
+    ///
+    /// The receiving process adopts it with [`try_receive`](MmapedVecBuilder::try_receive).
+    /// The passed descriptor refers to the same open file description as ours, which is what
+    /// the advisory lock belongs to, so the lock is handed over along with it instead of
+    /// having to be released by us and raced for by the receiver.
+    pub fn send_to(mut self, socket: &UnixStream) -> io::Result<()> {
+        self.flush()?;
+        send_fd(
+            socket,
+            self.file.as_raw_fd(),
+            self.path.as_os_str().as_bytes(),
+        )?;
+
+        // NOTE: The file stays open on the other end, so it must not be marked as closed.
+        let _ = self.into_parts();
+        Ok(())
+    }
+}
+
+impl MmapedVecBuilder {
+    /// Adopt a [`MmapedVec`](MmapedVec) handed off by another process with
+    /// [`send_to`](MmapedVec::send_to).
+    ///
+    /// The header is validated in the same way as when opening a file by path.
+    pub fn try_receive<T>(&self, socket: &UnixStream) -> io::Result<MmapedVec<T>> {
+        let (file, path) = recv_fd(socket)?;
+
+        // NOTE: Succeeds without blocking when the sender held the lock on this same open file description.
+        //       Where locks belong to processes instead, the sender's lock went away when it
+        //       closed its descriptor, and this takes a fresh one. A lock file is not sent
+        //       along, so its lock is taken anew once the sender has let go of it.
+        let lock_file = match locking::open_lock_file(&file, &path)? {
+            Some(lock_file) => {
+                locking::lock_exclusive(&lock_file, &lock_path(&path))?;
+                Some(lock_file)
+            }
+            None => {
+                locking::try_lock_exclusive(&file, &path)?;
+                None
+            }
+        };
+
+        self.try_from_locked_file(file, lock_file, &path, None)
+    }
+}
+
+// XXX: Room for a single file descriptor, in units that are suitably aligned for cmsghdr.
+const CMSG_BUF_LEN: usize = 8;
+
+fn send_fd(socket: &UnixStream, fd: RawFd, payload: &[u8]) -> io::Result<()> {
+    let mut cmsg_buf = [0u64; CMSG_BUF_LEN];
+
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as _;
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+    }
+
+    let n = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+
+    if n == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    if n as usize != payload.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "Handoff message was only partially sent.",
+        ));
+    }
+
+    Ok(())
+}
+
+fn recv_fd(socket: &UnixStream) -> io::Result<(File, PathBuf)> {
+    let mut cmsg_buf = [0u64; CMSG_BUF_LEN];
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&cmsg_buf) as _;
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    let flags = 0;
+
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, flags) };
+
+    if n == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let file = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Handoff message did not carry a file descriptor.",
+            ));
+        }
+
+        File::from_raw_fd(ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd))
+    };
+
+    if msg.msg_flags & (libc::MSG_TRUNC | libc::MSG_CTRUNC) != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Handoff message was truncated.",
+        ));
+    }
+
+    buf.truncate(n as usize);
+
+    Ok((file, PathBuf::from(OsString::from_vec(buf))))
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        new_mmaped_vec_of_example_persisting_in_tempdir, python3_try_lock_exclusive, Example,
+        EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES,
+    };
+
+    #[test]
+    pub fn test_handoff_over_unix_socket_keeps_lock_and_contents() -> Result<(), io::Error> {
+        let (_dir, pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+        let (sender, receiver) = UnixStream::pair()?;
+
+        mv.push(Example { hello: 3, world: 4 })?;
+        mv.send_to(&sender)?;
+
+        let mv = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .try_receive::<Example>(&receiver)?;
+
+        assert_eq!(mv.len(), 1);
+        assert_eq!((mv[0].hello, mv[0].world), (3, 4));
+        assert_eq!(
+            python3_try_lock_exclusive(pathbuf.as_path())?.code(),
+            Some(35)
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::format::{FileHeader, ENDIANNESS_MARKER, FILE_HEADER_LEN};
+use crate::MmapedVec;
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// The fields of the header of a file, as read with [`read_header`](read_header) or
+/// [`header_info`](MmapedVec::header_info), in the byte order of this host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderInfo {
+    pub magic_bytes: [u8; 8],
+    pub format_version: [u8; 3],
+    pub data_version: [u8; 3],
+    /// Whether the file was written on a host with the same endianness as this one. Files
+    /// that were not must be converted before they can be opened.
+    pub native_endian: bool,
+    pub flags: u32,
+    pub has_default_data: bool,
+    /// Whether the file is open for writing, or was not closed cleanly.
+    pub dirty: bool,
+    /// Length of the header and the padding after it, in bytes.
+    pub header_len: u64,
+    /// Length of the part of the header after its fields and the default data, in bytes,
+    /// which holds the header extensions and padding to align the body.
+    pub padding_len: u64,
+}
+
+impl From<FileHeader> for HeaderInfo {
+    fn from(fh: FileHeader) -> Self {
+        let fields_end = cmp::max(
+            FILE_HEADER_LEN as u64,
+            fh.default_data_offset as u64 + fh.default_data_len as u64,
+        );
+
+        Self {
+            magic_bytes: fh.magic_bytes,
+            format_version: fh.persistence_format_version,
+            data_version: fh.data_contained_version,
+            native_endian: fh.endianness == ENDIANNESS_MARKER,
+            flags: fh.flags,
+            has_default_data: fh.has_default_data(),
+            dirty: fh.is_dirty(),
+            header_len: fh.header_len,
+            padding_len: fh.header_len.saturating_sub(fields_end),
+        }
+    }
+}
+
+/// Read the header of the file at `path`, without locking it or checking anything more
+/// than that it has the fields of a header, for tooling and diagnostics.
+///
+/// The file may be open for writing elsewhere meanwhile, in which case the header may be
+/// read while it is being changed.
+pub fn read_header(path: &Path) -> io::Result<HeaderInfo> {
+    let file = File::open(path)?;
+    if file.metadata()?.len() < FILE_HEADER_LEN as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("File `{:?}`: Shorter than the header.", path),
+        ));
+    }
+
+    let fh = FileHeader::read_from(&file)?;
+    let native_endian = fh.endianness == ENDIANNESS_MARKER;
+    let fh = match fh.endianness {
+        ENDIANNESS_MARKER => fh,
+        marker if marker == ENDIANNESS_MARKER.swap_bytes() => fh.swap_bytes(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("File `{:?}`: Endianness-marker invalid.", path),
+            ))
+        }
+    };
+
+    Ok(HeaderInfo {
+        native_endian,
+        ..fh.into()
+    })
+}
+
+impl<T> MmapedVec<T> {
+    pub fn header_info(&self) -> HeaderInfo {
+        self.header().into()
+    }
+
+    pub fn magic_bytes(&self) -> [u8; 8] {
+        self.header().magic_bytes
+    }
+
+    /// Version of the format of the file, as opposed to that of the data it contains.
+    pub fn format_version(&self) -> [u8; 3] {
+        self.header().persistence_format_version
+    }
+
+    /// Version of the data contained, as given when the file was created.
+    pub fn data_version(&self) -> [u8; 3] {
+        self.header().data_contained_version
+    }
+
+    /// Length of the header and the padding after it, in bytes; the body starts there.
+    pub fn header_len(&self) -> usize {
+        self.header_len
+    }
+
+    /// See [`HeaderInfo::padding_len`](HeaderInfo::padding_len).
+    pub fn padding_len(&self) -> usize {
+        self.header_info().padding_len as usize
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::{format, MmapedVecBuilder};
+
+    #[test]
+    pub fn test_header_accessors_and_read_header() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mv = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .try_open_with_default_data::<u64>(&path, 7)?;
+
+        assert_eq!(mv.magic_bytes(), EXAMPLE_MAGIC_BYTES);
+        assert_eq!(mv.data_version(), EXAMPLE_DATA_CONTAINED_VERSION);
+        assert_eq!(mv.format_version(), format::PERSISTENCE_FORMAT_VERSION);
+        assert_eq!(mv.header_len(), mv.header_len);
+        assert_eq!(
+            mv.padding_len() as u32,
+            mv.header_len as u32 - (mv.header().default_data_offset + 8)
+        );
+
+        // NOTE: Read while the file is locked by `mv`.
+        let info = read_header(&path)?;
+        assert_eq!(info, mv.header_info());
+        assert!(info.native_endian && info.dirty && info.has_default_data);
+
+        drop(mv);
+        assert!(!read_header(&path)?.dirty);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Hints in the header of the options that a file was created with, so that it can be
+//! opened again without repeating them. See
+//! [`EXTENSION_TAG_HINTS`](crate::format::EXTENSION_TAG_HINTS).
+
+use crate::format::{
+    EXTENSION_TAG_HINTS, HINTS_VALUE_LEN, HINT_CHECK_FREE_SPACE, HINT_PREALLOCATE,
+};
+use crate::{MmapedVec, MmapedVecBuilder};
+use std::any;
+use std::convert::TryInto;
+use std::fmt::Debug;
+use std::io;
+use std::mem;
+use std::path::Path;
+
+/// The options recorded in the header of a file.
+struct Hints {
+    size: u64,
+    align: u64,
+    max_elements: Option<u64>,
+    max_len_bytes: Option<u64>,
+    preallocate: bool,
+    check_free_space: bool,
+}
+
+impl Hints {
+    fn to_value(&self) -> [u8; HINTS_VALUE_LEN] {
+        let mut value = [0u8; HINTS_VALUE_LEN];
+        let fields = [
+            self.size,
+            self.align,
+            self.max_elements.unwrap_or(u64::MAX),
+            self.max_len_bytes.unwrap_or(u64::MAX),
+        ];
+        for (i, field) in fields.iter().enumerate() {
+            value[i * 8..i * 8 + 8].copy_from_slice(&field.to_ne_bytes());
+        }
+        if self.preallocate {
+            value[32] |= HINT_PREALLOCATE;
+        }
+        if self.check_free_space {
+            value[32] |= HINT_CHECK_FREE_SPACE;
+        }
+        value
+    }
+
+    fn from_value(value: &[u8]) -> Option<Self> {
+        let field = |i: usize| -> Option<u64> {
+            Some(u64::from_ne_bytes(
+                value.get(i * 8..i * 8 + 8)?.try_into().ok()?,
+            ))
+        };
+        let limit = |i: usize| field(i).map(|n| Some(n).filter(|&n| n != u64::MAX));
+        let flags = *value.get(32)?;
+
+        Some(Self {
+            size: field(0)?,
+            align: field(1)?,
+            max_elements: limit(2)?,
+            max_len_bytes: limit(3)?,
+            preallocate: flags & HINT_PREALLOCATE != 0,
+            check_free_space: flags & HINT_CHECK_FREE_SPACE != 0,
+        })
+    }
+}
+
+impl MmapedVecBuilder {
+    /// Record the options that new files are created with in their header:
+    /// [`max_elements`](MmapedVecBuilder::max_elements),
+    /// [`max_len_bytes`](MmapedVecBuilder::max_len_bytes),
+    /// [`preallocate`](MmapedVecBuilder::preallocate) and
+    /// [`check_free_space`](MmapedVecBuilder::check_free_space), along with the size and
+    /// alignment of the element type.
+    ///
+    /// Files with hints are opened with the recorded options wherever the builder leaves
+    /// them unset, whatever this is set to, and fail to open with
+    /// [`InvalidInput`](io::ErrorKind::InvalidInput) where the builder sets them otherwise,
+    /// or with [`InvalidData`](io::ErrorKind::InvalidData) if the element type does not
+    /// match. The [`checksum`](MmapedVecBuilder::checksum) algorithm and the stride of
+    /// [padded](MmapedVecBuilder::try_open_padded) elements are always recorded.
+    pub fn header_hints(&mut self, header_hints: bool) -> &mut Self {
+        self.header_hints = header_hints;
+        self
+    }
+}
+
+/// Check that an option that the builder sets matches the one in the hints.
+fn check_hint<V: Debug + PartialEq>(
+    path: &Path,
+    name: &str,
+    hinted: V,
+    requested: Option<V>,
+) -> io::Result<()> {
+    match requested {
+        Some(requested) if requested != hinted => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "File `{:?}`: Created with {} set to {:?}, but opened with {:?}. Open it \
+      with the same {}, or leave it unset to use the one recorded in the file.",
+                path, name, hinted, requested, name
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// Record the options of `builder` in the header of a file that was just created, if it
+    /// asks for that, or else apply those recorded in the header, if any.
+    pub(crate) fn apply_header_hints(
+        &mut self,
+        builder: &MmapedVecBuilder,
+        created: bool,
+    ) -> io::Result<()> {
+        if created {
+            if !builder.header_hints {
+                return Ok(());
+            }
+            let hints = Hints {
+                size: mem::size_of::<T>() as u64,
+                align: mem::align_of::<T>() as u64,
+                max_elements: self.max_elements.map(|n| n as u64),
+                max_len_bytes: self.max_len_bytes,
+                preallocate: self.preallocate,
+                check_free_space: self.check_free_space,
+            };
+            return self.rewrite_extensions(EXTENSION_TAG_HINTS, Some(&hints.to_value()));
+        }
+
+        let hints = match self.header_extension(EXTENSION_TAG_HINTS) {
+            Some(value) => Hints::from_value(value).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("File `{:?}`: Malformed header hints.", self.path),
+                )
+            })?,
+            None => return Ok(()),
+        };
+
+        if hints.size != mem::size_of::<T>() as u64 || hints.align != mem::align_of::<T>() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "File `{:?}`: Created with elements of {} bytes aligned to {} bytes, but \
+          opened with {}, of {} bytes aligned to {} bytes.",
+                    self.path,
+                    hints.size,
+                    hints.align,
+                    any::type_name::<T>(),
+                    mem::size_of::<T>(),
+                    mem::align_of::<T>()
+                ),
+            ));
+        }
+
+        let max_elements = hints.max_elements.map(|n| n as usize);
+        check_hint(
+            &self.path,
+            "max_elements",
+            max_elements,
+            builder.max_elements.map(Some),
+        )?;
+        check_hint(
+            &self.path,
+            "max_len_bytes",
+            hints.max_len_bytes,
+            builder.max_len_bytes.map(Some),
+        )?;
+        check_hint(
+            &self.path,
+            "preallocate",
+            hints.preallocate,
+            builder.preallocate,
+        )?;
+        check_hint(
+            &self.path,
+            "check_free_space",
+            hints.check_free_space,
+            builder.check_free_space,
+        )?;
+
+        self.max_elements = max_elements;
+        self.max_len_bytes = hints.max_len_bytes;
+        self.preallocate = hints.preallocate;
+        self.check_free_space = hints.check_free_space;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::format;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+
+    #[test]
+    pub fn test_header_hints() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let mv: MmapedVec<u64> = builder.header_hints(true).max_elements(2).try_open(&path)?;
+        assert!(mv.header_extension(format::EXTENSION_TAG_HINTS).is_some());
+        drop(mv);
+
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        let mut mv: MmapedVec<u64> = builder.try_open(&path)?;
+        mv.extend([1, 2])?;
+        assert!(mv.push(3).is_err());
+        drop(mv);
+
+        let e = builder
+            .clone()
+            .max_elements(3)
+            .try_open::<u64>(&path)
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let e = builder.try_open::<u32>(&path).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        Ok(())
+    }
+}
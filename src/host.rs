@@ -0,0 +1,167 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::{MmapedVec, PinnedSlice};
+use std::io;
+use std::mem;
+
+/// Registration of host memory with a device API, such as `cudaHostRegister` or
+/// `VK_EXT_external_memory_host`, so that the device can access the body of a
+/// [`MmapedVec`](MmapedVec) without an intermediate copy.
+///
+/// `()` can be used when only locking the pages in memory is wanted.
+pub trait HostRegistration {
+    fn register(&mut self, ptr: *mut u8, len: usize) -> io::Result<()>;
+    fn unregister(&mut self, ptr: *mut u8, len: usize);
+}
+
+impl HostRegistration for () {
+    fn register(&mut self, _ptr: *mut u8, _len: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn unregister(&mut self, _ptr: *mut u8, _len: usize) {}
+}
+
+/// The body of a [`MmapedVec`](MmapedVec), pinned, locked in memory with `mlock` and
+/// registered with a [`HostRegistration`](HostRegistration), all of which is undone in
+/// reverse order when dropped.
+pub struct HostPin<T, R: HostRegistration> {
+    pinned: PinnedSlice<T>,
+    registration: R,
+}
+
+impl<T, R: HostRegistration> HostPin<T, R> {
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.pinned.as_ptr() as *mut u8
+    }
+
+    /// Length of the body in bytes.
+    pub fn len_bytes(&self) -> usize {
+        self.pinned.len() * mem::size_of::<T>()
+    }
+
+    pub fn pinned(&self) -> &PinnedSlice<T> {
+        &self.pinned
+    }
+
+    pub fn registration(&self) -> &R {
+        &self.registration
+    }
+}
+
+impl<T, R: HostRegistration> Drop for HostPin<T, R> {
+    fn drop(&mut self) {
+        let (ptr, len) = (self.as_ptr(), self.len_bytes());
+
+        self.registration.unregister(ptr, len);
+        if len > 0 {
+            let (start, len) = page_range(ptr, len);
+            unsafe { libc::munlock(start, len) };
+        }
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// [Pin](MmapedVec::pin) the body, lock it in memory and register it with `registration`,
+    /// for device APIs that require host memory to stay resident at a fixed address.
+    ///
+    /// Locking is subject to `RLIMIT_MEMLOCK`. Growing or truncating the vector fails for as
+    /// long as the returned [`HostPin`](HostPin) is held.
+    pub fn pin_for_host<R: HostRegistration>(
+        &self,
+        mut registration: R,
+    ) -> io::Result<HostPin<T, R>> {
+        let pinned = self.pin();
+        let ptr = pinned.as_ptr() as *mut u8;
+        let len = pinned.len() * mem::size_of::<T>();
+
+        if len > 0 {
+            let (start, page_len) = page_range(ptr, len);
+            if unsafe { libc::mlock(start, page_len) } == -1 {
+                let err = io::Error::last_os_error();
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!(
+                        "File `{:?}`: Failed to lock body in memory: {}",
+                        self.path, err
+                    ),
+                ));
+            }
+        }
+
+        if let Err(err) = registration.register(ptr, len) {
+            if len > 0 {
+                let (start, page_len) = page_range(ptr, len);
+                unsafe { libc::munlock(start, page_len) };
+            }
+            return Err(err);
+        }
+
+        Ok(HostPin {
+            pinned,
+            registration,
+        })
+    }
+}
+
+// NOTE: POSIX allows mlock to require a page aligned address, so round out to whole pages.
+fn page_range(ptr: *mut u8, len: usize) -> (*const libc::c_void, usize) {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let offset = ptr as usize % page_size;
+    (
+        ptr.wrapping_sub(offset) as *const libc::c_void,
+        offset + len,
+    )
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{new_mmaped_vec_of_example_persisting_in_tempdir, Example};
+
+    #[test]
+    pub fn test_pin_for_host() -> Result<(), io::Error> {
+        #[derive(Default)]
+        struct Recorder {
+            registered: Option<(*mut u8, usize)>,
+        }
+
+        impl HostRegistration for Recorder {
+            fn register(&mut self, ptr: *mut u8, len: usize) -> io::Result<()> {
+                self.registered = Some((ptr, len));
+                Ok(())
+            }
+
+            fn unregister(&mut self, ptr: *mut u8, len: usize) {
+                assert_eq!(self.registered.take(), Some((ptr, len)));
+            }
+        }
+
+        let (_dir, _pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+        mv.extend([Example { hello: 1, world: 2 }; 3])?;
+
+        let pin = mv.pin_for_host(Recorder::default())?;
+        assert_eq!(pin.len_bytes(), 3 * mem::size_of::<Example>());
+        assert_eq!(pin.registration().registered, Some((pin.as_ptr(), 6)));
+
+        assert!(mv.push(Example { hello: 3, world: 4 }).is_err());
+        drop(pin);
+        mv.push(Example { hello: 3, world: 4 })?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Cleaning up after crashes: the temporary files that writes aside leave behind when they
+//! are cut short, and the sidecars of files that are gone.
+//!
+//! Only files that this library wrote are touched, which is told by their contents: a file
+//! header, or the magic bytes that each kind of sidecar starts with. Files that another
+//! process holds a lock on are in use, and left alone.
+//!
+//! Sidecars of files that are still there are left alone too. Those are either current, or
+//! are brought up to date when the file is next opened with them, as a log is replayed.
+//! So are lock files: removing one that another process is about to lock would let two
+//! processes lock different files by the same name.
+
+use crate::bloom::BLOOM_MAGIC;
+use crate::free::FREE_MAGIC;
+use crate::locking;
+use crate::merkle::MERKLE_MAGIC;
+use crate::replay::REPLAY_MAGIC;
+use crate::stats::STATS_MAGIC;
+use crate::store::{check_header, SIDECAR_SUFFIXES, TEMP_SUFFIXES};
+use crate::tombstone::TOMBSTONES_MAGIC;
+use crate::wal::WAL_MAGIC;
+use crate::zones::ZONES_MAGIC;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+const SIDECAR_MAGICS: &[[u8; 8]] = &[
+    WAL_MAGIC,
+    MERKLE_MAGIC,
+    BLOOM_MAGIC,
+    STATS_MAGIC,
+    ZONES_MAGIC,
+    FREE_MAGIC,
+    TOMBSTONES_MAGIC,
+    REPLAY_MAGIC,
+];
+
+/// What [`clean`](clean) did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CleanReport {
+    /// Orphaned temporary files and sidecars that were removed.
+    pub removed: Vec<PathBuf>,
+    /// Orphaned temporary files and sidecars that were left alone, because another process
+    /// holds a lock on them.
+    pub in_use: Vec<PathBuf>,
+}
+
+/// Whether the file at `path` was written by this library.
+fn is_ours(path: &Path) -> io::Result<bool> {
+    if check_header(path).is_ok() {
+        return Ok(true);
+    }
+
+    let mut magic = [0u8; 8];
+    let mut file = File::open(path)?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(SIDECAR_MAGICS.contains(&magic)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The path of the file that the temporary file or sidecar by the name of `name` in `dir`
+/// belongs to, unless it is neither.
+fn owner(dir: &Path, name: &str) -> Option<PathBuf> {
+    TEMP_SUFFIXES
+        .iter()
+        .chain(SIDECAR_SUFFIXES)
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .filter(|owner| !owner.is_empty())
+        .map(|owner| dir.join(owner))
+}
+
+/// Remove the temporary files in `dir` that were left behind by writes that were cut short,
+/// and the sidecars of files that are gone, as far as they were written by this library and
+/// are not locked by another process. The library locks its temporary files while it writes
+/// them, so that those are left alone.
+pub fn clean(dir: &Path) -> io::Result<CleanReport> {
+    let mut report = CleanReport::default();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+
+        let is_temp = TEMP_SUFFIXES.iter().any(|suffix| name.ends_with(suffix));
+        let orphaned = match owner(dir, &name) {
+            Some(owner) => is_temp || !owner.exists(),
+            None => false,
+        };
+        let path = entry.path();
+        if !orphaned || !is_ours(&path)? {
+            continue;
+        }
+
+        // NOTE: Removed while locked, so that no one can take the lock in between.
+        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+        match locking::try_lock_exclusive(&file, &path) {
+            Ok(()) => {
+                fs::remove_file(&path)?;
+                report.removed.push(path);
+            }
+            Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                report.in_use.push(path)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    report.removed.sort();
+    report.in_use.sort();
+    Ok(report)
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::{MmapedVec, MmapedVecBuilder};
+    use fs2::FileExt;
+
+    #[test]
+    pub fn test_janitor_clean() -> Result<(), io::Error> {
+        let dir = tempfile::tempdir()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        for name in ["a", "b"] {
+            let mut mv: MmapedVec<u64> = builder.try_open(&dir.path().join(name))?;
+            mv.extend(0..10)?;
+            mv.statistics(0.01, |v| *v as f64)?;
+            mv.persist_to(&dir.path().join(format!("{}.copy", name)))?;
+        }
+        fs::remove_file(dir.path().join("b"))?;
+        fs::rename(dir.path().join("a.copy"), dir.path().join("a.tmp"))?;
+        fs::write(dir.path().join("c.tmp"), b"not ours")?;
+        let locked = File::open(dir.path().join("b.copy"))?;
+        fs::rename(dir.path().join("b.copy"), dir.path().join("b.roll-tmp"))?;
+        locked.try_lock_exclusive()?;
+
+        let report = clean(dir.path())?;
+        assert_eq!(
+            report.removed,
+            vec![dir.path().join("a.tmp"), dir.path().join("b.stats")]
+        );
+        assert_eq!(report.in_use, vec![dir.path().join("b.roll-tmp")]);
+        assert!(dir.path().join("a.stats").exists());
+        assert!(dir.path().join("c.tmp").exists());
+
+        drop(locked);
+        let report = clean(dir.path())?;
+        assert_eq!(report.removed, vec![dir.path().join("b.roll-tmp")]);
+
+        Ok(())
+    }
+}
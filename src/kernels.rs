@@ -0,0 +1,131 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::MmapedVec;
+use std::mem;
+use std::ops::Add;
+
+/// Bytes in a cache line, which chunks are a whole multiple of.
+const CACHE_LINE: usize = 64;
+
+/// Approximate size of the chunks, small enough for each one to stay in L1.
+const CHUNK_BYTES: usize = 4096;
+
+/// Number of elements in a chunk, chosen so that chunks are a whole number of cache lines,
+/// letting the compiler unroll and vectorize the loop over each chunk.
+fn chunk_elems<T>() -> usize {
+    let size = mem::size_of::<T>();
+    let lines = CACHE_LINE / gcd(size, CACHE_LINE);
+    (CHUNK_BYTES / (lines * size)).max(1) * lines
+}
+
+fn gcd(mut a: usize, mut b: usize) -> usize {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+impl<T> MmapedVec<T> {
+    /// Fold over the elements a chunk at a time. Chunks are a whole number of cache lines,
+    /// so that simple loops over them in `f` vectorize well.
+    pub fn fold_chunks<A, F>(&self, init: A, f: F) -> A
+    where
+        F: FnMut(A, &[T]) -> A,
+    {
+        self.chunks(chunk_elems::<T>()).fold(init, f)
+    }
+
+    /// Sum of `f` over all elements, summed per chunk and then across chunks.
+    pub fn sum_by<S, F>(&self, mut f: F) -> S
+    where
+        S: Default + Add<Output = S>,
+        F: FnMut(&T) -> S,
+    {
+        self.fold_chunks(S::default(), |acc, chunk| {
+            acc + chunk.iter().fold(S::default(), |sum, e| sum + f(e))
+        })
+    }
+
+    /// Least and greatest of `f` over all elements, or `None` if there are none. Keys that
+    /// are not comparable, such as NaN, are skipped over unless they come first.
+    pub fn minmax_by<K, F>(&self, mut f: F) -> Option<(K, K)>
+    where
+        K: PartialOrd + Copy,
+        F: FnMut(&T) -> K,
+    {
+        let first = f(self.first()?);
+
+        Some(
+            self.fold_chunks((first, first), |(mut min, mut max), chunk| {
+                for e in chunk {
+                    let k = f(e);
+                    if k < min {
+                        min = k;
+                    }
+                    if k > max {
+                        max = k;
+                    }
+                }
+                (min, max)
+            }),
+        )
+    }
+
+    /// Number of elements for which `pred` returns true.
+    pub fn count_if<F>(&self, mut pred: F) -> usize
+    where
+        F: FnMut(&T) -> bool,
+    {
+        self.fold_chunks(0, |count, chunk| {
+            count + chunk.iter().map(|e| pred(e) as usize).sum::<usize>()
+        })
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{new_mmaped_vec_of_example_persisting_in_tempdir, Example};
+    use std::io;
+
+    #[test]
+    pub fn test_aggregate_kernels() -> Result<(), io::Error> {
+        let (_dir, _pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        assert_eq!(mv.minmax_by(|e| e.hello), None);
+        assert_eq!(mv.sum_by(|e| u64::from(e.hello)), 0);
+
+        mv.extend((0..5000u32).map(|i| Example {
+            hello: (i % 200) as u8,
+            world: 1,
+        }))?;
+
+        let expected: u64 = (0..5000u64).map(|i| i % 200).sum();
+        assert_eq!(mv.sum_by(|e| u64::from(e.hello)), expected);
+        assert_eq!(mv.minmax_by(|e| e.hello), Some((0, 199)));
+        assert_eq!(mv.count_if(|e| e.hello < 10), 250);
+        assert_eq!(
+            mv.fold_chunks(0, |n, chunk| {
+                assert!(mem::size_of_val(chunk).is_multiple_of(64) || n + chunk.len() == 5000);
+                n + chunk.len()
+            }),
+            5000
+        );
+
+        Ok(())
+    }
+}
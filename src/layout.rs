@@ -0,0 +1,104 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Compile-time checks of element types, with the
+//! [`persist_assert_layout!`](crate::persist_assert_layout!) macro, for the mistakes that
+//! would otherwise only show as corrupted files: types whose layout the compiler is free to
+//! change between builds, and types that cannot be stored at all.
+
+use crate::CachePadded;
+
+/// Types whose layout is fixed, so that the bytes that one build of a program writes to a
+/// file mean the same to another build: primitive numbers, arrays of such types, and types
+/// declared `#[repr(C)]` or `#[repr(transparent)]` with fields of such types.
+///
+/// # Safety
+///
+/// Implement it only for types with one of those representations whose fields all
+/// implement it too. The layout of `#[repr(Rust)]` types, the default, may change with any
+/// build.
+pub unsafe trait ReprC {}
+
+macro_rules! repr_c {
+    ($($T:ty),*) => {
+        $(unsafe impl ReprC for $T {})*
+    };
+}
+
+repr_c!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: ReprC, const N: usize> ReprC for [T; N] {}
+unsafe impl<T: ReprC> ReprC for CachePadded<T> {}
+
+/// Assert at compile time that a type can be stored in a [`MmapedVec`](crate::MmapedVec):
+/// that it is not zero-sized, that it is not aligned to more than a page, and that it has a
+/// fixed layout, by implementing [`ReprC`](ReprC). Optionally, also that it is of the given
+/// `size` and `align`ment, so that a change to the type that would change the layout of the
+/// files fails to build instead.
+///
+/// ```
+/// # use persistence::{persist_assert_layout, ReprC};
+/// #[derive(Clone, Copy, Default)]
+/// #[repr(C)]
+/// struct Trade {
+///     price: f64,
+///     volume: u32,
+/// }
+///
+/// unsafe impl ReprC for Trade {}
+///
+/// persist_assert_layout!(Trade);
+/// persist_assert_layout!(Trade, size = 16, align = 8);
+/// ```
+///
+/// Types without a fixed layout do not build:
+///
+/// ```compile_fail
+/// # use persistence::persist_assert_layout;
+/// struct Trade {
+///     price: f64,
+///     volume: u32,
+/// }
+///
+/// persist_assert_layout!(Trade);
+/// ```
+#[macro_export]
+macro_rules! persist_assert_layout {
+    ($T:ty $(, size = $size:expr)? $(, align = $align:expr)? $(,)?) => {
+        const _: () = {
+            const fn assert_repr_c<T: $crate::ReprC>() {}
+            assert_repr_c::<$T>();
+
+            assert!(
+                ::core::mem::size_of::<$T>() != 0,
+                "Zero-sized element types are not supported."
+            );
+            // NOTE: The smallest page size of the platforms we support.
+            assert!(
+                ::core::mem::align_of::<$T>() <= 4096,
+                "Element types aligned to more than a page are not supported."
+            );
+            $(assert!(
+                ::core::mem::size_of::<$T>() == $size,
+                "The size of the element type has changed."
+            );)?
+            $(assert!(
+                ::core::mem::align_of::<$T>() == $align,
+                "The alignment of the element type has changed."
+            );)?
+        };
+    };
+}
@@ -0,0 +1,134 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::{locking, MmapedVec};
+use std::io;
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+
+/// Read-only access to a [`MmapedVec`](MmapedVec) whose writes are paused, as returned by
+/// [`pause_writes_for`](MmapedVec::pause_writes_for).
+///
+/// While the lease is held, the file is flushed, marked as closed cleanly, and only
+/// shared-locked, so that other processes can take a shared lock to copy it consistently.
+/// Dropping the lease waits for the exclusive lock to be available again, so a backup
+/// agent that holds its shared lock past the lease keeps writes paused until it is done.
+pub struct WriteLease<'a, T> {
+    mv: &'a mut MmapedVec<T>,
+    deadline: Instant,
+    released: bool,
+}
+
+impl<T> MmapedVec<T> {
+    /// Flush, mark the file as closed cleanly and downgrade to a shared lock, for external
+    /// tools to copy the file while this process keeps reading it through the lease.
+    ///
+    /// The `duration` is what the lease promises the tools, and is not enforced; once it
+    /// has passed, [`is_expired`](WriteLease::is_expired) tells the holder to drop the lease.
+    pub fn pause_writes_for(&mut self, duration: Duration) -> io::Result<WriteLease<'_, T>> {
+        self.check_poisoned()?;
+        self.check_not_forked()?;
+
+        self.flush()?;
+        self.set_dirty(false)?;
+        locking::try_lock_shared(self.locked_file(), &self.path)?;
+
+        Ok(WriteLease {
+            mv: self,
+            deadline: Instant::now() + duration,
+            released: false,
+        })
+    }
+}
+
+impl<T> WriteLease<'_, T> {
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    /// Time left of the lease, which is zero once it has expired.
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    /// Like dropping the lease, but returning any error retaking the exclusive lock.
+    pub fn release(mut self) -> io::Result<()> {
+        self.reacquire()
+    }
+
+    fn reacquire(&mut self) -> io::Result<()> {
+        self.released = true;
+        locking::lock_exclusive(self.mv.locked_file(), &self.mv.path)?;
+        self.mv.set_dirty(true)
+    }
+}
+
+impl<T> Deref for WriteLease<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.mv
+    }
+}
+
+impl<T> Drop for WriteLease<'_, T> {
+    fn drop(&mut self) {
+        if !self.released {
+            // XXX: Only fails if the file or the platform stopped supporting locks; the file
+            //      is then left shared-locked and marked clean, as during the lease.
+            let _ = self.reacquire();
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::format;
+    use crate::tests::{
+        python3_try_lock_exclusive, tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION,
+        EXAMPLE_MAGIC_BYTES,
+    };
+    use std::fs::{self, File};
+
+    #[test]
+    pub fn test_pause_writes_for_backup() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut mv =
+            MmapedVec::<u32>::try_new(&path, EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)?;
+        mv.extend([1, 2, 3])?;
+        assert!(mv.header().flags & format::FLAG_DIRTY != 0);
+
+        let lease = mv.pause_writes_for(std::time::Duration::from_secs(60))?;
+        assert_eq!(&lease[..], &[1, 2, 3]);
+        assert!(!lease.is_expired());
+
+        let backup = File::open(&path)?;
+        fs2::FileExt::try_lock_shared(&backup)?;
+        assert_eq!(fs::read(&path)?.len() as u64, backup.metadata()?.len());
+        assert!(File::open(&path)
+            .and_then(|f| fs2::FileExt::try_lock_exclusive(&f))
+            .is_err());
+        drop(backup);
+
+        lease.release()?;
+        assert!(mv.header().flags & format::FLAG_DIRTY != 0);
+        assert_eq!(python3_try_lock_exclusive(&path)?.code(), Some(35));
+        mv.push(4)?;
+
+        Ok(())
+    }
+}
@@ -48,10 +48,42 @@
 //! This library makes use of BSD `flock()` advisory locks on Unix platforms (Linux, macOS,
 //! FreeBSD, etc).
 //!
+//! On Solaris and illumos, where `flock()` is simulated with `fcntl()` locks that belong to
+//! the process, the first open checks what the locks actually exclude. If they exclude other
+//! processes, `fcntl()` locks are used directly, and it is up to you not to open the same
+//! file twice within one process. If they do not, opening fails with
+//! [`UnsupportedPlatform`](UnsupportedPlatform).
+//!
 //! Provided that your software runs in an environment where any process that attempts to open
 //! the files you are persisting your data to honor the advisory locks, everything will be
 //! fine and dandy :)
 //!
+//! The one exception that this library makes is
+//! [`read_unlocked`](MmapedVecBuilder::read_unlocked), which is unsafe, and meant for tools
+//! that inspect files without disturbing whoever is writing to them.
+//!
+//! ## Fork safety
+//!
+//! A process that forks after opening a file hands the child its open file description,
+//! and with it the lock and a shared mapping of the file. Parent and child would then both
+//! be able to write to the file, each believing that it holds the lock alone. A child that
+//! wants to use a handle it inherited must first call
+//! [`reinit_after_fork`](MmapedVec::reinit_after_fork), which only succeeds once the parent
+//! no longer has the file open. Until then, growing, truncating and flushing an inherited
+//! handle fail, and dropping it neither releases the parent's lock nor marks the file as
+//! cleanly closed. The `fork-detection` feature also catches children whose process ID is
+//! that of the parent, as in a new PID namespace.
+//!
+//! ## Testing under Miri
+//!
+//! Miri cannot map files, so opening a [`MmapedVec`](MmapedVec) under `cfg(miri)` fails
+//! with [`Unsupported`](std::io::ErrorKind::Unsupported) up front, rather than stopping the
+//! interpreter at the first mapping. Tests that need to run under Miri can keep their
+//! elements in a [`BufferedVec`](BufferedVec) on an in-memory backend instead, which lays
+//! down the same format without mapping anything. Headers are read field by field, and
+//! default data is copied out through an aligned temporary, so that neither is referenced
+//! at an unaligned address.
+//!
 //! ## Motivation
 //!
 //! Data persistence is achievable by many different means. No one solution fits all
@@ -118,31 +150,214 @@
 //! if you find this library interesting or useful.
 //!
 
+use format::{FileHeader, ENDIANNESS_MARKER, FILE_HEADER_LEN, PERSISTENCE_FORMAT_VERSION};
 use fs2::FileExt;
 use memmap::MmapMut;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
 use std::marker::PhantomData;
 use std::mem;
-use std::path::Path;
-use std::{io, slice};
-
-/// Bumped to match crate version when changes are made to format itself.
-const PERSISTENCE_FORMAT_VERSION: [u8; 3] = [0, 0, 5];
-
-#[repr(C, packed)]
-struct FileHeader<T> {
-    magic_bytes: [u8; 8],
-    endianness: u16,
-    persistence_format_version: [u8; 3],
-    data_contained_version: [u8; 3],
-    default_data: T,
-    number_of_padding_bytes_after_header: u16,
+use std::ops::{Deref, DerefMut, Range};
+use std::os::unix::fs::{FileExt as UnixFileExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, IntoRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::{io, ptr, slice};
+
+/// Give the failpoint a chance to trigger, when built with the `failpoints` feature.
+macro_rules! fail_point {
+    ($point:ident) => {{
+        #[cfg(feature = "failpoints")]
+        let res = crate::failpoints::hit(crate::failpoints::Failpoint::$point);
+        #[cfg(not(feature = "failpoints"))]
+        let res: io::Result<()> = Ok(());
+        res
+    }};
 }
 
+mod anonymous;
+mod atomic;
+mod backend;
+mod batch;
+mod bloom;
+mod buffered;
+mod checksum;
+mod chunks;
+mod debug;
+mod direct;
+mod epoch;
+mod error;
+mod expiry;
+mod extensions;
+#[cfg(feature = "failpoints")]
+pub mod failpoints;
+mod feed;
+mod fork;
+#[cfg(feature = "unstable-format")]
+pub mod format;
+#[cfg(not(feature = "unstable-format"))]
+#[allow(dead_code)]
+mod format;
+mod free;
+#[cfg(target_os = "linux")]
+mod futex;
+mod group;
+mod grouping;
+mod guard;
+mod handle;
+mod handoff;
+mod header;
+mod hints;
+mod host;
+pub mod janitor;
+mod kernels;
+mod layout;
+mod lease;
+mod locking;
+mod maintenance;
+mod manifest;
+#[cfg(target_os = "linux")]
+mod memfd;
+mod merkle;
+mod msync;
+mod padded;
+mod pin;
+mod project;
+#[cfg(kani)]
+mod proofs;
+mod queue;
+mod raw;
+mod read_only;
+mod recovery;
+mod registry;
+mod repair;
+mod replay;
+mod replication;
+mod residency;
+mod roll;
+mod sendfile;
+mod seqlock;
+mod soa;
+mod sort;
+mod sparse;
+mod stats;
+mod store;
+mod sync;
+mod temporary;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod tombstone;
+mod tracking;
+mod unlocked;
+mod versioning;
+mod wal;
+#[cfg(all(target_os = "linux", feature = "watch"))]
+mod watch;
+mod windowed;
+mod zones;
+
+pub use anonymous::AnonymousVec;
+pub use atomic::{AtomicElement, SharedAtomics};
+pub use backend::StorageBackend;
+pub use batch::Batch;
+pub use bloom::{bloom_path, BLOOM_SUFFIX};
+pub use buffered::BufferedVec;
+pub use checksum::ChecksumAlgorithm;
+pub use error::{
+    CapacityExceeded, Incompatible, InsufficientSpace, Poisoned, ReadOnlyFileSystem,
+    UnsupportedPlatform,
+};
+pub use expiry::{ExpiryReaper, HasExpiry};
+pub use feed::{ChangeEvent, ChangeKind};
+pub use free::{free_path, FREE_SUFFIX};
+pub use group::CommitGroup;
+pub use grouping::GroupRangesByKey;
+pub use guard::WriteGuard;
+pub use handle::{Cursor, ElemHandle};
+pub use header::{read_header, HeaderInfo};
+pub use host::{HostPin, HostRegistration};
+pub use layout::ReprC;
+pub use lease::WriteLease;
+pub use locking::{lock_path, LOCK_SUFFIX};
+pub use maintenance::{
+    FragmentationReport, MaintenancePolicy, MaintenanceRun, MaintenanceScheduler,
+};
+pub use manifest::{type_fingerprint, ManifestEntry, MANIFEST_FILE_NAME};
+pub use merkle::{merkle_path, MERKLE_SUFFIX};
+pub use msync::{FlushMode, FlushOrder};
+pub use padded::{CachePadded, CACHE_LINE_LEN};
+pub use pin::{PinnedBytes, PinnedSlice};
+pub use project::{StridedIter, StridedView};
+pub use queue::{consumers_path, MmapedQueue, CONSUMERS_SUFFIX, MAX_CONSUMER_NAME_LEN};
+pub use read_only::{Opened, ReadOnlyVec};
+pub use recovery::RecoveryReport;
+pub use registry::{SameFilePolicy, SharedMmapedVec};
+pub use replay::{replay_path, replay_steps, ReplayOp, ReplayStep, REPLAY_SUFFIX};
+pub use replication::ReplicationSink;
+pub use residency::ResidencyReport;
+pub use roll::RollPolicy;
+pub use seqlock::OptimisticReader;
+pub use soa::SoaConverter;
+pub use stats::{stats_path, StatsSketch, STATS_SUFFIX};
+pub use store::{Store, STORE_LOCK_FILE_NAME};
+pub use tombstone::{tombstones_path, TOMBSTONES_SUFFIX};
+pub use unlocked::UnlockedReader;
+pub use versioning::VersionDecision;
+pub use wal::{wal_path, WalSnapshot, WAL_SUFFIX};
+#[cfg(all(target_os = "linux", feature = "watch"))]
+pub use watch::Watch;
+pub use windowed::WindowedReader;
+pub use zones::{zones_path, ZONES_SUFFIX};
+
+// NOTE: Compressing cold element ranges into a sidecar and punching holes for them in the
+//       file is deliberately not supported. Elements are accessed directly in the mapping,
+//       so decompressing them on access would take an access path that copies out of a
+//       separate buffer, and the body would no longer be a single contiguous slice. Cold
+//       elements can instead be moved aside with `roll`, and the archives compressed.
 pub struct MmapedVec<T> {
-    file: File,
+    path: PathBuf,
+    // NOTE: Fields are dropped in order of declaration. The mapping must go before the file,
+    //       so that we do not unlock the file while we still have it mapped.
     mm: MmapMut,
+    file: File,
+    /// The lock file that the lock is held on, for files whose header says so.
+    lock_file: Option<File>,
+    header_len: usize,
+    max_len_bytes: Option<u64>,
+    max_elements: Option<usize>,
+    check_free_space: bool,
+    preallocate: bool,
+    protected_access: bool,
+    harden: bool,
+    flush_mode: FlushMode,
+    flush_order: FlushOrder,
+    synced_len_bytes: u64,
+    poisoned: bool,
+    replication_sink: Option<Box<dyn ReplicationSink + Send>>,
+    mapping_generation: u64,
+    pins: Arc<AtomicUsize>,
+    registration: registry::Registration,
+    owner_pid: u32,
+    fork_generation: u64,
+    recovery: Option<RecoveryReport>,
+    roll_policy: Option<RollPolicy>,
+    file_started: SystemTime,
+    tracker: Option<tracking::ModificationTracker>,
+    change_feed: Option<feed::ChangeFeed>,
+    wal: Option<wal::Wal>,
+    checksum: Option<ChecksumAlgorithm>,
+    merkle: Option<merkle::MerkleTree>,
+    direct: Option<direct::DirectWriter>,
+    bloom: Option<bloom::BloomFilter<T>>,
+    stats: Option<stats::Stats<T>>,
+    zones: Option<zones::ZoneMap<T>>,
+    free: Option<free::FreeList>,
+    tombstones: Option<tombstone::Tombstones>,
+    replay: Option<replay::ReplayRecorder>,
+    migration: Option<([u8; 3], [u8; 3])>,
+    retired: Vec<epoch::Retired>,
     _marker: PhantomData<T>,
 }
 
@@ -152,141 +367,1395 @@ impl<T: Sized + Default> MmapedVec<T> {
         magic_bytes: [u8; 8],
         data_contained_version: [u8; 3],
     ) -> io::Result<Self> {
-        // TODO: If the fs2 try_lock_exclusive simulated flock() on Solaris does not behave as it should,
-        //       then a preflight check might be needed, or we might blacklist target_os = "solaris".
-        //       It remains to be determined whether or not that is the case.
-        //       If it does misbehave, and we decide to blacklist, then we must be vigilant about
-        //       future changes in fs2, such as if the simulated flock() is enabled for more target OSes.
+        MmapedVecBuilder::new(magic_bytes, data_contained_version).try_open(path)
+    }
+}
 
-        let mut file = OpenOptions::new()
-            .read(true)
+impl<T> MmapedVec<T> {
+    /// Like [`try_new`](MmapedVec::try_new), but for element types that do not implement
+    /// `Default`. See [`try_open_with_default_data`](MmapedVecBuilder::try_open_with_default_data).
+    pub fn try_new_with_default_data(
+        path: &Path,
+        magic_bytes: [u8; 8],
+        data_contained_version: [u8; 3],
+        default_data: T,
+    ) -> io::Result<Self> {
+        MmapedVecBuilder::new(magic_bytes, data_contained_version)
+            .try_open_with_default_data(path, default_data)
+    }
+
+    /// The default data stored in the header, if the file was created with any.
+    pub fn default_data(&self) -> Option<&T> {
+        let fh = self.header();
+
+        let offset = fh.default_data_offset as usize;
+        let in_bounds = offset
+            .checked_add(mem::size_of::<T>())
+            .is_some_and(|end| end <= self.header_len);
+        if !fh.has_default_data() || !in_bounds {
+            return None;
+        }
+
+        // NOTE: The header of a file handed to try_from_parts() is not checked against `T`,
+        //       so the default data is not referenced in place unless it is aligned.
+        let ptr = unsafe { self.mm.as_ptr().add(offset) } as *const T;
+        match ptr.is_aligned() {
+            true => Some(unsafe { &*ptr }),
+            false => None,
+        }
+    }
+
+    pub(crate) fn header(&self) -> FileHeader {
+        let mut fh_buf = [0u8; FILE_HEADER_LEN];
+        fh_buf.copy_from_slice(&self.mm[..FILE_HEADER_LEN]);
+        FileHeader::from_bytes(&fh_buf)
+    }
+
+    /// Number of elements in the body of the file.
+    pub fn len(&self) -> usize {
+        (self.mm.len() - self.header_len) / mem::size_of::<T>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Check that the file has not been truncated to below the size of the mapping.
+    ///
+    /// Another process that does not honor the advisory lock could truncate the file, after
+    /// which touching the part of the mapping beyond the end of the file raises `SIGBUS`.
+    /// This turns that situation into an error, but note that it cannot guard against the
+    /// file being truncated *after* the check has been made.
+    pub fn revalidate(&self) -> io::Result<()> {
+        let flen = self.file.metadata()?.len();
+
+        if flen < self.mm.len() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "File `{:?}` has been truncated to {} bytes, but {} bytes of it are mapped. \
+          Another process has likely modified the file without honoring the advisory lock.",
+                    self.path,
+                    flen,
+                    self.mm.len()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Like dereferencing to a slice, but [`revalidate`](MmapedVec::revalidate) first.
+    pub fn try_as_slice(&self) -> io::Result<&[T]> {
+        self.check_poisoned()?;
+        self.revalidate()?;
+        Ok(self)
+    }
+
+    /// Like dereferencing to a mutable slice, but [`revalidate`](MmapedVec::revalidate) first.
+    pub fn try_as_mut_slice(&mut self) -> io::Result<&mut [T]> {
+        self.check_poisoned()?;
+        self.revalidate()?;
+        Ok(self)
+    }
+
+    /// Append an element, growing the file by the size of one element.
+    pub fn push(&mut self, value: T) -> io::Result<()> {
+        self.check_poisoned()?;
+        self.roll_if_due(1)?;
+        let len = self.len();
+        self.grow(1)?;
+        fail_point!(BetweenLengthCommitAndDataWrite)?;
+        self.set_writable(true)?;
+        unsafe { ptr::write(self.body_mut_ptr().add(len), value) };
+        self.set_writable(false)?;
+        self.replicate_range(len..len + 1)
+    }
+
+    /// Append all elements of `iter`, growing the file once to fit all of them.
+    pub fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) -> io::Result<()> {
+        self.check_poisoned()?;
+        let values: Vec<T> = iter.into_iter().collect();
+        self.roll_if_due(values.len())?;
+        let len = self.len();
+        self.grow(values.len())?;
+        fail_point!(BetweenLengthCommitAndDataWrite)?;
+        self.set_writable(true)?;
+        for (i, value) in values.into_iter().enumerate() {
+            unsafe { ptr::write(self.body_mut_ptr().add(len + i), value) };
+        }
+        self.set_writable(false)?;
+        self.replicate_range(len..self.len())
+    }
+
+    /// Resize to `new_len` elements, filling new elements with copies of the
+    /// [`default_data`](MmapedVec::default_data) stored in the header.
+    ///
+    /// Fails with [`InvalidInput`](io::ErrorKind::InvalidInput) when growing a file that
+    /// has no default data; use [`resize_with`](MmapedVec::resize_with) for those.
+    pub fn resize(&mut self, new_len: usize) -> io::Result<()> {
+        self.check_poisoned()?;
+
+        let len = self.len();
+
+        if new_len <= len {
+            return self.truncate(new_len);
+        }
+
+        let fh = self.header();
+
+        if !fh.has_default_data() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "File `{:?}`: Has no default data to fill new elements with.",
+                    self.path
+                ),
+            ));
+        }
+
+        self.grow(new_len - len)?;
+        fail_point!(BetweenLengthCommitAndDataWrite)?;
+        // NOTE: Copied out through an aligned temporary, which is never dropped, as the
+        //       elements are bitwise copies of the one in the header.
+        let default_data = mem::ManuallyDrop::new(unsafe {
+            format::read_value::<T>(&self.mm[fh.default_data_offset as usize..])
+        });
+        self.set_writable(true)?;
+        for i in len..new_len {
+            unsafe { ptr::copy_nonoverlapping(&*default_data, self.body_mut_ptr().add(i), 1) };
+        }
+        self.set_writable(false)?;
+        self.replicate_range(len..new_len)
+    }
+
+    /// Resize to `new_len` elements, filling new elements with values returned by `f`.
+    pub fn resize_with<F: FnMut() -> T>(&mut self, new_len: usize, mut f: F) -> io::Result<()> {
+        self.check_poisoned()?;
+
+        let len = self.len();
+
+        if new_len <= len {
+            return self.truncate(new_len);
+        }
+
+        self.extend((len..new_len).map(|_| f()))
+    }
+
+    /// Truncate away the elements at the end that are equal to the
+    /// [`default_data`](MmapedVec::default_data), returning how many there were.
+    ///
+    /// Elements are compared byte for byte, padding included. The length of the file is
+    /// the number of elements, so the file always shrinks along with them.
+    ///
+    /// Fails with [`InvalidInput`](io::ErrorKind::InvalidInput) for files that have no
+    /// default data.
+    pub fn trim_trailing_defaults(&mut self) -> io::Result<usize> {
+        self.check_poisoned()?;
+
+        let fh = self.header();
+
+        if !fh.has_default_data() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "File `{:?}`: Has no default data to compare elements with.",
+                    self.path
+                ),
+            ));
+        }
+
+        let size = mem::size_of::<T>();
+        let default_data = &self.mm[fh.default_data_offset as usize..][..size];
+        let body = &self.mm[self.header_len..];
+
+        let len = self.len();
+        let live = body
+            .chunks_exact(size)
+            .rposition(|elem| elem != default_data)
+            .map_or(0, |last| last + 1);
+
+        self.truncate(live)?;
+        Ok(len - live)
+    }
+
+    /// Shorten to `len` elements, shrinking the file. Has no effect if there are already
+    /// `len` elements or fewer.
+    ///
+    /// Shrinking is not passed on to the [`ReplicationSink`](ReplicationSink).
+    pub fn truncate(&mut self, len: usize) -> io::Result<()> {
+        self.check_poisoned()?;
+        self.check_not_forked()?;
+
+        if len >= self.len() {
+            return Ok(());
+        }
+
+        self.check_not_pinned()?;
+
+        // NOTE: Keeps optimistic readers away from the elements that are about to go.
+        self.set_writable(true)?;
+        self.file
+            .set_len((self.header_len + len * mem::size_of::<T>()) as u64)?;
+        self.mm = unsafe { MmapMut::map_mut(&self.file)? };
+        self.mapping_generation += 1;
+        if let Some(tracker) = self.tracker.as_mut() {
+            tracker.truncate(len);
+        }
+        if let Some(feed) = self.change_feed.as_mut() {
+            feed.removed(len);
+        }
+        if let Some(recorder) = self.replay.as_mut() {
+            recorder.truncated(len);
+        }
+
+        self.set_writable(false)
+    }
+
+    /// Copy the elements in `src` to `dest` and on, directly in the mapping. The ranges may
+    /// overlap. Like [`slice::copy_within`](slice::copy_within), but also works when hardened.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either range is out of bounds.
+    pub fn copy_within(&mut self, src: Range<usize>, dest: usize) -> io::Result<()>
+    where
+        T: Copy,
+    {
+        self.check_poisoned()?;
+
+        let len = self.len();
+        assert!(
+            src.start <= src.end && src.end <= len && dest <= len - (src.end - src.start),
+            "Range {}..{} copied to {} out of bounds for MmapedVec of length {}.",
+            src.start,
+            src.end,
+            dest,
+            len
+        );
+
+        let n = src.end - src.start;
+        self.set_writable(true)?;
+        unsafe {
+            let body = self.body_mut_ptr();
+            ptr::copy(body.add(src.start), body.add(dest), n);
+        }
+        self.set_writable(false)?;
+        self.replicate_range(dest..dest + n)
+    }
+
+    /// Move the elements in `src` so that they start at `dest`, shifting the elements in
+    /// between to fill the gap, with every element keeping its relative order. Elements are
+    /// swapped in place in the mapping, without copying them to the heap.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `src` is out of bounds, or if `dest` would put it out of bounds.
+    pub fn move_range(&mut self, src: Range<usize>, dest: usize) -> io::Result<()> {
+        self.check_poisoned()?;
+
+        let len = self.len();
+        assert!(
+            src.start <= src.end && src.end <= len && dest <= len - (src.end - src.start),
+            "Range {}..{} moved to {} out of bounds for MmapedVec of length {}.",
+            src.start,
+            src.end,
+            dest,
+            len
+        );
+
+        let n = src.end - src.start;
+        let affected = src.start.min(dest)..src.end.max(dest + n);
+
+        self.set_writable(true)?;
+        let body = unsafe { slice::from_raw_parts_mut(self.body_mut_ptr(), len) };
+        match dest < src.start {
+            true => body[affected.clone()].rotate_right(n),
+            false => body[affected.clone()].rotate_left(n),
+        }
+        self.set_writable(false)?;
+        self.replicate_range(affected)
+    }
+
+    /// Write the header and elements to a new file at `path`, in the same format as that of
+    /// files opened with [`try_open`](MmapedVecBuilder::try_open).
+    ///
+    /// The contents are first written to a temporary file next to `path`, which is then
+    /// renamed into place, so that `path` never holds a partially written file.
+    pub fn persist_to(&self, path: &Path) -> io::Result<()> {
+        self.check_poisoned()?;
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut tmp_file = OpenOptions::new()
             .write(true)
-            .create(true)
-            .open(path)?;
+            .create_new(true)
+            .open(&tmp_path)?;
+
+        // NOTE: Locked while it is written, so that the janitor leaves it alone.
+        let result = locking::try_lock_exclusive(&tmp_file, &tmp_path)
+            .and_then(|_| tmp_file.write_all(&self.mm))
+            .and_then(|_| tmp_file.sync_all())
+            .and_then(|_| fs::rename(&tmp_path, path));
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+
+        result
+    }
+
+    /// Flush, unmap, unlock and close the file, in that order, reporting any error.
+    ///
+    /// Dropping the [`MmapedVec`](MmapedVec) does the same on a best-effort basis, but has
+    /// no way of reporting errors.
+    ///
+    /// Fails without closing anything while the mapping is [pinned](MmapedVec::pin).
+    pub fn close(mut self) -> io::Result<()> {
+        self.check_not_pinned()?;
+
+        let flushed = self
+            .flush()
+            .and_then(|()| self.store_checksum())
+            .and_then(|()| self.set_dirty(false));
+
+        let lock_file = self.lock_file.take();
+        let (file, mm, layout) = self.into_parts();
+
+        drop(mm);
 
-        // TODO: Require that file has permissions 0600. See comments on https://stackoverflow.com/a/34935188
+        let unlocked = locking::unlock(&file, &layout.path);
+        drop(lock_file);
 
-        /*
-         * NOTE: The fs2 library is cross-platform beyond just the platforms that we support.
-         *       We use this library not because we want to try and support all of those,
-         *       but because it covers what we want to do and saves us some typing and thinking.
-         *       See the section about advisory locking the doc comments of this file.
-         */
-        file.try_lock_exclusive()?;
+        let closed = match unsafe { libc::close(file.into_raw_fd()) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        };
 
-        let fhs = mem::size_of::<FileHeader<T>>();
+        flushed.and(unlocked).and(closed)
+    }
 
-        let number_of_padding_bytes_after_header = match fhs % 4096 {
-            0 => 0,
-            _ => (4096 - fhs % 4096) as u16,
+    /// Take apart the [`MmapedVec`](MmapedVec), so that the file and the mapping can be
+    /// used directly, for example to `fstat()` or `posix_fadvise()` the file.
+    ///
+    /// The file stays locked for as long as it is open, except for files with a lock file,
+    /// whose lock is released. Put the parts back together with
+    /// [`try_from_parts`](MmapedVecBuilder::try_from_parts). Any replication sink is dropped,
+    /// and nothing is flushed.
+    pub fn into_parts(self) -> (File, MmapMut, FileLayout) {
+        let mut this = mem::ManuallyDrop::new(self);
+
+        // XXX: Failing to make a mapping that we own writable again should not happen.
+        let _ = this.set_writable(true);
+        // NOTE: Making the mapping writable begins a seqlock write. It must be ended here, as
+        //       there is no drop left to end it, and optimistic readers would otherwise keep
+        //       seeing an odd sequence number after the file was closed.
+        this.end_write();
+        this.leak_retired();
+
+        // NOTE: Destructured without `..`, so that a field added later cannot be forgotten
+        //       here. Any field that owns a resource must be read out, or it will leak.
+        let MmapedVec {
+            path,
+            mm,
+            file,
+            lock_file,
+            header_len,
+            max_len_bytes: _,
+            max_elements: _,
+            check_free_space: _,
+            preallocate: _,
+            protected_access: _,
+            harden: _,
+            flush_mode: _,
+            flush_order: _,
+            synced_len_bytes: _,
+            poisoned: _,
+            replication_sink,
+            mapping_generation: _,
+            pins,
+            registration,
+            owner_pid: _,
+            fork_generation: _,
+            recovery,
+            roll_policy,
+            file_started: _,
+            tracker,
+            change_feed,
+            wal,
+            checksum: _,
+            merkle,
+            direct,
+            bloom,
+            stats,
+            zones,
+            free,
+            tombstones,
+            replay,
+            migration: _,
+            retired,
+            _marker,
+        } = &*this;
+
+        let (path, mm, file) = unsafe { (ptr::read(path), ptr::read(mm), ptr::read(file)) };
+        let layout = FileLayout {
+            path,
+            header_len: *header_len,
         };
 
-        let fh = FileHeader {
+        // NOTE: Dropping the senders of the change feed is what tells subscribers that no
+        //       more changes will come.
+        unsafe {
+            drop((
+                ptr::read(lock_file),
+                ptr::read(replication_sink),
+                ptr::read(direct),
+                ptr::read(wal),
+                ptr::read(change_feed),
+                ptr::read(tracker),
+                ptr::read(merkle),
+                ptr::read(bloom),
+                ptr::read(stats),
+                ptr::read(zones),
+                ptr::read(free),
+                ptr::read(tombstones),
+                ptr::read(replay),
+                ptr::read(recovery),
+                ptr::read(roll_policy),
+                ptr::read(retired),
+            ));
+            drop((ptr::read(pins), ptr::read(registration)));
+        }
+
+        (file, mm, layout)
+    }
+
+    /// Flush outstanding modifications of the mapping to disk, synchronously unless another
+    /// [`flush_mode`](MmapedVecBuilder::flush_mode) was chosen.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.flush_with(self.flush_mode)
+    }
+
+    /// Like [`flush`](MmapedVec::flush), but with the given `mode` for this call only.
+    pub fn flush_with(&mut self, mode: FlushMode) -> io::Result<()> {
+        self.check_poisoned()?;
+        self.check_not_forked()?;
+        if self.protected_access {
+            self.revalidate()?;
+        }
+        // NOTE: In case the mapping was made writable by dereferencing mutably.
+        self.set_writable(false)?;
+        if let Some(wal) = self.wal.as_mut() {
+            wal.commit(&self.mm[self.header_len..])?;
+        }
+        // NOTE: The filter goes first, so that no element is durable before its key is.
+        let len = self.len();
+        if let Some(filter) = self.bloom.as_mut() {
+            filter.commit(len)?;
+        }
+        if let Some(free) = self.free.as_mut() {
+            free.commit(len)?;
+        }
+        if let Some(tombstones) = self.tombstones.as_mut() {
+            tombstones.commit(len)?;
+        }
+        let body = unsafe { slice::from_raw_parts(self.as_ptr(), len) };
+        if let Some(stats) = self.stats.as_mut() {
+            stats.commit(body)?;
+        }
+        if let Some(zones) = self.zones.as_mut() {
+            zones.commit(body)?;
+        }
+        fail_point!(DuringMsync)?;
+        if mode != FlushMode::Async {
+            self.flush_direct()?;
+        }
+        // NOTE: Also after direct writes, for the elements that were modified without being
+        //       marked modified. The kernel has written back those that were written directly,
+        //       so only the rest are written here.
+        self.msync(mode)?;
+        if mode != FlushMode::Async {
+            if let Some(wal) = self.wal.as_mut() {
+                wal.checkpoint()?;
+            }
+        }
+        if let Some(tree) = self.merkle.as_mut() {
+            tree.commit(&self.mm[self.header_len..])?;
+        }
+        if let Some(recorder) = self.replay.as_mut() {
+            recorder.commit()?;
+        }
+        if let Some(sink) = self.replication_sink.as_mut() {
+            sink.flush()?;
+        }
+        if let Some(feed) = self.change_feed.as_mut() {
+            feed.commit();
+        }
+        #[cfg(all(target_os = "linux", feature = "watch"))]
+        watch::touch(&self.file)?;
+        Ok(())
+    }
+
+    pub(crate) fn body_mut_ptr(&mut self) -> *mut T {
+        unsafe { self.mm.as_mut_ptr().add(self.header_len) as *mut T }
+    }
+
+    pub(crate) fn grow(&mut self, additional: usize) -> io::Result<()> {
+        self.check_not_forked()?;
+
+        let elements = self.len().saturating_add(additional);
+        let len_bytes = (self.header_len as u64)
+            .saturating_add((elements as u64).saturating_mul(mem::size_of::<T>() as u64));
+
+        if self.max_elements.is_some_and(|max| elements > max)
+            || self.max_len_bytes.is_some_and(|max| len_bytes > max)
+        {
+            return Err(io::Error::other(CapacityExceeded {
+                requested_elements: elements,
+                requested_len_bytes: len_bytes,
+                max_elements: self.max_elements,
+                max_len_bytes: self.max_len_bytes,
+            }));
+        }
+
+        check_mappable(&self.path, len_bytes)?;
+        self.check_not_pinned()?;
+
+        let old_len_bytes = self.mm.len() as u64;
+
+        if self.protected_access {
+            self.revalidate()?;
+        }
+
+        if self.check_free_space {
+            let available_bytes = available_space(&self.file)?;
+            let required_bytes = len_bytes - old_len_bytes;
+            if available_bytes < required_bytes {
+                return Err(io::Error::other(InsufficientSpace {
+                    path: self.path.clone(),
+                    required_bytes,
+                    available_bytes,
+                }));
+            }
+        }
+
+        resize_mapped(&self.file, &mut self.mm, len_bytes, self.preallocate)?;
+        self.mapping_generation += 1;
+
+        self.set_writable(false)
+    }
+
+    /// When hardened, switch the protection of the mapping between read-only and writable.
+    ///
+    /// Writes are made between making the mapping writable and read-only again, so this
+    /// is also where they are published to [`OptimisticReader`](OptimisticReader)s.
+    pub(crate) fn set_writable(&self, writable: bool) -> io::Result<()> {
+        if !self.harden {
+            match writable {
+                true => self.begin_write(),
+                false => self.end_write(),
+            }
+            return Ok(());
+        }
+
+        if !writable {
+            self.end_write();
+        }
+
+        self.protect(writable)?;
+
+        if writable {
+            self.begin_write();
+        }
+
+        Ok(())
+    }
+
+    /// Switch the protection of the mapping between read-only and writable.
+    fn protect(&self, writable: bool) -> io::Result<()> {
+        let prot = match writable {
+            true => libc::PROT_READ | libc::PROT_WRITE,
+            false => libc::PROT_READ,
+        };
+
+        if unsafe { libc::mprotect(self.mm.as_ptr() as *mut libc::c_void, self.mm.len(), prot) }
+            != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+/// Zero-sized types have no data to persist, and make element arithmetic meaningless.
+/// Types aligned to more than a page cannot be guaranteed to be aligned in the mapping.
+fn check_element_type<T>(path: &Path) -> io::Result<()> {
+    if mem::size_of::<T>() == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "File `{:?}`: Zero-sized element types are not supported.",
+                path
+            ),
+        ));
+    }
+
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+
+    if mem::align_of::<T>() > page_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "File `{:?}`: Element types aligned to more than the page size ({} bytes) \
+          are not supported.",
+                path, page_size
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+// TODO: Falling back to WindowedReader automatically when the file cannot be mapped has
+//       been requested, so that the same code works on 32-bit targets. MmapedVec derefs to a
+//       writable slice of the whole body, which a window cannot provide, so the fallback
+//       cannot be transparent. For now we fail early and point at try_open_windowed().
+/// Fail if `len_bytes` is more than can be mapped into the address space of this target.
+fn check_mappable(path: &Path, len_bytes: u64) -> io::Result<()> {
+    // Slices, and thereby mappings that we hand out slices of, are limited to isize::MAX bytes.
+    if len_bytes > isize::MAX as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            format!(
+                "File `{:?}`: {} bytes is more than can be mapped on this {}-bit target. \
+      Read it with MmapedVecBuilder::try_open_windowed() instead.",
+                path,
+                len_bytes,
+                usize::BITS
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Open the file at `path`, creating it if need be, and take its lock, returning the lock
+/// file that the lock is held on for files whose header says so.
+/// Resize `file` to `len_bytes` and map it anew into `mm`, putting the old length back if
+/// mapping it fails, so that a failed resize leaves the file as it was.
+pub(crate) fn resize_mapped(
+    file: &File,
+    mm: &mut MmapMut,
+    len_bytes: u64,
+    preallocate: bool,
+) -> io::Result<()> {
+    let old_len_bytes = mm.len() as u64;
+
+    /*
+     * NOTE: Without preallocation, the file may be sparse, and writing through the mapping
+     *       into pages that the file system then fails to allocate blocks for (ENOSPC)
+     *       raises SIGBUS, killing the process. Preallocating surfaces ENOSPC here instead.
+     */
+    if preallocate {
+        file.allocate(len_bytes)?;
+    } else {
+        file.set_len(len_bytes)?;
+    }
+
+    match fail_point!(MidRemap).and_then(|_| unsafe { MmapMut::map_mut(file) }) {
+        Ok(new_mm) => {
+            *mm = new_mm;
+            Ok(())
+        }
+        Err(e) => {
+            file.set_len(old_len_bytes)?;
+            Err(e)
+        }
+    }
+}
+
+pub(crate) fn open_locked(path: &Path, follow_symlinks: bool) -> io::Result<(File, Option<File>)> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(false);
+    if !follow_symlinks {
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+
+    let file = options.open(path).map_err(|e| match e.raw_os_error() {
+        Some(libc::ELOOP) if !follow_symlinks => io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("File `{:?}`: Refusing to open through a symlink.", path),
+        ),
+        Some(libc::EROFS) => io::Error::new(
+            io::ErrorKind::ReadOnlyFilesystem,
+            ReadOnlyFileSystem {
+                path: path.to_path_buf(),
+            },
+        ),
+        _ => e,
+    })?;
+
+    // NOTE: Checked before locking, since taking the lock through another open file
+    //       description than the one that holds it would fail without saying why.
+    registry::check_not_open(&file, path)?;
+
+    // TODO: Require that file has permissions 0600. See comments on https://stackoverflow.com/a/34935188
+
+    /*
+     * NOTE: The fs2 library is cross-platform beyond just the platforms that we support.
+     *       We use this library not because we want to try and support all of those,
+     *       but because it covers what we want to do and saves us some typing and thinking.
+     *       See the section about advisory locking the doc comments of this file.
+     *       Where fs2 simulates flock(), the locking module checks that the simulation
+     *       excludes other processes before relying on it.
+     */
+    if let Some(lock_file) = locking::open_lock_file(&file, path)? {
+        locking::try_lock_exclusive(&lock_file, &lock_path(path))?;
+        return Ok((file, Some(lock_file)));
+    }
+    locking::try_lock_exclusive(&file, path)?;
+
+    // NOTE: Whoever created the file may have given it a lock file since we looked, and
+    //       unlocked the file itself once the header said so.
+    match locking::open_lock_file(&file, path)? {
+        Some(lock_file) => {
+            locking::try_lock_exclusive(&lock_file, &lock_path(path))?;
+            locking::unlock(&file, path)?;
+            Ok((file, Some(lock_file)))
+        }
+        None => Ok((file, None)),
+    }
+}
+
+fn available_space(file: &File) -> io::Result<u64> {
+    let mut stat = mem::MaybeUninit::<libc::statvfs>::uninit();
+
+    if unsafe { libc::fstatvfs(file.as_raw_fd(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let stat = unsafe { stat.assume_init() };
+
+    // NOTE: The widths of these fields vary between platforms.
+    #[allow(clippy::useless_conversion)]
+    Ok(u64::from(stat.f_bavail) * u64::from(stat.f_frsize))
+}
+
+impl<T> Drop for MmapedVec<T> {
+    fn drop(&mut self) {
+        // NOTE: A handle inherited across fork() must not mark the file of the parent as
+        //       cleanly closed while the parent is still writing to it.
+        if !self.is_inherited_across_fork()
+            && self.flush().and_then(|()| self.store_checksum()).is_ok()
+        {
+            let _ = self.set_dirty(false);
+        }
+
+        // Pinned pointers must stay valid, so leak the mapping rather than unmap it.
+        self.leak_retired();
+        if self.is_pinned() {
+            if let Ok(placeholder) = MmapMut::map_anon(1) {
+                mem::forget(mem::replace(&mut self.mm, placeholder));
+            }
+        }
+    }
+}
+
+impl<T> Deref for MmapedVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe {
+            slice::from_raw_parts(
+                self.mm.as_ptr().add(self.header_len) as *const T,
+                self.len(),
+            )
+        }
+    }
+}
+
+impl<T> DerefMut for MmapedVec<T> {
+    /// If the [`MmapedVec`](MmapedVec) was opened with [`harden`](MmapedVecBuilder::harden),
+    /// this makes the mapping writable until the library makes it read-only again, the next
+    /// time that it writes to the mapping or flushes. Use
+    /// [`write_guard`](MmapedVec::write_guard) to keep it writable for no longer than needed.
+    fn deref_mut(&mut self) -> &mut [T] {
+        if self.harden {
+            // XXX: Failing to make a mapping that we own writable should not happen.
+            let _ = self.protect(true);
+        }
+        let len = self.len();
+        unsafe { slice::from_raw_parts_mut(self.body_mut_ptr(), len) }
+    }
+}
+
+/// Where the file of a [`MmapedVec`](MmapedVec) taken apart with
+/// [`into_parts`](MmapedVec::into_parts) is, and where its body starts.
+#[derive(Clone, Debug)]
+pub struct FileLayout {
+    pub path: PathBuf,
+    /// Length of the header and the padding after it, in bytes.
+    pub header_len: usize,
+}
+
+/// Options for opening a [`MmapedVec`](MmapedVec), in the manner of
+/// [`std::fs::OpenOptions`](std::fs::OpenOptions).
+#[derive(Clone, Debug)]
+pub struct MmapedVecBuilder {
+    magic_bytes: [u8; 8],
+    data_contained_version: [u8; 3],
+    max_len_bytes: Option<u64>,
+    max_elements: Option<usize>,
+    check_free_space: Option<bool>,
+    preallocate: Option<bool>,
+    protected_access: bool,
+    harden: bool,
+    flush_mode: FlushMode,
+    flush_order: Option<FlushOrder>,
+    follow_symlinks: bool,
+    lock_file: bool,
+    same_file_policy: SameFilePolicy,
+    repair_after_crash: bool,
+    track_modifications: Option<usize>,
+    wal: bool,
+    wal_history: u64,
+    checksum: Option<ChecksumAlgorithm>,
+    merkle_tree: Option<usize>,
+    direct_io: bool,
+    version_policy: Option<versioning::VersionPolicy>,
+    header_hints: bool,
+}
+
+impl MmapedVecBuilder {
+    pub fn new(magic_bytes: [u8; 8], data_contained_version: [u8; 3]) -> Self {
+        Self {
             magic_bytes,
-            endianness: 0x1234,
-            persistence_format_version: PERSISTENCE_FORMAT_VERSION,
             data_contained_version,
-            default_data: T::default(),
-            number_of_padding_bytes_after_header,
-        };
+            max_len_bytes: None,
+            max_elements: None,
+            check_free_space: None,
+            preallocate: None,
+            protected_access: false,
+            harden: false,
+            flush_mode: FlushMode::Sync,
+            flush_order: None,
+            follow_symlinks: true,
+            lock_file: false,
+            same_file_policy: SameFilePolicy::Error,
+            repair_after_crash: false,
+            track_modifications: None,
+            wal: false,
+            wal_history: wal::DEFAULT_WAL_HISTORY,
+            checksum: None,
+            merkle_tree: None,
+            direct_io: false,
+            version_policy: None,
+            header_hints: false,
+        }
+    }
 
-        let flen = file.metadata().unwrap().len();
+    /// Limit the size of the file, header included, to `n` bytes.
+    pub fn max_len_bytes(&mut self, n: u64) -> &mut Self {
+        self.max_len_bytes = Some(n);
+        self
+    }
 
-        let len_fh_and_padding = fhs as u64 + number_of_padding_bytes_after_header as u64;
-
-        if flen == 0 {
-            let buf = unsafe {
-                slice::from_raw_parts(
-                    &fh as *const FileHeader<T> as *const u8,
-                    mem::size_of::<FileHeader<T>>(),
-                )
-            };
-            file.write(buf)?;
-            file.set_len(len_fh_and_padding)?;
-        } else if flen < fhs as u64 {
+    /// Limit the number of elements in the file to `n`.
+    pub fn max_elements(&mut self, n: usize) -> &mut Self {
+        self.max_elements = Some(n);
+        self
+    }
+
+    /// Check the free space of the file system that the file resides on before growing,
+    /// failing with [`InsufficientSpace`](InsufficientSpace) if it is too small.
+    pub fn check_free_space(&mut self, check_free_space: bool) -> &mut Self {
+        self.check_free_space = Some(check_free_space);
+        self
+    }
+
+    /// Allocate disk blocks for the file when growing, rather than only extending its size.
+    ///
+    /// This makes growing fail up front when the disk is full, instead of the process
+    /// being killed by `SIGBUS` when it later writes to the grown part of the mapping.
+    pub fn preallocate(&mut self, preallocate: bool) -> &mut Self {
+        self.preallocate = Some(preallocate);
+        self
+    }
+
+    /// [`revalidate`](MmapedVec::revalidate) before growing and flushing, so that a file
+    /// truncated behind our back results in an error rather than in a `SIGBUS`.
+    ///
+    /// Only growing and flushing are protected. Reads, and writes through dereferencing the
+    /// [`MmapedVec`](MmapedVec) or indexing into it, touch the mapping without any check, and
+    /// still raise `SIGBUS` if the file was truncated, so call
+    /// [`revalidate`](MmapedVec::revalidate) before them where that is a concern.
+    pub fn protected_access(&mut self, protected_access: bool) -> &mut Self {
+        self.protected_access = protected_access;
+        self
+    }
+
+    /// Keep the mapping read-only, except for while the library itself writes to it and
+    /// while a [`WriteGuard`](WriteGuard) is held, so that stray writes from unrelated unsafe
+    /// code in the process fault instead of silently corrupting the persisted data.
+    ///
+    /// Dereferencing the [`MmapedVec`](MmapedVec) mutably still works, but leaves the mapping
+    /// writable until the next write through the library or flush.
+    pub fn harden(&mut self, harden: bool) -> &mut Self {
+        self.harden = harden;
+        self
+    }
+
+    /// Refuse to open files through a symlink in the last component of the path, with
+    /// `O_NOFOLLOW`, for when the path is in a directory that others can write to.
+    pub fn follow_symlinks(&mut self, follow_symlinks: bool) -> &mut Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Take the lock of new files on a lock file next to them, at
+    /// [`lock_path`](lock_path), rather than on the files themselves, for when tools such as
+    /// backup agents open the files and would conflict with locks on them. This is recorded
+    /// in the header, so that every process that opens the file locks the same one.
+    ///
+    /// Existing files are locked as their header says, whatever this is set to.
+    pub fn lock_file(&mut self, lock_file: bool) -> &mut Self {
+        self.lock_file = lock_file;
+        self
+    }
+
+    /// What to do when opening a file that is already open in this process, possibly
+    /// through another path. See [`SameFilePolicy`](SameFilePolicy).
+    pub fn same_file_policy(&mut self, same_file_policy: SameFilePolicy) -> &mut Self {
+        self.same_file_policy = same_file_policy;
+        self
+    }
+
+    pub fn try_open<T: Sized + Default>(&self, path: &Path) -> io::Result<MmapedVec<T>> {
+        self.try_open_with_default_data(path, T::default())
+    }
+
+    /// Like [`try_open`](MmapedVecBuilder::try_open), but storing `default_data` rather than
+    /// `T::default()` in the header when creating a new file.
+    ///
+    /// This allows for element types that do not implement `Default`, or whose default value
+    /// is not a suitable sentinel.
+    pub fn try_open_with_default_data<T>(
+        &self,
+        path: &Path,
+        default_data: T,
+    ) -> io::Result<MmapedVec<T>> {
+        self.open_path(path, Some(default_data))
+    }
+
+    /// Like [`try_open`](MmapedVecBuilder::try_open), but without storing any default data
+    /// in the header when creating a new file, which keeps the header small for large `T`.
+    pub fn try_open_without_default_data<T>(&self, path: &Path) -> io::Result<MmapedVec<T>> {
+        self.open_path(path, None)
+    }
+
+    fn open_path<T>(&self, path: &Path, default_data: Option<T>) -> io::Result<MmapedVec<T>> {
+        if cfg!(miri) {
             return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
+                io::ErrorKind::Unsupported,
                 format!(
-                    "File `{:?}` has non-zero size ({} bytes), but it is shorter than \
-          the expected header size ({} bytes).",
-                    path, flen, fhs
+                    "File `{:?}`: Files cannot be mapped under Miri. Use \
+          MmapedVecBuilder::try_open_buffered() with an in-memory backend instead.",
+                    path
                 ),
             ));
-        } else {
-            let mut fh_handle = file.try_clone()?.take(fhs as u64);
-            let mut fh_buf = vec![0u8; fhs];
+        }
+
+        let (file, lock_file) = open_locked(path, self.follow_symlinks)?;
+        let created = file.metadata()?.len() == 0;
+
+        let mut mv = self.try_from_locked_file(file, lock_file, path, default_data)?;
+        if created && self.lock_file {
+            mv.move_lock_to_lock_file()?;
+        }
+        Ok(mv)
+    }
 
-            fh_handle.read(fh_buf.as_mut_slice()).unwrap();
+    /// Rewrite a file written in an older version of the persistence format in the current
+    /// version, so that it can be opened. The versions that files can be upgraded from are
+    /// listed in [`HISTORICAL_FORMATS`](format::HISTORICAL_FORMATS).
+    ///
+    /// The file is locked while its contents are copied to a temporary file next to it,
+    /// which is then renamed into place. Files already in the current version are left as-is.
+    pub fn upgrade_format<T: Sized + Default>(&self, path: &Path) -> io::Result<()> {
+        check_element_type::<T>(path)?;
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        registry::check_not_open(&file, path)?;
+        locking::try_lock_exclusive(&file, path)?;
+
+        let fh_file = FileHeader::read_from(&file)?;
+
+        if fh_file.magic_bytes != self.magic_bytes || fh_file.endianness != ENDIANNESS_MARKER {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "File `{:?}`: Magic bytes mismatch, or wrong endianness.",
+                    path
+                ),
+            ));
+        }
 
-            let fh_file = unsafe { std::ptr::read(fh_buf.as_ptr() as *const FileHeader<T>) };
+        if fh_file.persistence_format_version == PERSISTENCE_FORMAT_VERSION {
+            return Ok(());
+        }
 
-            if fh_file.magic_bytes != fh.magic_bytes {
+        let layout = match format::historical_format(fh_file.persistence_format_version) {
+            Some(historical) => (historical.layout)(mem::size_of::<T>()),
+            None => {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
-                    format!("File `{:?}`: Magic bytes mismatch.", path),
-                ));
+                    format!(
+                        "File `{:?}`: Unsupported persistence format version {:?}.",
+                        path, fh_file.persistence_format_version
+                    ),
+                ))
             }
+        };
 
-            if fh_file.endianness != fh.endianness {
-                if (fh_file.endianness << 8 | fh_file.endianness >> 8) != fh.endianness {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("File `{:?}`: Endianness-marker invalid.", path),
-                    ));
-                } else {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("File `{:?}`: Wrong endianness.", path),
-                    ));
-                }
-            }
+        let old_header_len = layout.header_len;
+        let flen = file.metadata()?.len();
 
-            // TODO: Validate remaining fields
+        if flen < old_header_len
+            || !(flen - old_header_len).is_multiple_of(mem::size_of::<T>() as u64)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "File `{:?}`: Size does not match persistence format version {:?}.",
+                    path, fh_file.persistence_format_version
+                ),
+            ));
+        }
+
+        let mut default_data = vec![0u8; mem::size_of::<T>()];
+        if let Some(offset) = layout.default_data_offset {
+            file.read_exact_at(&mut default_data, offset as u64)?;
         }
 
-        if flen > 0 && flen < len_fh_and_padding {
-            // TODO: Error
+        let fh = FileHeader::new::<T>(
+            self.magic_bytes,
+            fh_file.data_contained_version,
+            layout.default_data_offset.is_some(),
+        );
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut tmp_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+
+        // NOTE: Locked while it is written, so that the janitor leaves it alone.
+        let result = locking::try_lock_exclusive(&tmp_file, &tmp_path)
+            .and_then(|_| tmp_file.write_all_at(&fh.to_bytes(), 0))
+            .and_then(|_| fail_point!(AfterHeaderWrite))
+            .and_then(|_| match fh.has_default_data() {
+                true => tmp_file.write_all_at(&default_data, fh.default_data_offset as u64),
+                false => Ok(()),
+            })
+            .and_then(|_| tmp_file.set_len(fh.header_len))
+            .and_then(|_| tmp_file.seek(SeekFrom::Start(fh.header_len)))
+            .and_then(|_| {
+                let mut body = &file;
+                body.seek(SeekFrom::Start(old_header_len))?;
+                io::copy(&mut body, &mut tmp_file)
+            })
+            .and_then(|_| tmp_file.sync_all())
+            .and_then(|_| fs::rename(&tmp_path, path));
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
         }
 
-        if flen > len_fh_and_padding
-            && ((flen - len_fh_and_padding) % mem::size_of::<T>() as u64 != 0)
+        result
+    }
+
+    /// Put back together a [`MmapedVec`](MmapedVec) taken apart with
+    /// [`into_parts`](MmapedVec::into_parts), with the options of this builder.
+    pub fn try_from_parts<T>(
+        &self,
+        file: File,
+        mm: MmapMut,
+        layout: FileLayout,
+    ) -> io::Result<MmapedVec<T>> {
+        check_element_type::<T>(&layout.path)?;
+
+        let registration = registry::Registration::new(&file, &layout.path)?;
+        let lock_file = locking::try_lock_exclusive_for(&file, &layout.path)?;
+
+        self.build_from_locked_parts(file, lock_file, mm, layout, registration)
+    }
+
+    /// Like [`try_from_parts`](MmapedVecBuilder::try_from_parts), but with the lock already
+    /// taken, on `lock_file` if the file has one.
+    fn build_from_locked_parts<T>(
+        &self,
+        file: File,
+        lock_file: Option<File>,
+        mm: MmapMut,
+        layout: FileLayout,
+        registration: registry::Registration,
+    ) -> io::Result<MmapedVec<T>> {
+        if mm.len() < layout.header_len
+            || !(mm.as_ptr() as usize + layout.header_len).is_multiple_of(mem::align_of::<T>())
+            || !(mm.len() - layout.header_len).is_multiple_of(mem::size_of::<T>())
+            || file.metadata()?.len() < mm.len() as u64
         {
             return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
+                io::ErrorKind::InvalidInput,
                 format!(
-                    "File `{:?}` has non-zero size, but file size minus header size and padding \
-          bytes is not an integer multiple of the size of the data type that the file supposedly \
-          contains. This indicates that the file might be corrupt, incorrectly versioned or \
-          malformed.",
-                    path
+                    "File `{:?}`: The mapping does not match the layout of the file.",
+                    layout.path
                 ),
             ));
         }
 
-        let mut mm = unsafe { MmapMut::map_mut(&file)? };
+        let flush_order = self.flush_order_for(&layout.path, layout.header_len)?;
+        let file_started = file
+            .metadata()?
+            .created()
+            .unwrap_or_else(|_| SystemTime::now());
+
+        let mut mv = MmapedVec {
+            path: layout.path,
+            mm,
+            file,
+            lock_file,
+            header_len: layout.header_len,
+            max_len_bytes: self.max_len_bytes,
+            max_elements: self.max_elements,
+            check_free_space: self.check_free_space.unwrap_or(false),
+            preallocate: self.preallocate.unwrap_or(false),
+            protected_access: self.protected_access,
+            harden: self.harden,
+            flush_mode: self.flush_mode,
+            flush_order,
+            synced_len_bytes: 0,
+            poisoned: false,
+            replication_sink: None,
+            mapping_generation: 0,
+            pins: Arc::new(AtomicUsize::new(0)),
+            registration,
+            owner_pid: std::process::id(),
+            fork_generation: fork::generation(),
+            recovery: None,
+            roll_policy: None,
+            file_started,
+            tracker: None,
+            change_feed: None,
+            wal: None,
+            checksum: self.checksum,
+            merkle: None,
+            direct: None,
+            bloom: None,
+            stats: None,
+            zones: None,
+            free: None,
+            tombstones: None,
+            replay: None,
+            migration: None,
+            retired: vec![],
+            _marker: PhantomData,
+        };
+
+        mv.tracker = self
+            .track_modifications
+            .map(|block_elems| tracking::ModificationTracker::new(block_elems, mv.len()));
+        if self.wal {
+            let body = &mv.mm[mv.header_len..];
+            mv.wal = Some(wal::Wal::open(
+                &mv.path,
+                mem::size_of::<T>(),
+                self.wal_history,
+                body,
+            )?);
+        }
+        if let Some(chunk_elems) = self.merkle_tree {
+            let algorithm = self.checksum.unwrap_or(ChecksumAlgorithm::Crc32c);
+            let body = &mv.mm[mv.header_len..];
+            mv.merkle = Some(merkle::MerkleTree::open(
+                &mv.path,
+                algorithm,
+                chunk_elems,
+                mem::size_of::<T>(),
+                body,
+            )?);
+        }
+        if self.direct_io {
+            mv.direct = Some(direct::DirectWriter::open(&mv.file, &mv.path)?);
+        }
+        mv.set_writable(false)?;
+
+        Ok(mv)
+    }
+
+    /// Check that the existing, non-empty `file` is a valid file of this builder's kind with
+    /// elements of type `T`, returning its header.
+    pub(crate) fn check_existing_file<T, B: StorageBackend + ?Sized>(
+        &self,
+        file: &B,
+        path: &Path,
+    ) -> io::Result<FileHeader> {
+        self.check_existing_layout(file, path, mem::size_of::<T>(), mem::align_of::<T>())
+    }
+
+    /// Like [`check_existing_file`](MmapedVecBuilder::check_existing_file), for elements of
+    /// `elem_size` bytes aligned to `elem_align`.
+    pub(crate) fn check_existing_layout<B: StorageBackend + ?Sized>(
+        &self,
+        file: &B,
+        path: &Path,
+        elem_size: usize,
+        elem_align: usize,
+    ) -> io::Result<FileHeader> {
+        let fh = format::check_existing_file(
+            file,
+            path,
+            self.magic_bytes,
+            self.data_contained_version,
+            elem_size,
+            elem_align,
+        )?;
+        self.check_data_version(path, FileHeader::read_from(file)?.data_contained_version)?;
+        Ok(fh)
+    }
+
+    /// The `default_data` is only used when creating a new file. For existing files,
+    /// the header determines whether there is default data.
+    pub(crate) fn try_from_locked_file<T>(
+        &self,
+        file: File,
+        lock_file: Option<File>,
+        path: &Path,
+        default_data: Option<T>,
+    ) -> io::Result<MmapedVec<T>> {
+        check_element_type::<T>(path)?;
+
+        let created = file.metadata()?.len() == 0;
+        let PreparedFile {
+            fh,
+            recovery,
+            migration,
+        } = self.prepare_locked_file(
+            &file,
+            path,
+            mem::size_of::<T>(),
+            mem::align_of::<T>(),
+            default_data.as_ref().map(format::as_bytes),
+        )?;
 
-        Ok(Self {
+        let mm = unsafe { MmapMut::map_mut(&file)? };
+
+        let registration = registry::Registration::new(&file, path)?;
+        let mut mv = self.build_from_locked_parts(
             file,
+            lock_file,
             mm,
-            _marker: PhantomData,
+            FileLayout {
+                path: path.to_path_buf(),
+                header_len: fh.header_len as usize,
+            },
+            registration,
+        )?;
+        mv.recovery = recovery;
+        mv.migration = migration;
+        mv.ensure_sequence_extension()?;
+        mv.check_stride()?;
+        mv.apply_header_hints(self, created)?;
+        mv.reserve_checksum()?;
+
+        Ok(mv)
+    }
+
+    /// Write the header of a new, empty `file`, with `default_data` if any, or recover and
+    /// validate an existing one, for elements of `elem_size` bytes aligned to `elem_align`,
+    /// and mark it as open.
+    pub(crate) fn prepare_locked_file(
+        &self,
+        file: &File,
+        path: &Path,
+        elem_size: usize,
+        elem_align: usize,
+        default_data: Option<&[u8]>,
+    ) -> io::Result<PreparedFile> {
+        let mut recovery = None;
+        let mut migration = None;
+        let fh = if file.metadata()?.len() == 0 {
+            let fh = FileHeader::with_layout(
+                self.magic_bytes,
+                self.data_contained_version,
+                elem_size,
+                elem_align,
+                default_data.is_some(),
+            );
+
+            fh.write_to(&mut &*file)?;
+            fail_point!(AfterHeaderWrite)?;
+            if let Some(default_data) = default_data {
+                file.write_all_at(default_data, fh.default_data_offset as u64)?;
+            }
+            file.set_len(fh.header_len)?;
+            fh
+        } else {
+            recovery = self.recover(file, path, elem_size, elem_align)?;
+            let fh = self.check_existing_layout(file, path, elem_size, elem_align)?;
+            if !self.wal {
+                wal::mark_unlogged(path, elem_size)?;
+            }
+            check_mappable(path, file.metadata()?.len())?;
+            self.verify_checksum(file, path, &fh)?;
+            let file_version = FileHeader::read_from(file)?.data_contained_version;
+            if self.check_data_version(path, file_version)? == VersionDecision::Migrate {
+                migration = Some((file_version, self.data_contained_version));
+            }
+            fh
+        };
+
+        recovery::set_dirty(file, true)?;
+
+        Ok(PreparedFile {
+            fh,
+            recovery,
+            migration,
         })
     }
 }
 
-#[cfg(test)]
+/// What [`prepare_locked_file`](MmapedVecBuilder::prepare_locked_file) found.
+pub(crate) struct PreparedFile {
+    pub(crate) fh: FileHeader,
+    pub(crate) recovery: Option<RecoveryReport>,
+    /// The versions to migrate the elements between, if they are to be migrated.
+    pub(crate) migration: Option<([u8; 3], [u8; 3])>,
+}
+
+// NOTE: The tests open real files, which are not modelled under loom.
+#[cfg(all(test, not(loom)))]
 mod tests {
     use super::*;
     use memoffset::offset_of;
-    use std::error::Error;
-    use std::io::{Seek, SeekFrom};
+    use std::io::Read;
+    use std::panic;
     use std::path::PathBuf;
     use std::process::{Command, ExitStatus, Stdio};
     use tempfile::TempDir;
 
+    #[derive(Clone, Copy)]
     #[repr(C, packed)]
-    struct Example {
-        hello: u8,
-        world: u8,
+    pub(crate) struct Example {
+        pub(crate) hello: u8,
+        pub(crate) world: u8,
     }
 
     impl Default for Example {
@@ -295,15 +1764,13 @@ mod tests {
         }
     }
 
-    const EXAMPLE_MAGIC_BYTES: [u8; 8] = [b'T', b'E', b'S', b'T', b'F', b'I', b'L', b'E'];
-    const EXAMPLE_CORRUPT_MAGIC_BYTES: [u8; 8] = [b'X', b'Y', b'Z', b'T', b'F', 0, 0, 0];
-    const EXAMPLE_DATA_CONTAINED_VERSION: [u8; 3] = [0, 1, 0];
-
-    // XXX: Type alias for use with offset_of!()
-    type ExampleFileHeader = FileHeader<Example>;
+    pub(crate) const EXAMPLE_MAGIC_BYTES: [u8; 8] =
+        [b'T', b'E', b'S', b'T', b'F', b'I', b'L', b'E'];
+    pub(crate) const EXAMPLE_CORRUPT_MAGIC_BYTES: [u8; 8] = [b'X', b'Y', b'Z', b'T', b'F', 0, 0, 0];
+    pub(crate) const EXAMPLE_DATA_CONTAINED_VERSION: [u8; 3] = [0, 1, 0];
 
     /// Helper function for tests.
-    fn tempdir_and_tempfile() -> io::Result<(TempDir, PathBuf)> {
+    pub(crate) fn tempdir_and_tempfile() -> io::Result<(TempDir, PathBuf)> {
         let dir = tempfile::tempdir()?;
         let pathbuf = dir.path().join("file.bin");
 
@@ -311,7 +1778,7 @@ mod tests {
     }
 
     /// Helper function for tests.
-    fn new_mmaped_vec_of_example_persisting_in_tempdir(
+    pub(crate) fn new_mmaped_vec_of_example_persisting_in_tempdir(
     ) -> io::Result<(TempDir, PathBuf, MmapedVec<Example>)> {
         let (dir, pathbuf) = tempdir_and_tempfile()?;
 
@@ -325,7 +1792,7 @@ mod tests {
     }
 
     /// Helper function for tests.
-    fn python3_try_lock_exclusive(path: &Path) -> io::Result<ExitStatus> {
+    pub(crate) fn python3_try_lock_exclusive(path: &Path) -> io::Result<ExitStatus> {
         // NOTE: Keep in mind that if the parent test fails, python3 might not be in your $PATH.
 
         let mut child = Command::new("python3")
@@ -416,7 +1883,7 @@ mod tests {
         .err()
         .unwrap();
 
-        assert!(mv_err.description().ends_with("Magic bytes mismatch."));
+        assert!(mv_err.to_string().ends_with("Magic bytes mismatch."));
 
         Ok(())
     }
@@ -429,7 +1896,7 @@ mod tests {
             .read(true)
             .write(true)
             .open(pathbuf.as_path())?;
-        let fhs = mem::size_of::<FileHeader<Example>>();
+        let fhs = mem::size_of::<FileHeader>();
 
         file.set_len((fhs - 1) as u64).unwrap();
 
@@ -442,7 +1909,7 @@ mod tests {
         .unwrap();
 
         assert!(mv_err
-            .description()
+            .to_string()
             .contains("shorter than the expected header size"));
 
         Ok(())
@@ -470,7 +1937,7 @@ mod tests {
         .unwrap();
 
         assert!(mv_err
-            .description()
+            .to_string()
             .contains("not an integer multiple of the size of the data type"));
 
         Ok(())
@@ -485,10 +1952,10 @@ mod tests {
             .write(true)
             .open(pathbuf.as_path())?;
 
-        let offs = SeekFrom::Start(offset_of!(ExampleFileHeader, endianness) as u64);
+        let offs = SeekFrom::Start(offset_of!(FileHeader, endianness) as u64);
 
         file.seek(offs).unwrap();
-        file.write(&[0u8, 0]).unwrap();
+        file.write_all(&[0u8, 0]).unwrap();
 
         let mv_err = MmapedVec::<Example>::try_new(
             pathbuf.as_path(),
@@ -498,7 +1965,7 @@ mod tests {
         .err()
         .unwrap();
 
-        assert!(mv_err.description().ends_with("Endianness-marker invalid."));
+        assert!(mv_err.to_string().ends_with("Endianness-marker invalid."));
 
         Ok(())
     }
@@ -512,7 +1979,7 @@ mod tests {
             .write(true)
             .open(pathbuf.as_path())?;
 
-        let offs = SeekFrom::Start(offset_of!(ExampleFileHeader, endianness) as u64);
+        let offs = SeekFrom::Start(offset_of!(FileHeader, endianness) as u64);
 
         file.seek(offs).unwrap();
 
@@ -521,7 +1988,264 @@ mod tests {
         buf.reverse();
 
         file.seek(offs).unwrap();
-        file.write(&buf).unwrap();
+        file.write_all(&buf).unwrap();
+
+        let mv_err = MmapedVec::<Example>::try_new(
+            pathbuf.as_path(),
+            EXAMPLE_MAGIC_BYTES,
+            EXAMPLE_DATA_CONTAINED_VERSION,
+        )
+        .err()
+        .unwrap();
+
+        assert!(mv_err.to_string().ends_with("Wrong endianness."));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_pushed_elements_persist_across_reopen() -> Result<(), io::Error> {
+        let (_dir, pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        mv.push(Example { hello: 3, world: 4 })?;
+        mv.extend(vec![Example::default(), Example { hello: 5, world: 6 }])?;
+        mv.flush()?;
+        drop(mv);
+
+        let mv = MmapedVec::<Example>::try_new(
+            pathbuf.as_path(),
+            EXAMPLE_MAGIC_BYTES,
+            EXAMPLE_DATA_CONTAINED_VERSION,
+        )?;
+
+        assert_eq!(mv.len(), 3);
+        assert_eq!((mv[0].hello, mv[0].world), (3, 4));
+        assert_eq!((mv[1].hello, mv[1].world), (1, 2));
+        assert_eq!((mv[2].hello, mv[2].world), (5, 6));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_push_beyond_max_elements_is_capacity_exceeded() -> Result<(), io::Error> {
+        let (_dir, pathbuf) = tempdir_and_tempfile()?;
+
+        let mut mv = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .max_elements(2)
+            .try_open::<Example>(pathbuf.as_path())?;
+
+        mv.extend(vec![Example::default(), Example::default()])?;
+
+        let mv_err = mv.push(Example::default()).err().unwrap();
+
+        assert!(mv_err
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<CapacityExceeded>()
+            .is_some());
+        assert_eq!(mv.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_extend_beyond_max_len_bytes_is_capacity_exceeded() -> Result<(), io::Error> {
+        let (_dir, pathbuf) = tempdir_and_tempfile()?;
+
+        let mut mv = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .max_len_bytes(4096 + 3 * mem::size_of::<Example>() as u64)
+            .try_open::<Example>(pathbuf.as_path())?;
+
+        mv.extend(vec![Example::default(), Example::default()])?;
+
+        let mv_err = mv
+            .extend(vec![Example::default(), Example::default()])
+            .err()
+            .unwrap();
+
+        assert!(mv_err.to_string().starts_with("Capacity exceeded"));
+        assert_eq!(mv.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_preallocate_allocates_disk_blocks_when_growing() -> Result<(), io::Error> {
+        let (_dir, pathbuf) = tempdir_and_tempfile()?;
+
+        let mut mv = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .check_free_space(true)
+            .preallocate(true)
+            .try_open::<Example>(pathbuf.as_path())?;
+
+        mv.extend((0..10_000).map(|_| Example::default()))?;
+
+        let file = File::open(pathbuf.as_path())?;
+        assert!(file.allocated_size()? >= file.metadata()?.len());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_detect_file_truncated_behind_our_back() -> Result<(), io::Error> {
+        let (_dir, pathbuf) = tempdir_and_tempfile()?;
+
+        let mut mv = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .protected_access(true)
+            .try_open::<Example>(pathbuf.as_path())?;
+
+        mv.extend(vec![Example::default(), Example::default()])?;
+        assert!(mv.try_as_slice().is_ok());
+
+        let file = OpenOptions::new().write(true).open(pathbuf.as_path())?;
+        file.set_len(file.metadata()?.len() - 1)?;
+
+        assert!(mv
+            .try_as_slice()
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("has been truncated"));
+        assert!(mv.push(Example::default()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_hardened_writes_go_through_write_guard() -> Result<(), io::Error> {
+        let (_dir, pathbuf) = tempdir_and_tempfile()?;
+
+        let mut mv = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .harden(true)
+            .try_open::<Example>(pathbuf.as_path())?;
+
+        mv.extend(vec![Example::default(), Example::default()])?;
+        mv.write_guard()?[1].hello = 7;
+        mv.push(Example::default())?;
+
+        assert_eq!(mv.len(), 3);
+        assert_eq!((mv[1].hello, mv[1].world), (7, 2));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_hardened_deref_mut_unprotects_until_flush() -> Result<(), io::Error> {
+        let (_dir, pathbuf) = tempdir_and_tempfile()?;
+
+        let mut mv = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .harden(true)
+            .try_open::<Example>(pathbuf.as_path())?;
+
+        mv.push(Example::default())?;
+        mv[0].hello = 7;
+        for example in mv.iter_mut() {
+            example.world = 8;
+        }
+        mv.flush()?;
+        mv.write_guard()?[0].hello += 1;
+        drop(mv);
+
+        let mv = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .try_open::<Example>(pathbuf.as_path())?;
+        assert_eq!((mv[0].hello, mv[0].world), (8, 8));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_file_is_unlocked_after_close() -> Result<(), io::Error> {
+        let (_dir, pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        mv.push(Example::default())?;
+        mv.close()?;
+
+        assert_eq!(
+            python3_try_lock_exclusive(pathbuf.as_path())?.code(),
+            Some(0)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_into_parts_and_back() -> Result<(), io::Error> {
+        let (_dir, pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        mv.push(Example { hello: 3, world: 4 })?;
+
+        let (file, mm, layout) = mv.into_parts();
+
+        assert_eq!(file.metadata()?.len(), mm.len() as u64);
+        assert_eq!(
+            python3_try_lock_exclusive(pathbuf.as_path())?.code(),
+            Some(35)
+        );
+
+        let mv = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .try_from_parts::<Example>(file, mm, layout)?;
+
+        assert_eq!(mv.len(), 1);
+        assert_eq!((mv[0].hello, mv[0].world), (3, 4));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_reject_zero_sized_element_type() -> Result<(), io::Error> {
+        let (_dir, pathbuf) = tempdir_and_tempfile()?;
+
+        let mv_err = MmapedVec::<()>::try_new(
+            pathbuf.as_path(),
+            EXAMPLE_MAGIC_BYTES,
+            EXAMPLE_DATA_CONTAINED_VERSION,
+        )
+        .err()
+        .unwrap();
+
+        assert!(mv_err.to_string().contains("Zero-sized element types"));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_over_aligned_elements_are_aligned() -> Result<(), io::Error> {
+        #[repr(C, align(64))]
+        #[derive(Default)]
+        struct CacheLine {
+            value: u64,
+        }
+
+        let (_dir, pathbuf) = tempdir_and_tempfile()?;
+
+        let mut mv = MmapedVec::<CacheLine>::try_new(
+            pathbuf.as_path(),
+            EXAMPLE_MAGIC_BYTES,
+            EXAMPLE_DATA_CONTAINED_VERSION,
+        )?;
+
+        mv.extend((0..3).map(|value| CacheLine { value }))?;
+
+        assert!((mv.as_ptr() as usize).is_multiple_of(64));
+        assert_eq!(mv[2].value, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_upgrade_format_from_0_0_5() -> Result<(), io::Error> {
+        let (_dir, pathbuf) = tempdir_and_tempfile()?;
+
+        // Hand-craft a file in the 0.0.5 layout, with a packed header and two elements.
+        let old_header_len = format::header_len_v0_0_5(mem::size_of::<Example>());
+        let mut buf = vec![0u8; old_header_len];
+        buf[0..8].copy_from_slice(&EXAMPLE_MAGIC_BYTES);
+        buf[8..10].copy_from_slice(&ENDIANNESS_MARKER.to_ne_bytes());
+        buf[10..13].copy_from_slice(&format::PERSISTENCE_FORMAT_VERSION_0_0_5);
+        buf[13..16].copy_from_slice(&EXAMPLE_DATA_CONTAINED_VERSION);
+        buf[16..18].copy_from_slice(&[1, 2]);
+        buf.extend_from_slice(&[3, 4, 5, 6]);
+        fs::write(pathbuf.as_path(), &buf)?;
 
         let mv_err = MmapedVec::<Example>::try_new(
             pathbuf.as_path(),
@@ -531,8 +2255,235 @@ mod tests {
         .err()
         .unwrap();
 
-        assert!(mv_err.description().ends_with("Wrong endianness."));
+        assert!(mv_err.to_string().contains("upgrade_format"));
+
+        MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .upgrade_format::<Example>(pathbuf.as_path())?;
+
+        let mv = MmapedVec::<Example>::try_new(
+            pathbuf.as_path(),
+            EXAMPLE_MAGIC_BYTES,
+            EXAMPLE_DATA_CONTAINED_VERSION,
+        )?;
+
+        assert_eq!(mv.len(), 2);
+        assert_eq!((mv[0].hello, mv[0].world), (3, 4));
+        assert_eq!((mv[1].hello, mv[1].world), (5, 6));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_default_data_explicit_or_absent() -> Result<(), io::Error> {
+        #[repr(C)]
+        struct NoDefault {
+            value: u32,
+        }
+
+        let (_dir, pathbuf) = tempdir_and_tempfile()?;
+        let pathbuf_without = pathbuf.with_extension("without");
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        builder.try_open_with_default_data(pathbuf.as_path(), NoDefault { value: 42 })?;
+        let mv = builder.try_open_without_default_data::<NoDefault>(pathbuf.as_path())?;
+        assert_eq!(mv.default_data().unwrap().value, 42);
+
+        let mut mv = builder.try_open_without_default_data::<NoDefault>(&pathbuf_without)?;
+        mv.push(NoDefault { value: 7 })?;
+        drop(mv);
+
+        let mv = builder.try_open_with_default_data(&pathbuf_without, NoDefault { value: 42 })?;
+        assert!(mv.default_data().is_none());
+        assert_eq!(mv[0].value, 7);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_resize_fills_with_default_data_and_truncates() -> Result<(), io::Error> {
+        let (_dir, pathbuf) = tempdir_and_tempfile()?;
+
+        let mut mv = MmapedVec::try_new_with_default_data(
+            pathbuf.as_path(),
+            EXAMPLE_MAGIC_BYTES,
+            EXAMPLE_DATA_CONTAINED_VERSION,
+            Example { hello: 8, world: 9 },
+        )?;
+
+        mv.resize(3)?;
+        assert_eq!(mv.len(), 3);
+        assert_eq!((mv[2].hello, mv[2].world), (8, 9));
+
+        mv.resize_with(5, || Example { hello: 5, world: 5 })?;
+        assert_eq!((mv[4].hello, mv[4].world), (5, 5));
+
+        mv.resize(1)?;
+        drop(mv);
+
+        let mv = MmapedVec::<Example>::try_new(
+            pathbuf.as_path(),
+            EXAMPLE_MAGIC_BYTES,
+            EXAMPLE_DATA_CONTAINED_VERSION,
+        )?;
+        assert_eq!(mv.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_copy_within_and_move_range() -> Result<(), io::Error> {
+        let (_dir, _pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        mv.extend((0..8).map(|i| Example { hello: i, world: 0 }))?;
+        let hellos = |mv: &MmapedVec<Example>| mv.iter().map(|e| e.hello).collect::<Vec<_>>();
+
+        mv.copy_within(0..3, 2)?;
+        assert_eq!(hellos(&mv), vec![0, 1, 0, 1, 2, 5, 6, 7]);
+
+        mv.move_range(5..7, 1)?;
+        assert_eq!(hellos(&mv), vec![0, 5, 6, 1, 0, 1, 2, 7]);
+
+        mv.move_range(0..2, 6)?;
+        assert_eq!(hellos(&mv), vec![6, 1, 0, 1, 2, 7, 0, 5]);
+
+        mv.move_range(3..3, 0)?;
+        assert_eq!(hellos(&mv), vec![6, 1, 0, 1, 2, 7, 0, 5]);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_growing_beyond_address_space_fails_early() -> Result<(), io::Error> {
+        let (_dir, _pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        let err = mv.resize(usize::MAX / 4).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+        assert!(err.to_string().contains("try_open_windowed"));
+        assert_eq!(mv.len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_close_releases_file_descriptors() -> Result<(), io::Error> {
+        let (dir, path) = tempdir_and_tempfile()?;
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        builder.wal(true);
+
+        // NOTE: Counts only descriptors of files in our own directory, as other tests open
+        //       files concurrently.
+        let open_in_dir = || -> io::Result<usize> {
+            let mut n = 0;
+            for entry in std::fs::read_dir("/proc/self/fd")? {
+                if let Ok(target) = std::fs::read_link(entry?.path()) {
+                    n += target.starts_with(dir.path()) as usize;
+                }
+            }
+            Ok(n)
+        };
+
+        for i in 0..10 {
+            let mut mv = builder.try_open::<u32>(&path)?;
+            mv.push(i)?;
+            assert!(open_in_dir()? >= 2);
+            mv.close()?;
+            assert_eq!(open_in_dir()?, 0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_trim_trailing_defaults() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        let mut mv = builder.try_open_with_default_data::<u32>(&path, 7)?;
+        mv.extend([1, 7, 2])?;
+        mv.resize(10)?;
+
+        assert_eq!(mv.trim_trailing_defaults()?, 7);
+        assert_eq!(&mv[..], &[1, 7, 2]);
+        assert_eq!(mv.trim_trailing_defaults()?, 0);
+
+        mv[0] = 7;
+        mv[2] = 7;
+        assert_eq!(mv.trim_trailing_defaults()?, 3);
+        assert!(mv.is_empty());
+
+        let mut mv = builder.try_open_without_default_data::<u32>(&path.with_extension("none"))?;
+        assert!(mv.trim_trailing_defaults().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_upgrade_format_rejects_unknown_versions() -> Result<(), io::Error> {
+        let (_dir, pathbuf) = tempdir_and_tempfile()?;
+
+        let layout = (format::historical_format(format::PERSISTENCE_FORMAT_VERSION_0_0_5)
+            .unwrap()
+            .layout)(mem::size_of::<Example>());
+        assert_eq!(
+            layout.header_len as usize,
+            format::header_len_v0_0_5(mem::size_of::<Example>())
+        );
+        assert!(format::historical_format(PERSISTENCE_FORMAT_VERSION).is_none());
+
+        let mut buf = vec![0u8; layout.header_len as usize];
+        buf[0..8].copy_from_slice(&EXAMPLE_MAGIC_BYTES);
+        buf[8..10].copy_from_slice(&ENDIANNESS_MARKER.to_ne_bytes());
+        buf[10..13].copy_from_slice(&[0, 0, 3]);
+        fs::write(pathbuf.as_path(), &buf)?;
+
+        let err = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .upgrade_format::<Example>(pathbuf.as_path())
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .contains("Unsupported persistence format version"));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_lock_file() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let mut mv: MmapedVec<u64> = builder.lock_file(true).try_open(&path)?;
+        mv.push(1)?;
+        assert!(mv
+            .header_extension(format::EXTENSION_TAG_LOCK_FILE)
+            .is_some());
+        assert_eq!(python3_try_lock_exclusive(&path)?.code(), Some(0));
+        assert_eq!(
+            python3_try_lock_exclusive(&lock_path(&path))?.code(),
+            Some(35)
+        );
+        drop(mv);
+        assert_eq!(
+            python3_try_lock_exclusive(&lock_path(&path))?.code(),
+            Some(0)
+        );
+
+        // NOTE: Existing files are locked as their header says.
+        let mv: MmapedVec<u64> = builder.lock_file(false).try_open(&path)?;
+        assert_eq!(mv[..], [1]);
+        assert_eq!(python3_try_lock_exclusive(&path)?.code(), Some(0));
+        assert_eq!(
+            python3_try_lock_exclusive(&lock_path(&path))?.code(),
+            Some(35)
+        );
 
         Ok(())
     }
+
+    unsafe impl ReprC for Example {}
+
+    persist_assert_layout!(Example, size = 2, align = 1);
+    persist_assert_layout!([u64; 4], size = 32, align = 8);
+    persist_assert_layout!(CachePadded<u32>, size = CACHE_LINE_LEN);
 }
@@ -0,0 +1,297 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Advisory locking, with a preflight for platforms where flock() is emulated.
+//!
+//! Files whose header has [`EXTENSION_TAG_LOCK_FILE`] are locked through a lock file next
+//! to them instead of directly, for tools that open the files themselves, such as backup
+//! agents, and would conflict with locks on them.
+//!
+//! On Solaris and illumos, fs2 simulates flock() with fcntl() locks, which belong to the
+//! process rather than to the open file description. Those still exclude other processes,
+//! but not other handles within the same process, and are released when any descriptor
+//! for the file is closed. Rather than trusting either, the first lock taken on those
+//! platforms probes what the locks actually do, and the result is used from then on.
+
+use crate::error::UnsupportedPlatform;
+use crate::format::{self, FileHeader, EXTENSION_TAG_LOCK_FILE, FILE_HEADER_LEN};
+use crate::MmapedVec;
+use fs2::FileExt;
+use std::ffi::{CString, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt as UnixFileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LockSupport {
+    /// Locks belong to the open file description, and exclude other handles and processes.
+    Flock,
+    /// Locks belong to the process, and only exclude other processes.
+    #[cfg_attr(
+        not(any(target_os = "solaris", target_os = "illumos")),
+        allow(dead_code)
+    )]
+    Fcntl,
+}
+
+fn lock_support(path: &Path) -> io::Result<LockSupport> {
+    static LOCK_SUPPORT: OnceLock<Option<LockSupport>> = OnceLock::new();
+
+    #[cfg(not(any(target_os = "solaris", target_os = "illumos")))]
+    let support = LOCK_SUPPORT.get_or_init(|| Some(LockSupport::Flock));
+    #[cfg(any(target_os = "solaris", target_os = "illumos"))]
+    let support = LOCK_SUPPORT.get_or_init(|| probe().ok().flatten());
+
+    support.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            UnsupportedPlatform {
+                path: path.to_path_buf(),
+            },
+        )
+    })
+}
+
+/// Find out what locks do on this platform, by holding a lock on a scratch file and trying
+/// to take it again, first through another handle and then from a child process. Returns
+/// `None` if not even the child process is excluded.
+#[cfg_attr(
+    not(any(target_os = "solaris", target_os = "illumos")),
+    allow(dead_code)
+)]
+pub(crate) fn probe() -> io::Result<Option<LockSupport>> {
+    let path = std::env::temp_dir().join(format!(".persistence-lock-probe-{}", process::id()));
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+    let held = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+    let res = (|| {
+        held.try_lock_exclusive()?;
+
+        let other = File::open(&path)?;
+        if other.try_lock_exclusive().is_err() {
+            return Ok(Some(LockSupport::Flock));
+        }
+        // NOTE: With per-process locks, dropping the other handle released our lock too.
+        drop(other);
+        fcntl_lock(&held, libc::F_WRLCK)?;
+
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => unsafe {
+                // NOTE: Only async-signal-safe calls between fork and _exit.
+                let fd = libc::open(c_path.as_ptr(), libc::O_RDWR);
+                let mut fl: libc::flock = std::mem::zeroed();
+                fl.l_type = libc::F_WRLCK as _;
+                fl.l_whence = libc::SEEK_SET as _;
+                let excluded = fd != -1 && libc::fcntl(fd, libc::F_SETLK, &fl) == -1;
+                libc::_exit(if excluded { 0 } else { 1 })
+            },
+            pid => {
+                let mut status = 0;
+                if unsafe { libc::waitpid(pid, &mut status, 0) } == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                let excluded = libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0;
+                Ok(if excluded {
+                    Some(LockSupport::Fcntl)
+                } else {
+                    None
+                })
+            }
+        }
+    })();
+
+    drop(held);
+    let _ = fs::remove_file(&path);
+    res
+}
+
+fn fcntl_lock(file: &File, lock_type: libc::c_int) -> io::Result<()> {
+    fcntl(file, libc::F_SETLK, lock_type)
+}
+
+fn fcntl(file: &File, cmd: libc::c_int, lock_type: libc::c_int) -> io::Result<()> {
+    let mut fl: libc::flock = unsafe { std::mem::zeroed() };
+    fl.l_type = lock_type as _;
+    fl.l_whence = libc::SEEK_SET as _;
+
+    match unsafe { libc::fcntl(file.as_raw_fd(), cmd, &fl) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn try_lock_exclusive(file: &File, path: &Path) -> io::Result<()> {
+    match lock_support(path)? {
+        LockSupport::Flock => FileExt::try_lock_exclusive(file),
+        LockSupport::Fcntl => fcntl_lock(file, libc::F_WRLCK),
+    }
+}
+
+/// Like [`try_lock_exclusive`], but waiting for other holders of the lock to release it.
+pub(crate) fn lock_exclusive(file: &File, path: &Path) -> io::Result<()> {
+    match lock_support(path)? {
+        LockSupport::Flock => FileExt::lock_exclusive(file),
+        LockSupport::Fcntl => fcntl(file, libc::F_SETLKW, libc::F_WRLCK),
+    }
+}
+
+/// Takes a shared lock, or turns an exclusive lock held through `file` into one.
+pub(crate) fn try_lock_shared(file: &File, path: &Path) -> io::Result<()> {
+    match lock_support(path)? {
+        LockSupport::Flock => FileExt::try_lock_shared(file),
+        LockSupport::Fcntl => fcntl_lock(file, libc::F_RDLCK),
+    }
+}
+
+pub(crate) fn unlock(file: &File, path: &Path) -> io::Result<()> {
+    match lock_support(path)? {
+        LockSupport::Flock => FileExt::unlock(file),
+        LockSupport::Fcntl => fcntl_lock(file, libc::F_UNLCK),
+    }
+}
+
+/// Suffix of the lock file that is kept next to files whose lock is taken on it, rather
+/// than on the file itself.
+pub const LOCK_SUFFIX: &str = ".lock";
+
+/// Path of the lock file of the file at `path`.
+pub fn lock_path(path: &Path) -> PathBuf {
+    let mut lock_path = OsString::from(path.as_os_str());
+    lock_path.push(LOCK_SUFFIX);
+    PathBuf::from(lock_path)
+}
+
+/// Whether the header of `file` says that its lock is taken on its lock file. Files too
+/// short to have a header, such as those that are still being created, do not.
+fn has_lock_file(file: &File) -> io::Result<bool> {
+    let flen = file.metadata()?.len();
+    if flen < FILE_HEADER_LEN as u64 {
+        return Ok(false);
+    }
+
+    let fh = FileHeader::read_from(file)?;
+    if fh.header_len > flen || (fh.extensions_offset as u64) > fh.header_len {
+        return Ok(false);
+    }
+
+    let mut area = vec![0u8; (fh.header_len - fh.extensions_offset as u64) as usize];
+    UnixFileExt::read_exact_at(file, &mut area, fh.extensions_offset as u64)?;
+    Ok(format::parse_extensions(&area)
+        .unwrap_or_default()
+        .iter()
+        .any(|(tag, _)| *tag == EXTENSION_TAG_LOCK_FILE))
+}
+
+/// The lock file of the file at `path`, opened and created if need be, if the header of
+/// `file` says that its lock is taken on that.
+pub(crate) fn open_lock_file(file: &File, path: &Path) -> io::Result<Option<File>> {
+    if !has_lock_file(file)? {
+        return Ok(None);
+    }
+
+    let lock_path = lock_path(path);
+    let lock_file = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+    {
+        // NOTE: Locks can be taken on files opened read-only, which is all that can be
+        //       done on a read-only file system, where no lock file can be created either.
+        Err(e) if e.raw_os_error() == Some(libc::EROFS) => File::open(&lock_path)?,
+        res => res?,
+    };
+    Ok(Some(lock_file))
+}
+
+/// Take the exclusive lock of the file at `path`, on its lock file if it has one, which is
+/// then returned for the caller to hold on to.
+pub(crate) fn try_lock_exclusive_for(file: &File, path: &Path) -> io::Result<Option<File>> {
+    match open_lock_file(file, path)? {
+        Some(lock_file) => {
+            try_lock_exclusive(&lock_file, &lock_path(path))?;
+            Ok(Some(lock_file))
+        }
+        None => {
+            try_lock_exclusive(file, path)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Like [`try_lock_exclusive_for`], but taking a shared lock.
+pub(crate) fn try_lock_shared_for(file: &File, path: &Path) -> io::Result<Option<File>> {
+    match open_lock_file(file, path)? {
+        Some(lock_file) => {
+            try_lock_shared(&lock_file, &lock_path(path))?;
+            Ok(Some(lock_file))
+        }
+        None => {
+            try_lock_shared(file, path)?;
+            Ok(None)
+        }
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// Move the lock of a file that was just created to its lock file, and record that in
+    /// the header, so that everyone locks the lock file from then on.
+    pub(crate) fn move_lock_to_lock_file(&mut self) -> io::Result<()> {
+        let path = lock_path(&self.path);
+        let lock_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        try_lock_exclusive(&lock_file, &path)?;
+
+        // NOTE: Recorded only once the lock file is locked, since whoever sees the record
+        //       goes straight for the lock file, and the file itself stays locked until then.
+        self.rewrite_extensions(EXTENSION_TAG_LOCK_FILE, Some(&[]))?;
+        unlock(&self.file, &self.path)?;
+        self.lock_file = Some(lock_file);
+        Ok(())
+    }
+
+    /// The file that the lock is held on, which is the lock file for files that have one.
+    pub(crate) fn locked_file(&self) -> &File {
+        self.lock_file.as_ref().unwrap_or(&self.file)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_lock_probe_finds_flock_semantics() -> Result<(), io::Error> {
+        assert_eq!(probe()?, Some(LockSupport::Flock));
+        Ok(())
+    }
+}
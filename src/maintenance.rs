@@ -0,0 +1,338 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Compacting files that have gathered blocks of nothing but zeros, and a scheduler for
+//! doing so when it is worth it and the application is quiet.
+
+use crate::{locking, registry, MmapedVec};
+use fs2::FileExt;
+use memmap::MmapMut;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::fs::FileExt as UnixFileExt;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Size of the blocks that [`compact`](MmapedVec::compact) leaves out when they are all
+/// zeros. Matches the alignment of the body, so the blocks line up with pages.
+const BLOCK_LEN: usize = 4096;
+
+/// How much of a file's disk space is spent on nothing, as measured by
+/// [`fragmentation`](MmapedVec::fragmentation).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FragmentationReport {
+    /// Size of the file, header included, in bytes.
+    pub logical_bytes: u64,
+    /// Bytes of disk allocated to the file.
+    pub allocated_bytes: u64,
+    /// Blocks of the body that hold only zeros, whether they are allocated or holes.
+    pub zero_blocks: usize,
+    pub total_blocks: usize,
+}
+
+impl FragmentationReport {
+    /// Bytes allocated beyond the size of the file, as left by preallocation.
+    pub fn slack_bytes(&self) -> u64 {
+        self.allocated_bytes.saturating_sub(self.logical_bytes)
+    }
+
+    /// Bytes of the file that have no disk allocated to them.
+    pub fn hole_bytes(&self) -> u64 {
+        self.logical_bytes.saturating_sub(self.allocated_bytes)
+    }
+
+    /// The fraction of the blocks of the body that hold only zeros.
+    pub fn zero_density(&self) -> f64 {
+        match self.total_blocks {
+            0 => 0.0,
+            n => self.zero_blocks as f64 / n as f64,
+        }
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// Measure how much of the disk space of the file is spent on nothing. Reads the whole
+    /// body.
+    pub fn fragmentation(&self) -> io::Result<FragmentationReport> {
+        let body = &self.mm[self.header_len..];
+
+        Ok(FragmentationReport {
+            logical_bytes: self.file_logical_size()?,
+            allocated_bytes: self.file_allocated_size()?,
+            zero_blocks: body
+                .chunks(BLOCK_LEN)
+                .filter(|block| block.iter().all(|&b| b == 0))
+                .count(),
+            total_blocks: body.len().div_ceil(BLOCK_LEN),
+        })
+    }
+
+    /// Rewrite the file into a new one next to it, which is then renamed into its place,
+    /// returning how many bytes of disk that freed, if any.
+    ///
+    /// Blocks of only zeros are left as holes, unless the file was opened with
+    /// [`preallocate`](crate::MmapedVecBuilder::preallocate), in which case the new file is
+    /// allocated in full, which gives the file system the chance to lay it out in one piece.
+    /// The elements stay the same, but are mapped anew, in the same way as when the file is
+    /// [rolled](MmapedVec::roll).
+    pub fn compact(&mut self) -> io::Result<u64> {
+        self.check_poisoned()?;
+        self.check_not_forked()?;
+        self.flush()?;
+
+        let allocated_before = self.file_allocated_size()?;
+
+        let mut tmp_name = self
+            .path
+            .file_name()
+            .map(OsString::from)
+            .unwrap_or_default();
+        tmp_name.push(".tmp");
+        let tmp = self.path.with_file_name(tmp_name);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&tmp)?;
+        let res = (|| {
+            locking::try_lock_exclusive(&file, &tmp)?;
+            if self.preallocate {
+                FileExt::allocate(&file, self.mm.len() as u64)?;
+            }
+            file.set_len(self.mm.len() as u64)?;
+            file.write_all_at(&self.mm[..self.header_len], 0)?;
+
+            let mut offset = self.header_len;
+            for block in self.mm[self.header_len..].chunks(BLOCK_LEN) {
+                if self.preallocate || block.iter().any(|&b| b != 0) {
+                    file.write_all_at(block, offset as u64)?;
+                }
+                offset += block.len();
+            }
+
+            file.sync_all()?;
+            fs::rename(&tmp, &self.path)
+        })();
+        if let Err(e) = res {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+
+        let mm = unsafe { MmapMut::map_mut(&file)? };
+        self.registration = registry::Registration::new(&file, &self.path)?;
+
+        // NOTE: The mapping goes before the old file, which unlocks it when dropped.
+        self.replace_mapping(mm);
+        drop(mem::replace(&mut self.file, file));
+        // NOTE: Files with a lock file stay locked through that, so the new file need not be.
+        if self.lock_file.is_some() {
+            locking::unlock(&self.file, &self.path)?;
+        }
+        self.synced_len_bytes = self.mm.len() as u64;
+
+        self.set_writable(false)?;
+
+        Ok(allocated_before.saturating_sub(self.file_allocated_size()?))
+    }
+}
+
+/// When a [`MaintenanceScheduler`](MaintenanceScheduler) compacts a file.
+#[derive(Clone, Debug)]
+pub struct MaintenancePolicy {
+    /// Compact once at least this fraction of the blocks of the body hold only zeros.
+    pub min_zero_density: f64,
+    /// Or once at least this many bytes are allocated beyond the size of the file.
+    pub min_slack_bytes: u64,
+    /// Only measure the file this often, since that reads the whole body.
+    pub min_interval: Duration,
+}
+
+impl Default for MaintenancePolicy {
+    fn default() -> Self {
+        Self {
+            min_zero_density: 0.25,
+            min_slack_bytes: u64::MAX,
+            min_interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// What a maintenance run did, as passed to the observers of a
+/// [`MaintenanceScheduler`](MaintenanceScheduler).
+#[derive(Clone, Debug)]
+pub struct MaintenanceRun {
+    pub before: FragmentationReport,
+    /// Bytes of disk freed.
+    pub reclaimed_bytes: u64,
+    pub duration: Duration,
+}
+
+type QuietFn = dyn Fn(SystemTime) -> bool + Send;
+type VetoFn = dyn FnMut(&FragmentationReport) -> bool + Send;
+type ObserveFn = dyn FnMut(&MaintenanceRun) + Send;
+
+/// Compacts a [`MmapedVec`](MmapedVec) as per a [`MaintenancePolicy`](MaintenancePolicy),
+/// when [`run`](MaintenanceScheduler::run) is called at a time that the application is
+/// quiet.
+///
+/// Nothing runs in the background; call [`run`](MaintenanceScheduler::run) from wherever the
+/// application does its housekeeping, as often as it likes.
+pub struct MaintenanceScheduler {
+    policy: MaintenancePolicy,
+    last_measured: Option<Instant>,
+    is_quiet: Box<QuietFn>,
+    vetoes: Vec<Box<VetoFn>>,
+    observers: Vec<Box<ObserveFn>>,
+}
+
+impl fmt::Debug for MaintenanceScheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MaintenanceScheduler")
+            .field("policy", &self.policy)
+            .field("last_measured", &self.last_measured)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MaintenanceScheduler {
+    /// A scheduler that considers any time quiet, until told otherwise with
+    /// [`quiet_when`](MaintenanceScheduler::quiet_when).
+    pub fn new(policy: MaintenancePolicy) -> Self {
+        Self {
+            policy,
+            last_measured: None,
+            is_quiet: Box::new(|_| true),
+            vetoes: vec![],
+            observers: vec![],
+        }
+    }
+
+    /// Only run when `is_quiet` returns true for the current time, such as outside of
+    /// business hours.
+    pub fn quiet_when<F>(&mut self, is_quiet: F) -> &mut Self
+    where
+        F: Fn(SystemTime) -> bool + Send + 'static,
+    {
+        self.is_quiet = Box::new(is_quiet);
+        self
+    }
+
+    /// Ask `veto` before each compaction, which is skipped if it returns false.
+    pub fn veto_with<F>(&mut self, veto: F) -> &mut Self
+    where
+        F: FnMut(&FragmentationReport) -> bool + Send + 'static,
+    {
+        self.vetoes.push(Box::new(veto));
+        self
+    }
+
+    /// Tell `observe` about each compaction after it has run.
+    pub fn observe_with<F>(&mut self, observe: F) -> &mut Self
+    where
+        F: FnMut(&MaintenanceRun) + Send + 'static,
+    {
+        self.observers.push(Box::new(observe));
+        self
+    }
+
+    /// Compact `mv` if it is quiet, the file has not been measured in the last
+    /// `min_interval`, and it is fragmented enough, unless vetoed. Returns what was done.
+    pub fn run<T>(&mut self, mv: &mut MmapedVec<T>) -> io::Result<Option<MaintenanceRun>> {
+        if self
+            .last_measured
+            .is_some_and(|last| last.elapsed() < self.policy.min_interval)
+            || !(self.is_quiet)(SystemTime::now())
+        {
+            return Ok(None);
+        }
+
+        self.last_measured = Some(Instant::now());
+        let before = mv.fragmentation()?;
+
+        if before.zero_density() < self.policy.min_zero_density
+            && before.slack_bytes() < self.policy.min_slack_bytes
+        {
+            return Ok(None);
+        }
+        if self.vetoes.iter_mut().any(|veto| !veto(&before)) {
+            return Ok(None);
+        }
+
+        let started = Instant::now();
+        let reclaimed_bytes = mv.compact()?;
+        let run = MaintenanceRun {
+            before,
+            reclaimed_bytes,
+            duration: started.elapsed(),
+        };
+
+        for observe in self.observers.iter_mut() {
+            observe(&run);
+        }
+
+        Ok(Some(run))
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    pub fn test_maintenance_scheduler_compacts_zero_blocks() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut mv =
+            MmapedVec::<u64>::try_new(&path, EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)?;
+        mv.extend(vec![0; 64 * 1024])?;
+        mv.push(7)?;
+
+        let report = mv.fragmentation()?;
+        assert!(report.zero_density() > 0.9);
+
+        let observed = Arc::new(Mutex::new(vec![]));
+        let mut vetoing = MaintenanceScheduler::new(MaintenancePolicy::default());
+        vetoing.veto_with(|_| false);
+        assert!(vetoing.run(&mut mv)?.is_none());
+
+        let mut scheduler = MaintenanceScheduler::new(MaintenancePolicy::default());
+        let sink = Arc::clone(&observed);
+        scheduler
+            .quiet_when(|_| true)
+            .observe_with(move |run| sink.lock().unwrap().push(run.reclaimed_bytes));
+
+        let run = scheduler.run(&mut mv)?.expect("Should have compacted.");
+        assert!(run.reclaimed_bytes > 0);
+        assert_eq!(*observed.lock().unwrap(), vec![run.reclaimed_bytes]);
+        assert!(mv.fragmentation()?.hole_bytes() > 0);
+        assert_eq!((mv.len(), mv[0], mv[64 * 1024]), (64 * 1024 + 1, 0, 7));
+
+        // NOTE: Not measured again until `min_interval` has passed.
+        assert!(scheduler.run(&mut mv)?.is_none());
+
+        mv.push(8)?;
+        drop(mv);
+        let mv =
+            MmapedVec::<u64>::try_new(&path, EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)?;
+        assert_eq!(&mv[64 * 1024..], &[7, 8]);
+
+        Ok(())
+    }
+}
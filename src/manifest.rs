@@ -0,0 +1,313 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Manifest of a [`Store`](Store), recording what each of its files contains, so that a
+//! set of files can be checked against what the application expects before any of them
+//! are opened.
+//!
+//! The manifest is kept next to the files as `.manifest.toml`, in a subset of TOML:
+//!
+//! ```toml
+//! [positions]
+//! magic_bytes = "504f534954494f4e"
+//! data_contained_version = "0.1.0"
+//! fingerprint = "9f3a0c2e51d7b864"
+//! ```
+
+use crate::error::Incompatible;
+use crate::{MmapedVec, MmapedVecBuilder, Store};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+
+/// Name of the manifest file in the directory of a [`Store`](Store).
+pub const MANIFEST_FILE_NAME: &str = ".manifest.toml";
+
+/// What the manifest records about a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub magic_bytes: [u8; 8],
+    pub data_contained_version: [u8; 3],
+    /// See [`type_fingerprint`](type_fingerprint).
+    pub fingerprint: u64,
+}
+
+impl ManifestEntry {
+    /// What a file opened with `builder` to hold elements of type `T` should be recorded as.
+    pub fn of<T>(builder: &MmapedVecBuilder) -> Self {
+        Self {
+            magic_bytes: builder.magic_bytes,
+            data_contained_version: builder.data_contained_version,
+            fingerprint: type_fingerprint::<T>(),
+        }
+    }
+}
+
+/// Fingerprint of the element type `T`, from its name, size and alignment.
+///
+/// The name of a type includes the path of the module it is defined in, so moving or
+/// renaming a type changes its fingerprint, even when its layout stays the same.
+pub fn type_fingerprint<T>() -> u64 {
+    // NOTE: FNV-1a, which is stable across Rust versions, unlike the hasher of std.
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let name = std::any::type_name::<T>().as_bytes();
+    let size = (mem::size_of::<T>() as u64).to_le_bytes();
+    let align = (mem::align_of::<T>() as u64).to_le_bytes();
+    for byte in name.iter().chain(&size).chain(&align) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+impl Store {
+    fn manifest_path(&self) -> PathBuf {
+        self.dir().join(MANIFEST_FILE_NAME)
+    }
+
+    /// The entries of the manifest, by file name. Empty if there is no manifest yet.
+    pub fn manifest(&self) -> io::Result<BTreeMap<String, ManifestEntry>> {
+        let text = match fs::read_to_string(self.manifest_path()) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(e),
+        };
+
+        parse_manifest(&text).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("File `{:?}`: Malformed manifest.", self.manifest_path()),
+            )
+        })
+    }
+
+    /// Record `entry` for the file by the name of `name`, replacing any previous entry.
+    pub fn record(&self, name: &str, entry: ManifestEntry) -> io::Result<()> {
+        self.path(name)?;
+
+        let mut manifest = self.manifest()?;
+        manifest.insert(name.to_string(), entry);
+
+        let mut tmp_path = self.manifest_path().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, format_manifest(&manifest))?;
+        fs::rename(&tmp_path, self.manifest_path())
+    }
+
+    /// Check the files that the application expects, as `(name, entry)`, against the
+    /// manifest, reporting every file that would not open as expected in one
+    /// [`Incompatible`](Incompatible) error. Files that do not exist yet are fine.
+    pub fn check_compatibility(&self, expected: &[(&str, ManifestEntry)]) -> io::Result<()> {
+        let manifest = self.manifest()?;
+        let mut problems = vec![];
+
+        for (name, want) in expected {
+            let problem = match manifest.get(*name) {
+                _ if !self.path(name)?.exists() => None,
+                None => Some("Not recorded in the manifest.".to_string()),
+                Some(have) => compare(have, want),
+            };
+            if let Some(problem) = problem {
+                problems.push((name.to_string(), problem));
+            }
+        }
+
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(io::Error::other(Incompatible {
+                dir: self.dir().to_path_buf(),
+                problems,
+            })),
+        }
+    }
+
+    /// Like [`open_vec`](Store::open_vec), but checking the file against the manifest first,
+    /// and recording it in the manifest if it is not there yet.
+    pub fn open_recorded<T: Sized + Default>(
+        &self,
+        builder: &MmapedVecBuilder,
+        name: &str,
+    ) -> io::Result<MmapedVec<T>> {
+        let entry = ManifestEntry::of::<T>(builder);
+        self.check_compatibility(&[(name, entry)])?;
+
+        let mv = self.open_vec(builder, name)?;
+        if self.manifest()?.get(name) != Some(&entry) {
+            self.record(name, entry)?;
+        }
+        Ok(mv)
+    }
+}
+
+fn compare(have: &ManifestEntry, want: &ManifestEntry) -> Option<String> {
+    if have.magic_bytes != want.magic_bytes {
+        Some(format!(
+            "Magic bytes {:?} recorded, {:?} expected.",
+            have.magic_bytes, want.magic_bytes
+        ))
+    } else if have.data_contained_version != want.data_contained_version {
+        Some(format!(
+            "Data contained version {:?} recorded, {:?} expected.",
+            have.data_contained_version, want.data_contained_version
+        ))
+    } else if have.fingerprint != want.fingerprint {
+        Some(format!(
+            "Element type fingerprint {:016x} recorded, {:016x} expected.",
+            have.fingerprint, want.fingerprint
+        ))
+    } else {
+        None
+    }
+}
+
+fn format_manifest(manifest: &BTreeMap<String, ManifestEntry>) -> String {
+    let mut text = String::new();
+    for (name, entry) in manifest {
+        let magic: String = entry
+            .magic_bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let [major, minor, patch] = entry.data_contained_version;
+        text.push_str(&format!(
+            "[{}]\nmagic_bytes = \"{}\"\ndata_contained_version = \"{}.{}.{}\"\n\
+             fingerprint = \"{:016x}\"\n\n",
+            name, magic, major, minor, patch, entry.fingerprint
+        ));
+    }
+    text
+}
+
+fn parse_manifest(text: &str) -> Option<BTreeMap<String, ManifestEntry>> {
+    fn unquote(value: &str) -> Option<&str> {
+        value.strip_prefix('"')?.strip_suffix('"')
+    }
+
+    let mut manifest = BTreeMap::new();
+    let mut current: Option<(String, [Option<&str>; 3])> = None;
+
+    let mut finish = |current: Option<(String, [Option<&str>; 3])>| -> Option<()> {
+        if let Some((name, [magic, version, fingerprint])) = current {
+            let magic = magic?;
+            let mut magic_bytes = [0u8; 8];
+            if magic.len() != 16 {
+                return None;
+            }
+            for (i, byte) in magic_bytes.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(magic.get(2 * i..2 * i + 2)?, 16).ok()?;
+            }
+
+            let mut data_contained_version = [0u8; 3];
+            let mut parts = version?.split('.');
+            for part in data_contained_version.iter_mut() {
+                *part = parts.next()?.parse().ok()?;
+            }
+            if parts.next().is_some() {
+                return None;
+            }
+
+            let fingerprint = u64::from_str_radix(fingerprint?, 16).ok()?;
+
+            manifest.insert(
+                name,
+                ManifestEntry {
+                    magic_bytes,
+                    data_contained_version,
+                    fingerprint,
+                },
+            );
+        }
+        Some(())
+    };
+
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            finish(current.take())?;
+            current = Some((name.to_string(), [None; 3]));
+            continue;
+        }
+
+        let (key, value) = line.split_once('=')?;
+        let value = unquote(value.trim())?;
+        let fields = &mut current.as_mut()?.1;
+        match key.trim() {
+            "magic_bytes" => fields[0] = Some(value),
+            "data_contained_version" => fields[1] = Some(value),
+            "fingerprint" => fields[2] = Some(value),
+            _ => {}
+        }
+    }
+    finish(current.take())?;
+
+    Some(manifest)
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{Example, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+
+    #[test]
+    pub fn test_store_manifest() -> Result<(), io::Error> {
+        let dir = tempfile::tempdir()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        let store = Store::open(dir.path())?;
+
+        store.open_recorded::<Example>(&builder, "examples")?;
+        store.open_recorded::<u64>(&builder, "counts")?;
+        store.open_vec::<u8>(&builder, "unrecorded")?;
+        assert_eq!(
+            store.manifest()?.get("examples"),
+            Some(&ManifestEntry::of::<Example>(&builder))
+        );
+
+        let other = MmapedVecBuilder::new(*b"OTHER000", EXAMPLE_DATA_CONTAINED_VERSION);
+        store.check_compatibility(&[
+            ("examples", ManifestEntry::of::<Example>(&builder)),
+            ("not yet created", ManifestEntry::of::<u8>(&builder)),
+        ])?;
+        let err = store
+            .check_compatibility(&[
+                ("examples", ManifestEntry::of::<u32>(&builder)),
+                ("counts", ManifestEntry::of::<u64>(&other)),
+                ("unrecorded", ManifestEntry::of::<u8>(&builder)),
+            ])
+            .err()
+            .unwrap();
+        let incompatible = err
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<Incompatible>()
+            .unwrap();
+        let names: Vec<_> = incompatible
+            .problems
+            .iter()
+            .map(|(n, _)| n.as_str())
+            .collect();
+        assert_eq!(names, vec!["examples", "counts", "unrecorded"]);
+
+        assert!(store.open_recorded::<u32>(&builder, "examples").is_err());
+        assert_eq!(store.names()?, vec!["counts", "examples", "unrecorded"]);
+
+        Ok(())
+    }
+}
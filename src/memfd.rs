@@ -0,0 +1,82 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::{locking, MmapedVec, MmapedVecBuilder};
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+
+impl MmapedVecBuilder {
+    /// Create a [`MmapedVec`](MmapedVec) backed by an anonymous `memfd_create()` file rather
+    /// than by a file on disk.
+    ///
+    /// The memory can be shared with other processes that are handed the file descriptor,
+    /// for example across `fork()`, and its contents can be spilled to a file on disk in the
+    /// standard format with [`persist_to`](MmapedVec::persist_to). The `name` is only used
+    /// for debugging purposes, and shows up in `/proc/<pid>/fd/`.
+    pub fn try_open_memfd<T: Sized + Default>(&self, name: &str) -> io::Result<MmapedVec<T>> {
+        let c_name = CString::new(name)?;
+
+        let fd = unsafe { libc::memfd_create(c_name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        locking::try_lock_exclusive(&file, Path::new(&format!("memfd:{}", name)))?;
+
+        self.try_from_locked_file(
+            file,
+            None,
+            Path::new(&format!("memfd:{}", name)),
+            Some(T::default()),
+        )
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        tempdir_and_tempfile, Example, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES,
+    };
+
+    #[test]
+    pub fn test_memfd_persist_to_standard_file() -> Result<(), io::Error> {
+        let (_dir, pathbuf) = tempdir_and_tempfile()?;
+
+        let mut mv = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .try_open_memfd::<Example>("example")?;
+
+        mv.extend(vec![Example { hello: 3, world: 4 }, Example::default()])?;
+        mv.persist_to(pathbuf.as_path())?;
+        drop(mv);
+
+        let mv = MmapedVec::<Example>::try_new(
+            pathbuf.as_path(),
+            EXAMPLE_MAGIC_BYTES,
+            EXAMPLE_DATA_CONTAINED_VERSION,
+        )?;
+
+        assert_eq!(mv.len(), 2);
+        assert_eq!((mv[0].hello, mv[0].world), (3, 4));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,362 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Merkle-tree mode, in which each flush updates a tree of hashes of chunks of the body in
+//! a sidecar next to the file, so that part of the body can be verified without reading
+//! the rest, with [`verify_range`](MmapedVec::verify_range).
+//!
+//! The sidecar starts with `MERKLE_MAGIC`, the id of the
+//! [`ChecksumAlgorithm`](crate::ChecksumAlgorithm) and seven bytes of padding, and then
+//! the number of elements per chunk and the number of elements, as `u64`s in native byte
+//! order. Then comes the root of the tree and the hash of each chunk, in order. A parent is
+//! the hash of the hashes of its two children, and a node without a sibling is its own
+//! parent.
+
+// TODO: The sidecar is rewritten whole on each flush that changed anything; for files with
+//       very many chunks, updating the changed hashes in place would be cheaper.
+
+use crate::{locking, ChecksumAlgorithm, MmapedVec, MmapedVecBuilder};
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Suffix of the sidecar that is kept next to files opened in Merkle-tree mode.
+pub const MERKLE_SUFFIX: &str = ".merkle";
+
+pub(crate) const MERKLE_MAGIC: [u8; 8] = *b"PERSMRKL";
+const MERKLE_HEADER_LEN: usize = 32;
+
+/// Path of the sidecar of the file at `path`.
+pub fn merkle_path(path: &Path) -> PathBuf {
+    let mut merkle_path = OsString::from(path.as_os_str());
+    merkle_path.push(MERKLE_SUFFIX);
+    PathBuf::from(merkle_path)
+}
+
+/// The hashes of the chunks of a file in Merkle-tree mode as of the last flush, and which
+/// chunks were modified since.
+pub(crate) struct MerkleTree {
+    path: PathBuf,
+    algorithm: ChecksumAlgorithm,
+    chunk_elems: usize,
+    elem_size: usize,
+    len: usize,
+    leaves: Vec<Vec<u8>>,
+    dirty: Vec<bool>,
+}
+
+impl MerkleTree {
+    /// Load the sidecar of the file at `path`, whose body is `body`, or start over with
+    /// every chunk to be hashed at the next flush if there is no sidecar that fits.
+    pub(crate) fn open(
+        path: &Path,
+        algorithm: ChecksumAlgorithm,
+        chunk_elems: usize,
+        elem_size: usize,
+        body: &[u8],
+    ) -> io::Result<Self> {
+        let len = body.len() / elem_size;
+        let mut tree = Self {
+            path: merkle_path(path),
+            algorithm,
+            chunk_elems,
+            elem_size,
+            len,
+            leaves: vec![],
+            dirty: vec![true; len.div_ceil(chunk_elems)],
+        };
+
+        // NOTE: A sidecar that does not fit is replaced at the next flush. One that fits but
+        //       went stale while the file was not in Merkle-tree mode is not noticed, and
+        //       the chunks modified meanwhile fail verification.
+        let buf = match fs::read(&tree.path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(tree),
+            Err(e) => return Err(e),
+        };
+        if let Some((hashed_len, leaves)) = tree.parse(&buf)? {
+            for dirty in tree.dirty.iter_mut().take(leaves.len()) {
+                *dirty = false;
+            }
+            tree.len = hashed_len;
+            tree.leaves = leaves;
+        }
+
+        Ok(tree)
+    }
+
+    /// The number of elements hashed and the hashes of the chunks in `buf`, if it is a
+    /// sidecar for the same algorithm and chunks, and its hashes add up to its root.
+    fn parse(&self, buf: &[u8]) -> io::Result<Option<(usize, Vec<Vec<u8>>)>> {
+        let digest_len = self.algorithm.digest(&[])?.len();
+        let u64_at = |pos: usize| u64::from_ne_bytes(buf[pos..pos + 8].try_into().unwrap());
+
+        if buf.len() < MERKLE_HEADER_LEN + digest_len
+            || buf[..8] != MERKLE_MAGIC
+            || buf[8] != self.algorithm.id()
+            || u64_at(16) != self.chunk_elems as u64
+            || !(buf.len() - MERKLE_HEADER_LEN).is_multiple_of(digest_len)
+        {
+            return Ok(None);
+        }
+
+        let hashed_len = u64_at(24) as usize;
+
+        let root = &buf[MERKLE_HEADER_LEN..MERKLE_HEADER_LEN + digest_len];
+        let leaves: Vec<Vec<u8>> = buf[MERKLE_HEADER_LEN + digest_len..]
+            .chunks(digest_len)
+            .map(<[u8]>::to_vec)
+            .collect();
+
+        match leaves.len() == hashed_len.div_ceil(self.chunk_elems) && self.root(&leaves)? == root {
+            true => Ok(Some((hashed_len, leaves))),
+            false => Ok(None),
+        }
+    }
+
+    fn root(&self, leaves: &[Vec<u8>]) -> io::Result<Vec<u8>> {
+        if leaves.is_empty() {
+            return self.algorithm.digest(&[]);
+        }
+
+        let mut level = leaves.to_vec();
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => self.algorithm.digest(&[&left[..], &right[..]].concat()),
+                    [single] => Ok(single.clone()),
+                    _ => unreachable!(),
+                })
+                .collect::<io::Result<_>>()?;
+        }
+
+        Ok(level.remove(0))
+    }
+
+    /// Whether the hash of `chunk` still holds for a body of `len` elements, as far as what
+    /// has been recorded as modified goes.
+    fn is_hashed(&self, chunk: usize, len: usize) -> bool {
+        // NOTE: The last chunk of the shorter of the two lengths changed length, if partial.
+        let changed_from = match len == self.len {
+            true => usize::MAX,
+            false => len.min(self.len) / self.chunk_elems,
+        };
+
+        chunk < changed_from && !self.dirty.get(chunk).copied().unwrap_or(true)
+    }
+
+    fn chunk_bytes(&self, chunk: usize, len: usize) -> Range<usize> {
+        let elems = chunk * self.chunk_elems..((chunk + 1) * self.chunk_elems).min(len);
+        elems.start * self.elem_size..elems.end * self.elem_size
+    }
+
+    pub(crate) fn chunk_elems(&self) -> usize {
+        self.chunk_elems
+    }
+
+    /// Whether `bytes` match the hash of `chunk` as of the last flush.
+    pub(crate) fn matches(&self, chunk: usize, bytes: &[u8]) -> io::Result<bool> {
+        match self.leaves.get(chunk) {
+            Some(leaf) => Ok(self.algorithm.digest(bytes)? == *leaf),
+            None => Ok(false),
+        }
+    }
+
+    pub(crate) fn modified(&mut self, range: Range<usize>) {
+        if range.start >= range.end {
+            return;
+        }
+
+        let chunks = range.start / self.chunk_elems..(range.end - 1) / self.chunk_elems + 1;
+        if self.dirty.len() < chunks.end {
+            self.dirty.resize(chunks.end, true);
+        }
+        for dirty in &mut self.dirty[chunks] {
+            *dirty = true;
+        }
+    }
+
+    /// Hash the chunks of `body` modified since the last commit, and write the sidecar, if
+    /// anything changed.
+    pub(crate) fn commit(&mut self, body: &[u8]) -> io::Result<()> {
+        let len = body.len() / self.elem_size;
+        let chunks = len.div_ceil(self.chunk_elems);
+
+        if len == self.len && self.dirty.iter().all(|dirty| !dirty) {
+            return Ok(());
+        }
+
+        let hashed: Vec<bool> = (0..chunks)
+            .map(|chunk| self.is_hashed(chunk, len))
+            .collect();
+        self.leaves.resize(chunks, vec![]);
+        for (chunk, hashed) in hashed.into_iter().enumerate() {
+            if !hashed {
+                self.leaves[chunk] = self.algorithm.digest(&body[self.chunk_bytes(chunk, len)])?;
+            }
+        }
+        self.dirty = vec![false; chunks];
+        self.len = len;
+
+        let mut buf = MERKLE_MAGIC.to_vec();
+        buf.push(self.algorithm.id());
+        buf.extend_from_slice(&[0; 7]);
+        buf.extend_from_slice(&(self.chunk_elems as u64).to_ne_bytes());
+        buf.extend_from_slice(&(len as u64).to_ne_bytes());
+        buf.extend_from_slice(&self.root(&self.leaves)?);
+        for leaf in &self.leaves {
+            buf.extend_from_slice(leaf);
+        }
+
+        // NOTE: Written aside and renamed into place, so that a crash leaves either sidecar.
+        let mut tmp_name = OsString::from(self.path.as_os_str());
+        tmp_name.push(".tmp");
+        let tmp = PathBuf::from(tmp_name);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)?;
+        locking::try_lock_exclusive(&file, &tmp)?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+impl MmapedVecBuilder {
+    /// Keep a tree of hashes of chunks of `chunk_elems` elements in a sidecar next to the
+    /// file, updated on each flush, for [`verify_range`](MmapedVec::verify_range). Off by
+    /// default. The hashes are made with the algorithm set with
+    /// [`checksum`](MmapedVecBuilder::checksum), or else CRC32C.
+    ///
+    /// Only the chunks of elements that were marked modified are hashed anew at the next
+    /// flush. Elements written through dereferencing the [`MmapedVec`](MmapedVec) mutably
+    /// must be marked with [`mark_modified`](MmapedVec::mark_modified), or else they fail
+    /// verification after the flush. The methods of the [`MmapedVec`](MmapedVec) and its
+    /// [`WriteGuard`](crate::WriteGuard) mark what they write.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_elems` is zero.
+    pub fn merkle_tree(&mut self, chunk_elems: usize) -> &mut Self {
+        assert!(chunk_elems > 0, "Chunks must hold at least one element.");
+        self.merkle_tree = Some(chunk_elems);
+        self
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// The chunks overlapping the elements in `range` that do not match their hashes as of
+    /// the last flush, as ranges of elements, in order. Only those chunks are read.
+    ///
+    /// Chunks marked modified since the last flush are not verified. After opening a file
+    /// that was not closed cleanly, this tells exactly which chunks were written after the
+    /// last flush, or are otherwise corrupt.
+    ///
+    /// Fails with [`Unsupported`](io::ErrorKind::Unsupported) unless opened with
+    /// [`merkle_tree`](MmapedVecBuilder::merkle_tree).
+    pub fn verify_range(&self, range: Range<usize>) -> io::Result<Vec<Range<usize>>> {
+        let tree = self.merkle.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("File `{:?}`: Not opened in Merkle-tree mode.", self.path),
+            )
+        })?;
+
+        let len = self.len();
+        let body = &self.mm[self.header_len..self.header_len + len * mem::size_of::<T>()];
+        let range = range.start.min(len)..range.end.min(len);
+        let mut corrupt = vec![];
+        if range.start >= range.end {
+            return Ok(corrupt);
+        }
+
+        for chunk in range.start / tree.chunk_elems..(range.end - 1) / tree.chunk_elems + 1 {
+            if !tree.is_hashed(chunk, len) {
+                continue;
+            }
+            let digest = tree.algorithm.digest(&body[tree.chunk_bytes(chunk, len)])?;
+            if digest != tree.leaves[chunk] {
+                let start = chunk * tree.chunk_elems;
+                corrupt.push(start..(start + tree.chunk_elems).min(len));
+            }
+        }
+
+        Ok(corrupt)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use std::os::unix::fs::FileExt;
+
+    #[test]
+    pub fn test_merkle_tree_verify_range() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        builder.merkle_tree(4);
+
+        let mut mv = builder.try_open::<u32>(&path)?;
+        mv.extend(0..10)?;
+        assert!(mv.verify_range(0..10)?.is_empty());
+        let header_len = mv.header_len;
+        mv.close()?;
+        assert!(merkle_path(&path).exists());
+
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.write_all_at(&[9], (header_len + 5 * 4) as u64)?;
+
+        let mut mv = builder.try_open::<u32>(&path)?;
+        assert_eq!(mv.verify_range(0..10)?, vec![4..8]);
+        assert!(mv.verify_range(0..4)?.is_empty());
+
+        mv.push(10)?;
+        assert!(mv.verify_range(8..11)?.is_empty());
+        mv[5] = 5;
+        mv.mark_modified(5..6);
+        mv.flush()?;
+        assert!(mv.verify_range(0..11)?.is_empty());
+
+        mv.write_guard()?[1] = 100;
+        assert!(mv.verify_range(0..11)?.is_empty());
+        mv.flush()?;
+        assert!(mv.verify_range(0..11)?.is_empty());
+
+        // NOTE: Writes that are not marked modified are not hashed, and so fail verification.
+        mv[9] = 900;
+        mv.flush()?;
+        assert_eq!(mv.verify_range(0..11)?, vec![8..11]);
+        mv.mark_modified(9..10);
+        mv.flush()?;
+        assert!(mv.verify_range(0..11)?.is_empty());
+        drop(mv);
+
+        let mv = builder.try_open::<u32>(&path)?;
+        assert_eq!((mv[1], mv[9]), (100, 900));
+        assert!(mv.verify_range(0..11)?.is_empty());
+
+        Ok(())
+    }
+}
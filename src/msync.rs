@@ -0,0 +1,381 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::{MmapedVec, MmapedVecBuilder};
+use memmap::MmapMut;
+use std::io;
+use std::mem;
+use std::ops::Range;
+use std::path::Path;
+
+/// How [`flush`](MmapedVec::flush) writes modifications of the mapping back to the file,
+/// set with [`flush_mode`](MmapedVecBuilder::flush_mode) or passed to
+/// [`flush_with`](MmapedVec::flush_with).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushMode {
+    /// `msync` with `MS_SYNC`, returning once the modifications are on disk. This is the
+    /// default.
+    #[default]
+    Sync,
+    /// `msync` with `MS_ASYNC`, scheduling write-back and returning at once. Nothing is
+    /// durable when it returns, but it is cheap to do often.
+    Async,
+    /// `msync` with `MS_SYNC | MS_INVALIDATE`, which also invalidates other mappings of the
+    /// file, for platforms where they are not kept coherent with the page cache.
+    SyncInvalidate,
+}
+
+/// In which order a synchronous [`flush`](MmapedVec::flush) makes the header and the body
+/// durable, set with [`flush_order`](MmapedVecBuilder::flush_order).
+///
+/// This is the contract for formats layered on top, for the [`FlushMode`](FlushMode)s that
+/// wait for the disk: with [`DataFirst`](FlushOrder::DataFirst), once anything written to
+/// the header by a flush is on disk, so is everything written to the body before that
+/// flush, so the header can be used as a commit record for the body. With
+/// [`HeaderFirst`](FlushOrder::HeaderFirst), it is the other way around, for headers that
+/// record intent before the body is written. The length of the file is file system metadata
+/// and is made durable along with the body by growing and shrinking, not by flushing.
+///
+/// With [`FlushMode::Async`](FlushMode::Async), write-back happens in whatever order the
+/// kernel pleases, and no order is guaranteed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushOrder {
+    /// The body, and then the header. This is the default, except on hosts with pages larger
+    /// than the header, where it is [`Unordered`](FlushOrder::Unordered).
+    #[default]
+    DataFirst,
+    /// The header, and then the body.
+    HeaderFirst,
+    /// Both at once, in a single `msync`, which is the cheapest.
+    Unordered,
+}
+
+impl MmapedVecBuilder {
+    /// In which order a synchronous flush makes the header and the body durable. See
+    /// [`FlushOrder`](FlushOrder).
+    ///
+    /// `msync` works on whole pages, so the header and the body can only be flushed apart
+    /// where the header fills whole pages. The header is padded to a multiple of 4096 bytes,
+    /// which on hosts with larger pages may leave it sharing a page with the body, and
+    /// opening such a file with an order other than [`Unordered`](FlushOrder::Unordered)
+    /// fails with [`InvalidInput`](io::ErrorKind::InvalidInput).
+    pub fn flush_order(&mut self, flush_order: FlushOrder) -> &mut Self {
+        self.flush_order = Some(flush_order);
+        self
+    }
+
+    /// The flush order to use for a file whose header is `header_len` bytes long.
+    pub(crate) fn flush_order_for(&self, path: &Path, header_len: usize) -> io::Result<FlushOrder> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        resolve_flush_order(self.flush_order, header_len, page_size).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "File `{:?}`: Its header of {} bytes shares a page of {} bytes with the \
+          body, so they cannot be flushed in order. Use FlushOrder::Unordered.",
+                    path, header_len, page_size
+                ),
+            )
+        })
+    }
+
+    /// How [`flush`](MmapedVec::flush) writes back modifications. See [`FlushMode`](FlushMode).
+    ///
+    /// This also applies to the flush made when closing or dropping a
+    /// [`MmapedVec`](MmapedVec), so with [`FlushMode::Async`](FlushMode::Async), call
+    /// [`flush_with`](MmapedVec::flush_with) before closing if the contents must be durable.
+    pub fn flush_mode(&mut self, flush_mode: FlushMode) -> &mut Self {
+        self.flush_mode = flush_mode;
+        self
+    }
+}
+
+/// The order that was asked for, or the default, unless the header shares a page with the
+/// body, which only [`Unordered`](FlushOrder::Unordered) allows.
+pub(crate) fn resolve_flush_order(
+    requested: Option<FlushOrder>,
+    header_len: usize,
+    page_size: usize,
+) -> Option<FlushOrder> {
+    let shares_page = !header_len.is_multiple_of(page_size);
+    match (requested, shares_page) {
+        (None, false) => Some(FlushOrder::DataFirst),
+        (None, true) | (Some(FlushOrder::Unordered), _) => Some(FlushOrder::Unordered),
+        (Some(order), false) => Some(order),
+        (Some(_), true) => None,
+    }
+}
+
+/// `msync` the header and the body of `mm`, whose body starts `header_len` bytes in, in
+/// `order`.
+pub(crate) fn msync(
+    mm: &MmapMut,
+    header_len: usize,
+    mode: FlushMode,
+    order: FlushOrder,
+) -> io::Result<()> {
+    let (header, body) = (0..header_len, header_len..mm.len());
+    let ranges = match (mode, order) {
+        (FlushMode::Async, _) | (_, FlushOrder::Unordered) => [0..mm.len(), 0..0],
+        (_, FlushOrder::DataFirst) => [body, header],
+        (_, FlushOrder::HeaderFirst) => [header, body],
+    };
+
+    for range in ranges.iter().filter(|range| !range.is_empty()) {
+        msync_range(mm, mode, range.clone())?;
+    }
+    Ok(())
+}
+
+/// `msync` the bytes of `mm` in `range`, which must start at a page boundary.
+fn msync_range(mm: &MmapMut, mode: FlushMode, range: Range<usize>) -> io::Result<()> {
+    match mode {
+        FlushMode::Sync => mm.flush_range(range.start, range.len()),
+        FlushMode::Async => mm.flush_async_range(range.start, range.len()),
+        FlushMode::SyncInvalidate => {
+            let flags = libc::MS_SYNC | libc::MS_INVALIDATE;
+            let ptr = unsafe { mm.as_ptr().add(range.start) } as *mut libc::c_void;
+            match unsafe { libc::msync(ptr, range.len(), flags) } {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        }
+    }
+}
+
+impl<T> MmapedVec<T> {
+    pub(crate) fn msync(&self, mode: FlushMode) -> io::Result<()> {
+        msync(&self.mm, self.header_len, mode, self.flush_order)
+    }
+
+    /// Flush modifications of the mapping to disk and make them durable, with `fdatasync`
+    /// rather than `fsync` as long as the file has not changed size since it was last made
+    /// durable this way, so that metadata that is not needed to read the data back, such as
+    /// the modification time, is not synced along with it.
+    ///
+    /// The first call, and the first call after the file has grown or shrunk, use `fsync`.
+    pub fn flush_data_only(&mut self) -> io::Result<()> {
+        self.flush_with(FlushMode::Sync)?;
+
+        let len_bytes = self.mm.len() as u64;
+        match self.synced_len_bytes == len_bytes {
+            true => self.file.sync_data()?,
+            false => {
+                self.file.sync_all()?;
+                self.synced_len_bytes = len_bytes;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Make every write made so far durable before returning, so that no write made after it
+    /// returns can reach the disk before them.
+    ///
+    /// The page cache writes back dirty pages in whatever order it pleases, so without a
+    /// barrier, a crash can leave a later write on disk while an earlier one is lost. This is
+    /// the primitive for commit protocols built on top, such as writing data and then a
+    /// pointer to it:
+    ///
+    /// ```no_run
+    /// # use persistence::MmapedVec;
+    /// # fn commit(mv: &mut MmapedVec<u64>, data: &[u64]) -> std::io::Result<()> {
+    /// mv.extend(data.iter().copied())?;
+    /// mv.barrier()?;
+    /// // The data is durable, so a crash can not leave the pointer pointing to garbage.
+    /// mv[0] = mv.len() as u64;
+    /// mv.barrier()
+    /// # }
+    /// ```
+    ///
+    /// It is [`flush_data_only`](MmapedVec::flush_data_only) by another name, which waits for
+    /// both `msync` and `fdatasync` (or `fsync`) to complete. It does not order writes with
+    /// respect to readers of the mapping; see [`OptimisticReader`](crate::OptimisticReader)
+    /// for that.
+    pub fn barrier(&mut self) -> io::Result<()> {
+        self.flush_data_only()
+    }
+
+    /// Whether the next [`flush_data_only`](MmapedVec::flush_data_only) can get away with
+    /// `fdatasync`.
+    pub fn is_size_synced(&self) -> bool {
+        self.synced_len_bytes == self.mm.len() as u64
+    }
+
+    /// Start writing back the elements in `range`, and if `wait` is set, wait until they are
+    /// written, without syncing the rest of the file.
+    ///
+    /// On Linux this uses `sync_file_range`, which does not sync the metadata of the file nor
+    /// flush the write cache of the disk, so it does not make anything durable on its own; it
+    /// lets write-back be spread out over time ahead of a [`flush`](MmapedVec::flush), which
+    /// then has less left to do. Elsewhere, the range is `msync`ed instead.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is out of bounds.
+    pub fn write_back(&mut self, range: Range<usize>, wait: bool) -> io::Result<()> {
+        self.check_poisoned()?;
+
+        let len = self.len();
+        assert!(
+            range.start <= range.end && range.end <= len,
+            "Range {}..{} out of bounds for MmapedVec of length {}.",
+            range.start,
+            range.end,
+            len
+        );
+
+        let size = mem::size_of::<T>();
+        let offset = self.header_len + range.start * size;
+        let nbytes = (range.end - range.start) * size;
+
+        if nbytes == 0 {
+            return Ok(());
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            let flags = match wait {
+                true => {
+                    libc::SYNC_FILE_RANGE_WAIT_BEFORE
+                        | libc::SYNC_FILE_RANGE_WRITE
+                        | libc::SYNC_FILE_RANGE_WAIT_AFTER
+                }
+                false => libc::SYNC_FILE_RANGE_WRITE,
+            };
+            let fd = self.file.as_raw_fd();
+            match unsafe {
+                libc::sync_file_range(fd, offset as libc::off64_t, nbytes as libc::off64_t, flags)
+            } {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            match wait {
+                true => self.mm.flush_range(offset, nbytes),
+                false => self.mm.flush_async_range(offset, nbytes),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+
+    #[test]
+    pub fn test_flush_modes_and_write_back() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut mv = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .flush_mode(FlushMode::Async)
+            .try_open::<u64>(&path)?;
+        mv.extend(0..1000)?;
+
+        mv.write_back(10..500, false)?;
+        mv.write_back(0..1000, true)?;
+        mv.flush()?;
+        mv.flush_with(FlushMode::SyncInvalidate)?;
+        mv.flush_with(FlushMode::Sync)?;
+        drop(mv);
+
+        let mv =
+            MmapedVec::<u64>::try_new(&path, EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)?;
+        assert_eq!(mv.len(), 1000);
+        assert_eq!(mv[999], 999);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_flush_data_only_escalates_after_growth() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut mv =
+            MmapedVec::<u64>::try_new(&path, EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)?;
+        assert!(!mv.is_size_synced());
+
+        mv.flush_data_only()?;
+        assert!(mv.is_size_synced());
+
+        mv.push(1)?;
+        assert!(!mv.is_size_synced());
+        mv.flush_data_only()?;
+        assert!(mv.is_size_synced());
+
+        mv[0] = 2;
+        mv.flush_data_only()?;
+        assert!(mv.is_size_synced());
+
+        mv.extend([3, 4])?;
+        mv.barrier()?;
+        assert!(mv.is_size_synced());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_flush_orders() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+
+        for (i, order) in [
+            FlushOrder::DataFirst,
+            FlushOrder::HeaderFirst,
+            FlushOrder::Unordered,
+        ]
+        .iter()
+        .enumerate()
+        {
+            let path = path.with_extension(i.to_string());
+            let mut mv = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+                .flush_order(*order)
+                .try_open::<u64>(&path)?;
+            mv.flush()?;
+            mv.extend([1, 2, 3])?;
+            mv.flush_with(FlushMode::SyncInvalidate)?;
+            mv.flush_with(FlushMode::Async)?;
+            drop(mv);
+
+            let mv = MmapedVec::<u64>::try_new(
+                &path,
+                EXAMPLE_MAGIC_BYTES,
+                EXAMPLE_DATA_CONTAINED_VERSION,
+            )?;
+            assert_eq!(&mv[..], &[1, 2, 3]);
+        }
+
+        // NOTE: With 16 KiB pages, a header of 4096 bytes shares its page with the body.
+        let resolve = |order, header_len| resolve_flush_order(order, header_len, 16384);
+        assert_eq!(resolve(None, 16384), Some(FlushOrder::DataFirst));
+        assert_eq!(resolve(None, 4096), Some(FlushOrder::Unordered));
+        assert_eq!(
+            resolve(Some(FlushOrder::HeaderFirst), 16384),
+            Some(FlushOrder::HeaderFirst)
+        );
+        assert_eq!(resolve(Some(FlushOrder::DataFirst), 4096), None);
+        assert_eq!(resolve(Some(FlushOrder::HeaderFirst), 4096), None);
+        assert_eq!(
+            resolve(Some(FlushOrder::Unordered), 4096),
+            Some(FlushOrder::Unordered)
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Elements padded out to whole cache lines, so that threads or processes that each write
+//! to their own elements do not contend for the same cache lines.
+
+use crate::format::EXTENSION_TAG_STRIDE;
+use crate::{MmapedVec, MmapedVecBuilder};
+use std::convert::TryInto;
+use std::io;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+
+/// Length of a cache line on the platforms we expect to run on, in bytes.
+pub const CACHE_LINE_LEN: usize = 64;
+
+/// An element padded out to a multiple of [`CACHE_LINE_LEN`](CACHE_LINE_LEN) bytes, and
+/// aligned to it, so that in a [`MmapedVec`](MmapedVec), each element starts a cache line.
+///
+/// The slice that the vector dereferences to steps over the padding, so `mv[i].0` or
+/// `*mv[i]` is the `i`th element either way. Open files of padded elements with
+/// [`try_open_padded`](MmapedVecBuilder::try_open_padded) to have the stride recorded in
+/// the header.
+// NOTE: The alignment must match CACHE_LINE_LEN; repr(align) does not take constants.
+#[repr(C, align(64))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct CachePadded<T>(pub T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> From<T> for CachePadded<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+fn stride_value<T>() -> [u8; 16] {
+    let mut value = [0u8; 16];
+    value[..8].copy_from_slice(&(mem::size_of::<CachePadded<T>>() as u64).to_ne_bytes());
+    value[8..].copy_from_slice(&(mem::size_of::<T>() as u64).to_ne_bytes());
+    value
+}
+
+impl MmapedVecBuilder {
+    /// Like [`try_open`](MmapedVecBuilder::try_open), but for elements padded out to whole
+    /// cache lines, recording the stride and the size of the element within it in the header
+    /// of new files, and checking it against `T` for existing ones.
+    pub fn try_open_padded<T: Sized + Default>(
+        &self,
+        path: &Path,
+    ) -> io::Result<MmapedVec<CachePadded<T>>> {
+        let mut mv = self.try_open::<CachePadded<T>>(path)?;
+
+        match mv.header_extension(EXTENSION_TAG_STRIDE) {
+            None => mv.rewrite_extensions(EXTENSION_TAG_STRIDE, Some(&stride_value::<T>()))?,
+            Some(value) if value == stride_value::<T>() => {}
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "File `{:?}`: Elements are padded, but not from elements of {} bytes.",
+                        path,
+                        mem::size_of::<T>()
+                    ),
+                ))
+            }
+        }
+
+        Ok(mv)
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// The stride and the size of the element within it, if the elements are padded.
+    pub fn padded_stride(&self) -> Option<(usize, usize)> {
+        let value = self.header_extension(EXTENSION_TAG_STRIDE)?;
+        let field = |range: std::ops::Range<usize>| {
+            u64::from_ne_bytes(value.get(range)?.try_into().ok()?)
+                .try_into()
+                .ok()
+        };
+        Some((field(0..8)?, field(8..16)?))
+    }
+
+    /// Check that elements of type `T` match the stride recorded in the header, if any.
+    pub(crate) fn check_stride(&self) -> io::Result<()> {
+        match self.padded_stride() {
+            Some((stride, _)) if stride != mem::size_of::<T>() => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "File `{:?}`: Elements are padded to {} bytes, but are of {} bytes.",
+                    self.path,
+                    stride,
+                    mem::size_of::<T>()
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+
+    #[test]
+    pub fn test_padded_elements() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let mut mv = builder.try_open_padded::<u32>(&path)?;
+        mv.extend((0..10).map(CachePadded))?;
+        assert_eq!(mem::size_of_val(&mv[0]), CACHE_LINE_LEN);
+        assert_eq!(*mv[7], 7);
+        assert_eq!(mv.padded_stride(), Some((CACHE_LINE_LEN, 4)));
+        drop(mv);
+
+        let mv = builder.try_open_padded::<u32>(&path)?;
+        assert_eq!(mv.len(), 10);
+        assert_eq!(mv[9].0, 9);
+        drop(mv);
+
+        assert!(builder.try_open_padded::<u64>(&path).is_err());
+        assert!(builder.try_open::<[u8; 32]>(&path).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::MmapedVec;
+use std::io;
+use std::mem;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Keeps the mapping of a [`MmapedVec`](MmapedVec) where it is for as long as it is held,
+/// so that the pointer to the body can be handed to code outside of Rust's view, such as
+/// FFI or device drivers.
+///
+/// While any pins are held, growing or shrinking the vector fails with
+/// [`ResourceBusy`](io::ErrorKind::ResourceBusy) instead of remapping, and if the vector
+/// is dropped, its mapping is leaked rather than unmapped. [Rolling](MmapedVec::roll) the
+/// file retires the mapping until the pins are dropped. Elements may still be modified
+/// in place.
+///
+/// A pin does not give access to the elements through references, since the vector can
+/// still be used to modify them; it only hands out the pointer and the length.
+pub struct PinnedSlice<T> {
+    ptr: *const T,
+    len: usize,
+    mapping_generation: u64,
+    pins: Arc<AtomicUsize>,
+}
+
+impl<T> PinnedSlice<T> {
+    pub fn as_ptr(&self) -> *const T {
+        self.ptr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The mapping generation that the pointer belongs to.
+    pub fn mapping_generation(&self) -> u64 {
+        self.mapping_generation
+    }
+}
+
+impl<T> Drop for PinnedSlice<T> {
+    fn drop(&mut self) {
+        self.pins.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// The bytes of a range of elements, kept in place by a pin on the mapping, as made by
+/// [`pin_bytes`](MmapedVec::pin_bytes).
+///
+/// Unlike a [`PinnedSlice`](PinnedSlice), this hands out the bytes as a slice, and is
+/// `Send`, `Sync` and `'static`, so that it can own the memory of buffers that are passed
+/// around without copying, such as `bytes::Bytes::from_owner()` for the bodies of HTTP
+/// responses. With the `bytes` feature, it converts into `bytes::Bytes` directly.
+pub struct PinnedBytes {
+    ptr: *const u8,
+    len: usize,
+    pins: Arc<AtomicUsize>,
+}
+
+// NOTE: The mapping stays valid for as long as it is pinned, even once the MmapedVec is
+//       dropped or rolled, and pin_bytes() requires that the bytes are not modified.
+unsafe impl Send for PinnedBytes {}
+unsafe impl Sync for PinnedBytes {}
+
+impl AsRef<[u8]> for PinnedBytes {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl PinnedBytes {
+    /// Hand the bytes over to `bytes::Bytes`, which holds on to the pin until the last of
+    /// its clones and slices is dropped.
+    pub fn into_bytes(self) -> bytes::Bytes {
+        bytes::Bytes::from_owner(self)
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl From<PinnedBytes> for bytes::Bytes {
+    fn from(pinned: PinnedBytes) -> Self {
+        pinned.into_bytes()
+    }
+}
+
+impl Drop for PinnedBytes {
+    fn drop(&mut self) {
+        self.pins.fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// Counter that is increased every time the file is remapped, which moves the body to
+    /// a different address. Raw pointers into the body that were taken in an earlier
+    /// generation must not be used.
+    pub fn mapping_generation(&self) -> u64 {
+        self.mapping_generation
+    }
+
+    /// Pin the mapping, preventing remaps until the returned guard is dropped.
+    pub fn pin(&self) -> PinnedSlice<T> {
+        self.pins.fetch_add(1, Ordering::Acquire);
+
+        PinnedSlice {
+            ptr: self.as_ptr(),
+            len: self.len(),
+            mapping_generation: self.mapping_generation,
+            pins: Arc::clone(&self.pins),
+        }
+    }
+
+    /// Pin the mapping, and hand out the bytes of the elements in `range`, for serving them
+    /// without copying.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is out of bounds.
+    ///
+    /// # Safety
+    ///
+    /// The elements in `range` must not be modified for as long as the returned
+    /// [`PinnedBytes`](PinnedBytes), or anything made from it, is around.
+    pub unsafe fn pin_bytes(&self, range: Range<usize>) -> PinnedBytes {
+        assert!(
+            range.start <= range.end && range.end <= self.len(),
+            "Range {}..{} out of bounds for length {}.",
+            range.start,
+            range.end,
+            self.len()
+        );
+
+        self.pins.fetch_add(1, Ordering::Acquire);
+
+        let size = mem::size_of::<T>();
+        PinnedBytes {
+            ptr: (self.as_ptr() as *const u8).add(range.start * size),
+            len: (range.end - range.start) * size,
+            pins: Arc::clone(&self.pins),
+        }
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.pins.load(Ordering::Acquire) > 0
+    }
+
+    pub(crate) fn check_not_pinned(&self) -> io::Result<()> {
+        match self.pins.load(Ordering::Acquire) {
+            0 => Ok(()),
+            n => Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!(
+                    "File `{:?}`: The mapping is pinned by {} PinnedSlice(s), so it cannot be \
+          remapped.",
+                    self.path, n
+                ),
+            )),
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        new_mmaped_vec_of_example_persisting_in_tempdir, tempdir_and_tempfile, Example,
+        EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES,
+    };
+    use crate::MmapedVecBuilder;
+
+    #[test]
+    pub fn test_pinned_slice_prevents_remap() -> Result<(), io::Error> {
+        let (_dir, _pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        let generation = mv.mapping_generation();
+        mv.push(Example { hello: 1, world: 2 })?;
+        assert_eq!(mv.mapping_generation(), generation + 1);
+
+        let pinned = mv.pin();
+        assert_eq!(pinned.len(), 1);
+        assert_eq!(pinned.mapping_generation(), mv.mapping_generation());
+
+        let err = mv.push(Example { hello: 3, world: 4 }).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        assert!(mv.truncate(0).is_err());
+        assert_eq!(mv.len(), 1);
+
+        mv[0].hello = 5;
+        assert_eq!(unsafe { (*pinned.as_ptr()).hello }, 5);
+
+        drop(mv);
+        assert_eq!(unsafe { (*pinned.as_ptr()).hello }, 5);
+        drop(pinned);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_pinned_bytes_outlive_the_vec() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+
+        let mut mv: MmapedVec<u32> =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+                .try_open(&path)?;
+        mv.extend(0..10)?;
+
+        let bytes = unsafe { mv.pin_bytes(2..4) };
+        assert!(mv.is_pinned());
+        assert!(mv.push(10).is_err());
+
+        let served = std::thread::spawn(move || bytes.as_ref().to_vec())
+            .join()
+            .unwrap();
+        assert_eq!(served, [2u32.to_ne_bytes(), 3u32.to_ne_bytes()].concat());
+        assert!(!mv.is_pinned());
+
+        let bytes = unsafe { mv.pin_bytes(9..10) };
+        drop(mv);
+        assert_eq!(bytes.as_ref(), 9u32.to_ne_bytes());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    pub fn test_pinned_bytes_into_bytes() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+
+        let mut mv: MmapedVec<u32> =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+                .try_open(&path)?;
+        mv.extend(0..10)?;
+
+        let bytes = bytes::Bytes::from(unsafe { mv.pin_bytes(2..4) });
+        let tail = bytes.slice(4..);
+        drop(bytes);
+        assert!(mv.is_pinned());
+        assert_eq!(tail, &3u32.to_ne_bytes()[..]);
+        drop(tail);
+        assert!(!mv.is_pinned());
+
+        let bytes = unsafe { mv.pin_bytes(9..10) }.into_bytes();
+        drop(mv);
+        assert_eq!(bytes, &9u32.to_ne_bytes()[..]);
+
+        Ok(())
+    }
+}
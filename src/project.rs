@@ -0,0 +1,207 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Views of a single field of each element, for scans over data laid out as an array of
+//! structs that only need one of the fields.
+
+use crate::MmapedVec;
+use std::iter::FusedIterator;
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+
+/// A view of the field of type `F` at the same offset in each element of a
+/// [`MmapedVec`](MmapedVec), as made by [`project`](MmapedVec::project) or the
+/// [`project!`](crate::project!) macro.
+///
+/// Fields are read by copying them out, unaligned, so that fields of packed structs can be
+/// projected too. A scan reads only the cache lines that hold the field, though with
+/// elements smaller than a cache line that is still every one of them.
+#[derive(Clone, Copy)]
+pub struct StridedView<'a, F> {
+    base: *const u8,
+    len: usize,
+    stride: usize,
+    _marker: PhantomData<&'a F>,
+}
+
+impl<T> MmapedVec<T> {
+    /// View the field of type `F` at `offset` bytes into each element, as given by
+    /// `std::mem::offset_of!`. The [`project!`](crate::project!) macro works out both from
+    /// the name of the field.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the field would extend past the end of the element.
+    ///
+    /// # Safety
+    ///
+    /// The bytes at `offset` in every element must be a valid `F`.
+    pub unsafe fn project<F: Copy>(&self, offset: usize) -> StridedView<'_, F> {
+        assert!(
+            offset
+                .checked_add(mem::size_of::<F>())
+                .is_some_and(|end| end <= mem::size_of::<T>()),
+            "Field at offset {} of {} bytes extends past the element of {} bytes.",
+            offset,
+            mem::size_of::<F>(),
+            mem::size_of::<T>()
+        );
+
+        StridedView {
+            base: self.mm.as_ptr().add(self.header_len + offset),
+            len: self.len(),
+            stride: mem::size_of::<T>(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Used by the [`project!`](crate::project!) macro, with `field` only there to infer
+    /// the type of the field.
+    ///
+    /// # Safety
+    ///
+    /// `offset` must be the offset of the field that `field` reads, as the macro makes sure.
+    #[doc(hidden)]
+    pub unsafe fn project_field<F: Copy>(
+        &self,
+        offset: usize,
+        _field: fn(&T) -> F,
+    ) -> StridedView<'_, F> {
+        self.project(offset)
+    }
+}
+
+/// View a field of each element of a [`MmapedVec`](MmapedVec) by name, as a
+/// [`StridedView`](StridedView).
+///
+/// ```
+/// # use persistence::{project, MmapedVecBuilder};
+/// #[derive(Clone, Copy, Default)]
+/// #[repr(C)]
+/// struct Trade {
+///     price: f64,
+///     volume: u32,
+/// }
+///
+/// # let dir = tempfile::tempdir()?;
+/// # let path = dir.path().join("trades.bin");
+/// let mut trades = MmapedVecBuilder::new(*b"TRADES\0\0", [0, 1, 0]).try_open(&path)?;
+/// trades.push(Trade { price: 1.5, volume: 10 })?;
+/// trades.push(Trade { price: 2.0, volume: 20 })?;
+///
+/// let volume: u32 = project!(trades, Trade, volume).iter().sum();
+/// assert_eq!(volume, 30);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[macro_export]
+macro_rules! project {
+    ($mv:expr, $T:ty, $field:ident) => {{
+        // NOTE: Evaluated outside of the unsafe block, so that it cannot hide unsafe code.
+        let mv = &$mv;
+        // SAFETY: The offset is that of the field that the closure reads, of type `F`.
+        unsafe { mv.project_field(::core::mem::offset_of!($T, $field), |e: &$T| e.$field) }
+    }};
+}
+
+impl<'a, F: Copy> StridedView<'a, F> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copy out the field of the element at `index`, or `None` if out of bounds.
+    pub fn get(&self, index: usize) -> Option<F> {
+        match index < self.len {
+            true => {
+                Some(unsafe { ptr::read_unaligned(self.base.add(index * self.stride) as *const F) })
+            }
+            false => None,
+        }
+    }
+
+    pub fn iter(&self) -> StridedIter<'a, F> {
+        StridedIter {
+            view: *self,
+            next: 0,
+        }
+    }
+}
+
+impl<'a, F: Copy> IntoIterator for StridedView<'a, F> {
+    type Item = F;
+    type IntoIter = StridedIter<'a, F>;
+
+    fn into_iter(self) -> StridedIter<'a, F> {
+        self.iter()
+    }
+}
+
+/// Iterator over the fields in a [`StridedView`](StridedView).
+pub struct StridedIter<'a, F> {
+    view: StridedView<'a, F>,
+    next: usize,
+}
+
+impl<'a, F: Copy> Iterator for StridedIter<'a, F> {
+    type Item = F;
+
+    fn next(&mut self) -> Option<F> {
+        let field = self.view.get(self.next)?;
+        self.next += 1;
+        Some(field)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.view.len - self.next;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, F: Copy> ExactSizeIterator for StridedIter<'a, F> {}
+
+impl<'a, F: Copy> FusedIterator for StridedIter<'a, F> {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{new_mmaped_vec_of_example_persisting_in_tempdir, Example};
+    use memoffset::offset_of;
+    use std::io;
+
+    #[test]
+    pub fn test_project_field_of_packed_struct() -> Result<(), io::Error> {
+        let (_dir, _path, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+        mv.extend((0..10).map(|i| Example {
+            hello: i,
+            world: 2 * i,
+        }))?;
+
+        let worlds = project!(mv, Example, world);
+        assert_eq!(worlds.len(), 10);
+        assert_eq!(worlds.get(3), Some(6));
+        assert_eq!(worlds.get(10), None);
+        assert!(worlds.iter().eq((0..10).map(|i| 2 * i)));
+
+        let hellos: StridedView<u8> = unsafe { mv.project(offset_of!(Example, hello)) };
+        assert_eq!(hellos.into_iter().map(u32::from).sum::<u32>(), 45);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Proof harnesses for [Kani](https://github.com/model-checking/kani), which check the
+//! claims that crash consistency rests on for all inputs up to a bound, rather than for
+//! those that the tests happen to try. Run them with `cargo kani`, which sets `cfg(kani)`.
+//!
+//! The harnesses cover the parts of the commit protocols that are plain computation: how
+//! a log is encoded, parsed and replayed, and how the length of a file is repaired after a
+//! crash. The order of the writes and syncs around them is covered by the crash harness of
+//! the `testing` feature.
+
+use crate::recovery::partial_bytes;
+use crate::wal::{encode_commit, parse, replay_extent, Record, WAL_HEADER_LEN, WAL_MAGIC};
+use std::path::Path;
+
+const ELEM_SIZE: usize = 2;
+
+fn log_header() -> Vec<u8> {
+    let mut buf = WAL_MAGIC.to_vec();
+    buf.extend_from_slice(&(ELEM_SIZE as u64).to_ne_bytes());
+    buf
+}
+
+/// Parsing any log, however corrupt, neither panics nor yields records past its end.
+#[kani::proof]
+#[kani::unwind(42)]
+fn parse_stays_in_bounds() {
+    let tail: [u8; 24] = kani::any();
+    let tail_len: usize = kani::any();
+    kani::assume(tail_len <= tail.len());
+
+    let mut buf = log_header();
+    buf.extend_from_slice(&tail[..tail_len]);
+
+    let (records, committed) = parse(&buf, ELEM_SIZE, Path::new("proof")).unwrap();
+    assert!(WAL_HEADER_LEN <= committed && committed <= buf.len());
+    for record in &records {
+        if let Record::Data { bytes, .. } = record {
+            assert!(bytes.start <= bytes.end && bytes.end <= buf.len());
+        }
+    }
+}
+
+/// A log cut off anywhere after a complete commit, as by a crash while appending the next,
+/// is committed up to the end of that commit, and no further, unless the next is complete.
+#[kani::proof]
+#[kani::unwind(80)]
+fn torn_commit_is_discarded() {
+    let body: [u8; 2 * ELEM_SIZE] = kani::any();
+    let generation: u64 = kani::any();
+    let start: usize = kani::any();
+    let end: usize = kani::any();
+    kani::assume(generation < u64::MAX);
+    kani::assume(start <= end && end <= 2);
+
+    let mut buf = log_header();
+    buf.extend(encode_commit(&body, ELEM_SIZE, vec![0..2], 2, generation));
+    let first = buf.len();
+    buf.extend(encode_commit(
+        &body,
+        ELEM_SIZE,
+        vec![start..end],
+        2,
+        generation + 1,
+    ));
+    let full = buf.len();
+
+    let cut: usize = kani::any();
+    kani::assume(first <= cut && cut <= full);
+    buf.truncate(cut);
+
+    let (records, committed) = parse(&buf, ELEM_SIZE, Path::new("proof")).unwrap();
+    match cut == full {
+        true => assert_eq!(committed, full),
+        false => assert_eq!(committed, first),
+    }
+    let last_commit = records.iter().rev().find_map(|record| match record {
+        Record::Commit { generation, len } => Some((*generation, *len)),
+        _ => None,
+    });
+    match cut == full {
+        true => assert_eq!(last_commit, Some((generation + 1, 2))),
+        false => assert_eq!(last_commit, Some((generation, 2))),
+    }
+}
+
+/// Replaying a commit of a whole body reconstructs the body.
+#[kani::proof]
+#[kani::unwind(40)]
+fn replay_reconstructs_body() {
+    let body: [u8; 2 * ELEM_SIZE] = kani::any();
+    let len: usize = kani::any();
+    kani::assume(len <= 2);
+
+    let mut buf = log_header();
+    buf.extend(encode_commit(&body, ELEM_SIZE, vec![0..len], len, 0));
+    let (records, _) = parse(&buf, ELEM_SIZE, Path::new("proof")).unwrap();
+
+    let mut replayed = vec![0u8; replay_extent(&records, ELEM_SIZE).unwrap()];
+    let mut replayed_len = 0;
+    for record in &records {
+        match record {
+            Record::Data { start, bytes } => replayed
+                [start * ELEM_SIZE..start * ELEM_SIZE + bytes.len()]
+                .copy_from_slice(&buf[bytes.clone()]),
+            Record::Commit { len, .. } => replayed_len = *len,
+            _ => {}
+        }
+    }
+
+    assert_eq!(replayed_len, len);
+    assert_eq!(replayed[..len * ELEM_SIZE], body[..len * ELEM_SIZE]);
+}
+
+/// The extent of a replay covers every record, and is `None` rather than wrapping around
+/// for records beyond what can be addressed.
+#[kani::proof]
+fn replay_extent_covers_records() {
+    let start: usize = kani::any();
+    let n: usize = kani::any();
+    let len: usize = kani::any();
+    let records = [
+        Record::Data { start, bytes: 0..n },
+        Record::Commit { generation: 0, len },
+    ];
+
+    if let Some(extent) = replay_extent(&records, ELEM_SIZE) {
+        assert!(start * ELEM_SIZE + n <= extent);
+        assert!(len * ELEM_SIZE <= extent);
+    }
+}
+
+/// Repairing the length of a file after a crash cuts off less than one element, and leaves
+/// a body of whole elements.
+#[kani::proof]
+fn repair_keeps_whole_elements() {
+    let flen: u64 = kani::any();
+    let header_len: u64 = kani::any();
+    let elem_size: u64 = kani::any();
+    kani::assume(elem_size > 0);
+
+    let partial = partial_bytes(flen, header_len, elem_size);
+    assert!(partial < elem_size);
+    if flen >= header_len {
+        assert_eq!((flen - partial - header_len) % elem_size, 0);
+    } else {
+        assert_eq!(partial, 0);
+    }
+}
@@ -0,0 +1,262 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! A persistent queue: a [`MmapedVec`](MmapedVec) that is only appended to, read by any
+//! number of named consumers whose acknowledged offsets are kept in a second file next to
+//! it, so that after a crash of the producer or of a consumer, each consumer resumes after
+//! what it last acknowledged.
+
+// TODO: Elements that every consumer has acknowledged are never dropped, so the queue grows
+//       without bound. Rolling the file once all consumers are past its end would fix that.
+
+use crate::{MmapedVec, MmapedVecBuilder};
+use std::ffi::OsString;
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// Suffix of the file of consumer offsets that is kept next to the file of a queue.
+pub const CONSUMERS_SUFFIX: &str = ".consumers";
+
+const CONSUMERS_MAGIC_BYTES: [u8; 8] = *b"PERSCONS";
+const CONSUMERS_DATA_VERSION: [u8; 3] = [0, 1, 0];
+
+/// Longest name of a consumer, in bytes.
+pub const MAX_CONSUMER_NAME_LEN: usize = 56;
+
+/// Path of the file of consumer offsets of the queue at `path`.
+pub fn consumers_path(path: &Path) -> PathBuf {
+    let mut consumers_path = OsString::from(path.as_os_str());
+    consumers_path.push(CONSUMERS_SUFFIX);
+    PathBuf::from(consumers_path)
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ConsumerSlot {
+    /// Name of the consumer, padded with zeros.
+    name: [u8; MAX_CONSUMER_NAME_LEN],
+    acked: u64,
+}
+
+impl Default for ConsumerSlot {
+    fn default() -> Self {
+        Self {
+            name: [0; MAX_CONSUMER_NAME_LEN],
+            acked: 0,
+        }
+    }
+}
+
+impl ConsumerSlot {
+    fn name(&self) -> &[u8] {
+        let len = self
+            .name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(self.name.len());
+        &self.name[..len]
+    }
+}
+
+/// A queue of elements of type `T` with named consumers, opened with
+/// [`try_open_queue`](MmapedVecBuilder::try_open_queue).
+///
+/// Elements are delivered at least once: a consumer that crashes after handling elements
+/// but before [acknowledging](MmapedQueue::ack) them is handed them again, but nothing it
+/// has acknowledged.
+pub struct MmapedQueue<T> {
+    log: MmapedVec<T>,
+    consumers: MmapedVec<ConsumerSlot>,
+}
+
+impl MmapedVecBuilder {
+    /// Open or create the queue at `path`, with the options of this builder, and its file of
+    /// consumer offsets next to it.
+    pub fn try_open_queue<T: Sized + Default>(&self, path: &Path) -> io::Result<MmapedQueue<T>> {
+        let log = self.try_open(path)?;
+        let consumers = MmapedVecBuilder::new(CONSUMERS_MAGIC_BYTES, CONSUMERS_DATA_VERSION)
+            .same_file_policy(self.same_file_policy)
+            .try_open(&consumers_path(path))?;
+
+        Ok(MmapedQueue { log, consumers })
+    }
+}
+
+impl<T> MmapedQueue<T> {
+    /// The elements of the queue, acknowledged by all consumers or not.
+    pub fn log(&self) -> &MmapedVec<T> {
+        &self.log
+    }
+
+    pub fn push(&mut self, value: T) -> io::Result<()> {
+        self.log.push(value)
+    }
+
+    pub fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) -> io::Result<()> {
+        self.log.extend(iter)
+    }
+
+    /// Make the elements pushed so far durable.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.log.flush()
+    }
+
+    fn slot(&self, name: &str) -> Option<usize> {
+        self.consumers
+            .iter()
+            .position(|slot| slot.name() == name.as_bytes())
+    }
+
+    fn slot_or_err(&self, name: &str) -> io::Result<usize> {
+        self.slot(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("File `{:?}`: No consumer {:?}.", self.log.path, name),
+            )
+        })
+    }
+
+    /// Register the consumer by the name of `name`, if it is not already, starting at the
+    /// first element. Returns the offset it has acknowledged up to.
+    pub fn consumer(&mut self, name: &str) -> io::Result<usize> {
+        if let Some(i) = self.slot(name) {
+            return Ok(self.consumers[i].acked as usize);
+        }
+
+        if name.is_empty() || name.len() > MAX_CONSUMER_NAME_LEN || name.contains('\0') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "File `{:?}`: Consumer names are 1 to {} bytes, without NUL.",
+                    self.log.path, MAX_CONSUMER_NAME_LEN
+                ),
+            ));
+        }
+
+        let mut slot = ConsumerSlot::default();
+        slot.name[..name.len()].copy_from_slice(name.as_bytes());
+        self.consumers.push(slot)?;
+        self.consumers.flush()?;
+        Ok(0)
+    }
+
+    /// The consumers and the offsets they have acknowledged up to.
+    pub fn consumers(&self) -> Vec<(String, usize)> {
+        self.consumers
+            .iter()
+            .map(|slot| {
+                let name = String::from_utf8_lossy(slot.name()).into_owned();
+                (name, slot.acked as usize)
+            })
+            .collect()
+    }
+
+    /// Forget the consumer by the name of `name`.
+    pub fn remove_consumer(&mut self, name: &str) -> io::Result<()> {
+        let i = self.slot_or_err(name)?;
+        let last = self.consumers.len() - 1;
+        self.consumers.swap(i, last);
+        self.consumers.truncate(last)?;
+        self.consumers.flush()
+    }
+
+    /// Up to `max` elements that the consumer by the name of `name` has not acknowledged,
+    /// starting with the first of them.
+    pub fn poll(&self, name: &str, max: usize) -> io::Result<&[T]> {
+        let start = self.consumers[self.slot_or_err(name)?].acked as usize;
+        let end = self.log.len().min(start.saturating_add(max));
+        Ok(&self.log[start.min(end)..end])
+    }
+
+    /// Record that the consumer by the name of `name` is done with the elements before
+    /// `offset`, durably, so that they are not delivered to it again.
+    ///
+    /// The elements are made durable first, so that an acknowledged offset never points
+    /// past what survives a crash. Acknowledging an offset before the current one is an
+    /// error, as is one past the end of the queue.
+    pub fn ack(&mut self, name: &str, offset: usize) -> io::Result<()> {
+        let i = self.slot_or_err(name)?;
+        let acked = self.consumers[i].acked as usize;
+
+        if offset < acked || offset > self.log.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "File `{:?}`: Consumer {:?} cannot acknowledge offset {}, being at {} of {}.",
+                    self.log.path,
+                    name,
+                    offset,
+                    acked,
+                    self.log.len()
+                ),
+            ));
+        }
+
+        self.log.flush()?;
+        self.consumers[i].acked = offset as u64;
+        self.consumers.mark_modified(i..i + 1);
+        self.consumers.flush()
+    }
+}
+
+impl<T> Deref for MmapedQueue<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.log
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+
+    #[test]
+    pub fn test_queue_consumers_resume_after_reopen() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let mut queue = builder.try_open_queue::<u64>(&path)?;
+        queue.extend(0..10)?;
+        assert_eq!(queue.consumer("indexer")?, 0);
+        assert_eq!(queue.consumer("mailer")?, 0);
+
+        assert_eq!(queue.poll("indexer", 4)?, &[0, 1, 2, 3]);
+        queue.ack("indexer", 4)?;
+        assert!(queue.ack("indexer", 3).is_err());
+        assert!(queue.ack("indexer", 11).is_err());
+        assert!(queue.poll("nobody", 1).is_err());
+        drop(queue);
+
+        let mut queue = builder.try_open_queue::<u64>(&path)?;
+        assert_eq!(
+            queue.consumers(),
+            vec![("indexer".to_string(), 4), ("mailer".to_string(), 0)]
+        );
+        assert_eq!(queue.poll("indexer", 100)?, &[4, 5, 6, 7, 8, 9]);
+        assert_eq!(queue.poll("mailer", 2)?, &[0, 1]);
+
+        queue.remove_consumer("indexer")?;
+        assert_eq!(queue.consumers(), vec![("mailer".to_string(), 0)]);
+        assert!(queue
+            .consumer(&"x".repeat(MAX_CONSUMER_NAME_LEN + 1))
+            .is_err());
+
+        Ok(())
+    }
+}
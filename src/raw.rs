@@ -0,0 +1,268 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Interop with files that hold nothing but elements back to back, as read and written by
+//! `numpy.fromfile()` and `ndarray.tofile()`, or mapped directly from C.
+
+use crate::format::{self, FileHeader, FILE_HEADER_LEN};
+use crate::{check_element_type, locking, registry, MmapedVec, MmapedVecBuilder};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+impl<T: Sized + Default> MmapedVec<T> {
+    /// Convert the headerless file of elements at `path` into a file in the format of this
+    /// library, and open it. See [`adopt_raw`](MmapedVecBuilder::adopt_raw).
+    pub fn adopt_raw(
+        path: &Path,
+        magic_bytes: [u8; 8],
+        data_contained_version: [u8; 3],
+    ) -> io::Result<Self> {
+        MmapedVecBuilder::new(magic_bytes, data_contained_version).adopt_raw(path)
+    }
+}
+
+impl MmapedVecBuilder {
+    /// Convert the file at `path`, which holds nothing but elements of type `T` back to back
+    /// in native byte order, into a file in the format of this library by putting a header
+    /// in front of them, and open it.
+    ///
+    /// Where the file system supports `fallocate()` with `FALLOC_FL_INSERT_RANGE`, the header
+    /// is inserted in place, without copying the elements. Elsewhere, the elements are
+    /// copied to a temporary file next to `path` behind the header, which is then renamed
+    /// into place.
+    ///
+    /// Fails with [`AlreadyExists`](io::ErrorKind::AlreadyExists) if the file already starts
+    /// with a header of this builder's kind.
+    pub fn adopt_raw<T: Sized + Default>(&self, path: &Path) -> io::Result<MmapedVec<T>> {
+        check_element_type::<T>(path)?;
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        registry::check_not_open(&file, path)?;
+        locking::try_lock_exclusive(&file, path)?;
+
+        let flen = file.metadata()?.len();
+        if !flen.is_multiple_of(mem::size_of::<T>() as u64) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "File `{:?}`: Length {} is not a multiple of the size of the elements.",
+                    path, flen
+                ),
+            ));
+        }
+        if flen >= FILE_HEADER_LEN as u64
+            && FileHeader::read_from(&file)?.magic_bytes == self.magic_bytes
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("File `{:?}`: Already has a header.", path),
+            ));
+        }
+
+        let fh = FileHeader::new::<T>(self.magic_bytes, self.data_contained_version, true);
+
+        let file = match insert_range(&file, fh.header_len, flen)? {
+            true => {
+                // NOTE: A crash before the header is written leaves the file with zeros in
+                //       front of the elements, which is not a valid header, so the file will
+                //       not open, but the elements are still there to adopt again from
+                //       `header_len` bytes in.
+                write_header::<T>(&file, &fh)?;
+                file.sync_all()?;
+                file
+            }
+            false => copy_behind_header::<T>(&file, path, &fh)?,
+        };
+
+        self.try_from_locked_file(file, None, path, None)
+    }
+}
+
+/// Insert `len` bytes of zeros at the start of `file`, whose length is `flen`, returning
+/// whether the file system supports doing so.
+fn insert_range(file: &File, len: u64, flen: u64) -> io::Result<bool> {
+    if flen == 0 {
+        file.set_len(len)?;
+        return Ok(true);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let ret = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_INSERT_RANGE,
+                0,
+                len as libc::off_t,
+            )
+        };
+        if ret == 0 {
+            return Ok(true);
+        }
+
+        // NOTE: EINVAL is also what file systems that support it return when the length is
+        //       not a multiple of their block size.
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) | Some(libc::ENOSYS) => Ok(false),
+            _ => Err(e),
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = (file, len);
+        Ok(false)
+    }
+}
+
+fn write_header<T: Default>(file: &File, fh: &FileHeader) -> io::Result<()> {
+    file.write_all_at(&fh.to_bytes(), 0)?;
+    file.write_all_at(
+        format::as_bytes(&T::default()),
+        fh.default_data_offset as u64,
+    )
+}
+
+/// Copy the contents of `file` behind a header in a temporary file, and rename that over
+/// `path`, returning the temporary file, locked.
+fn copy_behind_header<T: Default>(file: &File, path: &Path, fh: &FileHeader) -> io::Result<File> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut tmp_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)?;
+
+    let result = locking::try_lock_exclusive(&tmp_file, &tmp_path)
+        .and_then(|_| write_header::<T>(&tmp_file, fh))
+        .and_then(|_| tmp_file.set_len(fh.header_len))
+        .and_then(|_| tmp_file.seek(SeekFrom::Start(fh.header_len)))
+        .and_then(|_| {
+            let mut body = file;
+            body.seek(SeekFrom::Start(0))?;
+            io::copy(&mut body, &mut tmp_file)
+        })
+        .and_then(|_| tmp_file.sync_all())
+        .and_then(|_| fs::rename(&tmp_path, path));
+
+    match result {
+        Ok(()) => Ok(tmp_file),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            Err(e)
+        }
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// Write the elements to `path` back to back in native byte order, with no header, for
+    /// tools that expect flat arrays. Turn such a file back into one in the format of this
+    /// library with [`adopt_raw`](MmapedVecBuilder::adopt_raw).
+    ///
+    /// Like with [`persist_to`](MmapedVec::persist_to), the elements are first written to a
+    /// temporary file next to `path`, which is then renamed into place.
+    pub fn export_raw(&self, path: &Path) -> io::Result<()> {
+        self.check_poisoned()?;
+
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut tmp_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+
+        let result = tmp_file
+            .write_all(&self.mm[self.header_len..])
+            .and_then(|_| tmp_file.sync_all())
+            .and_then(|_| fs::rename(&tmp_path, path));
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+
+        result
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+
+    #[test]
+    pub fn test_adopt_raw_file() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+
+        let raw: Vec<u8> = (0..5000u64).flat_map(|i| i.to_ne_bytes()).collect();
+        fs::write(&path, &raw)?;
+
+        let mv: MmapedVec<u64> =
+            MmapedVec::adopt_raw(&path, EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)?;
+        assert_eq!(mv.len(), 5000);
+        assert!(mv.iter().copied().eq(0..5000));
+        assert_eq!(mv.default_data(), Some(&0));
+        drop(mv);
+
+        let mv: MmapedVec<u64> =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+                .try_open(&path)?;
+        assert_eq!(mv[4999], 4999);
+        drop(mv);
+
+        let again =
+            MmapedVec::<u64>::adopt_raw(&path, EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        assert!(matches!(again, Err(e) if e.kind() == io::ErrorKind::AlreadyExists));
+
+        fs::write(&path, [0u8; 7])?;
+        assert!(MmapedVec::<u64>::adopt_raw(
+            &path,
+            EXAMPLE_MAGIC_BYTES,
+            EXAMPLE_DATA_CONTAINED_VERSION
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_export_raw() -> Result<(), io::Error> {
+        let (dir, path) = tempdir_and_tempfile()?;
+        let raw_path = dir.path().join("raw.bin");
+
+        let mut mv: MmapedVec<u32> =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+                .try_open(&path)?;
+        mv.extend(0..100)?;
+        mv.export_raw(&raw_path)?;
+
+        let raw: Vec<u8> = (0..100u32).flat_map(|i| i.to_ne_bytes()).collect();
+        assert_eq!(fs::read(&raw_path)?, raw);
+        assert!(!dir.path().join("raw.bin.tmp").exists());
+
+        Ok(())
+    }
+}
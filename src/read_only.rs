@@ -0,0 +1,158 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Read-only access to files that cannot be opened for writing, such as those on read-only
+//! file systems.
+
+use crate::error::ReadOnlyFileSystem;
+use crate::{check_element_type, check_mappable, locking, registry, MmapedVec, MmapedVecBuilder};
+use memmap::Mmap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::slice;
+
+/// The elements of an existing file, mapped read-only, as returned by
+/// [`try_open_read_only`](MmapedVecBuilder::try_open_read_only).
+///
+/// Takes a shared lock on the file, or on its lock file if it has one, so there can be many
+/// readers at once, but not while a [`MmapedVec`](MmapedVec) has the file open.
+pub struct ReadOnlyVec<T> {
+    path: PathBuf,
+    // NOTE: Must be dropped before the file, like the mapping of a MmapedVec.
+    mm: Mmap,
+    // NOTE: Holds the lock, unless the file has a lock file.
+    _file: File,
+    _lock_file: Option<File>,
+    header_len: usize,
+    _marker: PhantomData<T>,
+}
+
+/// A file opened by [`try_open_or_read_only`](MmapedVecBuilder::try_open_or_read_only),
+/// for writing if it could be, or else read-only.
+// NOTE: Not boxed, as files are mostly opened for writing.
+#[allow(clippy::large_enum_variant)]
+pub enum Opened<T> {
+    Writable(MmapedVec<T>),
+    ReadOnly(ReadOnlyVec<T>),
+}
+
+impl MmapedVecBuilder {
+    /// Open an existing file for reading only, which works on read-only file systems too.
+    pub fn try_open_read_only<T>(&self, path: &Path) -> io::Result<ReadOnlyVec<T>> {
+        check_element_type::<T>(path)?;
+
+        let file = OpenOptions::new().read(true).open(path)?;
+        let lock_file = match registry::is_open_for_sharing(&file, path, self.same_file_policy)? {
+            true => None,
+            false => locking::try_lock_shared_for(&file, path)?,
+        };
+
+        let fh = self.check_existing_file::<T, _>(&file, path)?;
+        check_mappable(path, file.metadata()?.len())?;
+        let mm = unsafe { Mmap::map(&file)? };
+
+        Ok(ReadOnlyVec {
+            path: path.to_path_buf(),
+            mm,
+            _file: file,
+            _lock_file: lock_file,
+            header_len: fh.header_len as usize,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Like [`try_open`](MmapedVecBuilder::try_open), but falling back to
+    /// [`try_open_read_only`](MmapedVecBuilder::try_open_read_only) if the file is on a
+    /// read-only file system. Files that do not exist yet cannot be created there, so
+    /// opening those still fails with [`ReadOnlyFileSystem`](ReadOnlyFileSystem).
+    pub fn try_open_or_read_only<T: Sized + Default>(&self, path: &Path) -> io::Result<Opened<T>> {
+        match self.try_open(path) {
+            Ok(mv) => Ok(Opened::Writable(mv)),
+            Err(e) if e.get_ref().is_some_and(|e| e.is::<ReadOnlyFileSystem>()) => {
+                match self.try_open_read_only(path) {
+                    Ok(rv) => Ok(Opened::ReadOnly(rv)),
+                    Err(e2) if e2.kind() == io::ErrorKind::NotFound => Err(e),
+                    Err(e2) => Err(e2),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<T> ReadOnlyVec<T> {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl<T> Deref for ReadOnlyVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        let len = (self.mm.len() - self.header_len) / mem::size_of::<T>();
+        unsafe { slice::from_raw_parts(self.mm.as_ptr().add(self.header_len) as *const T, len) }
+    }
+}
+
+impl<T> Opened<T> {
+    /// Whether the file was opened for reading only.
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, Opened::ReadOnly(_))
+    }
+}
+
+impl<T> Deref for Opened<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Opened::Writable(mv) => mv,
+            Opened::ReadOnly(rv) => rv,
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+
+    #[test]
+    pub fn test_read_only() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let mut mv: MmapedVec<u64> = builder.try_open(&path)?;
+        mv.extend([1, 2, 3])?;
+        drop(mv);
+
+        let rv: ReadOnlyVec<u64> = builder.try_open_read_only(&path)?;
+        assert_eq!(rv[..], [1, 2, 3]);
+        assert!(builder.try_open::<u64>(&path).is_err());
+        drop(rv);
+
+        let opened: Opened<u64> = builder.try_open_or_read_only(&path)?;
+        assert!(!opened.is_read_only());
+        assert_eq!(opened[..], [1, 2, 3]);
+
+        Ok(())
+    }
+}
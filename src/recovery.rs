@@ -0,0 +1,186 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::format::{FileHeader, FILE_HEADER_LEN, FLAG_DIRTY, OFFSET_FLAGS};
+use crate::wal;
+use crate::{MmapedVec, MmapedVecBuilder};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// What was found, and repaired, when opening a file that was not closed cleanly.
+///
+/// Since the file is locked while open, finding it marked dirty when taking the lock means
+/// that whoever had it open before crashed, or was killed, before closing it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Bytes of a partially written element at the end of the body that were cut off, with
+    /// [`repair_after_crash`](MmapedVecBuilder::repair_after_crash).
+    pub truncated_bytes: u64,
+    /// Bytes of elements written back from the [log](MmapedVecBuilder::wal) of the file,
+    /// along with the length as of the last flush. Elements modified in place since then,
+    /// without being logged, may still hold those modifications.
+    pub replayed_bytes: u64,
+}
+
+impl RecoveryReport {
+    pub fn repaired(&self) -> bool {
+        self.truncated_bytes > 0 || self.replayed_bytes > 0
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// The report of the checks made when opening the file, if it had not been closed
+    /// cleanly. The elements are intact as far as those checks go, but any element may still
+    /// hold a write that was cut short, so check what matters to you as well.
+    pub fn recovered_from_crash(&self) -> Option<&RecoveryReport> {
+        self.recovery.as_ref()
+    }
+
+    /// Mark the file as open, or as cleanly closed, durably.
+    pub(crate) fn set_dirty(&self, dirty: bool) -> io::Result<()> {
+        set_dirty(&self.file, dirty)
+    }
+}
+
+pub(crate) fn set_dirty(file: &File, dirty: bool) -> io::Result<()> {
+    let mut flags = FileHeader::read_from(file)?.flags;
+    match dirty {
+        true => flags |= FLAG_DIRTY,
+        false => flags &= !FLAG_DIRTY,
+    }
+
+    file.write_all_at(&flags.to_ne_bytes(), OFFSET_FLAGS as u64)?;
+    file.sync_data()
+}
+
+impl MmapedVecBuilder {
+    /// Cut off a partially written element at the end of the body when opening a file that
+    /// was not closed cleanly, rather than refusing to open it. Off by default, so that
+    /// nothing is thrown away without asking for it.
+    pub fn repair_after_crash(&mut self, repair_after_crash: bool) -> &mut Self {
+        self.repair_after_crash = repair_after_crash;
+        self
+    }
+
+    /// Check an existing file at `path` that we hold the lock on for signs of a crash, before
+    /// it is validated, repairing what we have been asked to, and replaying its log.
+    pub(crate) fn recover(
+        &self,
+        file: &File,
+        path: &Path,
+        elem_size: usize,
+        elem_align: usize,
+    ) -> io::Result<Option<RecoveryReport>> {
+        let flen = file.metadata()?.len();
+        if flen < FILE_HEADER_LEN as u64 {
+            return Ok(None);
+        }
+
+        let fh_file = FileHeader::read_from(file)?;
+        if !fh_file.is_dirty() {
+            return Ok(None);
+        }
+
+        let mut report = RecoveryReport::default();
+
+        // NOTE: Anything else wrong with the header is left for validation to report.
+        let header_len = FileHeader::with_layout(
+            fh_file.magic_bytes,
+            fh_file.data_contained_version,
+            elem_size,
+            elem_align,
+            fh_file.has_default_data(),
+        )
+        .header_len;
+
+        if self.repair_after_crash {
+            let partial = partial_bytes(flen, header_len, elem_size as u64);
+            if partial > 0 {
+                file.set_len(flen - partial)?;
+                report.truncated_bytes = partial;
+            }
+        }
+
+        // NOTE: A file whose header does not match is refused by validation, and is better
+        //       left as it is than written to where the body would start otherwise.
+        if fh_file.header_len == header_len {
+            report.replayed_bytes = wal::recover(path, file, header_len, elem_size)?;
+        }
+
+        // NOTE: A checksum is only stored when closing cleanly, so there is none here that
+        //       could be verified; any is stale, and is replaced when the file is closed.
+
+        Ok(Some(report))
+    }
+}
+
+/// Bytes of a partially written element at the end of a body of `flen - header_len` bytes.
+pub(crate) fn partial_bytes(flen: u64, header_len: u64, elem_size: u64) -> u64 {
+    match flen > header_len {
+        true => (flen - header_len) % elem_size,
+        false => 0,
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        new_mmaped_vec_of_example_persisting_in_tempdir, Example, EXAMPLE_DATA_CONTAINED_VERSION,
+        EXAMPLE_MAGIC_BYTES,
+    };
+    use std::fs::OpenOptions;
+
+    #[test]
+    pub fn test_recovery_after_crash() -> Result<(), io::Error> {
+        let (_dir, pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+        mv.push(Example { hello: 1, world: 2 })?;
+        assert_eq!(mv.recovered_from_crash(), None);
+        assert!(FileHeader::read_from(&File::open(&pathbuf)?)?.is_dirty());
+        drop(mv);
+        assert!(!FileHeader::read_from(&File::open(&pathbuf)?)?.is_dirty());
+
+        // Simulate a crash in the middle of appending an element.
+        let file = OpenOptions::new().read(true).write(true).open(&pathbuf)?;
+        set_dirty(&file, true)?;
+        file.set_len(file.metadata()?.len() + 1)?;
+        drop(file);
+
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        assert!(builder.try_open::<Example>(&pathbuf).is_err());
+
+        let mv = builder
+            .repair_after_crash(true)
+            .try_open::<Example>(&pathbuf)?;
+        let report = mv.recovered_from_crash().unwrap();
+        assert!(report.repaired());
+        assert_eq!(report.truncated_bytes, 1);
+        assert_eq!(mv.len(), 1);
+        drop(mv);
+
+        assert_eq!(
+            builder
+                .try_open::<Example>(&pathbuf)?
+                .recovered_from_crash(),
+            None
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Process-wide registry of the files that are open as a [`MmapedVec`](crate::MmapedVec),
+//! keyed by device and inode, so that opening a file that is already open in this process,
+//! possibly through another path, is handled according to a [`SameFilePolicy`] rather than
+//! failing on the lock.
+
+use crate::{MmapedVec, MmapedVecBuilder};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+/// A [`MmapedVec`](MmapedVec) that can be shared within the process, as opened with
+/// [`try_open_shared`](MmapedVecBuilder::try_open_shared).
+pub type SharedMmapedVec<T> = Arc<Mutex<MmapedVec<T>>>;
+
+/// What to do when opening a file that is already open in this process, set with
+/// [`same_file_policy`](MmapedVecBuilder::same_file_policy).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameFilePolicy {
+    /// Fail with [`ResourceBusy`](io::ErrorKind::ResourceBusy). This is the default.
+    Error,
+    /// Let [`try_open_windowed`](MmapedVecBuilder::try_open_windowed) read the file without
+    /// taking a lock, since the lock is held by this process anyway. It is then up to you
+    /// not to shrink the file while it is being read.
+    SharedRead,
+    /// Make [`try_open_shared`](MmapedVecBuilder::try_open_shared) return another reference
+    /// to the handle that already has the file open, if it was opened in the same way and
+    /// with the same element type. The same way means with the same options, other than
+    /// those that only matter while opening: this one,
+    /// [`follow_symlinks`](MmapedVecBuilder::follow_symlinks),
+    /// [`repair_after_crash`](MmapedVecBuilder::repair_after_crash) and
+    /// [`version_policy`](MmapedVecBuilder::version_policy).
+    Clone,
+}
+
+type FileId = (u64, u64);
+
+struct Entry {
+    path: PathBuf,
+    /// The handle opened with [`try_open_shared`](MmapedVecBuilder::try_open_shared), and
+    /// the builder that it was opened with.
+    shared: Option<(Weak<dyn Any + Send + Sync>, MmapedVecBuilder)>,
+}
+
+static OPEN_FILES: Mutex<BTreeMap<FileId, Entry>> = Mutex::new(BTreeMap::new());
+
+fn file_id(file: &File) -> io::Result<FileId> {
+    let meta = file.metadata()?;
+    Ok((meta.dev(), meta.ino()))
+}
+
+fn already_open(path: &Path, open_as: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ResourceBusy,
+        format!(
+            "File `{:?}` is already open in this process, as `{:?}`.",
+            path, open_as
+        ),
+    )
+}
+
+/// Fail if the file is registered as open.
+pub(crate) fn check_not_open(file: &File, path: &Path) -> io::Result<()> {
+    let id = file_id(file)?;
+    match OPEN_FILES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&id)
+    {
+        Some(entry) => Err(already_open(path, &entry.path)),
+        None => Ok(()),
+    }
+}
+
+/// Whether the file is registered as open, for readers that may share it as per `policy`.
+pub(crate) fn is_open_for_sharing(
+    file: &File,
+    path: &Path,
+    policy: SameFilePolicy,
+) -> io::Result<bool> {
+    let id = file_id(file)?;
+    match OPEN_FILES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&id)
+    {
+        Some(_) if policy == SameFilePolicy::SharedRead => Ok(true),
+        Some(entry) => Err(already_open(path, &entry.path)),
+        None => Ok(false),
+    }
+}
+
+/// Entry in the registry, which is removed again when dropped.
+pub(crate) struct Registration {
+    id: FileId,
+}
+
+impl Registration {
+    pub(crate) fn new(file: &File, path: &Path) -> io::Result<Self> {
+        let id = file_id(file)?;
+        let mut open_files = OPEN_FILES.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(entry) = open_files.get(&id) {
+            return Err(already_open(path, &entry.path));
+        }
+
+        open_files.insert(
+            id,
+            Entry {
+                path: path.to_path_buf(),
+                shared: None,
+            },
+        );
+        Ok(Self { id })
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        OPEN_FILES
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
+}
+
+impl MmapedVecBuilder {
+    /// Like [`try_open`](MmapedVecBuilder::try_open), but returning a handle that can be
+    /// shared between threads, and that with [`SameFilePolicy::Clone`](SameFilePolicy::Clone)
+    /// is handed out again when the same file is opened anew, through any path.
+    pub fn try_open_shared<T: Sized + Default + Send + 'static>(
+        &self,
+        path: &Path,
+    ) -> io::Result<SharedMmapedVec<T>> {
+        // NOTE: Held throughout, so that two threads opening the same file at once do not
+        //       both find it closed and then have one of them fail on the lock.
+        static OPENING: Mutex<()> = Mutex::new(());
+        let _opening = OPENING.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Ok(file) = OpenOptions::new().read(true).open(path) {
+            let id = file_id(&file)?;
+            let open_files = OPEN_FILES.lock().unwrap_or_else(|e| e.into_inner());
+
+            if let Some(entry) = open_files.get(&id) {
+                let shared = match (self.same_file_policy, &entry.shared) {
+                    (SameFilePolicy::Clone, Some((shared, builder))) => {
+                        if !self.opens_alike(builder) {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!(
+                                    "File `{:?}` is already open in this process, as `{:?}`, \
+          with other options.",
+                                    path, entry.path
+                                ),
+                            ));
+                        }
+                        shared.upgrade()
+                    }
+                    _ => None,
+                };
+
+                return match shared.map(|shared| shared.downcast::<Mutex<MmapedVec<T>>>()) {
+                    Some(Ok(mv)) => Ok(mv),
+                    Some(Err(_)) => Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "File `{:?}` is already open in this process, as `{:?}`, \
+          with another element type.",
+                            path, entry.path
+                        ),
+                    )),
+                    None => Err(already_open(path, &entry.path)),
+                };
+            }
+        }
+
+        let mv = Arc::new(Mutex::new(self.try_open::<T>(path)?));
+
+        let id = mv.lock().unwrap_or_else(|e| e.into_inner()).registration.id;
+        let shared: Arc<dyn Any + Send + Sync> = mv.clone();
+        if let Some(entry) = OPEN_FILES
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_mut(&id)
+        {
+            entry.shared = Some((Arc::downgrade(&shared), self.clone()));
+        }
+
+        Ok(mv)
+    }
+
+    /// Whether handles opened with this builder and `other` would behave alike, leaving out
+    /// the options that only matter while opening.
+    fn opens_alike(&self, other: &Self) -> bool {
+        let format = |b: &Self| {
+            (
+                b.magic_bytes,
+                b.data_contained_version,
+                b.checksum,
+                b.header_hints,
+                b.lock_file,
+            )
+        };
+        let handle = |b: &Self| {
+            (
+                (
+                    b.max_len_bytes,
+                    b.max_elements,
+                    b.check_free_space,
+                    b.preallocate,
+                ),
+                (b.protected_access, b.harden, b.flush_mode, b.flush_order),
+                (
+                    b.track_modifications,
+                    b.wal,
+                    b.wal_history,
+                    b.merkle_tree,
+                    b.direct_io,
+                ),
+            )
+        };
+        format(self) == format(other) && handle(self) == handle(other)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        new_mmaped_vec_of_example_persisting_in_tempdir, tempdir_and_tempfile, Example,
+        EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES,
+    };
+
+    #[test]
+    pub fn test_symlinks_and_same_file_detection() -> Result<(), io::Error> {
+        let (dir, pathbuf, mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&pathbuf, &link)?;
+
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        let err = builder.try_open::<Example>(&link).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        assert!(err.to_string().contains("already open in this process"));
+
+        let err = builder
+            .follow_symlinks(false)
+            .try_open::<Example>(&link)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        drop(mv);
+        builder.try_open::<Example>(&pathbuf)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_same_file_policies() -> Result<(), io::Error> {
+        let (dir, pathbuf) = tempdir_and_tempfile()?;
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&pathbuf, &link)?;
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let shared = builder.try_open_shared::<Example>(&pathbuf)?;
+        shared
+            .lock()
+            .unwrap()
+            .push(Example { hello: 1, world: 2 })?;
+        assert!(builder.try_open_shared::<Example>(&link).is_err());
+        assert!(builder.try_open_windowed::<Example>(&link, 1).is_err());
+
+        builder.same_file_policy(SameFilePolicy::Clone);
+        let again = builder.try_open_shared::<Example>(&link)?;
+        assert!(Arc::ptr_eq(&shared, &again));
+        assert!(builder.try_open_shared::<u16>(&link).is_err());
+        let mut hardened = builder.clone();
+        hardened.harden(true);
+        match hardened.try_open_shared::<Example>(&link) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            Ok(_) => panic!("Shared a handle opened with other options."),
+        }
+
+        builder.same_file_policy(SameFilePolicy::SharedRead);
+        let reader = builder.try_open_windowed::<Example>(&link, 1)?;
+        assert_eq!(reader.len(), 1);
+
+        drop((shared, again));
+        builder.try_open::<Example>(&pathbuf)?;
+
+        Ok(())
+    }
+}
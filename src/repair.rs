@@ -0,0 +1,132 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::{MmapedVec, MmapedVecBuilder};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+impl<T> MmapedVec<T> {
+    /// Copy the chunks overlapping `range` that fail [`verify_range`](MmapedVec::verify_range)
+    /// over from the same place in the replica at `replica_path`, while the file stays open,
+    /// returning the chunks that are still corrupt.
+    ///
+    /// Only bytes of the replica that match the hashes of the chunks are copied, so a replica
+    /// that is corrupt or out of date in the same place leaves the chunk as it is. The
+    /// replica is only read, and is not locked, so it must not be written to meanwhile, as
+    /// for a snapshot or the target of a [`ReplicationSink`](crate::ReplicationSink).
+    pub fn repair_from(
+        &mut self,
+        replica_path: &Path,
+        range: Range<usize>,
+    ) -> io::Result<Vec<Range<usize>>> {
+        self.check_poisoned()?;
+        self.check_not_forked()?;
+
+        let corrupt = self.verify_range(range.clone())?;
+        if corrupt.is_empty() {
+            return Ok(corrupt);
+        }
+
+        let fh = self.header();
+        let replica = File::open(replica_path)?;
+        let replica_fh = MmapedVecBuilder::new(fh.magic_bytes, fh.data_contained_version)
+            .check_existing_file::<T, _>(&replica, replica_path)?;
+
+        let size = mem::size_of::<T>();
+        let replica_len = replica
+            .metadata()?
+            .len()
+            .saturating_sub(replica_fh.header_len) as usize;
+        let chunk_elems = self.merkle.as_ref().map_or(1, |tree| tree.chunk_elems());
+
+        let mut repaired = vec![];
+        for chunk in corrupt {
+            if chunk.end * size > replica_len {
+                continue;
+            }
+
+            let mut buf = vec![0u8; chunk.len() * size];
+            replica.read_exact_at(
+                &mut buf,
+                replica_fh.header_len + (chunk.start * size) as u64,
+            )?;
+
+            let matches = match self.merkle.as_ref() {
+                Some(tree) => tree.matches(chunk.start / chunk_elems, &buf)?,
+                None => false,
+            };
+            if !matches {
+                continue;
+            }
+
+            let from = self.header_len + chunk.start * size;
+            self.set_writable(true)?;
+            self.mm[from..from + buf.len()].copy_from_slice(&buf);
+            self.set_writable(false)?;
+            repaired.push(chunk);
+        }
+
+        // NOTE: Verified before being recorded as modified, which would stop verification of
+        //       the repaired chunks until the next flush.
+        let still_corrupt = self.verify_range(range)?;
+        for chunk in repaired {
+            self.replicate_range(chunk)?;
+        }
+        self.flush()?;
+
+        Ok(still_corrupt)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use std::fs::{self, OpenOptions};
+
+    #[test]
+    pub fn test_repair_from_replica() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let replica = path.with_extension("replica");
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        builder.merkle_tree(4);
+
+        let mut mv = builder.try_open::<u32>(&path)?;
+        mv.extend(0..10)?;
+        mv.flush()?;
+        fs::copy(&path, &replica)?;
+        let header_len = mv.header_len;
+        mv.close()?;
+
+        let file = OpenOptions::new().write(true).open(&path)?;
+        file.write_all_at(&[9], (header_len + 5 * 4) as u64)?;
+        file.write_all_at(&[0xff], (header_len + 9 * 4) as u64)?;
+        let file = OpenOptions::new().write(true).open(&replica)?;
+        file.write_all_at(&[7], (header_len + 9 * 4) as u64)?;
+
+        let mut mv = builder.try_open::<u32>(&path)?;
+        assert_eq!(mv.repair_from(&replica, 0..10)?, vec![8..10]);
+        assert_eq!(mv[5], 5);
+        assert!(mv.verify_range(0..8)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,343 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Replay logs, which record each change made to a file, in order, so that the file can be
+//! rebuilt step by step elsewhere with [`replay`](MmapedVecBuilder::replay), to find the
+//! change that put it in a bad state.
+//!
+//! The log starts with `REPLAY_MAGIC` and the size of the elements as a `u64`, followed by
+//! steps in native byte order. Each step is its op and the generation, the number of
+//! flushes before it, and then the arguments of the op: for `OP_WRITE`, the index of the
+//! first element, the number of bytes and the bytes, and for `OP_TRUNCATE`, the new length.
+//! `OP_FLUSH` has none.
+
+use crate::{MmapedVec, MmapedVecBuilder};
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Suffix of the replay log that is kept next to files that [record](MmapedVec::record_replay)
+/// their changes.
+pub const REPLAY_SUFFIX: &str = ".replay";
+
+pub(crate) const REPLAY_MAGIC: [u8; 8] = *b"PERSRPLY";
+const REPLAY_HEADER_LEN: usize = 16;
+const OP_WRITE: u8 = 1;
+const OP_TRUNCATE: u8 = 2;
+const OP_FLUSH: u8 = 3;
+
+/// Path of the replay log of the file at `path`.
+pub fn replay_path(path: &Path) -> PathBuf {
+    let mut replay_path = OsString::from(path.as_os_str());
+    replay_path.push(REPLAY_SUFFIX);
+    PathBuf::from(replay_path)
+}
+
+/// A change recorded in a replay log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplayOp {
+    /// The elements from `start` on were written with `bytes`, growing the file if need be.
+    Write { start: usize, bytes: Vec<u8> },
+    /// The file was truncated to `len` elements.
+    Truncate { len: usize },
+    /// The file was flushed.
+    Flush,
+}
+
+/// A step of a replay log, as read by [`replay_steps`](replay_steps).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplayStep {
+    /// The number of flushes before the step.
+    pub generation: u64,
+    pub op: ReplayOp,
+}
+
+/// The log that the changes of a file are recorded to, and the steps recorded since it
+/// was last written to.
+pub(crate) struct ReplayRecorder {
+    file: File,
+    generation: u64,
+    buf: Vec<u8>,
+}
+
+impl ReplayRecorder {
+    /// Start a log of elements of `elem_size` bytes at `log`, replacing any log there.
+    pub(crate) fn create(log: &Path, elem_size: usize) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(log)?;
+
+        let mut header = REPLAY_MAGIC.to_vec();
+        header.extend_from_slice(&(elem_size as u64).to_ne_bytes());
+        file.write_all(&header)?;
+        file.sync_data()?;
+
+        Ok(Self {
+            file,
+            generation: 0,
+            buf: vec![],
+        })
+    }
+
+    fn step(&mut self, op: u8) {
+        self.buf.push(op);
+        self.buf.extend_from_slice(&self.generation.to_ne_bytes());
+    }
+
+    fn write(&mut self, start: usize, bytes: &[u8]) {
+        self.step(OP_WRITE);
+        self.buf.extend_from_slice(&(start as u64).to_ne_bytes());
+        self.buf
+            .extend_from_slice(&(bytes.len() as u64).to_ne_bytes());
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub(crate) fn truncated(&mut self, len: usize) {
+        self.step(OP_TRUNCATE);
+        self.buf.extend_from_slice(&(len as u64).to_ne_bytes());
+    }
+
+    /// Record a flush, and write the steps recorded since the last one to the log.
+    pub(crate) fn commit(&mut self) -> io::Result<()> {
+        self.step(OP_FLUSH);
+        self.generation += 1;
+
+        self.file.write_all(&mem::take(&mut self.buf))?;
+        self.file.sync_data()
+    }
+}
+
+/// The steps in `buf`, up to where a crash cut the last one short, if any.
+fn parse(buf: &[u8], path: &Path) -> io::Result<(usize, Vec<ReplayStep>)> {
+    if buf.len() < REPLAY_HEADER_LEN || buf[..8] != REPLAY_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("File `{:?}`: Not a replay log.", path),
+        ));
+    }
+    let elem_size = u64::from_ne_bytes(buf[8..16].try_into().unwrap()) as usize;
+
+    let u64_at = |pos: usize| -> Option<u64> {
+        Some(u64::from_ne_bytes(
+            buf.get(pos..pos.checked_add(8)?)?.try_into().unwrap(),
+        ))
+    };
+
+    let mut steps = vec![];
+    let mut pos = REPLAY_HEADER_LEN;
+    while pos < buf.len() {
+        let generation = match u64_at(pos + 1) {
+            Some(generation) => generation,
+            None => break,
+        };
+        let op = match buf[pos] {
+            OP_WRITE => {
+                let (start, n) = match (u64_at(pos + 9), u64_at(pos + 17)) {
+                    (Some(start), Some(n)) => (start as usize, n as usize),
+                    _ => break,
+                };
+                let bytes = match buf.get(pos + 25..(pos + 25).saturating_add(n)) {
+                    Some(bytes) => bytes.to_vec(),
+                    None => break,
+                };
+                pos += 25 + n;
+                ReplayOp::Write { start, bytes }
+            }
+            OP_TRUNCATE => match u64_at(pos + 9) {
+                Some(len) => {
+                    pos += 17;
+                    ReplayOp::Truncate { len: len as usize }
+                }
+                None => break,
+            },
+            OP_FLUSH => {
+                pos += 9;
+                ReplayOp::Flush
+            }
+            _ => break,
+        };
+        steps.push(ReplayStep { generation, op });
+    }
+
+    Ok((elem_size, steps))
+}
+
+/// The steps recorded in the replay log at `log`.
+pub fn replay_steps(log: &Path) -> io::Result<Vec<ReplayStep>> {
+    Ok(parse(&fs::read(log)?, log)?.1)
+}
+
+impl<T> MmapedVec<T> {
+    /// Record each change made to the file from here on in a replay log next to it,
+    /// [`replay_path`](replay_path), replacing any log already there. The log starts with
+    /// the elements as they are now, so that the file can be rebuilt from the log alone
+    /// with [`replay`](MmapedVecBuilder::replay).
+    ///
+    /// Appending, resizing and truncating are recorded, and so are the elements in each
+    /// range [marked modified](MmapedVec::mark_modified), as they are at the time. Steps are
+    /// kept in memory until the next flush, which writes them to the log.
+    ///
+    /// NOTE: Writes through dereferencing the [`MmapedVec`](MmapedVec) mutably are only
+    /// recorded once marked modified, like they are for [replication](crate::ReplicationSink).
+    pub fn record_replay(&mut self) -> io::Result<()> {
+        let mut recorder = ReplayRecorder::create(&replay_path(&self.path), mem::size_of::<T>())?;
+        if !self.is_empty() {
+            recorder.write(0, &self.mm[self.header_len..]);
+        }
+        self.replay = Some(recorder);
+        Ok(())
+    }
+
+    pub(crate) fn replay_modified(&mut self, range: Range<usize>) {
+        let size = mem::size_of::<T>();
+        let end = range.end.min(self.len());
+        if let Some(recorder) = self.replay.as_mut() {
+            if range.start < end {
+                let body = &self.mm[self.header_len..];
+                recorder.write(range.start, &body[range.start * size..end * size]);
+            }
+        }
+    }
+}
+
+impl MmapedVecBuilder {
+    /// Rebuild the file whose changes were [recorded](MmapedVec::record_replay) to the
+    /// replay log at `log` in a new file at `fresh_path`, taking the first `steps` steps, or
+    /// all of them if there are fewer. Taking more and more steps, as listed by
+    /// [`replay_steps`](replay_steps), finds the one that put the file in a bad state.
+    ///
+    /// Fails with [`AlreadyExists`](io::ErrorKind::AlreadyExists) if there are elements at
+    /// `fresh_path` already, and with [`InvalidData`](io::ErrorKind::InvalidData) if the log
+    /// is not of elements of type `T`.
+    pub fn replay<T: Sized + Default>(
+        &self,
+        log: &Path,
+        fresh_path: &Path,
+        steps: usize,
+    ) -> io::Result<MmapedVec<T>> {
+        let (elem_size, recorded) = parse(&fs::read(log)?, log)?;
+        if elem_size != mem::size_of::<T>() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "File `{:?}`: Records elements of {} bytes, not of {} bytes.",
+                    log,
+                    elem_size,
+                    mem::size_of::<T>()
+                ),
+            ));
+        }
+
+        let mut mv = self.try_open::<T>(fresh_path)?;
+        if !mv.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("File `{:?}`: Has elements already.", fresh_path),
+            ));
+        }
+
+        for step in recorded.into_iter().take(steps) {
+            match step.op {
+                ReplayOp::Write { start, bytes } => {
+                    let offset = start.checked_mul(elem_size).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("File `{:?}`: Write out of bounds.", log),
+                        )
+                    })?;
+                    mv.apply_replicated(offset as u64, &bytes)?;
+                }
+                ReplayOp::Truncate { len } => mv.truncate(len)?,
+                ReplayOp::Flush => mv.flush()?,
+            }
+        }
+
+        Ok(mv)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+
+    #[test]
+    pub fn test_record_and_replay() -> Result<(), io::Error> {
+        let (dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let mut mv: MmapedVec<u64> = builder.try_open(&path)?;
+        mv.push(1)?;
+        mv.record_replay()?;
+        mv.push(2)?;
+        mv.flush()?;
+        mv.extend([3, 4])?;
+        mv.truncate(3)?;
+        drop(mv);
+
+        let steps = replay_steps(&replay_path(&path))?;
+        let ops: Vec<_> = steps
+            .iter()
+            .map(|step| (step.generation, &step.op))
+            .collect();
+        let bytes = |values: &[u64]| values.iter().flat_map(|v| v.to_ne_bytes()).collect();
+        assert_eq!(
+            ops,
+            [
+                (
+                    0,
+                    &ReplayOp::Write {
+                        start: 0,
+                        bytes: bytes(&[1])
+                    }
+                ),
+                (
+                    0,
+                    &ReplayOp::Write {
+                        start: 1,
+                        bytes: bytes(&[2])
+                    }
+                ),
+                (0, &ReplayOp::Flush),
+                (
+                    1,
+                    &ReplayOp::Write {
+                        start: 2,
+                        bytes: bytes(&[3, 4])
+                    }
+                ),
+                (1, &ReplayOp::Truncate { len: 3 }),
+                (1, &ReplayOp::Flush),
+            ]
+        );
+
+        let mv: MmapedVec<u64> = builder.try_open(&path)?;
+        let replayed: MmapedVec<u64> =
+            builder.replay(&replay_path(&path), &dir.path().join("all"), usize::MAX)?;
+        assert_eq!(replayed[..], mv[..]);
+        let replayed: MmapedVec<u64> =
+            builder.replay(&replay_path(&path), &dir.path().join("some"), 3)?;
+        assert_eq!(replayed[..], [1, 2]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::MmapedVec;
+use std::ops::Range;
+use std::{io, mem};
+
+/// Receiver of the bytes written to a [`MmapedVec`](MmapedVec), for building warm-standby
+/// copies of it, for example on other machines.
+///
+/// On the receiving side, the bytes are applied to a [`MmapedVec`](MmapedVec) of the same
+/// element type with [`apply_replicated`](MmapedVec::apply_replicated). The header is not
+/// replicated, so the copy may have a header of another length.
+pub trait ReplicationSink {
+    /// Called as the bytes are written, before they are flushed, with the offset into the
+    /// body of the file that they were written at, and the bytes. The body is what comes
+    /// after the header, so the offset is that of the first element written, in bytes.
+    fn replicate(&mut self, offset: u64, bytes: &[u8]) -> io::Result<()>;
+
+    /// Called after the [`MmapedVec`](MmapedVec) has been flushed to disk.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// Replicate all appends made from here on to `sink`.
+    ///
+    /// Elements modified in place through the slice are not tracked; replicate those
+    /// with [`replicate_range`](MmapedVec::replicate_range).
+    pub fn set_replication_sink(&mut self, sink: Box<dyn ReplicationSink + Send>) {
+        self.replication_sink = Some(sink);
+    }
+
+    /// Send the elements in `range` to the replication sink, if one is set, and record them
+    /// as [modified](MmapedVec::mark_modified).
+    ///
+    /// Fails with [`InvalidInput`](io::ErrorKind::InvalidInput) if `range` is out of bounds.
+    pub fn replicate_range(&mut self, range: Range<usize>) -> io::Result<()> {
+        if range.start > range.end || range.end > self.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "File `{:?}`: Range {}..{} out of bounds for {} elements.",
+                    self.path,
+                    range.start,
+                    range.end,
+                    self.len()
+                ),
+            ));
+        }
+
+        self.mark_modified(range.clone());
+
+        let bytes = &self.mm[self.header_len..];
+        let size = mem::size_of::<T>();
+        let offset = (range.start * size) as u64;
+
+        if let Some(sink) = self.replication_sink.as_mut() {
+            sink.replicate(offset, &bytes[range.start * size..range.end * size])?;
+        }
+
+        Ok(())
+    }
+
+    /// Write bytes received by a [`ReplicationSink`](ReplicationSink) at the same offset
+    /// into the body of this file, growing it if needed.
+    pub fn apply_replicated(&mut self, offset: u64, bytes: &[u8]) -> io::Result<()> {
+        self.check_poisoned()?;
+
+        let size = mem::size_of::<T>() as u64;
+
+        if !offset.is_multiple_of(size) || !(bytes.len() as u64).is_multiple_of(size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "File `{:?}`: Replicated bytes at offset {} do not line up with the elements.",
+                    self.path, offset
+                ),
+            ));
+        }
+
+        let start = (offset / size) as usize;
+        let end = start + bytes.len() / size as usize;
+
+        if end > self.len() {
+            let additional = end - self.len();
+            self.grow(additional)?;
+        }
+
+        let from = self.header_len + start * size as usize;
+        self.set_writable(true)?;
+        self.mm[from..from + bytes.len()].copy_from_slice(bytes);
+        self.set_writable(false)?;
+        self.mark_modified(start..end);
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{new_mmaped_vec_of_example_persisting_in_tempdir, Example};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    pub fn test_apply_replicated_appends_and_modifications() -> Result<(), io::Error> {
+        type Replicated = Vec<(u64, Vec<u8>)>;
+
+        #[derive(Clone, Default)]
+        struct CollectingSink(Arc<Mutex<Replicated>>);
+
+        impl ReplicationSink for CollectingSink {
+            fn replicate(&mut self, offset: u64, bytes: &[u8]) -> io::Result<()> {
+                self.0.lock().unwrap().push((offset, bytes.to_vec()));
+                Ok(())
+            }
+        }
+
+        let (_dir, _pathbuf, mut primary) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+        let (_dir_f, _pathbuf_f, mut follower) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        let sink = CollectingSink::default();
+        primary.set_replication_sink(Box::new(sink.clone()));
+
+        primary.push(Example { hello: 3, world: 4 })?;
+        primary.extend(vec![Example::default(), Example::default()])?;
+        primary[1].hello = 7;
+        primary.replicate_range(1..2)?;
+        assert_eq!(
+            primary.replicate_range(2..4).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        let offsets: Vec<u64> = sink.0.lock().unwrap().iter().map(|(o, _)| *o).collect();
+        assert_eq!(offsets, vec![0, 2, 2]);
+        for (offset, bytes) in sink.0.lock().unwrap().iter() {
+            follower.apply_replicated(*offset, bytes)?;
+        }
+
+        assert_eq!(follower.len(), 3);
+        assert_eq!((follower[0].hello, follower[0].world), (3, 4));
+        assert_eq!((follower[1].hello, follower[1].world), (7, 2));
+        assert_eq!((follower[2].hello, follower[2].world), (1, 2));
+
+        Ok(())
+    }
+}
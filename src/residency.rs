@@ -0,0 +1,219 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::MmapedVec;
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::{io, mem};
+
+/// Which pages of the body of a [`MmapedVec`](MmapedVec) were resident in the page cache
+/// when [`residency`](MmapedVec::residency) was called.
+///
+/// The kernel may evict or read in pages at any moment, so this is a snapshot that is
+/// only good for deciding what to warm up or advise away, and for sizing working sets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResidencyReport {
+    /// Size of a page on this host, in bytes.
+    pub page_size: usize,
+    /// Whether each page that holds part of the body is resident, in order.
+    pub pages: Vec<bool>,
+    /// Offset into the file of the first page in `pages`.
+    first_page_offset: usize,
+    header_len: usize,
+    len_bytes: usize,
+    elem_size: usize,
+}
+
+impl ResidencyReport {
+    pub fn total_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn resident_pages(&self) -> usize {
+        self.pages.iter().filter(|&&resident| resident).count()
+    }
+
+    /// Bytes of the body in resident pages.
+    pub fn resident_bytes(&self) -> usize {
+        self.pages
+            .iter()
+            .enumerate()
+            .filter(|(_, &resident)| resident)
+            .map(|(i, _)| {
+                let (start, end) = self.page_bounds(i);
+                end - start
+            })
+            .sum()
+    }
+
+    /// Ranges of element indexes that lie at least partly in resident pages, merged where
+    /// they are adjacent.
+    pub fn resident_elements(&self) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = vec![];
+
+        for (i, _) in self.pages.iter().enumerate().filter(|(_, &r)| r) {
+            let (start, end) = self.page_bounds(i);
+            let range = start / self.elem_size..end.div_ceil(self.elem_size);
+
+            match ranges.last_mut() {
+                Some(last) if last.end >= range.start => last.end = range.end,
+                _ => ranges.push(range),
+            }
+        }
+
+        ranges
+    }
+
+    /// Bounds of the part of the body that is in page `i`, in bytes from the start of the
+    /// body.
+    fn page_bounds(&self, i: usize) -> (usize, usize) {
+        let page_start = self.first_page_offset + i * self.page_size;
+        let start = page_start.max(self.header_len) - self.header_len;
+        let end = (page_start + self.page_size).min(self.len_bytes) - self.header_len;
+        (start, end)
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// Find out which parts of the body are resident in the page cache, using `mincore()`.
+    pub fn residency(&self) -> io::Result<ResidencyReport> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let first_page = self.header_len / page_size;
+        let end_page = self.mm.len().div_ceil(page_size);
+
+        let mut report = ResidencyReport {
+            page_size,
+            pages: vec![],
+            first_page_offset: first_page * page_size,
+            header_len: self.header_len,
+            len_bytes: self.mm.len(),
+            elem_size: mem::size_of::<T>(),
+        };
+
+        if self.mm.len() == self.header_len {
+            return Ok(report);
+        }
+
+        let mut vec = vec![0u8; end_page - first_page];
+
+        let ret = unsafe {
+            libc::mincore(
+                self.mm.as_ptr().add(report.first_page_offset) as *mut libc::c_void,
+                self.mm.len() - report.first_page_offset,
+                vec.as_mut_ptr() as *mut _,
+            )
+        };
+
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        report.pages = vec.iter().map(|&v| v & 1 != 0).collect();
+
+        Ok(report)
+    }
+
+    /// Bytes of address space taken up by the mapping, header included.
+    pub fn virtual_size(&self) -> usize {
+        self.mm.len()
+    }
+
+    /// Bytes of the mapping, header included, that are resident in the page cache, found
+    /// with `mincore()`.
+    ///
+    /// Pages of the file that are resident are counted whether or not this process has
+    /// touched them, and they are shared with other processes that map or read the file.
+    pub fn resident_size(&self) -> io::Result<usize> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut vec = vec![0u8; self.mm.len().div_ceil(page_size)];
+
+        let ret = unsafe {
+            libc::mincore(
+                self.mm.as_ptr() as *mut libc::c_void,
+                self.mm.len(),
+                vec.as_mut_ptr() as *mut _,
+            )
+        };
+
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let resident_pages = vec.iter().filter(|&&v| v & 1 != 0).count();
+        Ok((resident_pages * page_size).min(self.mm.len()))
+    }
+
+    /// Bytes of disk allocated to the file, from the number of blocks reported by `fstat()`,
+    /// which is less than the logical size for sparse files.
+    pub fn file_allocated_size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.blocks() * 512)
+    }
+
+    /// Size of the file, header included, in bytes.
+    pub fn file_logical_size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        new_mmaped_vec_of_example_persisting_in_tempdir, tempdir_and_tempfile, Example,
+        EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES,
+    };
+
+    #[test]
+    pub fn test_residency() -> Result<(), io::Error> {
+        let (_dir, _pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        assert_eq!(mv.residency()?.total_pages(), 0);
+
+        mv.extend((0..5000).map(|_| Example { hello: 3, world: 4 }))?;
+        let sum: u32 = mv.iter().map(|e| u32::from(e.hello)).sum();
+        assert_eq!(sum, 15000);
+
+        let report = mv.residency()?;
+        assert_eq!(
+            report.total_pages(),
+            (10000usize).div_ceil(report.page_size)
+        );
+        assert!(report.resident_pages() <= report.total_pages());
+        assert!(report.resident_bytes() <= 10000);
+        for range in report.resident_elements() {
+            assert!(range.end <= mv.len());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_memory_accounting() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut mv =
+            MmapedVec::<u64>::try_new(&path, EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)?;
+        mv.set(1 << 20, 1)?;
+        mv[0] = 1;
+
+        let logical = mv.file_logical_size()?;
+        assert_eq!(mv.virtual_size() as u64, logical);
+        assert!(mv.file_allocated_size()? < logical);
+        let resident = mv.resident_size()?;
+        assert!(resident > 0 && resident <= mv.virtual_size());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,482 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::{
+    bloom, locking, recovery, registry, replay, wal, MmapedVec, BLOOM_SUFFIX, FREE_SUFFIX,
+    MERKLE_SUFFIX, REPLAY_SUFFIX, STATS_SUFFIX, TOMBSTONES_SUFFIX, WAL_SUFFIX, ZONES_SUFFIX,
+};
+use memmap::MmapMut;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// When to move the elements of a [`MmapedVec`](MmapedVec) aside into an archive file and
+/// carry on with an empty one, set with [`roll_when`](MmapedVec::roll_when).
+///
+/// Each limit that is set is checked before appending with [`push`](MmapedVec::push) or
+/// [`extend`](MmapedVec::extend), and the file is rolled if appending would take it past
+/// the limit, or if it has grown older than `max_age`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RollPolicy {
+    /// Size of the file, header included, in bytes.
+    pub max_len_bytes: Option<u64>,
+    pub max_elements: Option<usize>,
+    /// Time since the file was created, or last rolled.
+    pub max_age: Option<Duration>,
+}
+
+impl<T> MmapedVec<T> {
+    /// Roll the file as per `policy` from now on, for when the elements are a log, such as
+    /// telemetry, that should be kept in files of bounded size or age.
+    ///
+    /// After a roll the vector is empty, so indices, [`ElemHandle`](crate::ElemHandle)s and
+    /// [`Cursor`](crate::Cursor)s into it no longer refer to the same elements.
+    pub fn roll_when(&mut self, policy: RollPolicy) {
+        self.roll_policy = Some(policy);
+    }
+
+    /// Roll the file now, whatever the policy, returning the path of the archive.
+    ///
+    /// The current file is flushed and hard linked to an archive name, which is the file
+    /// name followed by the time of the roll, and an empty file with the same header is
+    /// then renamed into its place. The path therefore always names a complete file, and
+    /// the archive is only ever the complete old file. The sidecars of the file are hard
+    /// linked next to the archive likewise, so that it can be opened with its statistics,
+    /// zone maps and so on, and start over along with the file. A replication sink sees
+    /// offsets into the new file from then on.
+    ///
+    /// If the mapping is [pinned](MmapedVec::pin), it is retired until the pins are dropped,
+    /// rather than unmapped; see [`retired_generations`](MmapedVec::retired_generations).
+    /// [`OptimisticReader`](crate::OptimisticReader)s in other processes find out with
+    /// [`is_replaced`](crate::OptimisticReader::is_replaced).
+    pub fn roll(&mut self) -> io::Result<PathBuf> {
+        self.check_poisoned()?;
+        self.check_not_forked()?;
+        self.flush()?;
+        self.store_checksum()?;
+
+        let now = SystemTime::now();
+        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut archive_name = self
+            .path
+            .file_name()
+            .map(OsString::from)
+            .unwrap_or_default();
+        archive_name.push(format!(
+            ".{}.{:09}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_nanos()
+        ));
+        let archive = self.path.with_file_name(archive_name);
+
+        let mut tmp_name = self
+            .path
+            .file_name()
+            .map(OsString::from)
+            .unwrap_or_default();
+        tmp_name.push(".roll-tmp");
+        let tmp = self.path.with_file_name(tmp_name);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&tmp)?;
+        let mut sidecars = Sidecars::default();
+        let res = (|| {
+            locking::try_lock_exclusive(&file, &tmp)?;
+            file.write_all_at(&self.mm[..self.header_len], 0)?;
+            file.set_len(self.header_len as u64)?;
+            recovery::set_dirty(&file, true)?;
+            self.create_sidecars(&tmp, &mut sidecars)?;
+            sidecars.archive(&self.path, &archive)?;
+            fs::hard_link(&self.path, &archive)?;
+            if let Err(e) = fs::rename(&tmp, &self.path) {
+                let _ = fs::remove_file(&archive);
+                return Err(e);
+            }
+            Ok(())
+        })();
+        if let Err(e) = res {
+            let _ = fs::remove_file(&tmp);
+            sidecars.remove();
+            return Err(e);
+        }
+
+        let res = fail_point!(AfterRollRename).and_then(|_| {
+            let mm = unsafe { MmapMut::map_mut(&file)? };
+            let registration = registry::Registration::new(&file, &self.path)?;
+            Ok((mm, registration))
+        });
+        let (mm, registration) = match res {
+            Ok(res) => res,
+            Err(e) => {
+                // NOTE: Puts the old file back, so that the path names the file that is open.
+                let _ = fs::rename(&archive, &self.path);
+                sidecars.remove();
+                return Err(e);
+            }
+        };
+
+        let old_file = mem::replace(&mut self.file, file);
+        // NOTE: Files with a lock file stay locked through that, so the new file need not be.
+        if self.lock_file.is_some() {
+            locking::unlock(&self.file, &self.path)?;
+        }
+        self.replace_mapping(mm);
+        self.registration = registration;
+        self.file_started = now;
+        self.synced_len_bytes = 0;
+        if let Some(tracker) = self.tracker.as_mut() {
+            tracker.truncate(0);
+        }
+        if let Some(feed) = self.change_feed.as_mut() {
+            feed.removed(0);
+        }
+
+        // NOTE: The archive is complete, so it is marked as closed cleanly, and unlocked.
+        let _ = recovery::set_dirty(&old_file, false);
+        drop(old_file);
+
+        self.set_writable(false)?;
+        self.start_sidecars_over(sidecars)?;
+
+        Ok(archive)
+    }
+
+    /// Create the sidecars that are written in place for an empty file at `tmp`, so that
+    /// those of the file being rolled are left as they are.
+    fn create_sidecars(&self, tmp: &Path, sidecars: &mut Sidecars) -> io::Result<()> {
+        if let Some(wal) = self.wal.as_ref() {
+            sidecars.aside(wal::wal_path(tmp), wal::wal_path(&self.path))?;
+            sidecars.wal = Some(wal::Wal::open(
+                tmp,
+                mem::size_of::<T>(),
+                wal.history(),
+                &[],
+            )?);
+        }
+        if let Some(filter) = self.bloom.as_ref() {
+            let path = sidecars.aside(bloom::bloom_path(tmp), bloom::bloom_path(&self.path))?;
+            sidecars.bloom = Some(filter.create_empty(&path)?);
+        }
+        if self.replay.is_some() {
+            let path = sidecars.aside(replay::replay_path(tmp), replay::replay_path(&self.path))?;
+            sidecars.replay = Some(replay::ReplayRecorder::create(&path, mem::size_of::<T>())?);
+        }
+        Ok(())
+    }
+
+    /// Move the `sidecars` into place for the empty file that the roll put in place, and
+    /// bring the other sidecars up to date with it.
+    fn start_sidecars_over(&mut self, sidecars: Sidecars) -> io::Result<()> {
+        // NOTE: Those that the archive has, but that are not kept for the file now, would
+        //       otherwise be left with it, and the rest are replaced either way.
+        for (path, _) in &sidecars.archived {
+            fs::remove_file(path)?;
+        }
+        if let Some(wal) = sidecars.wal {
+            self.wal = Some(wal);
+        }
+        if let (Some(filter), Some(bloom)) = (self.bloom.as_mut(), sidecars.bloom) {
+            filter.replace_sidecar(bloom);
+        }
+        if let Some(recorder) = sidecars.replay {
+            self.replay = Some(recorder);
+        }
+        for (aside, path) in &sidecars.paths {
+            fs::rename(aside, path)?;
+        }
+
+        // NOTE: The rest are written aside and renamed into place, whether rolled or not.
+        if let Some(tree) = self.merkle.as_mut() {
+            tree.commit(&[])?;
+        }
+        if let Some(stats) = self.stats.as_mut() {
+            stats.commit(&[])?;
+        }
+        if let Some(zones) = self.zones.as_mut() {
+            zones.commit(&[])?;
+        }
+        if let Some(free) = self.free.as_mut() {
+            free.clear(0)?;
+        }
+        if let Some(tombstones) = self.tombstones.as_mut() {
+            tombstones.commit(0)?;
+        }
+        Ok(())
+    }
+
+    /// Roll the file if appending `additional` elements would break the roll policy.
+    pub(crate) fn roll_if_due(&mut self, additional: usize) -> io::Result<()> {
+        let policy = match &self.roll_policy {
+            Some(policy) if !self.is_empty() => policy,
+            _ => return Ok(()),
+        };
+
+        let elements = self.len().saturating_add(additional);
+        let len_bytes = (self.header_len as u64)
+            .saturating_add((elements as u64).saturating_mul(mem::size_of::<T>() as u64));
+        let age = self.file_started.elapsed().unwrap_or_default();
+
+        if policy.max_elements.is_some_and(|max| elements > max)
+            || policy.max_len_bytes.is_some_and(|max| len_bytes > max)
+            || policy.max_age.is_some_and(|max| age >= max)
+        {
+            self.roll()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Suffixes of the sidecars that go with the archive when rolling. Lock files stay with the
+/// path, and consumers of a [`Queue`](crate::Queue) with the queue.
+const ARCHIVED_SUFFIXES: &[&str] = &[
+    WAL_SUFFIX,
+    MERKLE_SUFFIX,
+    BLOOM_SUFFIX,
+    STATS_SUFFIX,
+    ZONES_SUFFIX,
+    FREE_SUFFIX,
+    TOMBSTONES_SUFFIX,
+    REPLAY_SUFFIX,
+];
+
+/// The sidecars made for the empty file of a roll before it is put in place, and where
+/// they go once it is, and the sidecars of the file being rolled that were linked next to
+/// the archive.
+#[derive(Default)]
+struct Sidecars {
+    wal: Option<wal::Wal>,
+    bloom: Option<(MmapMut, File)>,
+    replay: Option<replay::ReplayRecorder>,
+    paths: Vec<(PathBuf, PathBuf)>,
+    archived: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Sidecars {
+    /// Hard link the sidecars of the file at `path` next to `archive`.
+    fn archive(&mut self, path: &Path, archive: &Path) -> io::Result<()> {
+        for suffix in ARCHIVED_SUFFIXES {
+            let mut sidecar = OsString::from(path.as_os_str());
+            sidecar.push(suffix);
+            let mut archived = OsString::from(archive.as_os_str());
+            archived.push(suffix);
+
+            match fs::hard_link(&sidecar, &archived) {
+                Ok(()) => self.archived.push((sidecar.into(), archived.into())),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Make way for a sidecar at `aside` that goes to `path`, returning `aside`.
+    fn aside(&mut self, aside: PathBuf, path: PathBuf) -> io::Result<PathBuf> {
+        // NOTE: Left by a roll that failed, as the file at the path of the roll is ours.
+        match fs::remove_file(&aside) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        self.paths.push((aside.clone(), path));
+        Ok(aside)
+    }
+
+    fn remove(self) {
+        for (aside, _) in &self.paths {
+            let _ = fs::remove_file(aside);
+        }
+        for (_, archived) in &self.archived {
+            let _ = fs::remove_file(archived);
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        new_mmaped_vec_of_example_persisting_in_tempdir, tempdir_and_tempfile, Example,
+        EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES,
+    };
+    use crate::{
+        merkle_path, replay_path, replay_steps, stats_path, zones_path, MmapedVecBuilder,
+        StatsSketch,
+    };
+    use std::slice;
+
+    #[test]
+    pub fn test_roll_by_elements() -> Result<(), io::Error> {
+        let (dir, pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+        mv.roll_when(RollPolicy {
+            max_elements: Some(2),
+            ..RollPolicy::default()
+        });
+
+        for i in 0..5 {
+            mv.push(Example { hello: i, world: 0 })?;
+        }
+        assert_eq!(mv.len(), 1);
+        assert_eq!(mv[0].hello, 4);
+
+        let archive = mv.roll()?;
+        assert!(mv.is_empty());
+        drop(mv);
+
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        let mut archives: Vec<_> = fs::read_dir(dir.path())?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()?;
+        archives.retain(|path| path != &pathbuf);
+        archives.sort();
+        assert_eq!(archives.len(), 3);
+        assert_eq!(archives[2], archive);
+
+        let contents: Vec<Vec<u8>> = archives
+            .iter()
+            .map(|path| {
+                let mv = builder.try_open::<Example>(path)?;
+                assert_eq!(mv.recovered_from_crash(), None);
+                Ok(mv.iter().map(|e| e.hello).collect())
+            })
+            .collect::<io::Result<_>>()?;
+        assert_eq!(contents, vec![vec![0, 1], vec![2, 3], vec![4]]);
+        assert!(builder.try_open::<Example>(&pathbuf)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_roll_starts_sidecars_over() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        builder.wal(true).merkle_tree(2);
+
+        let mut mv = builder.try_open::<u64>(&path)?;
+        mv.bloom_filter(100, 0.01, |elem: &u64| elem.to_ne_bytes())?;
+        mv.statistics(0.01, |elem: &u64| *elem as f64)?;
+        mv.zone_map(2, |elem: &u64| *elem as i64)?;
+        mv.free_list()?;
+        mv.tombstones()?;
+        mv.record_replay()?;
+        mv.extend([10, 20, 30, 40])?;
+        mv.vacate(1)?;
+        mv.mark_deleted(2);
+        mv.flush()?;
+        assert_eq!(mv.wal_generation(), Some(1));
+
+        mv.roll()?;
+        assert_eq!(mv.wal_generation(), Some(0));
+        assert!(!mv.maybe_contains(10u64.to_ne_bytes()));
+        assert_eq!(mv.stats().map(StatsSketch::count), Some(0));
+        mv.extend([1, 2, 3])?;
+        assert_eq!((mv.free_slots(), mv.deleted_count()), (0, 0));
+        assert_eq!(mv.insert_any(4)?, 3);
+        mv.flush()?;
+        assert_eq!(mv.wal_generation(), Some(1));
+        assert_eq!(mv.verify_range(0..4)?, vec![]);
+        assert_eq!(
+            mv.scan_where(2..=3).copied().collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(replay_steps(&replay_path(&path))?.len(), 3);
+        drop(mv);
+
+        let mut mv = builder.try_open::<u64>(&path)?;
+        mv.statistics(0.01, |elem: &u64| *elem as f64)?;
+        assert_eq!(mv.stats().map(StatsSketch::count), Some(4));
+        assert_eq!(mv.verify_range(0..4)?, vec![]);
+        assert_eq!(
+            builder.open_at_generation::<u64>(&path, 1)?.to_vec(),
+            vec![1, 2, 3, 4]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_roll_archives_sidecars() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        builder.merkle_tree(2);
+
+        let mut mv = builder.try_open::<u64>(&path)?;
+        mv.statistics(0.01, |elem: &u64| *elem as f64)?;
+        mv.zone_map(2, |elem: &u64| *elem as i64)?;
+        mv.extend([10, 20, 30])?;
+        let archive = mv.roll()?;
+        mv.push(1)?;
+        drop(mv);
+
+        for sidecar in [merkle_path, stats_path, zones_path] {
+            assert!(sidecar(&archive).exists());
+        }
+
+        let mut archived = builder.try_open::<u64>(&archive)?;
+        archived.statistics(0.01, |elem: &u64| *elem as f64)?;
+        archived.zone_map(2, |elem: &u64| *elem as i64)?;
+        assert_eq!(archived.verify_range(0..3)?, vec![]);
+        assert_eq!(archived.stats().map(StatsSketch::count), Some(3));
+        assert_eq!(
+            archived.scan_where(15..).copied().collect::<Vec<_>>(),
+            vec![20, 30]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_roll_retires_pinned_mapping() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        let mut mv = builder.try_open::<u64>(&path)?;
+        mv.extend([1, 2, 3])?;
+
+        let mut reader = builder.try_open_optimistic::<u64>(&path)?;
+        let pinned = mv.pin();
+        let generation = mv.mapping_generation();
+
+        mv.roll()?;
+        assert_eq!(mv.retired_generations(), vec![generation]);
+        assert!(!mv.is_pinned());
+        mv.push(4)?;
+
+        // NOTE: The retired mapping is still there for the pin.
+        let old = unsafe { slice::from_raw_parts(pinned.as_ptr(), pinned.len()) };
+        assert_eq!(old, &[1, 2, 3]);
+        assert!(pinned.mapping_generation() < mv.mapping_generation());
+
+        drop(pinned);
+        assert_eq!(mv.reclaim_retired(), 0);
+        assert!(mv.retired_generations().is_empty());
+
+        assert!(reader.is_replaced()?);
+        assert_eq!(reader.len()?, 3);
+        reader.reopen(&builder)?;
+        assert!(!reader.is_replaced()?);
+        assert_eq!(reader.read_range(0..1)?, vec![4]);
+
+        Ok(())
+    }
+}
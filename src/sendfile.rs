@@ -0,0 +1,146 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Sending elements from the file straight to a socket, for replication and bulk export
+//! endpoints.
+
+use crate::MmapedVec;
+use std::io;
+use std::mem;
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+
+impl<T> MmapedVec<T> {
+    /// Send the bytes of the elements in `range` to `socket`, or to any other file
+    /// descriptor that can be written to, returning how many bytes were sent.
+    ///
+    /// On Linux, the bytes go from the page cache to the socket with `sendfile()`, without
+    /// passing through userspace. Elsewhere, or where the kernel refuses, they are written
+    /// from the mapping. Either way, nothing needs to be flushed first, since the mapping
+    /// and the page cache are the same memory.
+    ///
+    /// On a non-blocking socket, stops early when the socket would block, once some bytes
+    /// have been sent, so the caller can resume from there.
+    pub fn send_range_to<S: AsRawFd>(&self, range: Range<usize>, socket: &S) -> io::Result<u64> {
+        self.check_poisoned()?;
+
+        if range.start > range.end || range.end > self.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "File `{:?}`: Range {}..{} out of bounds for length {}.",
+                    self.path,
+                    range.start,
+                    range.end,
+                    self.len()
+                ),
+            ));
+        }
+
+        let size = mem::size_of::<T>();
+        let start = self.header_len + range.start * size;
+        let end = self.header_len + range.end * size;
+        let fd = socket.as_raw_fd();
+
+        let mut sent = 0;
+        while start + sent < end {
+            let n = match self.send_chunk(fd, start + sent..end) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && sent > 0 => break,
+                Err(e) => return Err(e),
+            };
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    format!("File `{:?}`: Socket accepted no more bytes.", self.path),
+                ));
+            }
+            sent += n;
+        }
+
+        Ok(sent as u64)
+    }
+
+    /// Send some of the bytes at `range` in the file to `fd`.
+    fn send_chunk(&self, fd: libc::c_int, range: Range<usize>) -> io::Result<usize> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            let mut offset = range.start as libc::off_t;
+            let n = unsafe {
+                libc::sendfile(
+                    fd,
+                    self.file.as_raw_fd(),
+                    &mut offset,
+                    range.end - range.start,
+                )
+            };
+            if n >= 0 {
+                return Ok(n as usize);
+            }
+
+            // NOTE: EINVAL and ENOSYS mean that sendfile() does not support this kind of
+            //       file or descriptor, so fall back to writing from the mapping.
+            let e = io::Error::last_os_error();
+            if !matches!(e.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS)) {
+                return Err(e);
+            }
+        }
+
+        let bytes = &self.mm[range];
+        let n = unsafe { libc::write(fd, bytes.as_ptr() as *const libc::c_void, bytes.len()) };
+        match n {
+            -1 => Err(io::Error::last_os_error()),
+            n => Ok(n as usize),
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::MmapedVecBuilder;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    pub fn test_send_range_to_socket() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+
+        let mut mv: MmapedVec<u64> =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+                .try_open(&path)?;
+        mv.extend(0..1000)?;
+
+        let (tx, mut rx) = UnixStream::pair()?;
+        let reader = std::thread::spawn(move || {
+            let mut buf = vec![];
+            rx.read_to_end(&mut buf).map(|_| buf)
+        });
+
+        assert_eq!(mv.send_range_to(100..600, &tx)?, 500 * 8);
+        assert_eq!(mv.send_range_to(0..0, &tx)?, 0);
+        assert!(mv.send_range_to(999..1001, &tx).is_err());
+        drop(tx);
+
+        let received = reader.join().unwrap()?;
+        let expected: Vec<u8> = (100..600u64).flat_map(|i| i.to_ne_bytes()).collect();
+        assert_eq!(received, expected);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,475 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Reading a file while a [`MmapedVec`](MmapedVec) is writing to it, without locks, by
+//! checking a sequence number that the writer bumps around each write, in the manner of a
+//! seqlock. See [`EXTENSION_TAG_SEQUENCE`](crate::format::EXTENSION_TAG_SEQUENCE).
+
+use crate::format::{self, FileHeader, FILE_HEADER_LEN};
+use crate::sync::{self, fence, AtomicU64, Ordering};
+use crate::{check_element_type, MmapedVec, MmapedVecBuilder};
+use memmap::{Mmap, MmapMut};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::thread;
+
+/// How many times a read is retried while the writer keeps interfering, before giving up.
+const MAX_READ_ATTEMPTS: usize = 1000;
+
+/// The sequence number and the length, in a mapping that starts at `base`.
+///
+/// # Safety
+///
+/// The mapping must hold a whole header, and be mapped for at least as long as the
+/// returned references are used.
+pub(crate) unsafe fn sequence_fields<'a>(
+    base: *const u8,
+    mapped: usize,
+) -> Option<[&'a AtomicU64; 2]> {
+    let mut fh_buf = [0u8; FILE_HEADER_LEN];
+    ptr::copy_nonoverlapping(base, fh_buf.as_mut_ptr(), FILE_HEADER_LEN);
+    let fh = FileHeader::from_bytes(&fh_buf);
+
+    let start = fh.extensions_offset as usize;
+    let end = (fh.header_len as usize).min(mapped);
+    let area = std::slice::from_raw_parts(base.add(start), end.checked_sub(start)?);
+    let offset = format::sequence_offset(start, area)?;
+
+    Some([
+        sync::atomic_u64_from_ptr(base.add(offset) as *mut u64),
+        sync::atomic_u64_from_ptr(base.add(offset + 8) as *mut u64),
+    ])
+}
+
+/// Make the sequence number odd, unless a write is already in progress.
+pub(crate) fn begin_write(mm: &MmapMut) {
+    if let Some([sequence, _]) = unsafe { sequence_fields(mm.as_ptr(), mm.len()) } {
+        publish_begin(sequence)
+    }
+}
+
+/// Publish the length and make the sequence number even, if a write is in progress.
+pub(crate) fn end_write(mm: &MmapMut, elems: u64) {
+    if let Some([sequence, len]) = unsafe { sequence_fields(mm.as_ptr(), mm.len()) } {
+        publish_end(sequence, len, elems)
+    }
+}
+
+// NOTE: The protocol itself, apart from where the sequence number and the length live, so
+//       that it can be checked under loom.
+
+fn publish_begin(sequence: &AtomicU64) {
+    let n = sequence.load(Ordering::Relaxed);
+    if n.is_multiple_of(2) {
+        sequence.store(n + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+    }
+}
+
+fn publish_end(sequence: &AtomicU64, len: &AtomicU64, elems: u64) {
+    let n = sequence.load(Ordering::Relaxed);
+    if n % 2 == 1 {
+        len.store(elems, Ordering::Relaxed);
+        sequence.store(n + 1, Ordering::Release);
+    }
+}
+
+/// The sequence number to [validate](read_validate) against once done reading, or `None`
+/// if a write is in progress.
+pub(crate) fn read_begin(sequence: &AtomicU64) -> Option<u64> {
+    let n = sequence.load(Ordering::Acquire);
+    match n % 2 {
+        0 => Some(n),
+        _ => None,
+    }
+}
+
+/// Whether no write has been made since [`read_begin`] returned `n`.
+pub(crate) fn read_validate(sequence: &AtomicU64, n: u64) -> bool {
+    fence(Ordering::Acquire);
+    sequence.load(Ordering::Relaxed) == n
+}
+
+impl<T> MmapedVec<T> {
+    // NOTE: A handle inherited across fork() shares the mapping with the parent, whose
+    //       writes it would interfere with, so it publishes nothing.
+
+    pub(crate) fn begin_write(&self) {
+        if !self.is_inherited_across_fork() {
+            begin_write(&self.mm)
+        }
+    }
+
+    pub(crate) fn end_write(&self) {
+        if !self.is_inherited_across_fork() {
+            end_write(&self.mm, self.len() as u64)
+        }
+    }
+}
+
+/// Read-only access to the elements of a file that may be open for writing as a
+/// [`MmapedVec`](MmapedVec) at the same time, in this or another process.
+///
+/// Takes no lock, and never waits for the writer. Instead, each read checks the sequence
+/// number that the writer publishes before and after reading, and is retried if a write
+/// happened in between, so that torn elements are never returned.
+///
+/// Only writes through the methods of [`MmapedVec`](MmapedVec) and its
+/// [`WriteGuard`](crate::WriteGuard) are published; writes through dereferencing it mutably
+/// are not. Shrinking the file while a read is in progress can still raise `SIGBUS` in the
+/// reader, as the pages it is reading may go away under it.
+pub struct OptimisticReader<T> {
+    path: PathBuf,
+    // NOTE: Must be dropped before the file, like the mapping of a MmapedVec.
+    mm: Mmap,
+    file: File,
+    header_len: usize,
+    /// The sequence number as of when the reader was opened or last refreshed.
+    seen: u64,
+    _marker: PhantomData<T>,
+}
+
+impl MmapedVecBuilder {
+    /// Open an existing file for optimistic reads. The file must have been opened for
+    /// writing by a version of this library that publishes the sequence of its writes.
+    pub fn try_open_optimistic<T: Copy>(&self, path: &Path) -> io::Result<OptimisticReader<T>> {
+        check_element_type::<T>(path)?;
+
+        let file = OpenOptions::new().read(true).open(path)?;
+        let fh = self.check_existing_file::<T, _>(&file, path)?;
+        let mm = unsafe { Mmap::map(&file)? };
+
+        let seen = match unsafe { sequence_fields(mm.as_ptr(), mm.len()) } {
+            Some([sequence, _]) => sequence.load(Ordering::Acquire),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!(
+                        "File `{:?}`: Has no sequence for optimistic reads. Open it for writing \
+          with this version of the library first.",
+                        path
+                    ),
+                ))
+            }
+        };
+
+        Ok(OptimisticReader {
+            path: path.to_path_buf(),
+            mm,
+            file,
+            header_len: fh.header_len as usize,
+            seen,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T: Copy> OptimisticReader<T> {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the path now names a different file than the one being read, as after the
+    /// writer [rolls](MmapedVec::roll) it. Reads carry on from the old file, which is no
+    /// longer written to, so [`reopen`](OptimisticReader::reopen) to follow the path.
+    pub fn is_replaced(&self) -> io::Result<bool> {
+        let (old, new) = (self.file.metadata()?, fs::metadata(&self.path)?);
+        Ok((old.dev(), old.ino()) != (new.dev(), new.ino()))
+    }
+
+    /// Open the file that the path names now, with `builder`.
+    pub fn reopen(&mut self, builder: &MmapedVecBuilder) -> io::Result<()> {
+        *self = builder.try_open_optimistic(&self.path)?;
+        Ok(())
+    }
+
+    /// Watch the file being read, to [wait](crate::Watch::wait) for the writer to flush
+    /// rather than poll [`has_changed`](OptimisticReader::has_changed).
+    #[cfg(all(target_os = "linux", feature = "watch"))]
+    pub fn watch(&self) -> io::Result<crate::Watch> {
+        crate::Watch::new(&self.path)
+    }
+
+    fn fields(&self) -> [&AtomicU64; 2] {
+        // NOTE: Checked when opening, and the header is never rewritten in place.
+        unsafe { sequence_fields(self.mm.as_ptr(), self.mm.len()) }
+            .expect("Sequence extension went missing.")
+    }
+
+    /// Whether the writer has written to the file since the reader was opened or last
+    /// [refreshed](OptimisticReader::refresh), which is cheap enough to check before each
+    /// time that the elements would be read anew.
+    pub fn has_changed(&self) -> bool {
+        self.fields()[0].load(Ordering::Acquire) != self.seen
+    }
+
+    /// Catch up with the writer, mapping the file again if it has grown, and return the
+    /// number of elements as of the last completed write. Until the next write,
+    /// [`has_changed`](OptimisticReader::has_changed) is false after this.
+    pub fn refresh(&mut self) -> io::Result<usize> {
+        let (sequence, len) =
+            self.read(|this, len| Ok((this.fields()[0].load(Ordering::Relaxed), len)))?;
+        self.seen = sequence;
+        Ok(len)
+    }
+
+    /// Start an optimistic read, returning the sequence number to
+    /// [`validate`](OptimisticReader::validate) against once done reading, or `None` if a
+    /// write is in progress.
+    pub fn begin(&self) -> Option<u64> {
+        read_begin(self.fields()[0])
+    }
+
+    /// Whether no write has been made since [`begin`](OptimisticReader::begin) returned
+    /// `sequence`, so that what was read in between is consistent.
+    pub fn validate(&self, sequence: u64) -> bool {
+        read_validate(self.fields()[0], sequence)
+    }
+
+    /// The length published by the writer. Only consistent with other reads between
+    /// [`begin`](OptimisticReader::begin) and a successful
+    /// [`validate`](OptimisticReader::validate).
+    pub fn published_len(&self) -> usize {
+        self.fields()[1].load(Ordering::Relaxed) as usize
+    }
+
+    /// Number of elements, as of the last completed write.
+    pub fn len(&mut self) -> io::Result<usize> {
+        self.read(|this, _| Ok(this.published_len()))
+    }
+
+    pub fn is_empty(&mut self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Copy out the element at `index`, or `None` if out of bounds.
+    pub fn get(&mut self, index: usize) -> io::Result<Option<T>> {
+        self.read(|this, len| match index < len {
+            true => Ok(Some(this.read_volatile(index..index + 1)[0])),
+            false => Ok(None),
+        })
+    }
+
+    /// Copy out the elements in `range`, as they were between two writes.
+    pub fn read_range(&mut self, range: Range<usize>) -> io::Result<Vec<T>> {
+        self.read(|this, len| {
+            if range.start > range.end || range.end > len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "File `{:?}`: Range {}..{} out of bounds for length {}.",
+                        this.path, range.start, range.end, len
+                    ),
+                ));
+            }
+            Ok(this.read_volatile(range.clone()))
+        })
+    }
+
+    /// Run `f` with the published length until it is not interfered with by a write,
+    /// remapping the file first if it has grown beyond the mapping.
+    fn read<R, F: FnMut(&Self, usize) -> io::Result<R>>(&mut self, mut f: F) -> io::Result<R> {
+        for attempt in 0..MAX_READ_ATTEMPTS {
+            if attempt > 0 && attempt % 16 == 0 {
+                thread::yield_now();
+            }
+
+            let sequence = match self.begin() {
+                Some(sequence) => sequence,
+                None => continue,
+            };
+
+            let len = self.published_len();
+            if self.header_len + len.saturating_mul(mem::size_of::<T>()) > self.mm.len() {
+                if self.validate(sequence) {
+                    self.mm = unsafe { Mmap::map(&self.file)? };
+                }
+                continue;
+            }
+
+            let result = f(self, len);
+            if self.validate(sequence) {
+                return result;
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            format!(
+                "File `{:?}`: Writes kept interfering with reading, giving up after {} attempts.",
+                self.path, MAX_READ_ATTEMPTS
+            ),
+        ))
+    }
+
+    /// Copy elements that may be being written to concurrently, which is why this goes
+    /// through volatile reads, and why the result must be validated before being used.
+    fn read_volatile(&self, range: Range<usize>) -> Vec<T> {
+        let body = unsafe { self.mm.as_ptr().add(self.header_len) as *const T };
+        range
+            .map(|i| unsafe { ptr::read_volatile(body.add(i)) })
+            .collect()
+    }
+}
+
+#[cfg(all(test, loom))]
+mod tests {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    // NOTE: The elements are relaxed atomics here, for the volatile reads and writes of a
+    //       mapping, which loom cannot model.
+
+    #[test]
+    pub fn test_loom_reads_validate_only_whole_writes() {
+        loom::model(|| {
+            let shared = Arc::new([0, 1, 2, 3].map(|_| AtomicU64::new(0)));
+
+            let writer = {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    let [sequence, len, a, b] = &*shared;
+                    for value in 1..=2 {
+                        publish_begin(sequence);
+                        a.store(value, Ordering::Relaxed);
+                        b.store(value, Ordering::Relaxed);
+                        publish_end(sequence, len, value);
+                    }
+                })
+            };
+
+            let [sequence, len, a, b] = &*shared;
+            if let Some(n) = read_begin(sequence) {
+                let seen = [len, a, b].map(|x| x.load(Ordering::Relaxed));
+                if read_validate(sequence, n) {
+                    assert_eq!(seen, [n / 2; 3]);
+                }
+            }
+
+            writer.join().unwrap();
+        });
+    }
+
+    #[test]
+    pub fn test_loom_reads_after_write_see_it() {
+        loom::model(|| {
+            let shared = Arc::new([0, 1, 2].map(|_| AtomicU64::new(0)));
+
+            let writer = {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    let [sequence, len, a] = &*shared;
+                    publish_begin(sequence);
+                    // NOTE: Nested writes publish once, when the outermost one ends.
+                    publish_begin(sequence);
+                    a.store(7, Ordering::Relaxed);
+                    publish_end(sequence, len, 1);
+                    publish_end(sequence, len, 1);
+                })
+            };
+
+            let [sequence, len, a] = &*shared;
+            if let Some(n) = read_begin(sequence) {
+                let seen = [len, a].map(|x| x.load(Ordering::Relaxed));
+                if read_validate(sequence, n) {
+                    match n {
+                        0 => assert_eq!(seen, [0, 0]),
+                        _ => assert_eq!(seen, [1, 7]),
+                    }
+                }
+            }
+
+            writer.join().unwrap();
+            assert_eq!(read_begin(sequence), Some(2));
+        });
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+
+    #[test]
+    pub fn test_optimistic_reads() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        let mut mv = builder.try_open::<u32>(&path)?;
+        mv.extend([1, 2, 3])?;
+
+        let mut reader = builder.try_open_optimistic::<u32>(&path)?;
+        assert_eq!(reader.read_range(0..3)?, vec![1, 2, 3]);
+
+        let sequence = reader.begin().unwrap();
+        mv.push(4)?;
+        assert!(!reader.validate(sequence));
+        assert_eq!(reader.len()?, 4);
+        assert_eq!(reader.get(3)?, Some(4));
+        assert!(reader.read_range(2..5).is_err());
+
+        {
+            let mut guard = mv.write_guard()?;
+            guard[0] = 10;
+            assert!(reader.begin().is_none());
+            assert!(reader.get(0).is_err());
+        }
+        assert_eq!(reader.get(0)?, Some(10));
+
+        mv.truncate(1)?;
+        assert_eq!(reader.len()?, 1);
+
+        assert!(reader.has_changed());
+        assert_eq!(reader.refresh()?, 1);
+        assert!(!reader.has_changed());
+        mv.extend([20, 30])?;
+        assert!(reader.has_changed());
+        assert_eq!(reader.refresh()?, 3);
+        assert!(!reader.has_changed());
+        mv.truncate(1)?;
+
+        drop(mv);
+        let mv = builder.try_open::<u32>(&path)?;
+        assert_eq!(reader.read_range(0..1)?, vec![10]);
+        assert!(mv
+            .header_extension(format::EXTENSION_TAG_SEQUENCE)
+            .is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_optimistic_reads_after_close() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        let mut mv = builder.try_open::<u32>(&path)?;
+        mv.extend([1, 2, 3])?;
+        mv.close()?;
+
+        let mut reader = builder.try_open_optimistic::<u32>(&path)?;
+        assert!(reader.begin().is_some());
+        assert_eq!(reader.len()?, 3);
+        assert_eq!(reader.read_range(0..3)?, vec![1, 2, 3]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Converting a [`MmapedVec`](MmapedVec) of structs into one [`MmapedVec`](MmapedVec) per
+//! field, side by side in a [`Store`](Store), for users moving from an array of structs to a
+//! struct of arrays.
+
+use crate::{MmapedVec, MmapedVecBuilder, Store};
+use std::io;
+
+/// Number of elements converted at a time, unless set with
+/// [`chunk_elems`](SoaConverter::chunk_elems).
+const DEFAULT_CHUNK_ELEMS: usize = 64 * 1024;
+
+trait ColumnWriter<T> {
+    fn write_chunk(&mut self, chunk: &[T]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+struct Column<F, E> {
+    mv: MmapedVec<F>,
+    extract: E,
+}
+
+impl<T, F, E: Fn(&T) -> F> ColumnWriter<T> for Column<F, E> {
+    fn write_chunk(&mut self, chunk: &[T]) -> io::Result<()> {
+        self.mv.extend(chunk.iter().map(&self.extract))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.mv.flush()
+    }
+}
+
+/// Conversion of the elements of a [`MmapedVec`](MmapedVec) into columns, as started with
+/// [`to_columns`](MmapedVec::to_columns).
+pub struct SoaConverter<'a, T> {
+    source: &'a MmapedVec<T>,
+    store: &'a Store,
+    columns: Vec<Box<dyn ColumnWriter<T> + 'a>>,
+    chunk_elems: usize,
+}
+
+impl<T> MmapedVec<T> {
+    /// Start converting the elements into columns in `store`, one for each field added with
+    /// [`column`](SoaConverter::column), which are written by [`run`](SoaConverter::run).
+    pub fn to_columns<'a>(&'a self, store: &'a Store) -> SoaConverter<'a, T> {
+        SoaConverter {
+            source: self,
+            store,
+            columns: vec![],
+            chunk_elems: DEFAULT_CHUNK_ELEMS,
+        }
+    }
+}
+
+impl<'a, T> SoaConverter<'a, T> {
+    /// Add a column by the name of `name` in the store, opened with `builder`, holding what
+    /// `extract` returns for each element.
+    ///
+    /// Fails with [`AlreadyExists`](io::ErrorKind::AlreadyExists) if the file of the column
+    /// already holds elements, rather than appending to them.
+    pub fn column<F, E>(
+        &mut self,
+        name: &str,
+        builder: &MmapedVecBuilder,
+        extract: E,
+    ) -> io::Result<&mut Self>
+    where
+        F: Sized + Default + 'a,
+        E: Fn(&T) -> F + 'a,
+    {
+        let mv: MmapedVec<F> = self.store.open_vec(builder, name)?;
+        if !mv.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "File `{:?}`: Already holds elements.",
+                    self.store.path(name)?
+                ),
+            ));
+        }
+
+        self.columns.push(Box::new(Column { mv, extract }));
+        Ok(self)
+    }
+
+    /// Convert `chunk_elems` elements at a time, which bounds the memory used for buffering
+    /// to that many of the largest field.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_elems` is zero.
+    pub fn chunk_elems(&mut self, chunk_elems: usize) -> &mut Self {
+        assert!(chunk_elems > 0, "Chunks must hold at least one element.");
+        self.chunk_elems = chunk_elems;
+        self
+    }
+
+    /// Write all columns, a chunk of elements at a time, and flush them, returning the
+    /// number of elements converted.
+    pub fn run(&mut self) -> io::Result<usize> {
+        for chunk in self.source.chunks(self.chunk_elems) {
+            for column in self.columns.iter_mut() {
+                column.write_chunk(chunk)?;
+            }
+        }
+
+        for column in self.columns.iter_mut() {
+            column.flush()?;
+        }
+
+        Ok(self.source.len())
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        tempdir_and_tempfile, Example, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES,
+    };
+
+    #[test]
+    pub fn test_convert_to_columns() -> Result<(), io::Error> {
+        let (dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let mut mv: MmapedVec<Example> = builder.try_open(&path)?;
+        mv.extend((0..100).map(|i| Example {
+            hello: i,
+            world: i / 2,
+        }))?;
+
+        let store = Store::open(&dir.path().join("columns"))?;
+        let n = mv
+            .to_columns(&store)
+            .column("hello", &builder, |e: &Example| e.hello)?
+            .column("world", &builder, |e: &Example| u32::from(e.world))?
+            .chunk_elems(7)
+            .run()?;
+        assert_eq!(n, 100);
+
+        let hello: MmapedVec<u8> = store.open_vec(&builder, "hello")?;
+        let world: MmapedVec<u32> = store.open_vec(&builder, "world")?;
+        assert!(hello.iter().copied().eq(0..100));
+        assert!(world.iter().copied().eq((0..100).map(|i| i / 2)));
+        drop((hello, world));
+
+        assert!(mv
+            .to_columns(&store)
+            .column("hello", &builder, |e: &Example| e.hello)
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::MmapedVec;
+use fs2::FileExt;
+use memmap::MmapMut;
+use std::cmp::Ordering;
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{io, mem, process, ptr, slice};
+
+/// Size of the runs that are sorted in memory before being merged.
+const RUN_BYTES: usize = 64 << 20;
+
+impl<T> MmapedVec<T> {
+    /// Sort the elements with the comparator function `cmp`, for bodies larger than memory.
+    ///
+    /// The body is split into runs that are sorted one at a time in place, which are then
+    /// merged into a temporary file in `scratch_dir`, and copied back. The temporary file is
+    /// as large as the body, and is removed when done. Like [`slice::sort_by`], the sort is
+    /// stable.
+    ///
+    /// The sort is not crash safe. If it is interrupted while the merged elements are being
+    /// copied back, some elements will be lost and others duplicated. Take a copy of the
+    /// file first with [`persist_to`](MmapedVec::persist_to) if that matters.
+    pub fn sort_external_by<F>(&mut self, cmp: F, scratch_dir: &Path) -> io::Result<()>
+    where
+        F: FnMut(&T, &T) -> Ordering,
+    {
+        let run_elems = (RUN_BYTES / mem::size_of::<T>()).max(1);
+        self.sort_external_by_with_run_len(cmp, scratch_dir, run_elems)
+    }
+
+    pub(crate) fn sort_external_by_with_run_len<F>(
+        &mut self,
+        mut cmp: F,
+        scratch_dir: &Path,
+        run_elems: usize,
+    ) -> io::Result<()>
+    where
+        F: FnMut(&T, &T) -> Ordering,
+    {
+        self.check_poisoned()?;
+
+        let len = self.len();
+        if len < 2 {
+            return Ok(());
+        }
+
+        self.set_writable(true)?;
+        let body = unsafe { slice::from_raw_parts_mut(self.body_mut_ptr(), len) };
+
+        let runs: Vec<(usize, usize)> = (0..len)
+            .step_by(run_elems)
+            .map(|start| (start, len.min(start + run_elems)))
+            .collect();
+
+        for &(start, end) in &runs {
+            body[start..end].sort_by(&mut cmp);
+        }
+
+        if runs.len() == 1 {
+            self.set_writable(false)?;
+            return self.replicate_range(0..len);
+        }
+
+        let scratch_path = scratch_dir.join(format!(
+            "persistence-sort-{}-{}.tmp",
+            process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0)
+        ));
+        let scratch = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&scratch_path)?;
+        // Nobody else needs to see the file, and this way it goes away however we exit.
+        fs::remove_file(&scratch_path)?;
+
+        // Allocate rather than set the length, so that running out of space is an error
+        // here rather than a SIGBUS when writing to the mapping.
+        scratch.allocate((len * mem::size_of::<T>()) as u64)?;
+        let mut out = unsafe { MmapMut::map_mut(&scratch)? };
+        let out_ptr = out.as_mut_ptr() as *mut T;
+
+        let mut pos: Vec<usize> = runs.iter().map(|&(start, _)| start).collect();
+        let mut heap: Vec<usize> = (0..runs.len()).collect();
+
+        // Ties go to the earlier run, which keeps the sort stable.
+        let mut less = |a: usize, b: usize, pos: &[usize]| match cmp(&body[pos[a]], &body[pos[b]]) {
+            Ordering::Less => true,
+            Ordering::Greater => false,
+            Ordering::Equal => a < b,
+        };
+
+        for i in (0..heap.len() / 2).rev() {
+            sift_down(&mut heap, i, |a, b| less(a, b, &pos));
+        }
+
+        for k in 0..len {
+            let run = heap[0];
+            unsafe { ptr::copy_nonoverlapping(&body[pos[run]], out_ptr.add(k), 1) };
+            pos[run] += 1;
+
+            if pos[run] == runs[run].1 {
+                let last = heap.pop().unwrap();
+                if heap.is_empty() {
+                    break;
+                }
+                heap[0] = last;
+            }
+
+            sift_down(&mut heap, 0, |a, b| less(a, b, &pos));
+        }
+
+        unsafe { ptr::copy_nonoverlapping(out_ptr as *const T, body.as_mut_ptr(), len) };
+        drop(out);
+
+        self.set_writable(false)?;
+        self.replicate_range(0..len)
+    }
+}
+
+/// Restore the heap property of the min-heap `heap` below `i`.
+fn sift_down<L: FnMut(usize, usize) -> bool>(heap: &mut [usize], mut i: usize, mut less: L) {
+    loop {
+        let left = 2 * i + 1;
+        let right = left + 1;
+        let mut smallest = i;
+
+        if left < heap.len() && less(heap[left], heap[smallest]) {
+            smallest = left;
+        }
+        if right < heap.len() && less(heap[right], heap[smallest]) {
+            smallest = right;
+        }
+        if smallest == i {
+            return;
+        }
+
+        heap.swap(i, smallest);
+        i = smallest;
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{new_mmaped_vec_of_example_persisting_in_tempdir, Example};
+
+    #[test]
+    pub fn test_sort_external_by() -> Result<(), io::Error> {
+        let (dir, _pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+
+        mv.extend((0..1000u32).map(|i| Example {
+            hello: (i * 7919 % 1000 / 10) as u8,
+            world: (i % 251) as u8,
+        }))?;
+        let mut expected: Vec<(u8, u8)> = mv.iter().map(|e| (e.hello, e.world)).collect();
+        expected.sort_by_key(|&(hello, _)| hello);
+
+        mv.sort_external_by_with_run_len(|a, b| a.hello.cmp(&b.hello), dir.path(), 64)?;
+
+        let sorted: Vec<(u8, u8)> = mv.iter().map(|e| (e.hello, e.world)).collect();
+        assert_eq!(sorted, expected);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+
+        mv.sort_external_by(|a, b| b.world.cmp(&a.world), dir.path())?;
+        assert!(mv.windows(2).all(|w| w[0].world >= w[1].world));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::MmapedVec;
+use std::{io, mem};
+
+impl<T> MmapedVec<T> {
+    /// Grow to hold an element at `index`, if it does not already, filling new elements
+    /// with copies of the [`default_data`](MmapedVec::default_data) like
+    /// [`resize`](MmapedVec::resize), so that the elements can be addressed by external IDs.
+    ///
+    /// When the default data is all zero bytes, new elements are not written at all, and
+    /// the file is left sparse where they are, so that elements that are never set take up
+    /// no disk space on file systems that support sparse files.
+    ///
+    /// Fails with [`InvalidInput`](io::ErrorKind::InvalidInput) when growing a file that
+    /// has no default data.
+    pub fn ensure_index(&mut self, index: usize) -> io::Result<()> {
+        self.check_poisoned()?;
+
+        let len = self.len();
+
+        if index < len {
+            return Ok(());
+        }
+
+        let new_len = index.checked_add(1).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("File `{:?}`: Index {} is too large.", self.path, index),
+            )
+        })?;
+
+        let fh = self.header();
+        let size = mem::size_of::<T>();
+        let default_is_zero = fh.has_default_data()
+            && self.mm[fh.default_data_offset as usize..][..size]
+                .iter()
+                .all(|b| *b == 0);
+
+        if !default_is_zero {
+            return self.resize(new_len);
+        }
+
+        // NOTE: Growing the file reads back as zeros, as does preallocated space.
+        self.grow(new_len - len)?;
+        self.replicate_range(len..new_len)
+    }
+
+    /// Replace the element at `index`, first growing with
+    /// [`ensure_index`](MmapedVec::ensure_index) if needed.
+    pub fn set(&mut self, index: usize, value: T) -> io::Result<()> {
+        self.ensure_index(index)?;
+
+        self.set_writable(true)?;
+        unsafe { *self.body_mut_ptr().add(index) = value };
+        self.set_writable(false)?;
+        self.replicate_range(index..index + 1)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        tempdir_and_tempfile, Example, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES,
+    };
+    use crate::MmapedVecBuilder;
+    use fs2::FileExt;
+    use std::fs::File;
+
+    #[test]
+    pub fn test_ensure_index_and_set() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let mut mv = builder.try_open::<u64>(&path)?;
+        mv.set(1 << 20, 42)?;
+        mv.set(3, 1)?;
+        assert_eq!(mv.len(), (1 << 20) + 1);
+        assert_eq!((mv[2], mv[3], mv[1 << 20]), (0, 1, 42));
+        let file = File::open(&path)?;
+        assert!(file.allocated_size()? < file.metadata()?.len() / 2);
+
+        let mut mv = builder.try_open_with_default_data::<Example>(
+            &path.with_extension("nonzero"),
+            Example { hello: 7, world: 8 },
+        )?;
+        mv.ensure_index(2)?;
+        mv.set(1, Example { hello: 1, world: 2 })?;
+        assert_eq!((mv[0].hello, mv[1].hello, mv[2].world), (7, 1, 8));
+
+        let mut mv = builder.try_open_without_default_data::<u64>(&path.with_extension("none"))?;
+        assert!(mv.ensure_index(0).is_err());
+
+        Ok(())
+    }
+}
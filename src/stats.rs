@@ -0,0 +1,411 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Summary statistics of a key of the elements, kept in a sidecar next to the file, for
+//! query planning and monitoring to consult without scanning the body, with
+//! [`stats`](MmapedVec::stats).
+//!
+//! Quantiles are estimated with a sketch in the manner of DDSketch: keys are counted in
+//! buckets whose bounds grow geometrically, so that any quantile is within a relative
+//! accuracy of the true value, however the keys are distributed.
+//!
+//! The sidecar starts with `STATS_MAGIC`, and then, in native byte order, the relative
+//! accuracy, the number of elements covered, the count, minimum, maximum and sum of the
+//! keys, the count of zeros and the number of buckets, as 8 bytes each. Then comes each
+//! bucket, as an `i64` index, negative for negative keys, and a `u64` count.
+
+use crate::MmapedVec;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Suffix of the sidecar that is kept next to files with statistics.
+pub const STATS_SUFFIX: &str = ".stats";
+
+pub(crate) const STATS_MAGIC: [u8; 8] = *b"PERSSTAT";
+const STATS_HEADER_LEN: usize = 8 * 9;
+
+/// Path of the statistics sidecar of the file at `path`.
+pub fn stats_path(path: &Path) -> PathBuf {
+    let mut stats_path = OsString::from(path.as_os_str());
+    stats_path.push(STATS_SUFFIX);
+    PathBuf::from(stats_path)
+}
+
+/// Summary statistics of the keys of the elements of a file, as returned by
+/// [`stats`](MmapedVec::stats). Keys that are NaN are not counted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatsSketch {
+    relative_accuracy: f64,
+    count: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    zeros: u64,
+    /// Counts by bucket index, with the index negated for negative keys.
+    buckets: BTreeMap<i64, u64>,
+}
+
+impl StatsSketch {
+    fn new(relative_accuracy: f64) -> Self {
+        Self {
+            relative_accuracy,
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sum: 0.0,
+            zeros: 0,
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    fn gamma(&self) -> f64 {
+        (1.0 + self.relative_accuracy) / (1.0 - self.relative_accuracy)
+    }
+
+    fn add(&mut self, key: f64) {
+        if key.is_nan() {
+            return;
+        }
+
+        self.count += 1;
+        self.min = self.min.min(key);
+        self.max = self.max.max(key);
+        self.sum += key;
+
+        // NOTE: Magnitudes below one are shifted into positive indices by the offset, so
+        //       that the sign of the index is free to encode the sign of the key.
+        if key == 0.0 {
+            self.zeros += 1;
+        } else {
+            let index = (key.abs().ln() / self.gamma().ln()).ceil() as i64 + BUCKET_OFFSET;
+            let index = index.clamp(1, 2 * BUCKET_OFFSET);
+            let signed = match key > 0.0 {
+                true => index,
+                false => -index,
+            };
+            *self.buckets.entry(signed).or_insert(0) += 1;
+        }
+    }
+
+    /// The relative accuracy of the quantiles.
+    pub fn relative_accuracy(&self) -> f64 {
+        self.relative_accuracy
+    }
+
+    /// The number of keys counted.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The smallest key, or `None` when no keys were counted.
+    pub fn min(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    /// The largest key, or `None` when no keys were counted.
+    pub fn max(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    /// The sum of the keys.
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// The mean of the keys, or `None` when no keys were counted.
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+
+    /// The key at quantile `q`, between zero and one, within the relative accuracy.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 || !(0.0..=1.0).contains(&q) {
+            return None;
+        }
+
+        let rank = (q * (self.count - 1) as f64).round() as u64;
+        let value = |index: i64| {
+            let exponent = (index.abs() - BUCKET_OFFSET) as f64;
+            let magnitude = 2.0 * self.gamma().powf(exponent) / (self.gamma() + 1.0);
+            magnitude.copysign(index as f64)
+        };
+
+        // NOTE: Negative keys come in order of decreasing magnitude, so the most negative
+        //       bucket, which has the lowest index, comes first.
+        let mut seen = 0;
+        for (index, count) in self.buckets.range(..0) {
+            seen += count;
+            if rank < seen {
+                return Some(value(*index).clamp(self.min, self.max));
+            }
+        }
+        seen += self.zeros;
+        if rank < seen {
+            return Some(0.0);
+        }
+        for (index, count) in self.buckets.range(1..) {
+            seen += count;
+            if rank < seen {
+                return Some(value(*index).clamp(self.min, self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    fn to_bytes(&self, covered: usize) -> Vec<u8> {
+        let mut buf = STATS_MAGIC.to_vec();
+        buf.extend_from_slice(&self.relative_accuracy.to_ne_bytes());
+        buf.extend_from_slice(&(covered as u64).to_ne_bytes());
+        buf.extend_from_slice(&self.count.to_ne_bytes());
+        buf.extend_from_slice(&self.min.to_ne_bytes());
+        buf.extend_from_slice(&self.max.to_ne_bytes());
+        buf.extend_from_slice(&self.sum.to_ne_bytes());
+        buf.extend_from_slice(&self.zeros.to_ne_bytes());
+        buf.extend_from_slice(&(self.buckets.len() as u64).to_ne_bytes());
+        for (index, count) in &self.buckets {
+            buf.extend_from_slice(&index.to_ne_bytes());
+            buf.extend_from_slice(&count.to_ne_bytes());
+        }
+        buf
+    }
+
+    /// The sketch in `buf`, and how many elements it covers.
+    fn from_bytes(buf: &[u8]) -> Option<(Self, usize)> {
+        let field = |i: usize| -> Option<[u8; 8]> { buf.get(i * 8..i * 8 + 8)?.try_into().ok() };
+
+        if buf.get(..8)? != STATS_MAGIC {
+            return None;
+        }
+        let mut sketch = Self::new(f64::from_ne_bytes(field(1)?));
+        let covered = u64::from_ne_bytes(field(2)?) as usize;
+        sketch.count = u64::from_ne_bytes(field(3)?);
+        sketch.min = f64::from_ne_bytes(field(4)?);
+        sketch.max = f64::from_ne_bytes(field(5)?);
+        sketch.sum = f64::from_ne_bytes(field(6)?);
+        sketch.zeros = u64::from_ne_bytes(field(7)?);
+        let buckets = u64::from_ne_bytes(field(8)?) as usize;
+
+        for i in 0..buckets {
+            let at = STATS_HEADER_LEN / 8 + 2 * i;
+            let index = i64::from_ne_bytes(field(at)?);
+            let count = u64::from_ne_bytes(field(at + 1)?);
+            sketch.buckets.insert(index, count);
+        }
+        Some((sketch, covered))
+    }
+}
+
+/// Offset of the bucket index of keys of magnitude one.
+const BUCKET_OFFSET: i64 = 1 << 20;
+
+type KeyFn<T> = dyn Fn(&T) -> f64 + Send;
+
+/// The statistics of a file, which elements they cover, and how to get the key of an
+/// element.
+pub(crate) struct Stats<T> {
+    path: PathBuf,
+    sketch: StatsSketch,
+    /// The statistics are of the elements before this index.
+    covered: usize,
+    /// Elements before `covered` were modified, so the statistics must be rebuilt.
+    stale: bool,
+    committed: bool,
+    key: Box<KeyFn<T>>,
+}
+
+impl<T> Stats<T> {
+    fn rebuild(&mut self, body: &[T]) {
+        self.sketch = StatsSketch::new(self.sketch.relative_accuracy);
+        self.covered = 0;
+        self.stale = false;
+        self.append(body);
+    }
+
+    /// Add the keys of `elems`, which come right after those covered.
+    fn append(&mut self, elems: &[T]) {
+        for elem in elems {
+            self.sketch.add((self.key)(elem));
+        }
+        self.covered += elems.len();
+        self.committed = false;
+    }
+
+    pub(crate) fn modified(&mut self, range: Range<usize>, body: &[T]) {
+        match range.start == self.covered && range.end <= body.len() {
+            true => self.append(&body[range]),
+            false => self.stale = true,
+        }
+    }
+
+    fn refresh(&mut self, body: &[T]) {
+        if self.stale || self.covered > body.len() {
+            self.rebuild(body);
+        } else if self.covered < body.len() {
+            self.append(&body[self.covered..]);
+        }
+    }
+
+    /// Bring the statistics up to date with `body`, and write them to the sidecar if they
+    /// changed since it was last written.
+    pub(crate) fn commit(&mut self, body: &[T]) -> io::Result<()> {
+        self.refresh(body);
+        if self.committed {
+            return Ok(());
+        }
+
+        // NOTE: Written aside and renamed into place, so that a crash leaves either sidecar.
+        let mut tmp_name = OsString::from(self.path.as_os_str());
+        tmp_name.push(".tmp");
+        let tmp = PathBuf::from(tmp_name);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)?;
+        file.write_all(&self.sketch.to_bytes(self.covered))?;
+        file.sync_data()?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// Keep statistics of the keys that `key` returns for the elements, in a sidecar next to
+    /// the file that is written on each flush, with quantiles within `relative_accuracy`.
+    /// The statistics are rebuilt from the elements when the sidecar does not match, such
+    /// as after a crash.
+    ///
+    /// Appended elements are added as they come. Any other modification recorded with
+    /// [`mark_modified`](MmapedVec::mark_modified), and shrinking, make the statistics be
+    /// rebuilt by scanning the body on the next flush or call to
+    /// [`stats`](MmapedVec::stats).
+    ///
+    /// NOTE: Modifications made while the file is opened without statistics go unnoticed,
+    /// unless they change its length, so enable them every time the file is opened.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `relative_accuracy` is between zero and one.
+    pub fn statistics<F>(&mut self, relative_accuracy: f64, key: F) -> io::Result<()>
+    where
+        F: Fn(&T) -> f64 + Send + 'static,
+    {
+        assert!(
+            relative_accuracy > 0.0 && relative_accuracy < 1.0,
+            "The relative accuracy must be between zero and one."
+        );
+
+        let path = stats_path(&self.path);
+        let loaded = match fs::read(&path) {
+            Ok(buf) => StatsSketch::from_bytes(&buf),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        let mut stats = Stats {
+            path,
+            sketch: StatsSketch::new(relative_accuracy),
+            covered: 0,
+            stale: true,
+            committed: false,
+            key: Box::new(key),
+        };
+
+        if let Some((sketch, covered)) = loaded {
+            if sketch.relative_accuracy == relative_accuracy
+                && covered == self.len()
+                && self.recovered_from_crash().is_none()
+            {
+                stats.sketch = sketch;
+                stats.covered = covered;
+                stats.stale = false;
+                stats.committed = true;
+            }
+        }
+
+        stats.commit(self)?;
+        self.stats = Some(stats);
+        Ok(())
+    }
+
+    pub(crate) fn stats_modified(&mut self, range: Range<usize>) {
+        let body = unsafe { std::slice::from_raw_parts(self.as_ptr(), self.len()) };
+        if let Some(stats) = self.stats.as_mut() {
+            stats.modified(range, body);
+        }
+    }
+
+    /// The statistics of the keys of the elements, brought up to date first, or `None`
+    /// without [`statistics`](MmapedVec::statistics).
+    pub fn stats(&mut self) -> Option<&StatsSketch> {
+        let body = unsafe { std::slice::from_raw_parts(self.as_ptr(), self.len()) };
+        let stats = self.stats.as_mut()?;
+        stats.refresh(body);
+        Some(&stats.sketch)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::MmapedVecBuilder;
+
+    #[test]
+    pub fn test_statistics() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        let key = |v: &u64| *v as f64;
+
+        let mut mv: MmapedVec<u64> = builder.try_open(&path)?;
+        assert!(mv.stats().is_none());
+        mv.extend(1..=500)?;
+        mv.statistics(0.01, key)?;
+        mv.extend(501..=1000)?;
+        let stats = mv.stats().unwrap();
+        assert_eq!(stats.count(), 1000);
+        assert_eq!(stats.min(), Some(1.0));
+        assert_eq!(stats.max(), Some(1000.0));
+        assert_eq!(stats.mean(), Some(500.5));
+        let median = stats.quantile(0.5).unwrap();
+        assert!((median - 500.0).abs() <= 500.0 * 0.01, "median {}", median);
+        let p99 = stats.quantile(0.99).unwrap();
+        assert!((p99 - 990.0).abs() <= 990.0 * 0.01, "p99 {}", p99);
+        mv.flush()?;
+        drop(mv);
+        assert!(stats_path(&path).exists());
+
+        let mut mv: MmapedVec<u64> = builder.try_open(&path)?;
+        mv.statistics(0.01, key)?;
+        assert_eq!(mv.stats().unwrap().count(), 1000);
+        mv[0] = 5000;
+        mv.mark_modified(0..1);
+        mv.truncate(10)?;
+        let stats = mv.stats().unwrap();
+        assert_eq!(stats.count(), 10);
+        assert_eq!(stats.min(), Some(2.0));
+        assert_eq!(stats.max(), Some(5000.0));
+
+        Ok(())
+    }
+}
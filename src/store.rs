@@ -0,0 +1,259 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::format::{
+    self, FileHeader, ENDIANNESS_MARKER, EXTENSION_TAG_CRITICAL, FILE_HEADER_LEN,
+    KNOWN_EXTENSION_TAGS, PERSISTENCE_FORMAT_VERSION,
+};
+use crate::{
+    janitor, locking, MmapedVec, MmapedVecBuilder, BLOOM_SUFFIX, CONSUMERS_SUFFIX, FREE_SUFFIX,
+    LOCK_SUFFIX, MERKLE_SUFFIX, REPLAY_SUFFIX, STATS_SUFFIX, TOMBSTONES_SUFFIX, WAL_SUFFIX,
+    ZONES_SUFFIX,
+};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+/// Name of the file in the directory of a [`Store`](Store) that is locked while the store
+/// is open.
+pub const STORE_LOCK_FILE_NAME: &str = ".store.lock";
+
+/// Suffixes of the temporary files that the library writes next to the files it manages,
+/// which are orphaned when it is interrupted.
+pub(crate) const TEMP_SUFFIXES: &[&str] = &[".tmp", ".roll-tmp"];
+
+/// Suffixes of the files that the library keeps next to the files it manages, which are
+/// not files of their own.
+pub(crate) const SIDECAR_SUFFIXES: &[&str] = &[
+    WAL_SUFFIX,
+    MERKLE_SUFFIX,
+    CONSUMERS_SUFFIX,
+    BLOOM_SUFFIX,
+    STATS_SUFFIX,
+    ZONES_SUFFIX,
+    FREE_SUFFIX,
+    TOMBSTONES_SUFFIX,
+    LOCK_SUFFIX,
+    REPLAY_SUFFIX,
+];
+
+/// A directory of files, each opened by its name within the directory.
+///
+/// The directory is locked for as long as the store is open, so that only one process at a
+/// time manages it.
+pub struct Store {
+    dir: PathBuf,
+    _lock: File,
+}
+
+impl Store {
+    /// Open the store in `dir`, creating the directory if needed.
+    ///
+    /// All files in the directory have their headers checked, as far as that can be done
+    /// without knowing what they contain, and opening fails listing every file that would
+    /// not open. Orphaned temporary files and the sidecars of files that are gone are removed
+    /// with [`janitor::clean`](janitor::clean), which leaves alone files that this library did
+    /// not write, and files that are locked.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let lock_path = dir.join(STORE_LOCK_FILE_NAME);
+        let lock = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)?;
+        locking::try_lock_exclusive(&lock, &lock_path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Store `{:?}`: Could not lock; is it in use? ({})", dir, e),
+            )
+        })?;
+
+        let store = Self {
+            dir: dir.to_path_buf(),
+            _lock: lock,
+        };
+
+        janitor::clean(dir)?;
+
+        let mut problems = vec![];
+        for name in store.names()? {
+            if let Err(e) = check_header(&store.path(&name)?) {
+                problems.push(e.to_string());
+            }
+        }
+        if !problems.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Store `{:?}`: {} file(s) failed validation: {}",
+                    dir,
+                    problems.len(),
+                    problems.join(" ")
+                ),
+            ));
+        }
+
+        Ok(store)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Names of the files in the store, sorted.
+    pub fn names(&self) -> io::Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                if !name.starts_with('.') && !is_temp_file(name) && !is_sidecar_file(name) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Path of the file by the name of `name`, which must be a plain file name that is
+    /// neither hidden nor that of a temporary or sidecar file.
+    pub fn path(&self, name: &str) -> io::Result<PathBuf> {
+        if name.is_empty()
+            || name.starts_with('.')
+            || name.contains('/')
+            || is_temp_file(name)
+            || is_sidecar_file(name)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Store `{:?}`: Invalid name {:?}.", self.dir, name),
+            ));
+        }
+
+        Ok(self.dir.join(name))
+    }
+
+    /// Open or create the file by the name of `name` with `builder`.
+    pub fn open_vec<T: Sized + Default>(
+        &self,
+        builder: &MmapedVecBuilder,
+        name: &str,
+    ) -> io::Result<MmapedVec<T>> {
+        builder.try_open(&self.path(name)?)
+    }
+}
+
+fn is_temp_file(name: &str) -> bool {
+    TEMP_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+}
+
+fn is_sidecar_file(name: &str) -> bool {
+    SIDECAR_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+}
+
+/// Check what can be checked of a header without knowing the magic bytes or element type.
+pub(crate) fn check_header(path: &Path) -> io::Result<FileHeader> {
+    let invalid = |msg: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("File `{:?}`: {}", path, msg),
+        )
+    };
+
+    let file = File::open(path)?;
+    let flen = file.metadata()?.len();
+    if flen == 0 {
+        return Err(invalid("Empty."));
+    }
+    if flen < FILE_HEADER_LEN as u64 {
+        return Err(invalid("Shorter than the header."));
+    }
+
+    let fh = FileHeader::read_from(&file)?;
+
+    if fh.endianness != ENDIANNESS_MARKER {
+        return Err(invalid("Wrong endianness, or endianness-marker invalid."));
+    }
+    if fh.persistence_format_version != PERSISTENCE_FORMAT_VERSION {
+        return Err(invalid(&format!(
+            "Unsupported persistence format version {:?}.",
+            fh.persistence_format_version
+        )));
+    }
+    if flen < fh.header_len || fh.header_len < fh.extensions_offset as u64 {
+        return Err(invalid("Header length is out of bounds."));
+    }
+
+    let mut extensions_area = vec![0u8; (fh.header_len - fh.extensions_offset as u64) as usize];
+    file.read_exact_at(&mut extensions_area, fh.extensions_offset as u64)?;
+    match format::parse_extensions(&extensions_area) {
+        None => return Err(invalid("Malformed header extensions.")),
+        Some(extensions) => {
+            if extensions.iter().any(|(tag, _)| {
+                tag & EXTENSION_TAG_CRITICAL != 0 && !KNOWN_EXTENSION_TAGS.contains(tag)
+            }) {
+                return Err(invalid("Requires an unsupported header extension."));
+            }
+        }
+    }
+
+    Ok(fh)
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{Example, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+
+    #[test]
+    pub fn test_store() -> Result<(), io::Error> {
+        let dir = tempfile::tempdir()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let store = Store::open(dir.path())?;
+        assert!(Store::open(dir.path()).is_err());
+        store
+            .open_vec::<Example>(&builder, "b")?
+            .push(Example::default())?;
+        store.open_vec::<Example>(&builder, "a")?;
+        assert!(store.path("../a").is_err());
+        assert!(store.path("a.tmp").is_err());
+        drop(store);
+
+        // NOTE: Not written by the library, so it is not for the store to remove.
+        fs::write(dir.path().join("a.tmp"), b"not ours")?;
+        fs::copy(dir.path().join("b"), dir.path().join("b.tmp"))?;
+        let store = Store::open(dir.path())?;
+        assert_eq!(store.names()?, vec!["a", "b"]);
+        assert!(dir.path().join("a.tmp").exists());
+        assert!(!dir.path().join("b.tmp").exists());
+        assert_eq!(store.open_vec::<Example>(&builder, "b")?.len(), 1);
+        drop(store);
+
+        fs::write(dir.path().join("c"), b"garbage")?;
+        let err = Store::open(dir.path()).err().unwrap();
+        assert!(err.to_string().contains("1 file(s) failed validation"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! The atomics through which a writer publishes its writes to
+//! [`OptimisticReader`](crate::OptimisticReader)s, which are loom's under `cfg(loom)`, so
+//! that the protocol can be checked with `RUSTFLAGS="--cfg loom" cargo test --lib loom`.
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{fence, AtomicU64, Ordering};
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::{fence, AtomicU64, Ordering};
+
+/// The atomic at `ptr`, in memory that may be shared with other processes.
+///
+/// # Safety
+///
+/// As for [`AtomicU64::from_ptr`].
+#[cfg(not(loom))]
+pub(crate) unsafe fn atomic_u64_from_ptr<'a>(ptr: *mut u64) -> &'a AtomicU64 {
+    AtomicU64::from_ptr(ptr)
+}
+
+// NOTE: Loom cannot model memory shared with other processes, so only the protocol itself
+//       is checked under loom, on atomics that it owns, and never a mapping.
+#[cfg(loom)]
+pub(crate) unsafe fn atomic_u64_from_ptr<'a>(_ptr: *mut u64) -> &'a AtomicU64 {
+    unimplemented!("Mapped atomics are not modelled under loom.")
+}
@@ -0,0 +1,154 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Temporary files, for intermediate results that are spilled to disk but should not outlive
+//! the process, with the same API as files that persist.
+
+use crate::{locking, MmapedVec, MmapedVecBuilder};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+static TEMPORARY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl<T: Sized + Default> MmapedVec<T> {
+    /// Like [`try_new`](MmapedVec::try_new), but backed by a temporary file that is deleted
+    /// when the [`MmapedVec`](MmapedVec) is dropped. See
+    /// [`try_open_temporary`](MmapedVecBuilder::try_open_temporary).
+    pub fn temporary(magic_bytes: [u8; 8], data_contained_version: [u8; 3]) -> io::Result<Self> {
+        MmapedVecBuilder::new(magic_bytes, data_contained_version).try_open_temporary(None)
+    }
+}
+
+impl MmapedVecBuilder {
+    /// Create a [`MmapedVec`](MmapedVec) backed by a file in `dir`, or in the temporary
+    /// directory of the system if `None`, which has no name and so goes away once closed.
+    ///
+    /// Where supported, the file is created with `O_TMPFILE` and never has a name. Elsewhere,
+    /// the file is created under a unique name, which is removed again right after it has
+    /// been opened. Either way, the file is also deleted if the process crashes. The
+    /// contents can be kept anyway with [`persist_to`](MmapedVec::persist_to).
+    ///
+    /// Fails for builders with [`wal`](MmapedVecBuilder::wal) or
+    /// [`merkle_tree`](MmapedVecBuilder::merkle_tree) set, as their sidecars would outlive
+    /// the file.
+    pub fn try_open_temporary<T: Sized + Default>(
+        &self,
+        dir: Option<&Path>,
+    ) -> io::Result<MmapedVec<T>> {
+        let dir = dir.map_or_else(env::temp_dir, Path::to_path_buf);
+        let path = PathBuf::from(format!("tmpfile:{}", dir.display()));
+
+        if self.wal || self.merkle_tree.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "File `{:?}`: Temporary files cannot have a log or a hash tree.",
+                    path
+                ),
+            ));
+        }
+
+        let file = create_unnamed(&dir)?;
+        locking::try_lock_exclusive(&file, &path)?;
+
+        self.try_from_locked_file(file, None, &path, Some(T::default()))
+    }
+}
+
+/// Create a file in `dir` that has no name.
+fn create_unnamed(dir: &Path) -> io::Result<File> {
+    #[cfg(target_os = "linux")]
+    {
+        let result = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .mode(0o600)
+            .custom_flags(libc::O_TMPFILE)
+            .open(dir);
+
+        // NOTE: Filesystems without support for O_TMPFILE fail with EOPNOTSUPP, and kernels
+        //       that predate it may treat it as O_DIRECTORY and fail with EISDIR.
+        match result {
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::EOPNOTSUPP) | Some(libc::EISDIR)
+                ) => {}
+            result => return result,
+        }
+    }
+
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    let path = dir.join(format!(
+        ".persistence-{}-{}-{}.tmp",
+        process::id(),
+        TEMPORARY_COUNTER.fetch_add(1, Ordering::Relaxed),
+        nanos
+    ));
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+
+    Ok(file)
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+
+    #[test]
+    pub fn test_temporary_vec_leaves_nothing_behind() -> Result<(), io::Error> {
+        let dir = tempfile::tempdir()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let mut mv: MmapedVec<u64> = builder.try_open_temporary(Some(dir.path()))?;
+        mv.extend(0..1000)?;
+        mv.flush()?;
+        assert_eq!(mv[999], 999);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 0);
+
+        let kept = dir.path().join("kept.bin");
+        mv.persist_to(&kept)?;
+        drop(mv);
+
+        let mv: MmapedVec<u64> = builder.try_open(&kept)?;
+        assert_eq!(mv.len(), 1000);
+        drop(mv);
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+
+        assert!(builder
+            .clone()
+            .wal(true)
+            .try_open_temporary::<u64>(Some(dir.path()))
+            .is_err());
+
+        Ok(())
+    }
+}
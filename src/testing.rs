@@ -0,0 +1,329 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Crash-simulation harness for verifying that data survives crashes, enabled with the
+//! `testing` feature.
+//!
+//! The harness runs a workload against a [`MmapedVec`](MmapedVec) in a child process,
+//! which is killed at a randomly chosen [`Failpoint`](Failpoint), as if the machine had
+//! crashed there. The file is then reopened in the parent process, and checked.
+//!
+//! Elements are written in place and the length of the file is increased before new
+//! elements are written, so a crash in the middle of an append can leave zeroed elements
+//! at the end of the file that was being appended to. Checks should allow for this.
+//!
+//! There are also helpers for property-based testing of custom element types against the
+//! format. They take plain values and bytes, so that they plug into proptest, quickcheck
+//! or cargo-fuzz alike:
+//!
+//! ```ignore
+//! proptest! {
+//!     #[test]
+//!     fn round_trips(seed in any::<[u8; 11]>(), elems in vec(any::<MyElem>(), 0..100)) {
+//!         let dir = tempfile::tempdir().unwrap();
+//!         let builder = testing::builder_from_bytes(&seed);
+//!         let path = dir.path().join("file.bin");
+//!         prop_assert!(testing::check_round_trip(&builder, &path, None, &elems).is_ok());
+//!     }
+//! }
+//! ```
+
+use crate::failpoints::{self, Action, Failpoint};
+use crate::{MmapedVec, MmapedVecBuilder};
+use std::fmt::Debug;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::{io, mem, ptr};
+
+const FAILPOINTS: [Failpoint; 5] = [
+    Failpoint::AfterHeaderWrite,
+    Failpoint::MidRemap,
+    Failpoint::BetweenLengthCommitAndDataWrite,
+    Failpoint::DuringMsync,
+    Failpoint::AfterRollRename,
+];
+
+/// Runs a workload repeatedly in child processes that are killed at random failpoints.
+///
+/// The same seed always kills the children at the same points, so that failures can be
+/// reproduced.
+pub struct CrashHarness {
+    path: PathBuf,
+    iterations: usize,
+    max_skip: usize,
+    seed: u64,
+}
+
+impl CrashHarness {
+    /// A harness for the file at `path`, which persists between iterations.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            iterations: 16,
+            max_skip: 8,
+            seed: 0x5eed,
+        }
+    }
+
+    /// Number of times to run the workload. Defaults to 16.
+    pub fn iterations(&mut self, iterations: usize) -> &mut Self {
+        self.iterations = iterations;
+        self
+    }
+
+    /// The chosen failpoint may be reached up to this many times before the child is
+    /// killed, so that it is not always killed at the first opportunity. Defaults to 8.
+    pub fn max_skip(&mut self, max_skip: usize) -> &mut Self {
+        self.max_skip = max_skip;
+        self
+    }
+
+    pub fn seed(&mut self, seed: u64) -> &mut Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Run `workload` on the vector returned by `open` in a child process per iteration,
+    /// then reopen the file with `open` and run `check` on it.
+    ///
+    /// Fails if the file cannot be reopened after a crash, if `check` fails, or if the
+    /// workload fails for any other reason than the simulated crash. The error tells the
+    /// iteration and the failpoint, for reproducing the failure.
+    ///
+    /// NOTE: Uses `fork()`. The child process only runs `open` and `workload`, but if other
+    ///       threads in the calling process hold locks that these need, the child will hang.
+    pub fn run<T, O, W, C>(&self, open: O, workload: W, check: C) -> io::Result<()>
+    where
+        O: Fn(&Path) -> io::Result<MmapedVec<T>>,
+        W: Fn(&mut MmapedVec<T>) -> io::Result<()>,
+        C: Fn(&MmapedVec<T>) -> io::Result<()>,
+    {
+        let mut rng = self.seed.max(1);
+
+        for iteration in 0..self.iterations {
+            let point = FAILPOINTS[(next_random(&mut rng) % FAILPOINTS.len() as u64) as usize];
+            let skip = (next_random(&mut rng) % (self.max_skip as u64 + 1)) as usize;
+
+            let context = |what: &str| {
+                format!(
+                    "File `{:?}`: {} in iteration {} (seed {:#x}, failpoint {:?} after {} hits).",
+                    self.path, what, iteration, self.seed, point, skip
+                )
+            };
+
+            match unsafe { libc::fork() } {
+                -1 => return Err(io::Error::last_os_error()),
+                0 => {
+                    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                        failpoints::set_after(point, skip, Action::Abort);
+                        let mut mv = open(&self.path)?;
+                        workload(&mut mv)?;
+                        mv.flush()
+                    }));
+                    let code = match res {
+                        Ok(Ok(())) => 0,
+                        Ok(Err(_)) => 1,
+                        Err(_) => 2,
+                    };
+                    unsafe { libc::_exit(code) };
+                }
+                pid => {
+                    let mut status = 0;
+                    if unsafe { libc::waitpid(pid, &mut status, 0) } == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+
+                    let crashed =
+                        libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGABRT;
+                    let completed = libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0;
+
+                    if !crashed && !completed {
+                        return Err(io::Error::other(context(
+                            "Workload failed for another reason than the simulated crash",
+                        )));
+                    }
+                }
+            }
+
+            let mv = open(&self.path).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    context(&format!("Could not reopen after crash ({})", e)),
+                )
+            })?;
+
+            check(&mv).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    context(&format!("Check failed after crash ({})", e)),
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A builder with magic bytes and data contained version taken from `bytes`, which is
+/// padded with zeroes if it is shorter than 11 bytes.
+pub fn builder_from_bytes(bytes: &[u8]) -> MmapedVecBuilder {
+    let mut buf = [0u8; 11];
+    let n = bytes.len().min(buf.len());
+    buf[..n].copy_from_slice(&bytes[..n]);
+
+    let mut magic_bytes = [0u8; 8];
+    let mut data_contained_version = [0u8; 3];
+    magic_bytes.copy_from_slice(&buf[..8]);
+    data_contained_version.copy_from_slice(&buf[8..]);
+
+    MmapedVecBuilder::new(magic_bytes, data_contained_version)
+}
+
+/// Elements made from the bytes in `bytes`, ignoring any bytes left over at the end.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid `T`, as is the case for integers, floats and
+/// `#[repr(C)]` structs of those without padding.
+pub unsafe fn elements_from_bytes<T: Copy>(bytes: &[u8]) -> Vec<T> {
+    bytes
+        .chunks_exact(mem::size_of::<T>())
+        .map(|chunk| ptr::read_unaligned(chunk.as_ptr() as *const T))
+        .collect()
+}
+
+/// Create a file at `path` holding `elements`, with or without default data, then reopen
+/// it and check that the header, the default data and the elements all read back the same,
+/// and that the file is exactly as long as the format says it should be.
+///
+/// Fails with [`InvalidData`](io::ErrorKind::InvalidData) describing the first mismatch.
+pub fn check_round_trip<T: Clone + PartialEq + Debug>(
+    builder: &MmapedVecBuilder,
+    path: &Path,
+    default_data: Option<T>,
+    elements: &[T],
+) -> io::Result<()> {
+    let open = |default_data: Option<T>| match default_data {
+        Some(default_data) => builder.try_open_with_default_data(path, default_data),
+        None => builder.try_open_without_default_data(path),
+    };
+
+    let mut mv = open(default_data.clone())?;
+    let header = mv.header();
+    mv.extend(elements.iter().cloned())?;
+    mv.flush()?;
+    drop(mv);
+
+    let mv = open(default_data.clone())?;
+
+    let mismatch = |what: &str, expected: &dyn Debug, found: &dyn Debug| {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "File `{:?}`: {} did not round-trip; expected {:?}, found {:?}.",
+                path, what, expected, found
+            ),
+        ))
+    };
+
+    if mv.header() != header {
+        return mismatch("Header", &header, &mv.header());
+    }
+
+    if mv.default_data() != default_data.as_ref() {
+        return mismatch("Default data", &default_data, &mv.default_data());
+    }
+
+    if &mv[..] != elements {
+        return mismatch("Elements", &elements, &&mv[..]);
+    }
+
+    let expected_len = header.header_len + mem::size_of_val(elements) as u64;
+    let flen = mv.file.metadata()?.len();
+    if flen != expected_len {
+        return mismatch("File length", &expected_len, &flen);
+    }
+
+    Ok(())
+}
+
+/// xorshift64, which is plenty for picking failpoints.
+fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        tempdir_and_tempfile, Example, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES,
+    };
+
+    #[test]
+    pub fn test_crash_harness() -> Result<(), io::Error> {
+        let (_dir, pathbuf) = tempdir_and_tempfile()?;
+
+        CrashHarness::new(&pathbuf).iterations(8).run(
+            |path| {
+                MmapedVec::<Example>::try_new(
+                    path,
+                    EXAMPLE_MAGIC_BYTES,
+                    EXAMPLE_DATA_CONTAINED_VERSION,
+                )
+            },
+            |mv| {
+                for _ in 0..4 {
+                    mv.push(Example { hello: 3, world: 4 })?;
+                    mv.flush()?;
+                }
+                Ok(())
+            },
+            |mv| match mv
+                .iter()
+                .all(|e| (e.hello, e.world) == (3, 4) || (e.hello, e.world) == (0, 0))
+            {
+                true => Ok(()),
+                false => Err(io::Error::new(io::ErrorKind::InvalidData, "Bad element.")),
+            },
+        )
+    }
+
+    #[test]
+    pub fn test_round_trip_helpers() -> Result<(), io::Error> {
+        let dir = tempfile::tempdir()?;
+
+        for i in 0..16u8 {
+            let seed: Vec<u8> = (0..11)
+                .map(|j| i.wrapping_mul(31).wrapping_add(j))
+                .collect();
+            let builder = builder_from_bytes(&seed);
+            let bytes: Vec<u8> = (0..i as usize * 13).map(|j| j as u8 ^ i).collect();
+            let elements = unsafe { elements_from_bytes::<u32>(&bytes) };
+            let default_data = match i % 2 {
+                0 => Some(u32::from(i)),
+                _ => None,
+            };
+
+            let path = dir.path().join(format!("file-{}.bin", i));
+            check_round_trip(&builder, &path, default_data, &elements)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,322 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Deletion by tombstone: [`mark_deleted`](MmapedVec::mark_deleted) only records that an
+//! element is gone, in a sidecar next to the file, so that indices stay put, and
+//! [`vacuum`](MmapedVec::vacuum) later moves the remaining elements together and reports
+//! where each of them went.
+//!
+//! The sidecar starts with `TOMBSTONES_MAGIC` and the length of the file as a `u64` in
+//! native byte order, followed by one bit per element, set for those marked deleted, in
+//! `u64` words.
+
+use crate::MmapedVec;
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+/// Suffix of the sidecar that is kept next to files with tombstones.
+pub const TOMBSTONES_SUFFIX: &str = ".tombstones";
+
+pub(crate) const TOMBSTONES_MAGIC: [u8; 8] = *b"PERSTOMB";
+const TOMBSTONES_HEADER_LEN: usize = 16;
+
+/// Path of the tombstone sidecar of the file at `path`.
+pub fn tombstones_path(path: &Path) -> PathBuf {
+    let mut tombstones_path = OsString::from(path.as_os_str());
+    tombstones_path.push(TOMBSTONES_SUFFIX);
+    PathBuf::from(tombstones_path)
+}
+
+/// The elements of a file that are marked deleted.
+pub(crate) struct Tombstones {
+    path: PathBuf,
+    words: Vec<u64>,
+    committed: bool,
+}
+
+impl Tombstones {
+    fn contains(&self, index: usize) -> bool {
+        self.words
+            .get(index / 64)
+            .is_some_and(|word| word & (1 << (index % 64)) != 0)
+    }
+
+    fn count(&self, len: usize) -> usize {
+        let full: u32 = self
+            .words
+            .iter()
+            .take(len / 64)
+            .map(|w| w.count_ones())
+            .sum();
+        let partial = self
+            .words
+            .get(len / 64)
+            .map_or(0, |word| (word & ((1 << (len % 64)) - 1)).count_ones());
+        (full + partial) as usize
+    }
+
+    /// Write the tombstones to the sidecar if they changed since it was last written.
+    pub(crate) fn commit(&mut self, len: usize) -> io::Result<()> {
+        // NOTE: Tombstones past the end were cut off by truncating the file.
+        if self.words.len() > len.div_ceil(64) {
+            self.words.truncate(len.div_ceil(64));
+            self.committed = false;
+        }
+        if let Some(last) = self.words.get_mut(len / 64) {
+            if *last >> (len % 64) != 0 {
+                *last &= (1 << (len % 64)) - 1;
+                self.committed = false;
+            }
+        }
+        if self.committed {
+            return Ok(());
+        }
+
+        let mut buf = TOMBSTONES_MAGIC.to_vec();
+        buf.extend_from_slice(&(len as u64).to_ne_bytes());
+        for i in 0..len.div_ceil(64) {
+            let word = self.words.get(i).copied().unwrap_or(0);
+            buf.extend_from_slice(&word.to_ne_bytes());
+        }
+
+        // NOTE: Written aside and renamed into place, so that a crash leaves either sidecar.
+        let mut tmp_name = OsString::from(self.path.as_os_str());
+        tmp_name.push(".tmp");
+        let tmp = PathBuf::from(tmp_name);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.committed = true;
+        Ok(())
+    }
+}
+
+/// The words of tombstones in `buf`, if it was written for a file of at most `len`
+/// elements.
+fn parse_words(buf: &[u8], len: usize) -> Option<Vec<u64>> {
+    if buf.get(..8)? != TOMBSTONES_MAGIC {
+        return None;
+    }
+
+    // NOTE: A sidecar for a longer file is left from a vacuum that the crash cut short,
+    //       after the elements were moved, so its tombstones are of elements long gone.
+    let recorded_len = u64::from_ne_bytes(buf.get(8..16)?.try_into().ok()?) as usize;
+    let words = buf.get(TOMBSTONES_HEADER_LEN..)?;
+    if recorded_len > len || words.len() != recorded_len.div_ceil(64) * 8 {
+        return None;
+    }
+
+    Some(
+        words
+            .chunks_exact(8)
+            .map(|word| u64::from_ne_bytes(word.try_into().unwrap()))
+            .collect(),
+    )
+}
+
+impl<T> MmapedVec<T> {
+    /// Keep tombstones in a sidecar next to the file, so that elements can be
+    /// [marked deleted](MmapedVec::mark_deleted) without moving any other, and removed
+    /// for good by [`vacuum`](MmapedVec::vacuum). The tombstones are written on each flush.
+    ///
+    /// NOTE: Shrinking the file while it is opened without tombstones, and then growing it
+    /// again, leaves tombstones on the new elements, so enable them every time the file is
+    /// opened.
+    pub fn tombstones(&mut self) -> io::Result<()> {
+        let path = tombstones_path(&self.path);
+        let words = match fs::read(&path) {
+            Ok(buf) => parse_words(&buf, self.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        let mut tombstones = Tombstones {
+            path,
+            words: words.unwrap_or_default(),
+            committed: false,
+        };
+        tombstones.commit(self.len())?;
+
+        self.tombstones = Some(tombstones);
+        Ok(())
+    }
+
+    /// Mark the element at `index` deleted. It stays where it is, and so do all others,
+    /// until the next [`vacuum`](MmapedVec::vacuum).
+    ///
+    /// # Panics
+    ///
+    /// Panics without [`tombstones`](MmapedVec::tombstones), or if `index` is out of bounds.
+    pub fn mark_deleted(&mut self, index: usize) {
+        assert!(index < self.len(), "Index out of bounds.");
+
+        let tombstones = self
+            .tombstones
+            .as_mut()
+            .expect("Marking elements deleted requires tombstones.");
+        if tombstones.words.len() <= index / 64 {
+            tombstones.words.resize(index / 64 + 1, 0);
+        }
+        tombstones.words[index / 64] |= 1 << (index % 64);
+        tombstones.committed = false;
+    }
+
+    /// Whether the element at `index` is [marked deleted](MmapedVec::mark_deleted).
+    pub fn is_deleted(&self, index: usize) -> bool {
+        index < self.len()
+            && self
+                .tombstones
+                .as_ref()
+                .is_some_and(|tombstones| tombstones.contains(index))
+    }
+
+    /// The number of elements [marked deleted](MmapedVec::mark_deleted).
+    pub fn deleted_count(&self) -> usize {
+        self.tombstones
+            .as_ref()
+            .map_or(0, |tombstones| tombstones.count(self.len()))
+    }
+
+    /// Iterate over the elements that are not [marked deleted](MmapedVec::mark_deleted),
+    /// along with their indices.
+    pub fn iter_live(&self) -> impl Iterator<Item = (usize, &T)> + '_ {
+        self.iter()
+            .enumerate()
+            .filter(move |(i, _)| !self.is_deleted(*i))
+    }
+
+    /// Remove the elements that are [marked deleted](MmapedVec::mark_deleted), along with
+    /// the slots of the [free list](MmapedVec::free_list), by moving the other elements
+    /// together, keeping their order, and shrinking the file. Returns where each element
+    /// went: the new index of the element that was at each old index, or `None` for those
+    /// removed.
+    ///
+    /// The moved elements are flushed before the tombstones are cleared, so that a crash in
+    /// between never leaves tombstones on elements that were not marked deleted.
+    ///
+    /// # Panics
+    ///
+    /// Panics without [`tombstones`](MmapedVec::tombstones).
+    pub fn vacuum(&mut self) -> io::Result<Vec<Option<usize>>> {
+        self.check_poisoned()?;
+
+        let len = self.len();
+        let tombstones = self
+            .tombstones
+            .as_ref()
+            .expect("Vacuuming requires tombstones.");
+        let free = self.free.as_ref();
+        let dead = |i: usize| tombstones.contains(i) || free.is_some_and(|free| free.contains(i));
+
+        let mut remapping = Vec::with_capacity(len);
+        let mut moves = vec![];
+        let mut new_len = 0;
+        for i in 0..len {
+            if dead(i) {
+                remapping.push(None);
+                continue;
+            }
+            if new_len != i {
+                moves.push((i, new_len));
+            }
+            remapping.push(Some(new_len));
+            new_len += 1;
+        }
+
+        if let Some(&(_, first)) = moves.first() {
+            self.set_writable(true)?;
+            for (from, to) in moves {
+                let body = self.body_mut_ptr();
+                unsafe { ptr::copy_nonoverlapping(body.add(from), body.add(to), 1) };
+            }
+            self.set_writable(false)?;
+            self.mark_modified(first..new_len);
+        }
+        self.truncate(new_len)?;
+        self.flush()?;
+
+        if let Some(tombstones) = self.tombstones.as_mut() {
+            tombstones.words.clear();
+            tombstones.committed = false;
+            tombstones.commit(new_len)?;
+        }
+        if let Some(free) = self.free.as_mut() {
+            free.clear(new_len)?;
+        }
+
+        Ok(remapping)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::MmapedVecBuilder;
+
+    #[test]
+    pub fn test_tombstones_and_vacuum() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let mut mv: MmapedVec<u64> = builder.try_open(&path)?;
+        mv.extend(0..100)?;
+        mv.tombstones()?;
+        for i in (0..100).step_by(3) {
+            mv.mark_deleted(i);
+        }
+        assert!(mv.is_deleted(99));
+        assert!(!mv.is_deleted(98));
+        assert_eq!(mv.deleted_count(), 34);
+        assert_eq!(mv.iter_live().count(), 66);
+        mv.flush()?;
+        drop(mv);
+        assert!(tombstones_path(&path).exists());
+
+        let mut mv: MmapedVec<u64> = builder.try_open(&path)?;
+        mv.tombstones()?;
+        assert_eq!(mv.deleted_count(), 34);
+        mv.free_list()?;
+        assert_eq!(mv.vacate(1)?, 1);
+        let remapping = mv.vacuum()?;
+        assert_eq!(remapping.len(), 100);
+        assert_eq!(
+            remapping[..6],
+            [None, None, Some(0), None, Some(1), Some(2)]
+        );
+        assert_eq!(mv.len(), 65);
+        assert_eq!(mv[..4], [2, 4, 5, 7]);
+        assert_eq!(mv.deleted_count(), 0);
+        assert_eq!(mv.free_slots(), 0);
+        drop(mv);
+
+        let mut mv: MmapedVec<u64> = builder.try_open(&path)?;
+        mv.tombstones()?;
+        assert_eq!(mv.deleted_count(), 0);
+
+        Ok(())
+    }
+}
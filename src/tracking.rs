@@ -0,0 +1,202 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::{MmapedVec, MmapedVecBuilder};
+use std::ops::Range;
+
+/// Generation of each block of elements, for [`modified_since`](MmapedVec::modified_since).
+pub(crate) struct ModificationTracker {
+    block_elems: usize,
+    generation: u64,
+    blocks: Vec<u64>,
+}
+
+impl ModificationTracker {
+    /// Track `len` elements, all of which count as modified in the first generation, since
+    /// what happened to them before they were opened is not known.
+    pub(crate) fn new(block_elems: usize, len: usize) -> Self {
+        Self {
+            block_elems,
+            generation: 1,
+            blocks: vec![1; len.div_ceil(block_elems)],
+        }
+    }
+
+    pub(crate) fn record(&mut self, range: Range<usize>) {
+        if range.start >= range.end {
+            return;
+        }
+
+        self.generation += 1;
+
+        let blocks = range.start / self.block_elems..(range.end - 1) / self.block_elems + 1;
+        if self.blocks.len() < blocks.end {
+            self.blocks.resize(blocks.end, 0);
+        }
+        for block in &mut self.blocks[blocks] {
+            *block = self.generation;
+        }
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.blocks.truncate(len.div_ceil(self.block_elems));
+    }
+}
+
+impl MmapedVecBuilder {
+    /// Keep track of which elements are modified, in blocks of `block_elems` elements, for
+    /// [`modified_since`](MmapedVec::modified_since). Off by default.
+    ///
+    /// The generation of each block is kept in memory only, so after reopening, all
+    /// elements count as modified in the first generation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_elems` is zero.
+    pub fn track_modifications(&mut self, block_elems: usize) -> &mut Self {
+        assert!(block_elems > 0, "Blocks must hold at least one element.");
+        self.track_modifications = Some(block_elems);
+        self
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// The generation of the last modification, to pass to
+    /// [`modified_since`](MmapedVec::modified_since) later on, or `None` unless opened with
+    /// [`track_modifications`](MmapedVecBuilder::track_modifications).
+    pub fn modification_generation(&self) -> Option<u64> {
+        self.tracker.as_ref().map(|tracker| tracker.generation)
+    }
+
+    /// The ranges of elements modified after `generation`, in order and with adjacent
+    /// ranges joined. The ranges cover whole blocks, so they may include elements that were
+    /// not modified themselves.
+    ///
+    /// Modifications made by the methods of the [`MmapedVec`](MmapedVec) are recorded as they
+    /// are made. Those made through dereferencing it mutably must be recorded with
+    /// [`mark_modified`](MmapedVec::mark_modified), which a [`WriteGuard`](crate::WriteGuard)
+    /// does for the elements it covers when dropped. Without tracking, nothing is returned.
+    pub fn modified_since(&self, generation: u64) -> impl Iterator<Item = Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = vec![];
+        let len = self.len();
+
+        if let Some(tracker) = self.tracker.as_ref() {
+            for (i, _) in tracker
+                .blocks
+                .iter()
+                .enumerate()
+                .filter(|(_, g)| **g > generation)
+            {
+                let start = i * tracker.block_elems;
+                if start >= len {
+                    break;
+                }
+                let end = (start + tracker.block_elems).min(len);
+                match ranges.last_mut() {
+                    Some(last) if last.end == start => last.end = end,
+                    _ => ranges.push(start..end),
+                }
+            }
+        }
+
+        ranges.into_iter()
+    }
+
+    /// Record the elements in `range` as modified, if modifications are tracked,
+    /// [subscribed to](MmapedVec::subscribe), [logged](MmapedVecBuilder::wal),
+    /// [hashed](MmapedVecBuilder::merkle_tree), [summarized](MmapedVec::statistics),
+    /// [zone mapped](MmapedVec::zone_map) or [recorded](MmapedVec::record_replay).
+    pub fn mark_modified(&mut self, range: Range<usize>) {
+        if let Some(wal) = self.wal.as_mut() {
+            wal.modified(range.clone());
+        }
+        if let Some(feed) = self.change_feed.as_mut() {
+            feed.modified(range.clone());
+        }
+        if let Some(tree) = self.merkle.as_mut() {
+            tree.modified(range.clone());
+        }
+        if let Some(direct) = self.direct.as_mut() {
+            direct.modified(range.clone());
+        }
+        self.bloom_insert(range.clone());
+        self.stats_modified(range.clone());
+        self.zones_modified(range.clone());
+        self.replay_modified(range.clone());
+        if let Some(tracker) = self.tracker.as_mut() {
+            tracker.record(range);
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use std::io;
+
+    #[test]
+    pub fn test_modified_since() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        builder.try_open::<u32>(&path)?.extend(0..10)?;
+
+        let mut mv = builder
+            .clone()
+            .track_modifications(4)
+            .try_open::<u32>(&path)?;
+        assert_eq!(mv.modified_since(0).collect::<Vec<_>>(), vec![0..10]);
+
+        let generation = mv.modification_generation().unwrap();
+        assert_eq!(mv.modified_since(generation).count(), 0);
+
+        mv.push(10)?;
+        mv[1] = 100;
+        mv.mark_modified(1..2);
+        assert_eq!(
+            mv.modified_since(generation).collect::<Vec<_>>(),
+            vec![0..4, 8..11]
+        );
+
+        let generation = mv.modification_generation().unwrap();
+        mv.copy_within(0..2, 4)?;
+        mv.truncate(5)?;
+        assert_eq!(
+            mv.modified_since(generation).collect::<Vec<_>>(),
+            vec![4..5]
+        );
+
+        let generation = mv.modification_generation().unwrap();
+        mv.write_guard_range(4..5)?[0] = 1;
+        assert_eq!(
+            mv.modified_since(generation).collect::<Vec<_>>(),
+            vec![4..5]
+        );
+        assert_eq!(
+            mv.write_guard_range(4..6).err().unwrap().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        let generation = mv.modification_generation().unwrap();
+        mv.write_guard()?[0] = 1;
+        assert_eq!(
+            mv.modified_since(generation).collect::<Vec<_>>(),
+            vec![0..5]
+        );
+
+        Ok(())
+    }
+}
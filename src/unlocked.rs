@@ -0,0 +1,170 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Reading a file as it is, without taking any lock and without any of the checks that
+//! keep reads consistent, for forensic and monitoring tools that must not disturb whoever is
+//! writing to it. Use an [`OptimisticReader`](crate::OptimisticReader) instead wherever it
+//! will do.
+
+use crate::seqlock::{read_begin, read_validate, sequence_fields};
+use crate::sync::Ordering;
+use crate::{check_element_type, MmapedVecBuilder};
+use memmap::Mmap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::slice;
+
+/// The elements of a file, mapped read-only without a lock, as returned by
+/// [`read_unlocked`](MmapedVecBuilder::read_unlocked).
+///
+/// Holds the elements that fit in the file as it was when last mapped. Any of them may be
+/// torn, if a writer was in the middle of writing it, and those that the writer has
+/// appended but not yet written hold zeros.
+pub struct UnlockedReader<T> {
+    path: PathBuf,
+    // NOTE: Must be dropped before the file, like the mapping of a MmapedVec.
+    mm: Mmap,
+    file: File,
+    header_len: usize,
+    /// The sequence number as of when the file was last mapped, if it publishes one.
+    seen: Option<u64>,
+    _marker: PhantomData<T>,
+}
+
+impl MmapedVecBuilder {
+    /// Map the file at `path` read-only, without taking its lock, so that neither a writer
+    /// that holds it nor one that is about to take it is held up.
+    ///
+    /// # Safety
+    ///
+    /// Nothing keeps a writer from changing the elements while they are being read, which
+    /// is a data race, and the reader may see them torn, or see one but not another of two
+    /// writes that go together. Shrinking the file while it is being read raises `SIGBUS`
+    /// in the reader, as the pages it is reading go away under it.
+    ///
+    /// The caller must make sure that no process writes to the file, or put up with all of
+    /// that, as a tool that only inspects the file might. Where the writer publishes the
+    /// sequence of its writes, [`begin`](UnlockedReader::begin) and
+    /// [`validate`](UnlockedReader::validate) tell whether a write happened while reading.
+    pub unsafe fn read_unlocked<T: Copy>(&self, path: &Path) -> io::Result<UnlockedReader<T>> {
+        check_element_type::<T>(path)?;
+
+        let file = OpenOptions::new().read(true).open(path)?;
+        let fh = self.check_existing_file::<T, _>(&file, path)?;
+        let mm = Mmap::map(&file)?;
+
+        Ok(UnlockedReader {
+            path: path.to_path_buf(),
+            seen: sequence(&mm),
+            mm,
+            file,
+            header_len: fh.header_len as usize,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T: Copy> UnlockedReader<T> {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Map the file again, to take in what has been appended to it since it was mapped.
+    pub fn remap(&mut self) -> io::Result<()> {
+        self.mm = unsafe { Mmap::map(&self.file)? };
+        self.seen = sequence(&self.mm);
+        Ok(())
+    }
+
+    /// Whether the file has changed length, or the writer has published a write, since the
+    /// file was last [mapped](UnlockedReader::remap).
+    pub fn has_changed(&self) -> io::Result<bool> {
+        Ok(self.file.metadata()?.len() != self.mm.len() as u64 || sequence(&self.mm) != self.seen)
+    }
+
+    /// Watch the file being read, to [wait](crate::Watch::wait) for the writer to flush or
+    /// resize it rather than poll [`has_changed`](UnlockedReader::has_changed).
+    #[cfg(all(target_os = "linux", feature = "watch"))]
+    pub fn watch(&self) -> io::Result<crate::Watch> {
+        crate::Watch::new(&self.path)
+    }
+
+    /// The sequence number of the writes to the file, to
+    /// [`validate`](UnlockedReader::validate) against once done reading, or `None` if a
+    /// write is in progress, or the file does not publish the sequence of its writes.
+    pub fn begin(&self) -> Option<u64> {
+        let [sequence, _] = unsafe { sequence_fields(self.mm.as_ptr(), self.mm.len()) }?;
+        read_begin(sequence)
+    }
+
+    /// Whether no write has been made since [`begin`](UnlockedReader::begin) returned
+    /// `sequence`. Only writes that the writer publishes are seen.
+    pub fn validate(&self, sequence: u64) -> bool {
+        unsafe { sequence_fields(self.mm.as_ptr(), self.mm.len()) }
+            .is_some_and(|[n, _]| read_validate(n, sequence))
+    }
+}
+
+/// The sequence number that the writer publishes in `mm`, if it does.
+fn sequence(mm: &Mmap) -> Option<u64> {
+    let [sequence, _] = unsafe { sequence_fields(mm.as_ptr(), mm.len()) }?;
+    Some(sequence.load(Ordering::Acquire))
+}
+
+impl<T> Deref for UnlockedReader<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        let len = (self.mm.len().saturating_sub(self.header_len)) / mem::size_of::<T>();
+        unsafe { slice::from_raw_parts(self.mm.as_ptr().add(self.header_len) as *const T, len) }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::MmapedVec;
+
+    #[test]
+    pub fn test_read_unlocked() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        let mut mv: MmapedVec<u64> = builder.try_open(&path)?;
+        mv.extend(0..10)?;
+
+        let mut reader = unsafe { builder.read_unlocked::<u64>(&path)? };
+        assert_eq!(reader[..], (0..10).collect::<Vec<_>>()[..]);
+        let sequence = reader.begin().unwrap();
+        assert!(reader.validate(sequence));
+
+        assert!(!reader.has_changed()?);
+        mv.push(10)?;
+        assert!(!reader.validate(sequence));
+        assert!(reader.has_changed()?);
+        reader.remap()?;
+        assert!(!reader.has_changed()?);
+        assert_eq!(reader.len(), 11);
+        assert_eq!(reader[10], 10);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::format::OFFSET_DATA_CONTAINED_VERSION;
+use crate::{MmapedVec, MmapedVecBuilder};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// What to do when opening a file whose `data_contained_version` differs from the one
+/// asked for, as decided by the policy set with
+/// [`version_policy`](MmapedVecBuilder::version_policy).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VersionDecision {
+    /// Open the file, leaving its version as it is.
+    Accept,
+    /// Open the file for the elements to be migrated, after which
+    /// [`finish_migration`](MmapedVec::finish_migration) gives it the version asked for.
+    Migrate,
+    /// Fail with [`InvalidData`](io::ErrorKind::InvalidData).
+    Reject,
+}
+
+type PolicyFn = dyn Fn([u8; 3], [u8; 3]) -> VersionDecision + Send + Sync;
+
+#[derive(Clone)]
+pub(crate) struct VersionPolicy(Arc<PolicyFn>);
+
+impl fmt::Debug for VersionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("VersionPolicy(..)")
+    }
+}
+
+impl MmapedVecBuilder {
+    /// Decide what to do with files whose `data_contained_version` differs from the one of
+    /// this builder, by calling `policy` with the version of the file and the one asked for.
+    ///
+    /// Without a policy, only files of the same version are opened. A policy for accepting
+    /// any minor and patch version of the same major version is:
+    ///
+    /// ```
+    /// # use persistence::{MmapedVecBuilder, VersionDecision};
+    /// let mut builder = MmapedVecBuilder::new(*b"EXAMPLE\0", [1, 2, 0]);
+    /// builder.version_policy(|file, requested| match file[0] == requested[0] {
+    ///     true => VersionDecision::Accept,
+    ///     false => VersionDecision::Reject,
+    /// });
+    /// ```
+    pub fn version_policy<F>(&mut self, policy: F) -> &mut Self
+    where
+        F: Fn([u8; 3], [u8; 3]) -> VersionDecision + Send + Sync + 'static,
+    {
+        self.version_policy = Some(VersionPolicy(Arc::new(policy)));
+        self
+    }
+
+    /// Decide what to do with a file of `file_version`, failing if it is rejected.
+    pub(crate) fn check_data_version(
+        &self,
+        path: &Path,
+        file_version: [u8; 3],
+    ) -> io::Result<VersionDecision> {
+        let requested = self.data_contained_version;
+        let decision = match (file_version == requested, &self.version_policy) {
+            (true, _) => VersionDecision::Accept,
+            (false, Some(VersionPolicy(policy))) => policy(file_version, requested),
+            (false, None) => VersionDecision::Reject,
+        };
+
+        match decision {
+            VersionDecision::Reject => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "File `{:?}`: Data version {:?} is not accepted for data version {:?}.",
+                    path, file_version, requested
+                ),
+            )),
+            decision => Ok(decision),
+        }
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// The version that the file had when it was opened for migration, until the migration
+    /// is [finished](MmapedVec::finish_migration).
+    pub fn migrating_from(&self) -> Option<[u8; 3]> {
+        self.migration.map(|(from, _)| from)
+    }
+
+    /// Give the file the `data_contained_version` it was opened with, once its elements have
+    /// been migrated, and flush. Does nothing unless opened for migration.
+    pub fn finish_migration(&mut self) -> io::Result<()> {
+        let to = match self.migration {
+            Some((_, to)) => to,
+            None => return Ok(()),
+        };
+
+        self.set_writable(true)?;
+        self.mm[OFFSET_DATA_CONTAINED_VERSION..][..3].copy_from_slice(&to);
+        self.set_writable(false)?;
+        self.flush()?;
+
+        self.migration = None;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_MAGIC_BYTES};
+
+    #[test]
+    pub fn test_version_policy() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut mv =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, [1, 2, 0]).try_open::<u32>(&path)?;
+        mv.push(1)?;
+        drop(mv);
+
+        let mut builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, [1, 3, 0]);
+        let err = builder.try_open::<u32>(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        builder.version_policy(|file, requested| match (file[0] == requested[0], file[1]) {
+            (true, 2) => VersionDecision::Migrate,
+            (true, _) => VersionDecision::Accept,
+            (false, _) => VersionDecision::Reject,
+        });
+        let mut mv = builder.try_open::<u32>(&path)?;
+        assert_eq!(mv.migrating_from(), Some([1, 2, 0]));
+        mv[0] = 2;
+        mv.finish_migration()?;
+        assert_eq!((mv.migrating_from(), mv.data_version()), (None, [1, 3, 0]));
+        drop(mv);
+
+        let mv = builder.try_open::<u32>(&path)?;
+        assert_eq!((mv.migrating_from(), mv[0]), (None, 2));
+        drop(mv);
+
+        assert!(MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, [2, 0, 0])
+            .version_policy(|_, _| VersionDecision::Reject)
+            .try_open::<u32>(&path)
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,835 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! WAL mode, in which each flush first appends the elements that it commits to a log next
+//! to the file, so that the state of the file as of any past flush can be reconstructed
+//! with [`open_at_generation`](MmapedVecBuilder::open_at_generation).
+//!
+//! The log starts with `WAL_MAGIC` and the size of the elements as a `u64`, followed by
+//! records in native byte order. A data record is `RECORD_DATA`, the index of the first
+//! element, the number of bytes and the bytes. A commit record is `RECORD_COMMIT`, the
+//! generation and the number of elements, and commits the data records before it.
+//!
+//! A checkpoint record is `RECORD_CHECKPOINT`, the generation and zero, and is appended once
+//! a flush has written the elements back to the file, so that recovery after a crash need
+//! not replay the records before it. An unlogged record is `RECORD_UNLOGGED`, the index of
+//! the first element and the number of elements, whose contents the log can no longer tell,
+//! as when the file is opened for writing without WAL mode, until they are logged again.
+
+use crate::locking;
+use crate::{check_element_type, MmapedVec, MmapedVecBuilder};
+use memmap::{Mmap, MmapMut};
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::iter;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, Range};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::slice;
+
+/// Suffix of the log that is kept next to files opened in WAL mode.
+pub const WAL_SUFFIX: &str = ".wal";
+
+pub(crate) const WAL_MAGIC: [u8; 8] = *b"PERSWAL\0";
+pub(crate) const WAL_HEADER_LEN: usize = 16;
+const RECORD_DATA: u8 = 1;
+const RECORD_COMMIT: u8 = 2;
+const RECORD_CHECKPOINT: u8 = 3;
+const RECORD_UNLOGGED: u8 = 4;
+
+/// Number of past generations that the log keeps at least, unless set with
+/// [`wal_history`](MmapedVecBuilder::wal_history).
+pub(crate) const DEFAULT_WAL_HISTORY: u64 = 64;
+
+/// Path of the log of the file at `path`.
+pub fn wal_path(path: &Path) -> PathBuf {
+    let mut wal_path = OsString::from(path.as_os_str());
+    wal_path.push(WAL_SUFFIX);
+    PathBuf::from(wal_path)
+}
+
+pub(crate) enum Record {
+    Data { start: usize, bytes: Range<usize> },
+    Commit { generation: u64, len: usize },
+    Checkpoint { generation: u64 },
+    Unlogged { elems: Range<usize> },
+}
+
+/// The records of a log, and how far into it the last complete record other than a data
+/// record ends. Data records after that are left out.
+pub(crate) fn parse(buf: &[u8], elem_size: usize, path: &Path) -> io::Result<(Vec<Record>, usize)> {
+    if buf.len() < WAL_HEADER_LEN
+        || buf[..8] != WAL_MAGIC
+        || u64::from_ne_bytes(buf[8..16].try_into().unwrap()) != elem_size as u64
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "File `{:?}`: Not a log of elements of {} bytes.",
+                path, elem_size
+            ),
+        ));
+    }
+
+    let u64_at = |pos: usize| -> Option<u64> {
+        Some(u64::from_ne_bytes(
+            buf.get(pos..pos + 8)?.try_into().unwrap(),
+        ))
+    };
+
+    let mut records = vec![];
+    let mut complete = 0;
+    let mut committed = WAL_HEADER_LEN;
+    let mut pos = WAL_HEADER_LEN;
+
+    // NOTE: Anything after the last complete commit record was torn by a crash.
+    while pos < buf.len() {
+        let (a, b) = match (u64_at(pos + 1), u64_at(pos + 9)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => break,
+        };
+        match buf[pos] {
+            RECORD_DATA => {
+                let bytes = pos + 17..(pos + 17).saturating_add(b as usize);
+                if bytes.end > buf.len() {
+                    break;
+                }
+                pos = bytes.end;
+                records.push(Record::Data {
+                    start: a as usize,
+                    bytes,
+                });
+                continue;
+            }
+            RECORD_COMMIT => records.push(Record::Commit {
+                generation: a,
+                len: b as usize,
+            }),
+            RECORD_CHECKPOINT => records.push(Record::Checkpoint { generation: a }),
+            RECORD_UNLOGGED => records.push(Record::Unlogged {
+                elems: a as usize..(a as usize).saturating_add(b as usize),
+            }),
+            _ => break,
+        }
+        pos += 17;
+        committed = pos;
+        complete = records.len();
+    }
+    records.truncate(complete);
+
+    Ok((records, committed))
+}
+
+/// Map the log in `file`, so that only the headers of the records need to be paged in to
+/// parse it, rather than reading all of it. `None` if it is empty, as that cannot be mapped.
+fn map(file: &File) -> io::Result<Option<Mmap>> {
+    match file.metadata()?.len() {
+        0 => Ok(None),
+        _ => Ok(Some(unsafe { Mmap::map(file)? })),
+    }
+}
+
+/// The ranges of elements that the log cannot tell the contents of, as of the records that
+/// have been [gone through](Unlogged::record).
+#[derive(Default)]
+struct Unlogged(Vec<Range<usize>>);
+
+impl Unlogged {
+    fn record(&mut self, record: &Record, elem_size: usize) {
+        match record {
+            Record::Data { start, bytes } => self.remove(*start..start + bytes.len() / elem_size),
+            Record::Commit { len, .. } => self.remove(*len..usize::MAX),
+            Record::Checkpoint { .. } => {}
+            Record::Unlogged { elems } => self.0.push(elems.clone()),
+        }
+    }
+
+    fn remove(&mut self, logged: Range<usize>) {
+        self.0 = self
+            .0
+            .iter()
+            .flat_map(|r| {
+                [
+                    r.start..r.end.min(logged.start),
+                    r.start.max(logged.end)..r.end,
+                ]
+            })
+            .filter(|r| r.start < r.end)
+            .collect();
+    }
+}
+
+/// The elements as of the commit that ends `records`, replayed from `log` into memory,
+/// along with the ranges of them that the log cannot tell the contents of.
+fn reconstruct(
+    log: &[u8],
+    records: &[Record],
+    elem_size: usize,
+    path: &Path,
+) -> io::Result<(MmapMut, usize, Vec<Range<usize>>)> {
+    let capacity = replay_extent(records, elem_size).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("File `{:?}`: Records out of bounds.", path),
+        )
+    })?;
+
+    let mut mm = MmapMut::map_anon(capacity.max(1))?;
+    let mut len = 0;
+    let mut unlogged = Unlogged::default();
+    for record in records {
+        unlogged.record(record, elem_size);
+        match record {
+            Record::Data { start, bytes } => mm[start * elem_size..start * elem_size + bytes.len()]
+                .copy_from_slice(&log[bytes.clone()]),
+            Record::Commit { len: l, .. } => len = *l,
+            _ => {}
+        }
+    }
+
+    Ok((mm, len, unlogged.0))
+}
+
+/// Append `record`, whose data is in `log`, to `buf`.
+fn encode_record(record: &Record, log: &[u8], buf: &mut Vec<u8>) {
+    let (kind, a, b) = match record {
+        Record::Data { start, bytes } => (RECORD_DATA, *start, bytes.len()),
+        Record::Commit { generation, len } => (RECORD_COMMIT, *generation as usize, *len),
+        Record::Checkpoint { generation } => (RECORD_CHECKPOINT, *generation as usize, 0),
+        Record::Unlogged { elems } => (RECORD_UNLOGGED, elems.start, elems.end - elems.start),
+    };
+    buf.push(kind);
+    buf.extend_from_slice(&(a as u64).to_ne_bytes());
+    buf.extend_from_slice(&(b as u64).to_ne_bytes());
+    if let Record::Data { bytes, .. } = record {
+        buf.extend_from_slice(&log[bytes.clone()]);
+    }
+}
+
+/// Write the elements logged since the last checkpoint of the log of the file at `path` back
+/// to `file`, whose body starts `header_len` bytes in, and put back the length as of the last
+/// commit, after a crash. Returns the number of bytes written back.
+pub(crate) fn recover(
+    path: &Path,
+    file: &File,
+    header_len: u64,
+    elem_size: usize,
+) -> io::Result<u64> {
+    let wal_path = wal_path(path);
+    let log = match File::open(&wal_path) {
+        Ok(log) => log,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let log = match map(&log)? {
+        Some(log) => log,
+        None => return Ok(0),
+    };
+    let (records, _) = parse(&log, elem_size, &wal_path)?;
+
+    // NOTE: The file holds the elements logged before the last checkpoint already. After
+    //       an unlogged record, it was last written to without WAL mode, and holds newer
+    //       elements than the log does.
+    let from = match records
+        .iter()
+        .rposition(|record| matches!(record, Record::Checkpoint { .. } | Record::Unlogged { .. }))
+    {
+        Some(i) if matches!(records[i], Record::Unlogged { .. }) => return Ok(0),
+        Some(i) => i + 1,
+        None => 0,
+    };
+
+    let mut replayed = 0;
+    for record in &records[from..] {
+        if let Record::Data { start, bytes } = record {
+            file.write_all_at(&log[bytes.clone()], header_len + (start * elem_size) as u64)?;
+            replayed += bytes.len() as u64;
+        }
+    }
+    let len = records.iter().rev().find_map(|record| match record {
+        Record::Commit { len, .. } => Some(*len),
+        _ => None,
+    });
+    if let Some(len) = len {
+        file.set_len(header_len + (len * elem_size) as u64)?;
+    }
+    file.sync_data()?;
+
+    Ok(replayed)
+}
+
+/// Note in the log of the file at `path`, if it has one, that the file is open for writing
+/// without WAL mode, so that none of its elements are taken from the log until they have
+/// been logged again, as they are when it is next opened in WAL mode.
+pub(crate) fn mark_unlogged(path: &Path, elem_size: usize) -> io::Result<()> {
+    let wal_path = wal_path(path);
+    let mut file = match OpenOptions::new().read(true).append(true).open(&wal_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let committed = match map(&file)? {
+        Some(log) => match parse(&log, elem_size, &wal_path)? {
+            (records, _) if matches!(records.last(), Some(Record::Unlogged { .. })) => {
+                return Ok(())
+            }
+            (_, committed) => committed,
+        },
+        None => return Ok(()),
+    };
+    file.set_len(committed as u64)?;
+
+    let mut buf = vec![];
+    let unlogged = Record::Unlogged {
+        elems: 0..usize::MAX,
+    };
+    encode_record(&unlogged, &[], &mut buf);
+    file.write_all(&buf)?;
+    file.sync_data()
+}
+
+/// The open log of a file in WAL mode, and the ranges of elements modified since the last
+/// commit.
+pub(crate) struct Wal {
+    path: PathBuf,
+    file: File,
+    elem_size: usize,
+    history: u64,
+    first_generation: u64,
+    generation: u64,
+    checkpointed: Option<u64>,
+    len: usize,
+    pending: Vec<Range<usize>>,
+}
+
+impl Wal {
+    /// Open the log of the file at `path`, whose body is `body`, creating it with the whole
+    /// body as generation zero if there is none. Keeps at least `history` past generations.
+    pub(crate) fn open(
+        path: &Path,
+        elem_size: usize,
+        history: u64,
+        body: &[u8],
+    ) -> io::Result<Self> {
+        let wal_path = wal_path(path);
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&wal_path)?;
+
+        let len = body.len() / elem_size;
+        let log = map(&file)?;
+
+        let mut wal = Self {
+            path: wal_path,
+            file,
+            elem_size,
+            history,
+            first_generation: 0,
+            generation: 0,
+            checkpointed: None,
+            len,
+            pending: vec![],
+        };
+
+        let log = match log {
+            Some(log) => log,
+            None => {
+                let mut header = WAL_MAGIC.to_vec();
+                header.extend_from_slice(&(elem_size as u64).to_ne_bytes());
+                wal.file.write_all(&header)?;
+
+                wal.modified(0..len);
+                wal.write_commit(body, len, 0)?;
+                return Ok(wal);
+            }
+        };
+
+        let (records, committed) = parse(&log, elem_size, &wal.path)?;
+        let mut unlogged = Unlogged::default();
+        let mut first_generation = None;
+        wal.len = 0;
+        for record in &records {
+            unlogged.record(record, elem_size);
+            match record {
+                Record::Commit { generation, len } => {
+                    first_generation.get_or_insert(*generation);
+                    wal.generation = *generation;
+                    wal.len = *len;
+                }
+                Record::Checkpoint { generation } => wal.checkpointed = Some(*generation),
+                _ => {}
+            }
+        }
+        wal.first_generation = first_generation.unwrap_or(0);
+        drop(log);
+        wal.file.set_len(committed as u64)?;
+
+        // NOTE: Elements written while not in WAL mode are logged again as a new generation,
+        //       and so is the whole body if it changed length, as that was not logged either.
+        //       Modifications in place that were not marked modified go unnoticed.
+        if wal.len != len {
+            wal.modified(0..len);
+        }
+        for range in unlogged.0 {
+            wal.modified(range.start..range.end.min(len));
+        }
+        wal.commit(body)?;
+        Ok(wal)
+    }
+
+    pub(crate) fn history(&self) -> u64 {
+        self.history
+    }
+
+    pub(crate) fn modified(&mut self, range: Range<usize>) {
+        if range.start < range.end {
+            self.pending.push(range);
+        }
+    }
+
+    /// Log the pending modifications of `body` as a new generation, if there are any.
+    pub(crate) fn commit(&mut self, body: &[u8]) -> io::Result<()> {
+        let len = body.len() / self.elem_size;
+
+        if self.pending.is_empty() && len == self.len {
+            return Ok(());
+        }
+
+        self.write_commit(body, len, self.generation + 1)
+    }
+
+    fn write_commit(&mut self, body: &[u8], len: usize, generation: u64) -> io::Result<()> {
+        let ranges = mem::take(&mut self.pending);
+        let buf = encode_commit(body, self.elem_size, ranges, len, generation);
+
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+
+        self.generation = generation;
+        self.len = len;
+        Ok(())
+    }
+
+    /// Note that the file holds the elements as of the last commit, once a flush has written
+    /// them back, and drop the oldest generations once there are twice as many as are kept.
+    pub(crate) fn checkpoint(&mut self) -> io::Result<()> {
+        if self.checkpointed == Some(self.generation) {
+            return Ok(());
+        }
+
+        let mut buf = vec![];
+        let checkpoint = Record::Checkpoint {
+            generation: self.generation,
+        };
+        encode_record(&checkpoint, &[], &mut buf);
+        // NOTE: Not synced, as losing it only makes recovery replay more than it needs to.
+        self.file.write_all(&buf)?;
+        self.checkpointed = Some(self.generation);
+
+        if self.generation - self.first_generation > self.history.saturating_mul(2) {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the log with the elements as of the oldest generation that is kept as its
+    /// first commit, followed by the records after that.
+    fn compact(&mut self) -> io::Result<()> {
+        let oldest = self.generation - self.history;
+        let log = match map(&self.file)? {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let (records, _) = parse(&log, self.elem_size, &self.path)?;
+        let end = match records.iter().position(
+            |record| matches!(record, Record::Commit { generation, .. } if *generation == oldest),
+        ) {
+            Some(end) => end,
+            None => return Ok(()),
+        };
+
+        // NOTE: Should the log not tell all the elements as of that generation, it is kept
+        //       as it is, rather than have their history look complete.
+        let (state, len, unlogged) =
+            reconstruct(&log, &records[..=end], self.elem_size, &self.path)?;
+        if !unlogged.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf = WAL_MAGIC.to_vec();
+        buf.extend_from_slice(&(self.elem_size as u64).to_ne_bytes());
+        let whole = iter::once(0..len).collect();
+        buf.extend(encode_commit(&state, self.elem_size, whole, len, oldest));
+        for record in &records[end + 1..] {
+            encode_record(record, &log, &mut buf);
+        }
+
+        // NOTE: Written aside and renamed into place, so that a crash leaves either log.
+        let mut tmp_name = OsString::from(self.path.as_os_str());
+        tmp_name.push(".tmp");
+        let tmp = PathBuf::from(tmp_name);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&tmp)?;
+        locking::try_lock_exclusive(&file, &tmp)?;
+        file.set_len(0)?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.file = file;
+        self.first_generation = oldest;
+        Ok(())
+    }
+}
+
+/// The records that commit the elements of `body` in `ranges` as `generation`, with `len`
+/// elements. Overlapping ranges are logged once, and elements past `len` not at all.
+pub(crate) fn encode_commit(
+    body: &[u8],
+    elem_size: usize,
+    mut ranges: Vec<Range<usize>>,
+    len: usize,
+    generation: u64,
+) -> Vec<u8> {
+    ranges.sort_by_key(|range| range.start);
+
+    let mut buf = vec![];
+    let mut next = 0;
+    for range in ranges {
+        let range = range.start.max(next)..range.end.min(len);
+        if range.start >= range.end {
+            continue;
+        }
+        next = range.end;
+
+        let bytes = &body[range.start * elem_size..range.end * elem_size];
+        buf.push(RECORD_DATA);
+        buf.extend_from_slice(&(range.start as u64).to_ne_bytes());
+        buf.extend_from_slice(&(bytes.len() as u64).to_ne_bytes());
+        buf.extend_from_slice(bytes);
+    }
+    buf.push(RECORD_COMMIT);
+    buf.extend_from_slice(&generation.to_ne_bytes());
+    buf.extend_from_slice(&(len as u64).to_ne_bytes());
+    buf
+}
+
+/// The number of bytes that replaying `records` writes up to, or `None` if a record lies
+/// beyond what can be addressed, as only a corrupt log would have.
+pub(crate) fn replay_extent(records: &[Record], elem_size: usize) -> Option<usize> {
+    records.iter().try_fold(0, |extent: usize, record| {
+        let end = match record {
+            Record::Data { start, bytes } => {
+                start.checked_mul(elem_size)?.checked_add(bytes.len())?
+            }
+            Record::Commit { len, .. } => len.checked_mul(elem_size)?,
+            Record::Checkpoint { .. } | Record::Unlogged { .. } => 0,
+        };
+        Some(extent.max(end))
+    })
+}
+
+impl MmapedVecBuilder {
+    /// Keep a log of each flush next to the file, for
+    /// [`open_at_generation`](MmapedVecBuilder::open_at_generation). Off by default.
+    ///
+    /// Modifications made through dereferencing the [`MmapedVec`](MmapedVec) mutably are only
+    /// logged once recorded with [`mark_modified`](MmapedVec::mark_modified), which a
+    /// [`WriteGuard`](crate::WriteGuard) does for the elements it covers when dropped.
+    ///
+    /// After a crash, the elements logged since the last flush that wrote them back are
+    /// written back again, and the length as of the last flush is put back, whether or not
+    /// the file is opened in WAL mode then. Opening the file without WAL mode while it has a
+    /// log makes the whole body be logged again when it is next opened in WAL mode.
+    pub fn wal(&mut self, wal: bool) -> &mut Self {
+        self.wal = wal;
+        self
+    }
+
+    /// Keep at least `generations` past generations in the log for
+    /// [`open_at_generation`](MmapedVecBuilder::open_at_generation), dropping the older ones
+    /// on flush once there are twice as many. 64 by default.
+    pub fn wal_history(&mut self, generations: u64) -> &mut Self {
+        self.wal_history = generations;
+        self
+    }
+
+    /// Reconstruct the elements of the file at `path` as of the flush that made `generation`,
+    /// from its log, into a read-only mapping of memory.
+    ///
+    /// Fails with [`InvalidData`](io::ErrorKind::InvalidData) if any of the elements had
+    /// been written to without being logged, as when the file was opened without WAL mode,
+    /// and not logged again since, as of that generation.
+    pub fn open_at_generation<T>(
+        &self,
+        path: &Path,
+        generation: u64,
+    ) -> io::Result<WalSnapshot<T>> {
+        check_element_type::<T>(path)?;
+
+        let wal_path = wal_path(path);
+        let size = mem::size_of::<T>();
+        let log = map(&File::open(&wal_path)?)?;
+        let log = log.as_deref().unwrap_or(&[]);
+        let (records, _) = parse(log, size, &wal_path)?;
+
+        let end = records
+            .iter()
+            .position(
+                |record| matches!(record, Record::Commit { generation: g, .. } if *g == generation),
+            )
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "File `{:?}`: No generation {} in the log.",
+                        wal_path, generation
+                    ),
+                )
+            })?;
+
+        let (mm, len, unlogged) = reconstruct(log, &records[..=end], size, &wal_path)?;
+        if let Some(range) = unlogged.first() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "File `{:?}`: Elements {}..{} were written without being logged, as of \
+          generation {}.",
+                    wal_path, range.start, range.end, generation
+                ),
+            ));
+        }
+
+        Ok(WalSnapshot {
+            mm: mm.make_read_only()?,
+            len,
+            generation,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// The elements of a file as of a past generation, as reconstructed by
+/// [`open_at_generation`](MmapedVecBuilder::open_at_generation).
+pub struct WalSnapshot<T> {
+    mm: Mmap,
+    len: usize,
+    generation: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T> WalSnapshot<T> {
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl<T> Deref for WalSnapshot<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.mm.as_ptr() as *const T, self.len) }
+    }
+}
+
+impl<T> MmapedVec<T> {
+    /// The generation that the last flush was logged as, or `None` unless opened in
+    /// [`wal`](MmapedVecBuilder::wal) mode.
+    pub fn wal_generation(&self) -> Option<u64> {
+        self.wal.as_ref().map(|wal| wal.generation)
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::{recovery, FlushMode, Store};
+
+    #[test]
+    pub fn test_open_at_generation() -> Result<(), io::Error> {
+        let dir = tempfile::tempdir()?;
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        builder.wal(true);
+        let store = Store::open(dir.path())?;
+        let path = store.path("values")?;
+
+        let mut mv = store.open_vec::<u32>(&builder, "values")?;
+        mv.extend([1, 2, 3])?;
+        assert_eq!(mv.wal_generation(), Some(0));
+        mv.flush()?;
+        mv.flush()?;
+        assert_eq!(mv.wal_generation(), Some(1));
+
+        mv[0] = 10;
+        mv.mark_modified(0..1);
+        mv.push(4)?;
+        mv.flush()?;
+        mv.truncate(2)?;
+        drop(mv);
+
+        let mv = store.open_vec::<u32>(&builder, "values")?;
+        assert_eq!(mv.wal_generation(), Some(3));
+        drop(mv);
+
+        let at = |generation| -> io::Result<Vec<u32>> {
+            Ok(builder
+                .open_at_generation::<u32>(&path, generation)?
+                .to_vec())
+        };
+        assert_eq!(at(0)?, Vec::<u32>::new());
+        assert_eq!(at(1)?, vec![1, 2, 3]);
+        assert_eq!(at(2)?, vec![10, 2, 3, 4]);
+        assert_eq!(at(3)?, vec![10, 2]);
+        assert!(at(4).is_err());
+
+        assert_eq!(store.names()?, vec!["values"]);
+        drop(store);
+        Store::open(dir.path())?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_wal_recovery() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        builder.wal(true).flush_mode(FlushMode::Async);
+
+        let mut mv = builder.try_open::<u32>(&path)?;
+        mv.extend([1, 2, 3])?;
+        mv.flush()?;
+        mv.write_guard()?[0] = 10;
+        drop(mv);
+
+        // Simulate a crash before the elements were written back, after appending another.
+        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+        recovery::set_dirty(&file, true)?;
+        let flen = file.metadata()?.len();
+        file.write_all_at(&1u32.to_ne_bytes(), flen - 12)?;
+        file.set_len(flen + 4)?;
+        drop(file);
+
+        let mv = builder.try_open::<u32>(&path)?;
+        assert!(mv.recovered_from_crash().unwrap().replayed_bytes > 0);
+        assert_eq!(&mv[..], &[10, 2, 3]);
+        drop(mv);
+
+        // Elements written without WAL mode are newer than those in the log.
+        let mut mv = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+            .try_open::<u32>(&path)?;
+        mv[1] = 20;
+        drop(mv);
+        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+        recovery::set_dirty(&file, true)?;
+        drop(file);
+
+        let mv = builder.try_open::<u32>(&path)?;
+        assert_eq!(mv.recovered_from_crash().unwrap().replayed_bytes, 0);
+        assert_eq!(&mv[..], &[10, 20, 3]);
+        let generation = mv.wal_generation().unwrap();
+        drop(mv);
+        assert_eq!(
+            builder
+                .open_at_generation::<u32>(&path, generation)?
+                .to_vec(),
+            vec![10, 20, 3]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_open_at_generation_refuses_unlogged() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        builder.wal(true);
+
+        let mut mv = builder.try_open::<u32>(&path)?;
+        mv.extend([1, 2, 3])?;
+        drop(mv);
+        drop(
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION)
+                .try_open::<u32>(&path)?,
+        );
+
+        // A generation committed after the file was written to without WAL mode, by a writer
+        // that did not log the elements again.
+        let mut log = OpenOptions::new().append(true).open(wal_path(&path))?;
+        log.write_all(&encode_commit(&[], 4, vec![], 3, 2))?;
+        drop(log);
+
+        assert_eq!(
+            builder.open_at_generation::<u32>(&path, 1)?.to_vec(),
+            vec![1, 2, 3]
+        );
+        let e = builder.open_at_generation::<u32>(&path, 2).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let mv = builder.try_open::<u32>(&path)?;
+        assert_eq!(mv.wal_generation(), Some(3));
+        drop(mv);
+        assert_eq!(
+            builder.open_at_generation::<u32>(&path, 3)?.to_vec(),
+            vec![1, 2, 3]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_wal_history() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let mut builder =
+            MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        builder.wal(true).wal_history(2);
+
+        let mut mv = builder.try_open::<u32>(&path)?;
+        for i in 0..10 {
+            mv.push(i)?;
+            mv.flush()?;
+        }
+        assert_eq!(mv.wal_generation(), Some(10));
+        let log_len = fs::metadata(wal_path(&path))?.len();
+        drop(mv);
+
+        let at = |generation| builder.open_at_generation::<u32>(&path, generation);
+        assert_eq!(at(5).err().unwrap().kind(), io::ErrorKind::NotFound);
+        assert_eq!(at(6)?.to_vec(), (0..6).collect::<Vec<_>>());
+        assert_eq!(at(10)?.to_vec(), (0..10).collect::<Vec<_>>());
+        assert!(log_len < 10 * (17 + 4 + 17 + 17));
+
+        let mut mv = builder.try_open::<u32>(&path)?;
+        assert_eq!(mv.wal_generation(), Some(10));
+        mv.push(10)?;
+        mv.flush()?;
+        assert_eq!(mv.wal_generation(), Some(11));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Waiting with inotify for the writer of a file to change it, for
+//! [`OptimisticReader`](crate::OptimisticReader)s and
+//! [`UnlockedReader`](crate::UnlockedReader)s that would otherwise poll for it.
+//!
+//! Writes through a mapping make no inotify events, so a writer built with the `watch`
+//! feature touches the modification time of the file each time it flushes. A watch wakes
+//! on that, on the file being resized, and on it being renamed, unlinked or replaced, as
+//! when it is [rolled](crate::MmapedVec::roll).
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::time::Duration;
+
+const WATCH_MASK: u32 =
+    libc::IN_MODIFY | libc::IN_ATTRIB | libc::IN_MOVE_SELF | libc::IN_DELETE_SELF;
+
+/// A watch on the file that a path named when the watch was made, as returned by
+/// [`OptimisticReader::watch`](crate::OptimisticReader::watch) and
+/// [`UnlockedReader::watch`](crate::UnlockedReader::watch).
+///
+/// Its file descriptor can also be polled for readability along with others, and
+/// [`wait`](Watch::wait) called with a zero timeout once it is readable.
+pub struct Watch {
+    inotify: File,
+}
+
+impl Watch {
+    pub(crate) fn new(path: &Path) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let inotify = unsafe { File::from_raw_fd(fd) };
+
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        if unsafe { libc::inotify_add_watch(fd, c_path.as_ptr(), WATCH_MASK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { inotify })
+    }
+
+    /// Sleep until the file changes, or until `timeout` has passed. Returns whether it
+    /// changed, rather than timed out. Changes since the last call count, so that none
+    /// are missed in between calls.
+    ///
+    /// A change does not mean that the elements read differently, only that they may. Once
+    /// the path names a different file, as told by `is_replaced`, reopen the reader and
+    /// watch it anew, as this watch stays with the old file.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> io::Result<bool> {
+        let timeout = match timeout {
+            Some(t) => t.as_millis().min(libc::c_int::MAX as u128) as libc::c_int,
+            None => -1,
+        };
+        let mut pfd = libc::pollfd {
+            fd: self.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            match unsafe { libc::poll(&mut pfd, 1, timeout) } {
+                0 => return Ok(false),
+                n if n > 0 => break,
+                _ => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        // NOTE: The events themselves are not looked at, only drained, so that the next
+        //       call sleeps until the next change.
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.inotify.read(&mut buf) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl AsRawFd for Watch {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
+
+/// Touch the modification time of `file`, so that watches on it wake.
+pub(crate) fn touch(file: &File) -> io::Result<()> {
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_NOW,
+        },
+    ];
+    match unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::MmapedVecBuilder;
+
+    #[test]
+    pub fn test_watch() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        let mut mv = builder.try_open::<u32>(&path)?;
+        mv.extend([1, 2, 3])?;
+        mv.flush()?;
+
+        let reader = builder.try_open_optimistic::<u32>(&path)?;
+        let unlocked = unsafe { builder.read_unlocked::<u32>(&path)? };
+        let (mut watch, mut unlocked_watch) = (reader.watch()?, unlocked.watch()?);
+        assert!(!watch.wait(Some(std::time::Duration::ZERO))?);
+
+        mv.write_guard()?[0] = 10;
+        assert!(!watch.wait(Some(std::time::Duration::ZERO))?);
+        mv.flush()?;
+        assert!(watch.wait(Some(std::time::Duration::from_secs(10)))?);
+        assert!(unlocked_watch.wait(None)?);
+        assert!(!watch.wait(Some(std::time::Duration::ZERO))?);
+
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            mv.push(4)?;
+            mv.flush()
+        });
+        assert!(watch.wait(Some(std::time::Duration::from_secs(10)))?);
+        writer.join().unwrap()?;
+
+        Ok(())
+    }
+}
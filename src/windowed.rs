@@ -0,0 +1,252 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+use crate::{check_element_type, locking, registry, MmapedVecBuilder};
+use memmap::{Mmap, MmapOptions};
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::slice;
+
+/// Read-only access to the elements of a file that maps no more than a window of
+/// `window_elems` elements of it at a time, for targets where address space or memory is
+/// too scarce to map the whole body.
+///
+/// Takes a shared lock on the file, or on its lock file if it has one, so there can be many
+/// readers at once, but not while a [`MmapedVec`](crate::MmapedVec) has the file open.
+pub struct WindowedReader<T> {
+    path: PathBuf,
+    // NOTE: Must be dropped before the file, like the mapping of a MmapedVec.
+    window: Option<Window>,
+    file: File,
+    _lock_file: Option<File>,
+    header_len: u64,
+    len: usize,
+    window_elems: usize,
+    cursor: usize,
+    _marker: PhantomData<T>,
+}
+
+struct Window {
+    start: usize,
+    len: usize,
+    mm: Mmap,
+}
+
+impl MmapedVecBuilder {
+    /// Open an existing file for reading through a window of `window_elems` elements.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `window_elems` is zero.
+    pub fn try_open_windowed<T>(
+        &self,
+        path: &Path,
+        window_elems: usize,
+    ) -> io::Result<WindowedReader<T>> {
+        assert!(window_elems > 0, "Windows must hold at least one element.");
+
+        check_element_type::<T>(path)?;
+
+        let file = OpenOptions::new().read(true).open(path)?;
+        let lock_file = match registry::is_open_for_sharing(&file, path, self.same_file_policy)? {
+            true => None,
+            false => locking::try_lock_shared_for(&file, path)?,
+        };
+
+        let fh = self.check_existing_file::<T, _>(&file, path)?;
+        let len = (file.metadata()?.len() - fh.header_len) / mem::size_of::<T>() as u64;
+        let len = usize::try_from(len).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "File `{:?}`: Holds {} elements, more than can be indexed on this host.",
+                    path, len
+                ),
+            )
+        })?;
+
+        Ok(WindowedReader {
+            path: path.to_path_buf(),
+            window: None,
+            file,
+            _lock_file: lock_file,
+            header_len: fh.header_len,
+            len,
+            window_elems,
+            cursor: 0,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl<T> WindowedReader<T> {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of elements in the body of the file.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn window_elems(&self) -> usize {
+        self.window_elems
+    }
+
+    /// The window of up to `window_elems` elements starting at `index`, mapping it unless
+    /// that is already mapped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is greater than the length.
+    pub fn window_at(&mut self, index: usize) -> io::Result<&[T]> {
+        assert!(
+            index <= self.len,
+            "Index {} out of bounds for WindowedReader of length {}.",
+            index,
+            self.len
+        );
+
+        let len = self.window_elems.min(self.len - index);
+
+        if len == 0 {
+            return Ok(&[]);
+        }
+
+        if !matches!(&self.window, Some(w) if w.start == index && w.len == len) {
+            // Unmap the old window first, so that there is never more than one.
+            self.window = None;
+
+            let size = mem::size_of::<T>();
+            let window_len = len.checked_mul(size).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "File `{:?}`: A window of {} elements is too large to map.",
+                        self.path, len
+                    ),
+                )
+            })?;
+            let mm = unsafe {
+                MmapOptions::new()
+                    .offset(self.header_len + index as u64 * size as u64)
+                    .len(window_len)
+                    .map(&self.file)?
+            };
+
+            self.window = Some(Window {
+                start: index,
+                len,
+                mm,
+            });
+        }
+
+        let w = self.window.as_ref().unwrap();
+        Ok(unsafe { slice::from_raw_parts(w.mm.as_ptr() as *const T, w.len) })
+    }
+
+    /// The element at `index`, which is read through the current window if it holds the
+    /// element, or else through a new window starting at `index`.
+    pub fn get(&mut self, index: usize) -> io::Result<Option<&T>> {
+        if index >= self.len {
+            return Ok(None);
+        }
+
+        let start = match &self.window {
+            Some(w) if (w.start..w.start + w.len).contains(&index) => w.start,
+            _ => index,
+        };
+
+        Ok(self.window_at(start)?.get(index - start))
+    }
+
+    /// The next window when streaming through the file from start to end, or `None` once
+    /// all elements have been seen.
+    pub fn next_window(&mut self) -> io::Result<Option<&[T]>> {
+        if self.cursor >= self.len {
+            return Ok(None);
+        }
+
+        let start = self.cursor;
+        self.cursor = self.len.min(start + self.window_elems);
+        self.window_at(start).map(Some)
+    }
+
+    /// Start streaming from the start of the file again.
+    pub fn rewind(&mut self) {
+        self.cursor = 0;
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{
+        new_mmaped_vec_of_example_persisting_in_tempdir, Example, EXAMPLE_DATA_CONTAINED_VERSION,
+        EXAMPLE_MAGIC_BYTES,
+    };
+    use crate::MmapedVec;
+
+    #[test]
+    pub fn test_windowed_reader() -> Result<(), io::Error> {
+        let (_dir, pathbuf, mut mv) = new_mmaped_vec_of_example_persisting_in_tempdir()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+
+        mv.extend((0..10000).map(|i| Example {
+            hello: i as u8,
+            world: (i / 256) as u8,
+        }))?;
+
+        assert!(builder
+            .try_open_windowed::<Example>(pathbuf.as_path(), 1000)
+            .is_err());
+        drop(mv);
+
+        let mut reader = builder.try_open_windowed::<Example>(pathbuf.as_path(), 3000)?;
+        assert_eq!(reader.len(), 10000);
+
+        let mut seen = 0;
+        while let Some(window) = reader.next_window()? {
+            for (i, e) in window.iter().enumerate() {
+                assert_eq!(e.hello, (seen + i) as u8);
+            }
+            seen += window.len();
+        }
+        assert_eq!(seen, 10000);
+
+        let e = reader.get(9999)?.unwrap();
+        assert_eq!((e.hello, e.world), (9999u16 as u8, (9999 / 256) as u8));
+        assert!(reader.get(10000)?.is_none());
+        assert_eq!(reader.window_at(9500)?.len(), 500);
+
+        assert!(MmapedVec::<Example>::try_new(
+            pathbuf.as_path(),
+            EXAMPLE_MAGIC_BYTES,
+            EXAMPLE_DATA_CONTAINED_VERSION
+        )
+        .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,296 @@
+/*
+ * Copyright (c) 2019 Erik Nordstrøm <erik@nordstroem.no>
+ *
+ * Permission to use, copy, modify, and/or distribute this software for any
+ * purpose with or without fee is hereby granted, provided that the above
+ * copyright notice and this permission notice appear in all copies.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+ * WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+ * MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+ * ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+ * WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+ * ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+ * OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+ */
+
+//! Zone maps, the smallest and largest key of each chunk of elements, kept in a sidecar
+//! next to the file, so that [`scan_where`](MmapedVec::scan_where) can skip the chunks that
+//! hold no key in range. Scans of time-ordered data, such as telemetry, then only touch the
+//! chunks of the time window they are after.
+//!
+//! The sidecar starts with `ZONES_MAGIC`, and then the number of elements per chunk and the
+//! number of elements covered, as `u64`s in native byte order. Then come the smallest and
+//! largest key of each chunk, as `i64`s.
+
+use crate::MmapedVec;
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::ops::{Bound, Range, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::slice;
+
+/// Suffix of the sidecar that is kept next to files with zone maps.
+pub const ZONES_SUFFIX: &str = ".zones";
+
+pub(crate) const ZONES_MAGIC: [u8; 8] = *b"PERSZONE";
+const ZONES_HEADER_LEN: usize = 24;
+
+/// Path of the zone map sidecar of the file at `path`.
+pub fn zones_path(path: &Path) -> PathBuf {
+    let mut zones_path = OsString::from(path.as_os_str());
+    zones_path.push(ZONES_SUFFIX);
+    PathBuf::from(zones_path)
+}
+
+type KeyFn<T> = dyn Fn(&T) -> i64 + Send;
+
+/// The smallest and largest key of each chunk of a file, and how to get the key of an
+/// element.
+pub(crate) struct ZoneMap<T> {
+    path: PathBuf,
+    chunk_elems: usize,
+    zones: Vec<(i64, i64)>,
+    /// The zones are of the elements before this index.
+    covered: usize,
+    committed: bool,
+    key: Box<KeyFn<T>>,
+}
+
+impl<T> ZoneMap<T> {
+    /// Recompute the zones of the chunks that overlap `range`.
+    fn recompute(&mut self, range: Range<usize>, body: &[T]) {
+        let end = range.end.min(self.covered);
+        if range.start >= end {
+            return;
+        }
+        for chunk in range.start / self.chunk_elems..end.div_ceil(self.chunk_elems) {
+            let start = chunk * self.chunk_elems;
+            let elems = &body[start..self.covered.min(start + self.chunk_elems)];
+            let keys = elems.iter().map(|elem| (self.key)(elem));
+            self.zones[chunk] = keys.fold((i64::MAX, i64::MIN), |(min, max), key| {
+                (min.min(key), max.max(key))
+            });
+        }
+        self.committed = false;
+    }
+
+    pub(crate) fn modified(&mut self, range: Range<usize>, body: &[T]) {
+        self.refresh(body);
+        self.recompute(range, body);
+    }
+
+    /// Bring the zones up to date with the length of `body`.
+    fn refresh(&mut self, body: &[T]) {
+        if self.covered == body.len() {
+            return;
+        }
+        let from = self.covered.min(body.len());
+        self.covered = body.len();
+        self.zones
+            .resize(self.covered.div_ceil(self.chunk_elems), (0, 0));
+        // NOTE: The chunk that the length now falls within changed either way.
+        let from = from / self.chunk_elems * self.chunk_elems;
+        self.recompute(from..self.covered, body);
+        self.committed = false;
+    }
+
+    /// Bring the zones up to date with `body`, and write them to the sidecar if they
+    /// changed since it was last written.
+    pub(crate) fn commit(&mut self, body: &[T]) -> io::Result<()> {
+        self.refresh(body);
+        if self.committed {
+            return Ok(());
+        }
+
+        let mut buf = ZONES_MAGIC.to_vec();
+        buf.extend_from_slice(&(self.chunk_elems as u64).to_ne_bytes());
+        buf.extend_from_slice(&(self.covered as u64).to_ne_bytes());
+        for (min, max) in &self.zones {
+            buf.extend_from_slice(&min.to_ne_bytes());
+            buf.extend_from_slice(&max.to_ne_bytes());
+        }
+
+        // NOTE: Written aside and renamed into place, so that a crash leaves either sidecar.
+        let mut tmp_name = OsString::from(self.path.as_os_str());
+        tmp_name.push(".tmp");
+        let tmp = PathBuf::from(tmp_name);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.committed = true;
+        Ok(())
+    }
+}
+
+/// The zones in `buf`, if it is a sidecar with `chunk_elems` elements per chunk that covers
+/// `len` elements.
+fn parse_zones(buf: &[u8], chunk_elems: usize, len: usize) -> Option<Vec<(i64, i64)>> {
+    let field = |i: usize| -> Option<[u8; 8]> { buf.get(i * 8..i * 8 + 8)?.try_into().ok() };
+
+    if buf.get(..8)? != ZONES_MAGIC
+        || u64::from_ne_bytes(field(1)?) != chunk_elems as u64
+        || u64::from_ne_bytes(field(2)?) != len as u64
+        || buf.len() != ZONES_HEADER_LEN + len.div_ceil(chunk_elems) * 16
+    {
+        return None;
+    }
+
+    (0..len.div_ceil(chunk_elems))
+        .map(|i| {
+            let at = ZONES_HEADER_LEN / 8 + 2 * i;
+            Some((
+                i64::from_ne_bytes(field(at)?),
+                i64::from_ne_bytes(field(at + 1)?),
+            ))
+        })
+        .collect()
+}
+
+/// Whether no key between `min` and `max` falls within `bounds`.
+fn excludes(bounds: &(Bound<i64>, Bound<i64>), (min, max): (i64, i64)) -> bool {
+    let below = match bounds.0 {
+        Bound::Included(start) => max < start,
+        Bound::Excluded(start) => max <= start,
+        Bound::Unbounded => false,
+    };
+    let above = match bounds.1 {
+        Bound::Included(end) => min > end,
+        Bound::Excluded(end) => min >= end,
+        Bound::Unbounded => false,
+    };
+    below || above
+}
+
+impl<T> MmapedVec<T> {
+    /// Keep zone maps of the keys that `key` returns for the elements, the smallest and
+    /// largest key of each chunk of `chunk_elems` elements, in a sidecar next to the file that
+    /// is written on each flush. The zone maps are rebuilt from the elements when the sidecar
+    /// does not match, such as after a crash.
+    ///
+    /// Chunks are brought up to date as elements are appended, and when recorded with
+    /// [`mark_modified`](MmapedVec::mark_modified).
+    ///
+    /// NOTE: Modifications made while the file is opened without zone maps go unnoticed,
+    /// unless they change its length, so enable them every time the file is opened.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_elems` is zero.
+    pub fn zone_map<F>(&mut self, chunk_elems: usize, key: F) -> io::Result<()>
+    where
+        F: Fn(&T) -> i64 + Send + 'static,
+    {
+        assert!(chunk_elems > 0, "Chunks must hold at least one element.");
+
+        let path = zones_path(&self.path);
+        let loaded = match fs::read(&path) {
+            Ok(buf) => parse_zones(&buf, chunk_elems, self.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        let mut zones = ZoneMap {
+            path,
+            chunk_elems,
+            zones: vec![],
+            covered: 0,
+            committed: false,
+            key: Box::new(key),
+        };
+
+        match loaded {
+            Some(loaded) if self.recovered_from_crash().is_none() => {
+                zones.zones = loaded;
+                zones.covered = self.len();
+                zones.committed = true;
+            }
+            _ => zones.commit(self)?,
+        }
+
+        self.zones = Some(zones);
+        Ok(())
+    }
+
+    /// Iterate over the elements whose key is within `range`, skipping the chunks whose
+    /// [zone map](MmapedVec::zone_map) holds no key in range. Elements come in the order that
+    /// they are in the file.
+    ///
+    /// # Panics
+    ///
+    /// Panics without [`zone_map`](MmapedVec::zone_map).
+    pub fn scan_where<R>(&mut self, range: R) -> impl Iterator<Item = &T> + '_
+    where
+        R: RangeBounds<i64> + 'static,
+    {
+        let body = unsafe { slice::from_raw_parts(self.as_ptr(), self.len()) };
+        let zones = self
+            .zones
+            .as_mut()
+            .expect("Scanning by key requires a zone map.");
+        zones.refresh(body);
+
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let chunk_elems = zones.chunk_elems;
+        let key = &zones.key;
+        zones
+            .zones
+            .iter()
+            .enumerate()
+            .filter(move |(_, zone)| !excludes(&bounds, **zone))
+            .flat_map(move |(i, _)| {
+                body[i * chunk_elems..body.len().min((i + 1) * chunk_elems)].iter()
+            })
+            .filter(move |elem| bounds.contains(&key(elem)))
+    }
+
+    pub(crate) fn zones_modified(&mut self, range: Range<usize>) {
+        let body = unsafe { slice::from_raw_parts(self.as_ptr(), self.len()) };
+        if let Some(zones) = self.zones.as_mut() {
+            zones.modified(range, body);
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::tests::{tempdir_and_tempfile, EXAMPLE_DATA_CONTAINED_VERSION, EXAMPLE_MAGIC_BYTES};
+    use crate::MmapedVecBuilder;
+
+    #[test]
+    pub fn test_zone_map() -> Result<(), io::Error> {
+        let (_dir, path) = tempdir_and_tempfile()?;
+        let builder = MmapedVecBuilder::new(EXAMPLE_MAGIC_BYTES, EXAMPLE_DATA_CONTAINED_VERSION);
+        let key = |v: &u64| *v as i64;
+
+        let mut mv: MmapedVec<u64> = builder.try_open(&path)?;
+        mv.extend(0..500)?;
+        mv.zone_map(64, key)?;
+        mv.extend(500..1000)?;
+        assert!(mv.scan_where(100..110).copied().eq(100..110));
+        assert!(mv.scan_where(990..).copied().eq(990..1000));
+        mv.flush()?;
+        drop(mv);
+        assert!(zones_path(&path).exists());
+
+        let mut mv: MmapedVec<u64> = builder.try_open(&path)?;
+        mv.zone_map(64, key)?;
+        mv[3] = 2000;
+        mv.mark_modified(3..4);
+        assert!(mv.scan_where(1500..=2000).copied().eq([2000]));
+        mv.truncate(200)?;
+        assert_eq!(mv.scan_where(..).count(), 200);
+        assert_eq!(mv.scan_where(190..300).count(), 10);
+
+        Ok(())
+    }
+}